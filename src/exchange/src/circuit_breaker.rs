@@ -0,0 +1,234 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use strategy_common::debug_log;
+
+use crate::error::*;
+use crate::types::*;
+
+/// Breaker for one venue or pool
+#[derive(Clone, Debug)]
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<u64>,        // Seconds since the epoch
+    probe_started: Option<u64>,    // Set while a half-open probe is in flight
+    last_error: Option<String>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            probe_started: None,
+            last_error: None,
+        }
+    }
+}
+
+thread_local! {
+    // Failures seen by any connector count towards the same breaker, keyed by target
+    static BREAKERS: RefCell<HashMap<BreakerTarget, Breaker>> = RefCell::new(HashMap::new());
+}
+
+/// Whether an error counts against a breaker; other errors mean the target answered
+pub fn trips(error: &ExchangeError) -> bool {
+    matches!(
+        error,
+        ExchangeError::CanisterCallError(_) | ExchangeError::Timeout | ExchangeError::TransactionFailed(_)
+    )
+}
+
+fn describe(target: &BreakerTarget) -> String {
+    match target {
+        BreakerTarget::Venue(id) => format!("venue {}", id),
+        BreakerTarget::Pool(id) => format!("pool {}", id),
+    }
+}
+
+/// Let a call through, or refuse it with `CircuitOpen` while the breaker is open
+///
+/// Once the cool-down has elapsed the breaker turns half-open and admits a single
+/// probe; its outcome, passed to `record`, closes or re-opens the breaker. A probe
+/// that never reports back (e.g. its call context trapped) is abandoned after
+/// another cool-down.
+pub fn allow(config: &CircuitBreakerConfig, target: BreakerTarget, now_secs: u64) -> ExchangeResult<()> {
+    BREAKERS.with(|breakers| {
+        let mut breakers = breakers.borrow_mut();
+        let breaker = match breakers.get_mut(&target) {
+            Some(breaker) => breaker,
+            None => return Ok(()),
+        };
+        let cooled_down = |since: Option<u64>| now_secs >= since.unwrap_or(0).saturating_add(config.open_secs);
+        match breaker.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open if cooled_down(breaker.opened_at) => {
+                debug_log!("Circuit breaker for {} half-open, probing", describe(&target));
+                breaker.state = BreakerState::HalfOpen;
+                breaker.probe_started = Some(now_secs);
+                Ok(())
+            }
+            BreakerState::HalfOpen if breaker.probe_started.is_none() || cooled_down(breaker.probe_started) => {
+                breaker.probe_started = Some(now_secs);
+                Ok(())
+            }
+            BreakerState::Open | BreakerState::HalfOpen => Err(ExchangeError::CircuitOpen(describe(&target))),
+        }
+    })
+}
+
+/// Record the outcome of a call that `allow` let through
+pub fn record<T>(config: &CircuitBreakerConfig, target: BreakerTarget, outcome: &ExchangeResult<T>, now_secs: u64) {
+    BREAKERS.with(|breakers| {
+        let mut breakers = breakers.borrow_mut();
+        let error = match outcome {
+            Err(e) if trips(e) => e,
+            _ => {
+                // The target answered, so any breaker on it closes
+                if let Some(breaker) = breakers.get_mut(&target) {
+                    if breaker.state != BreakerState::Closed {
+                        debug_log!("Circuit breaker for {} closed", describe(&target));
+                    }
+                    *breaker = Breaker::default();
+                }
+                return;
+            }
+        };
+
+        let breaker = breakers.entry(target).or_default();
+        breaker.consecutive_failures = breaker.consecutive_failures.saturating_add(1);
+        breaker.last_error = Some(error.to_string());
+        breaker.probe_started = None;
+        let should_open = breaker.state == BreakerState::HalfOpen
+            || (breaker.state == BreakerState::Closed && breaker.consecutive_failures >= config.failure_threshold.max(1));
+        if should_open {
+            debug_log!(
+                "Circuit breaker for {} opened after {} consecutive failures: {}",
+                describe(&target), breaker.consecutive_failures, error
+            );
+            breaker.state = BreakerState::Open;
+            breaker.opened_at = Some(now_secs);
+        }
+    })
+}
+
+/// Current state of a breaker; targets never seen are closed
+pub fn status(config: &CircuitBreakerConfig, target: BreakerTarget) -> BreakerStatus {
+    BREAKERS.with(|breakers| {
+        let breaker = breakers.borrow().get(&target).cloned().unwrap_or_default();
+        BreakerStatus {
+            target,
+            state: breaker.state,
+            consecutive_failures: breaker.consecutive_failures,
+            opened_at: breaker.opened_at,
+            retry_at: match breaker.state {
+                BreakerState::Open => breaker.opened_at.map(|opened_at| opened_at.saturating_add(config.open_secs)),
+                _ => None,
+            },
+            last_error: breaker.last_error,
+        }
+    })
+}
+
+/// Pool breakers that are not closed
+pub fn tripped_pools(config: &CircuitBreakerConfig) -> Vec<BreakerStatus> {
+    let targets: Vec<BreakerTarget> = BREAKERS.with(|breakers| {
+        breakers
+            .borrow()
+            .iter()
+            .filter(|(target, breaker)| matches!(target, BreakerTarget::Pool(_)) && breaker.state != BreakerState::Closed)
+            .map(|(target, _)| *target)
+            .collect()
+    });
+    targets.into_iter().map(|target| status(config, target)).collect()
+}
+
+/// Breakers currently refusing calls, i.e. open and still cooling down
+///
+/// Strategies check this before an execution cycle so they can skip it instead of
+/// failing part-way through a multi-leg trade.
+pub fn refusing(config: &CircuitBreakerConfig, now_secs: u64) -> Vec<BreakerStatus> {
+    let targets: Vec<BreakerTarget> = BREAKERS.with(|breakers| {
+        breakers
+            .borrow()
+            .iter()
+            .filter(|(_, breaker)| {
+                breaker.state == BreakerState::Open
+                    && now_secs < breaker.opened_at.unwrap_or(0).saturating_add(config.open_secs)
+            })
+            .map(|(target, _)| *target)
+            .collect()
+    });
+    targets.into_iter().map(|target| status(config, target)).collect()
+}
+
+/// Close a breaker by hand, e.g. after an operator confirmed the venue recovered
+pub fn reset(target: BreakerTarget) {
+    BREAKERS.with(|breakers| {
+        breakers.borrow_mut().remove(&target);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig { failure_threshold: 2, open_secs: 60 }
+    }
+
+    fn pool() -> BreakerTarget {
+        BreakerTarget::Pool(Principal::from_slice(&[1]))
+    }
+
+    fn fail(now: u64) {
+        record::<()>(&config(), pool(), &Err(ExchangeError::Timeout), now);
+    }
+
+    #[test]
+    fn opens_after_threshold_and_refuses_until_cool_down() {
+        fail(0);
+        assert_eq!(status(&config(), pool()).state, BreakerState::Closed);
+        fail(1);
+        assert_eq!(status(&config(), pool()).state, BreakerState::Open);
+        assert!(matches!(allow(&config(), pool(), 30), Err(ExchangeError::CircuitOpen(_))));
+        assert_eq!(refusing(&config(), 30).len(), 1);
+    }
+
+    #[test]
+    fn half_open_admits_one_probe() {
+        fail(0);
+        fail(0);
+        assert!(allow(&config(), pool(), 60).is_ok());
+        assert_eq!(status(&config(), pool()).state, BreakerState::HalfOpen);
+        assert!(allow(&config(), pool(), 61).is_err());
+    }
+
+    #[test]
+    fn probe_success_closes_and_failure_reopens() {
+        fail(0);
+        fail(0);
+        allow(&config(), pool(), 60).unwrap();
+        fail(61);
+        let reopened = status(&config(), pool());
+        assert_eq!(reopened.state, BreakerState::Open);
+        assert_eq!(reopened.retry_at, Some(121));
+
+        allow(&config(), pool(), 121).unwrap();
+        record(&config(), pool(), &Ok(()), 122);
+        assert_eq!(status(&config(), pool()).state, BreakerState::Closed);
+        assert_eq!(status(&config(), pool()).consecutive_failures, 0);
+    }
+
+    #[test]
+    fn answered_errors_do_not_trip() {
+        for now in 0..5 {
+            record::<()>(&config(), pool(), &Err(ExchangeError::InsufficientFunds), now);
+        }
+        assert!(allow(&config(), pool(), 5).is_ok());
+        assert_eq!(status(&config(), pool()).state, BreakerState::Closed);
+    }
+}
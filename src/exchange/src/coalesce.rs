@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candid::Principal;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use ic_cdk::api::call::CallResult;
+use strategy_common::debug_log;
+use strategy_common::runtime::Runtime;
+
+/// Read-only methods whose concurrent identical calls are served by a single request
+pub const COALESCED_METHODS: &[&str] = &[
+    "getPool",
+    "getPools",
+    "getAllPools",
+    "quote",
+    "metadata",
+    "getTicks",
+    "icrc1_fee",
+    "getTokenFee",
+    "icrc1_metadata",
+];
+
+/// Identity of a call: target, method and encoded arguments
+type CallKey = (Principal, String, Vec<u8>);
+
+type SharedCall = Shared<BoxFuture<'static, CallResult<Vec<u8>>>>;
+
+thread_local! {
+    // Requests other callers may still join; a request leaves once it completes
+    static IN_FLIGHT: RefCell<HashMap<CallKey, SharedCall>> = RefCell::new(HashMap::new());
+}
+
+/// Whether calls to `method` may be coalesced
+pub fn is_coalesced(method: &str) -> bool {
+    COALESCED_METHODS.contains(&method)
+}
+
+/// Number of distinct requests currently in flight
+pub fn in_flight() -> usize {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow().len())
+}
+
+/// Whether an identical request is in flight, so a call made now would join it
+pub fn is_in_flight(canister_id: Principal, method: &str, args: &[u8]) -> bool {
+    IN_FLIGHT.with(|in_flight| {
+        in_flight
+            .borrow()
+            .keys()
+            .any(|(id, m, a)| *id == canister_id && m == method && a.as_slice() == args)
+    })
+}
+
+/// Forgets an in-flight request whose waiter goes away before it completes
+///
+/// Without it a request abandoned by a trap or call-context cleanup would stay
+/// registered, and every later identical call would join a reply that never comes.
+struct InFlightGuard {
+    key: CallKey,
+    shared: SharedCall,
+    completed: bool,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        IN_FLIGHT.with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            if in_flight.get(&self.key).map_or(false, |current| current.ptr_eq(&self.shared)) {
+                debug_log!("Dropping abandoned in-flight {} call to {}", self.key.1, self.key.0);
+                in_flight.remove(&self.key);
+            }
+        });
+    }
+}
+
+/// Raw call that joins an identical in-flight request instead of issuing a new one
+///
+/// The request is forgotten as soon as it completes, so results are never reused
+/// by later callers; only callers that overlap with it share the reply.
+pub async fn call_raw(runtime: Arc<dyn Runtime>, canister_id: Principal, method: &str, args: Vec<u8>) -> CallResult<Vec<u8>> {
+    let key: CallKey = (canister_id, method.to_string(), args);
+    let shared = IN_FLIGHT.with(|in_flight| {
+        let mut in_flight = in_flight.borrow_mut();
+        if let Some(shared) = in_flight.get(&key) {
+            debug_log!("Joining in-flight {} call to {}", method, canister_id);
+            return shared.clone();
+        }
+
+        let request_key = key.clone();
+        let request: BoxFuture<'static, CallResult<Vec<u8>>> = async move {
+            let (canister_id, method, args) = request_key.clone();
+            let reply = runtime.call_raw(canister_id, &method, args, 0).await;
+            IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&request_key));
+            reply
+        }
+        .boxed();
+        let shared = request.shared();
+        in_flight.insert(key.clone(), shared.clone());
+        shared
+    });
+
+    let mut guard = InFlightGuard { key, shared: shared.clone(), completed: false };
+    let reply = shared.await;
+    guard.completed = true;
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::join;
+    use ic_cdk::api::call::RejectionCode;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use strategy_common::runtime::MockRuntime;
+
+    /// Mock runtime whose calls stay pending for one poll, so concurrent callers overlap
+    struct SlowRuntime(MockRuntime);
+
+    struct YieldOnce(bool);
+
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Runtime for SlowRuntime {
+        async fn call_raw(&self, canister_id: Principal, method: &str, args: Vec<u8>, payment: u128) -> CallResult<Vec<u8>> {
+            YieldOnce(false).await;
+            self.0.call_raw(canister_id, method, args, payment).await
+        }
+        fn time(&self) -> u64 { self.0.time() }
+        fn caller(&self) -> Principal { self.0.caller() }
+        fn id(&self) -> Principal { self.0.id() }
+        async fn sleep(&self, nanos: u64) { self.0.sleep(nanos).await }
+        fn instruction_counter(&self) -> u64 { self.0.instruction_counter() }
+        fn cycles_balance(&self) -> u128 { self.0.cycles_balance() }
+    }
+
+    fn pool() -> Principal {
+        Principal::from_slice(&[7])
+    }
+
+    fn runtime() -> Arc<SlowRuntime> {
+        let mock = MockRuntime::new(Principal::from_slice(&[1]), Principal::anonymous());
+        mock.set_default_reply(pool(), "quote", (42u64,));
+        Arc::new(SlowRuntime(mock))
+    }
+
+    #[test]
+    fn overlapping_calls_share_one_request() {
+        let runtime = runtime();
+        let (a, b) = block_on(join(
+            call_raw(runtime.clone(), pool(), "quote", vec![1]),
+            call_raw(runtime.clone(), pool(), "quote", vec![1]),
+        ));
+        assert_eq!(a, b);
+        assert_eq!(runtime.0.call_count("quote"), 1);
+        assert_eq!(in_flight(), 0);
+    }
+
+    #[test]
+    fn different_arguments_are_not_joined() {
+        let runtime = runtime();
+        block_on(join(
+            call_raw(runtime.clone(), pool(), "quote", vec![1]),
+            call_raw(runtime.clone(), pool(), "quote", vec![2]),
+        ));
+        assert_eq!(runtime.0.call_count("quote"), 2);
+    }
+
+    #[test]
+    fn sequential_calls_are_not_cached() {
+        let runtime = runtime();
+        runtime.0.push_reject(pool(), "quote", RejectionCode::SysTransient, "busy");
+        assert!(block_on(call_raw(runtime.clone(), pool(), "quote", vec![1])).is_err());
+        assert!(block_on(call_raw(runtime.clone(), pool(), "quote", vec![1])).is_ok());
+        assert_eq!(runtime.0.call_count("quote"), 2);
+    }
+
+    #[test]
+    fn abandoned_request_is_forgotten() {
+        let runtime = runtime();
+        let pending = call_raw(runtime.clone(), pool(), "quote", vec![1]).now_or_never();
+        assert!(pending.is_none());
+        assert!(!is_in_flight(pool(), "quote", &[1]));
+        assert_eq!(in_flight(), 0);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::error::*;
+use crate::pricing;
+use crate::quote_engine;
+use crate::traits::*;
+use crate::types::*;
+
+/// Sizes to evaluate: non-zero, without duplicates, smallest first
+pub fn ladder(sizes: &[u128]) -> Vec<u128> {
+    let mut ladder: Vec<u128> = sizes.iter().copied().filter(|size| *size > 0).collect();
+    ladder.sort_unstable();
+    ladder.dedup();
+    ladder
+}
+
+/// Depth point for an input size the pool could not fill at all
+pub fn unfilled_point(input_amount: u128) -> DepthPoint {
+    DepthPoint {
+        input_amount,
+        output_amount: 0,
+        effective_price: 0.0,
+        price: 0.0,
+        price_impact: 100.0,
+        fully_filled: false,
+    }
+}
+
+/// Depth point from an exchange quote
+pub fn point_from_quote(quote: &QuoteResult) -> DepthPoint {
+    DepthPoint {
+        input_amount: quote.input_amount,
+        output_amount: quote.output_amount,
+        effective_price: if quote.input_amount > 0 { quote.output_amount as f64 / quote.input_amount as f64 } else { 0.0 },
+        price: quote.price,
+        price_impact: quote.price_impact,
+        fully_filled: true,
+    }
+}
+
+/// Depth point from a quote computed against a pool snapshot
+pub fn point_from_local(pair: &TradingPair, direction: &TradeDirection, requested: u128, quote: &LocalQuote) -> DepthPoint {
+    DepthPoint {
+        input_amount: requested,
+        output_amount: quote.output_amount,
+        effective_price: if requested > 0 { quote.output_amount as f64 / requested as f64 } else { 0.0 },
+        price: pricing::price_pair(pair, direction, requested, quote.output_amount).0,
+        price_impact: quote.price_impact,
+        fully_filled: quote.fully_filled,
+    }
+}
+
+/// Build a depth curve by quoting every size in both directions on the exchange
+///
+/// Sizes the pool cannot fill are reported as unfilled points rather than failing the curve.
+pub async fn quote_depth_curve<T: Trading + ?Sized>(
+    exchange: &T,
+    pair: &TradingPair,
+    sizes: &[u128],
+    timestamp: u64,
+) -> ExchangeResult<DepthCurve> {
+    let ladder = ladder(sizes);
+    let mut curve = DepthCurve {
+        pair: pair.clone(),
+        sell: Vec::with_capacity(ladder.len()),
+        buy: Vec::with_capacity(ladder.len()),
+        timestamp,
+    };
+
+    for direction in [TradeDirection::Sell, TradeDirection::Buy] {
+        for amount in &ladder {
+            let params = TradeParams {
+                pair: pair.clone(),
+                direction: direction.clone(),
+                amount: *amount,
+                slippage_tolerance: 0.0,
+                deadline_secs: None,
+            };
+            let point = match exchange.get_quote(&params).await {
+                Ok(quote) => point_from_quote(&quote),
+                Err(ExchangeError::InsufficientLiquidity) => unfilled_point(*amount),
+                Err(e) => return Err(e),
+            };
+            match direction {
+                TradeDirection::Sell => curve.sell.push(point),
+                TradeDirection::Buy => curve.buy.push(point),
+            }
+        }
+    }
+
+    Ok(curve)
+}
+
+/// Build a depth curve from one pool snapshot, quoting every size locally in both directions
+pub fn local_depth_curve(pair: &TradingPair, snapshot: &PoolSnapshot, sizes: &[u128], timestamp: u64) -> ExchangeResult<DepthCurve> {
+    let ladder = ladder(sizes);
+    let side = |direction: TradeDirection| -> ExchangeResult<Vec<DepthPoint>> {
+        let (input_token, _) = pricing::trade_tokens(pair, &direction);
+        ladder
+            .iter()
+            .map(|amount| {
+                let quote = quote_engine::quote_trade(snapshot, &input_token.canister_id, *amount)?;
+                Ok(point_from_local(pair, &direction, *amount, &quote))
+            })
+            .collect()
+    };
+    Ok(DepthCurve {
+        pair: pair.clone(),
+        sell: side(TradeDirection::Sell)?,
+        buy: side(TradeDirection::Buy)?,
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulated::{SimulatedExchange, SimulatedPoolSeed};
+    use candid::Principal;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+    use strategy_common::runtime::MockRuntime;
+
+    const E18: u128 = 1_000_000_000_000_000_000;
+
+    fn token(id: u8, symbol: &str) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    fn pair(exchange: ExchangeType) -> TradingPair {
+        TradingPair { base_token: token(1, "AAA"), quote_token: token(2, "BBB"), exchange }
+    }
+
+    // Pool at tick 0 with 1e18 liquidity over [-600, 600] and another 5e17 over [-60, 60]
+    fn snapshot() -> PoolSnapshot {
+        PoolSnapshot {
+            pool_id: Principal::from_slice(&[7]),
+            token0: Principal::from_slice(&[1]),
+            token1: Principal::from_slice(&[2]),
+            fee: 3000,
+            sqrt_price_x96: quote_engine::sqrt_price_to_nat(primitive_types::U256::one() << 96),
+            tick: 0,
+            liquidity: 3 * E18 / 2,
+            ticks: vec![
+                TickLiquidity { tick: -600, liquidity_net: E18 as i128 },
+                TickLiquidity { tick: -60, liquidity_net: (E18 / 2) as i128 },
+                TickLiquidity { tick: 60, liquidity_net: -((E18 / 2) as i128) },
+                TickLiquidity { tick: 600, liquidity_net: -(E18 as i128) },
+            ],
+            timestamp: 0,
+        }
+    }
+
+    // Output never shrinks as the input grows, while the rate and impact only get worse
+    fn assert_monotonic(points: &[DepthPoint]) {
+        for window in points.windows(2) {
+            let (smaller, larger) = (&window[0], &window[1]);
+            assert!(smaller.input_amount < larger.input_amount);
+            assert!(smaller.output_amount <= larger.output_amount);
+            assert!(smaller.effective_price >= larger.effective_price);
+            assert!(smaller.price_impact <= larger.price_impact);
+        }
+    }
+
+    #[test]
+    fn ladder_drops_zero_and_duplicate_sizes() {
+        assert_eq!(ladder(&[500, 0, 20, 500, 1]), vec![1, 20, 500]);
+        assert!(ladder(&[0]).is_empty());
+    }
+
+    #[test]
+    fn quoted_curve_is_monotonic_in_both_directions() {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[9]), Principal::anonymous()));
+        let config = crate::factory::ExchangeFactory::new().get_config(&ExchangeType::Simulated).unwrap().clone();
+        let simulator = SimulatedExchange::for_canister(config, runtime);
+        simulator.seed_pool(&SimulatedPoolSeed {
+            token_a: token(1, "AAA"),
+            token_b: token(2, "BBB"),
+            fee: 3000,
+            amount_a: 1_000_000_000,
+            amount_b: 2_000_000_000,
+        });
+
+        let pair = pair(ExchangeType::Simulated);
+        let curve = block_on(quote_depth_curve(&simulator, &pair, &[100_000_000, 0, 10_000, 1_000_000, 10_000], 42)).unwrap();
+
+        assert_eq!(curve.timestamp, 42);
+        for side in [&curve.sell, &curve.buy] {
+            let sizes: Vec<u128> = side.iter().map(|point| point.input_amount).collect();
+            assert_eq!(sizes, vec![10_000, 1_000_000, 100_000_000]);
+            assert!(side.iter().all(|point| point.fully_filled));
+            assert_monotonic(side);
+        }
+    }
+
+    #[test]
+    fn local_curve_is_monotonic_across_ticks_and_past_the_last_one() {
+        let pair = pair(ExchangeType::ICPSwap);
+        // The largest size drains both ranges, which together hold well under 1e17 of either token
+        let sizes = [E18 / 10, E18 / 1_000_000, E18 / 1_000, E18 / 100];
+        let curve = local_depth_curve(&pair, &snapshot(), &sizes, 7).unwrap();
+
+        for side in [&curve.sell, &curve.buy] {
+            assert_eq!(side.len(), 4);
+            assert!(side[..3].iter().all(|point| point.fully_filled));
+            assert!(!side[3].fully_filled);
+            assert_monotonic(side);
+        }
+    }
+
+    #[test]
+    fn single_level_matches_the_quote_engine() {
+        let pair = pair(ExchangeType::ICPSwap);
+        let snapshot = snapshot();
+        let amount = E18 / 1_000;
+        let curve = local_depth_curve(&pair, &snapshot, &[amount], 0).unwrap();
+
+        // Selling base swaps token0 for token1, buying base swaps token1 for token0
+        for (point, zero_for_one) in [(&curve.sell[0], true), (&curve.buy[0], false)] {
+            let quote = quote_engine::quote_exact_input(&snapshot, zero_for_one, amount).unwrap();
+            assert_eq!(point.input_amount, amount);
+            assert_eq!(point.output_amount, quote.output_amount);
+            assert_eq!(point.price_impact, quote.price_impact);
+            assert_eq!(point.fully_filled, quote.fully_filled);
+            assert_eq!(point.effective_price, quote.output_amount as f64 / amount as f64);
+        }
+    }
+}
@@ -13,11 +13,13 @@ pub enum ExchangeError {
     CanisterCallError(String),
     Timeout,
     RateLimit,
+    CircuitOpen(String),
     
     // Trading related errors
     InsufficientFunds,
     SlippageExceeded,
     PriceChanged,
+    PriceDeviation(String),
     TradeRejected(String),
     TransactionFailed(String),
     
@@ -53,9 +55,11 @@ impl fmt::Display for ExchangeError {
             Self::CanisterCallError(msg) => write!(f, "Canister call error: {}", msg),
             Self::Timeout => write!(f, "Operation timed out"),
             Self::RateLimit => write!(f, "Rate limit reached"),
+            Self::CircuitOpen(target) => write!(f, "Circuit breaker open for {}", target),
             Self::InsufficientFunds => write!(f, "Insufficient funds"),
             Self::SlippageExceeded => write!(f, "Slippage tolerance exceeded"),
             Self::PriceChanged => write!(f, "Price has changed"),
+            Self::PriceDeviation(msg) => write!(f, "Price deviates from oracle: {}", msg),
             Self::TradeRejected(reason) => write!(f, "Trade rejected: {}", reason),
             Self::TransactionFailed(reason) => write!(f, "Transaction failed: {}", reason),
             Self::InsufficientLiquidity => write!(f, "Insufficient liquidity"),
@@ -7,17 +7,29 @@ use crate::types::*;
 use crate::traits::*;
 use crate::icpswap::ICPSwapConnector;
 use crate::kongswap::KongSwapConnector;
+use crate::simulated::SimulatedExchange;
+use strategy_common::runtime::{IcRuntime, Runtime};
+
+/// Shared handle to a full exchange connector
+pub type ExchangeHandle = Arc<dyn ExchangeConnector>;
 
 /// Exchange factory, used to create exchange connector instances
 pub struct ExchangeFactory {
     exchange_configs: HashMap<ExchangeType, ExchangeConfig>,
+    runtime: Arc<dyn Runtime>,  // Runtime handed to every connector created by this factory
 }
 
 impl ExchangeFactory {
     /// Creates a new instance of the exchange factory
     pub fn new() -> Self {
+        Self::with_runtime(Arc::new(IcRuntime))
+    }
+
+    /// Creates a factory whose connectors use the given runtime
+    pub fn with_runtime(runtime: Arc<dyn Runtime>) -> Self {
         let mut factory = Self {
             exchange_configs: HashMap::new(),
+            runtime,
         };
         
         // Add default configurations
@@ -39,6 +51,8 @@ impl ExchangeFactory {
                 max_slippage: 5.0,     // 5%
                 timeout_secs: 60,       // 60 seconds timeout
                 retry_count: 3,         // Retry up to 3 times
+                rate_limit: Some(RateLimitConfig::default()),
+                circuit_breaker: Some(CircuitBreakerConfig::default()),
             }
         );
         
@@ -53,6 +67,23 @@ impl ExchangeFactory {
                 max_slippage: 5.0,     // 5%
                 timeout_secs: 60,       // 60 seconds timeout
                 retry_count: 3,         // Retry up to 3 times
+                rate_limit: Some(RateLimitConfig::default()),
+                circuit_breaker: Some(CircuitBreakerConfig::default()),
+            }
+        );
+
+        // Simulated exchange configuration (paper trading, no canister involved)
+        self.exchange_configs.insert(
+            ExchangeType::Simulated,
+            ExchangeConfig {
+                exchange_type: ExchangeType::Simulated,
+                canister_id: Principal::anonymous(),
+                default_slippage: 0.5, // 0.5%
+                max_slippage: 5.0,     // 5%
+                timeout_secs: 60,       // 60 seconds timeout
+                retry_count: 0,         // Simulated calls never time out
+                rate_limit: None,       // Simulated calls cost nothing
+                circuit_breaker: None,  // Simulated calls never fail in transit
             }
         );
     }
@@ -71,13 +102,19 @@ impl ExchangeFactory {
     /// Creates an ICPSwap connector
     pub fn create_icpswap(&self) -> ExchangeResult<ICPSwapConnector> {
         let config = self.get_config(&ExchangeType::ICPSwap)?;
-        Ok(ICPSwapConnector::new(config.clone()))
+        Ok(ICPSwapConnector::with_runtime(config.clone(), self.runtime.clone()))
     }
     
     /// Creates a KongSwap connector
     pub fn create_kongswap(&self) -> ExchangeResult<KongSwapConnector> {
         let config = self.get_config(&ExchangeType::KongSwap)?;
-        Ok(KongSwapConnector::new(config.clone()))
+        Ok(KongSwapConnector::with_runtime(config.clone(), self.runtime.clone()))
+    }
+    
+    /// Creates a handle on the canister's simulated exchange; pools and balances persist across handles
+    pub fn create_simulated(&self) -> ExchangeResult<SimulatedExchange> {
+        let config = self.get_config(&ExchangeType::Simulated)?;
+        Ok(SimulatedExchange::for_canister(config.clone(), self.runtime.clone()))
     }
     
     /// Creates the corresponding connector based on the exchange type
@@ -91,10 +128,29 @@ impl ExchangeFactory {
                 let connector = self.create_kongswap()?;
                 Ok(Box::new(connector) as Box<dyn Trading>)
             },
+            ExchangeType::Simulated => {
+                let connector = self.create_simulated()?;
+                Ok(Box::new(connector) as Box<dyn Trading>)
+            },
             _ => Err(ExchangeError::NotImplemented),
         }
     }
     
+    /// Creates a full connector (trading, token operations and liquidity) for the exchange type
+    pub fn create_connector(&self, exchange_type: &ExchangeType) -> ExchangeResult<ExchangeHandle> {
+        match exchange_type {
+            ExchangeType::ICPSwap => Ok(Arc::new(self.create_icpswap()?) as ExchangeHandle),
+            ExchangeType::KongSwap => Ok(Arc::new(self.create_kongswap()?) as ExchangeHandle),
+            ExchangeType::Simulated => Ok(Arc::new(self.create_simulated()?) as ExchangeHandle),
+            _ => Err(ExchangeError::NotImplemented),
+        }
+    }
+    
+    /// Gets the capabilities of an exchange type
+    pub fn get_capabilities(&self, exchange_type: &ExchangeType) -> ExchangeResult<ExchangeCapabilities> {
+        Ok(self.create_connector(exchange_type)?.capabilities())
+    }
+    
     /// Creates the corresponding connector based on the trading pair
     pub fn create_exchange_for_pair(&self, pair: &TradingPair) -> ExchangeResult<Box<dyn Trading>> {
         self.create_exchange(&pair.exchange)
@@ -0,0 +1,58 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+/// Consecutive failed status checks after which a venue is reported unavailable
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Health of an exchange venue, tracked across connector instances
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VenueHealth {
+    pub consecutive_failures: u32,
+    pub last_latency_ms: Option<u64>,
+    pub last_success: Option<u64>,    // Seconds since the epoch
+    pub last_error: Option<String>,
+}
+
+impl VenueHealth {
+    /// Whether the venue should be offered to routers and UIs
+    pub fn is_available(&self) -> bool {
+        self.consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    }
+}
+
+thread_local! {
+    // Keyed by venue canister, so every status check of a venue updates the same record
+    static HEALTH: RefCell<HashMap<Principal, VenueHealth>> = RefCell::new(HashMap::new());
+}
+
+/// Current health of a venue
+pub fn venue_health(venue: &Principal) -> VenueHealth {
+    HEALTH.with(|health| health.borrow().get(venue).cloned().unwrap_or_default())
+}
+
+/// Record a successful call to a venue and return its updated health
+pub fn record_success(venue: &Principal, latency_ms: u64, now_secs: u64) -> VenueHealth {
+    HEALTH.with(|health| {
+        let mut health = health.borrow_mut();
+        let entry = health.entry(*venue).or_default();
+        entry.consecutive_failures = 0;
+        entry.last_latency_ms = Some(latency_ms);
+        entry.last_success = Some(now_secs);
+        entry.last_error = None;
+        entry.clone()
+    })
+}
+
+/// Record a failed call to a venue and return its updated health
+pub fn record_failure(venue: &Principal, error: String) -> VenueHealth {
+    HEALTH.with(|health| {
+        let mut health = health.borrow_mut();
+        let entry = health.entry(*venue).or_default();
+        entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+        entry.last_error = Some(error);
+        entry.clone()
+    })
+}
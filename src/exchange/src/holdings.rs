@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use candid::Principal;
+
+use crate::traits::*;
+use crate::types::*;
+
+/// Value of one raw unit of `token` in raw units of `valuation_token`, quoted on the exchange
+///
+/// Quotes one whole token so the rate reflects the pool price rather than the
+/// price impact of the (possibly large) holding. Returns None when no pool prices the pair.
+pub async fn unit_value<T: Trading + ?Sized>(exchange: &T, token: &TokenInfo, valuation_token: &TokenInfo) -> Option<f64> {
+    if token.canister_id == valuation_token.canister_id {
+        return Some(1.0);
+    }
+
+    let sample_amount = 10u128.checked_pow(token.decimals as u32)?;
+    let params = TradeParams {
+        pair: TradingPair {
+            base_token: token.clone(),
+            quote_token: valuation_token.clone(),
+            exchange: exchange.get_exchange_type(),
+        },
+        direction: TradeDirection::Sell,
+        amount: sample_amount,
+        slippage_tolerance: 0.0,
+        deadline_secs: None,
+    };
+    match exchange.get_quote(&params).await {
+        Ok(quote) if quote.input_amount > 0 => Some(quote.output_amount as f64 / quote.input_amount as f64),
+        _ => None,
+    }
+}
+
+/// Values raw holdings in `valuation_token` and totals them
+///
+/// Allowances are reported and valued but left out of the total, since the
+/// approved tokens are still counted in the wallet balance.
+pub async fn value_holdings<T: Trading + ?Sized>(
+    exchange: &T,
+    account: Account,
+    valuation_token: &TokenInfo,
+    mut holdings: Vec<Holding>,
+    unreadable_pools: Vec<PoolReadError>,
+    timestamp: u64,
+) -> HoldingsBreakdown {
+    let mut rates: HashMap<Principal, Option<f64>> = HashMap::new();
+    let mut total_value = 0u128;
+    let mut unpriced_tokens: Vec<TokenInfo> = Vec::new();
+
+    for holding in holdings.iter_mut() {
+        let rate = match rates.get(&holding.token.canister_id) {
+            Some(rate) => *rate,
+            None => {
+                let rate = unit_value(exchange, &holding.token, valuation_token).await;
+                rates.insert(holding.token.canister_id, rate);
+                rate
+            }
+        };
+
+        holding.value = rate.map(|rate| (holding.amount as f64 * rate) as u128);
+        match holding.value {
+            Some(value) => {
+                if !matches!(holding.location, HoldingLocation::Allowance { .. }) {
+                    total_value = total_value.saturating_add(value);
+                }
+            }
+            None => {
+                if holding.amount > 0 && !unpriced_tokens.iter().any(|t| t.canister_id == holding.token.canister_id) {
+                    unpriced_tokens.push(holding.token.clone());
+                }
+            }
+        }
+    }
+
+    HoldingsBreakdown {
+        account,
+        valuation_token: valuation_token.clone(),
+        holdings,
+        total_value,
+        unpriced_tokens,
+        unreadable_pools,
+        timestamp,
+    }
+}
+
+/// Tokens to inspect: the requested tokens plus the valuation token, without duplicates
+pub fn holdings_tokens(tokens: &[TokenInfo], valuation_token: &TokenInfo) -> Vec<TokenInfo> {
+    let mut result: Vec<TokenInfo> = Vec::new();
+    for token in tokens.iter().chain(std::iter::once(valuation_token)) {
+        if !result.iter().any(|t| t.canister_id == token.canister_id) {
+            result.push(token.clone());
+        }
+    }
+    result
+}
@@ -2,21 +2,42 @@ use async_trait::async_trait;
 use candid::{CandidType, Deserialize, Principal, Nat, Int};
 use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::Arc;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use ic_cdk::api::call::{CallResult, RejectionCode};
 use std::convert::TryFrom;
-use ic_ledger_types::{AccountIdentifier, AccountBalanceArgs, Tokens, DEFAULT_SUBACCOUNT};
+use ic_ledger_types::{AccountIdentifier, Tokens, DEFAULT_FEE};
 
 use crate::error::*;
 use crate::types::*;
 use crate::traits::*;
+use crate::coalesce;
+use crate::circuit_breaker;
+use crate::depth;
+use crate::health;
+use crate::holdings;
+use crate::journal::{self, JournalEntry};
+use crate::metering::{self, MeteredOperation};
+use crate::pricing;
+use crate::quote_engine;
+use crate::rate_limit::{self, RateLimitKey};
 use crate::utils;
+use crate::verification::{self, ExpectedTransfer};
+use strategy_common::debug_log;
+use strategy_common::runtime::{self, IcRuntime, Runtime};
 
 /// Connector for the ICPSwap exchange
 pub struct ICPSwapConnector {
     config: ExchangeConfig,
     factory_canister_id: Principal,  // ICPSwap Factory Canister ID
+    runtime: Arc<dyn Runtime>,       // System API used for calls, time and caller
+    subaccount: Option<Subaccount>,  // Canister subaccount that trades are funded from
+    stats_canister_id: Option<Principal>,  // ICPSwap node index publishing pool TVL and volume
 }
 
+/// ICPSwap node index canister (mainnet), which publishes pool statistics
+pub const ICPSWAP_NODE_INDEX_CANISTER: &str = "ggzvv-5qaaa-aaaag-qck7a-cai";
+
 /// ICPSwap specific Token type
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ICPSwapToken {
@@ -119,6 +140,120 @@ pub enum ICPSwapBalanceResult {
     err(ICPSwapError),
 }
 
+/// ICPSwap pool listing result type
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ICPSwapPoolsResult {
+    ok(Vec<ICPSwapPoolData>),
+    err(ICPSwapError),
+}
+
+/// ICPSwap node index pool overview (fields used by this connector)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ICPSwapPoolOverview {
+    pub pool: String,
+    pub token0Id: String,
+    pub token1Id: String,
+    pub token0Symbol: String,
+    pub token1Symbol: String,
+    pub token0Decimals: f64,
+    pub token1Decimals: f64,
+    pub tvlUSD: f64,
+    pub volumeUSD1d: f64,
+}
+
+/// ICPSwap pool metadata (fields used by this connector)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ICPSwapPoolMetadata {
+    pub fee: Nat,
+    pub key: String,
+    pub sqrtPriceX96: Nat,
+    pub tick: Int,
+    pub liquidity: Nat,
+    pub token0: ICPSwapToken,
+    pub token1: ICPSwapToken,
+}
+
+/// ICPSwap pool metadata result type
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ICPSwapPoolMetadataResult {
+    ok(ICPSwapPoolMetadata),
+    err(ICPSwapError),
+}
+
+/// ICPSwap liquidity position owned by a user
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ICPSwapUserPosition {
+    pub id: Nat,
+    pub tickLower: Int,
+    pub tickUpper: Int,
+    pub liquidity: Nat,
+    pub tokensOwed0: Nat,
+    pub tokensOwed1: Nat,
+}
+
+/// ICPSwap user positions result type
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ICPSwapUserPositionsResult {
+    ok(Vec<ICPSwapUserPosition>),
+    err(ICPSwapError),
+}
+
+/// ICPSwap initialized tick and its liquidity
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ICPSwapTickLiquidityInfo {
+    pub tickIndex: Int,
+    pub liquidityNet: Int,
+    pub liquidityGross: Nat,
+}
+
+/// ICPSwap page of initialized ticks
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ICPSwapTicksPage {
+    pub content: Vec<ICPSwapTickLiquidityInfo>,
+    pub offset: Nat,
+    pub limit: Nat,
+    pub totalElements: Nat,
+}
+
+/// ICPSwap ticks result type
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ICPSwapTicksResult {
+    ok(ICPSwapTicksPage),
+    err(ICPSwapError),
+}
+
+/// Number of ticks requested per getTicks page
+const TICKS_PAGE_SIZE: u64 = 500;
+
+/// ICRC-2 allowance query arguments
+#[derive(CandidType, Debug)]
+struct ICRC2AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+/// ICRC-2 allowance
+#[derive(CandidType, Deserialize, Debug)]
+struct ICRC2Allowance {
+    allowance: candid::Nat,
+    expires_at: Option<u64>,
+}
+
+/// ICRC-1 metadata value
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Debug)]
+enum ICRC1MetadataValue {
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(serde_bytes::ByteBuf),
+}
+
+thread_local! {
+    // Symbol and decimals per ledger; they never change, so entries live until the next upgrade
+    static TOKEN_METADATA: std::cell::RefCell<HashMap<Principal, (String, u8)>> = std::cell::RefCell::new(HashMap::new());
+}
+
 /// ICPSwap withdraw arguments
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ICPSwapWithdrawArgs {
@@ -172,15 +307,213 @@ enum ICPTransferError {
     TxDuplicate { duplicate_of: u64 }, // Corresponds to ic_ledger_types::BlockIndex
 }
 
+/// DIP20 transfer receipt
+#[derive(CandidType, Deserialize, Debug)]
+enum DIP20TxReceipt {
+    Ok(candid::Nat),
+    Err(DIP20TxError),
+}
+
+/// DIP20 transfer error type
+#[derive(CandidType, Deserialize, Debug)]
+enum DIP20TxError {
+    InsufficientAllowance,
+    InsufficientBalance,
+    ErrorOperationStyle,
+    Unauthorized,
+    LedgerTrap,
+    ErrorTo,
+    Other(String),
+    BlockUsed,
+    AmountTooSmall,
+}
+
+/// EXT user: a principal or a hex account identifier
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+enum EXTUser {
+    address(String),
+    principal(Principal),
+}
+
+/// EXT common error type
+#[derive(CandidType, Deserialize, Debug)]
+enum EXTCommonError {
+    InvalidToken(String),
+    Other(String),
+}
+
+/// EXT balance request
+#[derive(CandidType, Debug)]
+struct EXTBalanceRequest {
+    user: EXTUser,
+    token: String,
+}
+
+/// EXT balance response
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+enum EXTBalanceResponse {
+    ok(candid::Nat),
+    err(EXTCommonError),
+}
+
+/// EXT transfer request
+#[derive(CandidType, Debug)]
+struct EXTTransferRequest {
+    from: EXTUser,
+    to: EXTUser,
+    token: String,
+    amount: candid::Nat,
+    memo: serde_bytes::ByteBuf,
+    notify: bool,
+    subaccount: Option<serde_bytes::ByteBuf>,
+}
+
+/// EXT transfer response
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+enum EXTTransferResponse {
+    ok(candid::Nat),
+    err(EXTTransferError),
+}
+
+/// EXT transfer error type
+#[derive(CandidType, Deserialize, Debug)]
+enum EXTTransferError {
+    Unauthorized(String),
+    InsufficientBalance,
+    Rejected,
+    InvalidToken(String),
+    CannotNotify(String),
+    Other(String),
+}
+
 impl ICPSwapConnector {
     /// Creates a new instance of the ICPSwap connector
     pub fn new(config: ExchangeConfig) -> Self {
+        Self::with_runtime(config, Arc::new(IcRuntime))
+    }
+
+    /// Creates a connector that performs all system calls through the given runtime
+    pub fn with_runtime(config: ExchangeConfig, runtime: Arc<dyn Runtime>) -> Self {
         Self {
             factory_canister_id: config.canister_id.clone(),
             config,
+            runtime,
+            subaccount: None,
+            stats_canister_id: Principal::from_text(ICPSWAP_NODE_INDEX_CANISTER).ok(),
         }
     }
 
+    /// Reads pool TVL and volume from the given node index canister, or skips statistics when None
+    pub fn with_stats_canister(mut self, stats_canister_id: Option<Principal>) -> Self {
+        self.stats_canister_id = stats_canister_id;
+        self
+    }
+
+    /// Trades from (and returns proceeds to) the given subaccount of this canister
+    pub fn with_subaccount(mut self, subaccount: Option<Subaccount>) -> Self {
+        self.subaccount = subaccount;
+        self
+    }
+
+    /// Canister account this connector trades from
+    pub fn account(&self) -> Account {
+        Account::with_subaccount(self.runtime.id(), self.subaccount)
+    }
+
+    /// Runtime used by this connector
+    pub fn runtime(&self) -> Arc<dyn Runtime> {
+        self.runtime.clone()
+    }
+
+    /// Typed inter-canister call routed through the runtime
+    ///
+    /// Read-only pool and fee queries join an identical request that is already in
+    /// flight, so concurrent split orders share one `getPool`/`quote` call.
+    async fn call<T, R>(&self, canister_id: Principal, method: &str, args: T) -> CallResult<R>
+    where
+        T: ArgumentEncoder + Send,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        if !coalesce::is_coalesced(method) {
+            return runtime::call(self.runtime.as_ref(), canister_id, method, args).await;
+        }
+        let bytes = candid::encode_args(args)
+            .map_err(|e| (RejectionCode::CanisterError, format!("Failed to encode arguments for {}: {}", method, e)))?;
+        self.call_coalesced(canister_id, method, bytes).await
+    }
+
+    /// Like `call`, but first takes a token from the call budget of `key`
+    ///
+    /// A call that joins an identical in-flight request sends nothing, so only the
+    /// request it joins is charged.
+    async fn limited_call<T, R>(&self, key: RateLimitKey, canister_id: Principal, method: &str, args: T) -> ExchangeResult<CallResult<R>>
+    where
+        T: ArgumentEncoder + Send,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        if !coalesce::is_coalesced(method) {
+            self.rate_limit(key).await?;
+            return Ok(runtime::call(self.runtime.as_ref(), canister_id, method, args).await);
+        }
+        let bytes = match candid::encode_args(args) {
+            Ok(bytes) => bytes,
+            Err(e) => return Ok(Err((RejectionCode::CanisterError, format!("Failed to encode arguments for {}: {}", method, e)))),
+        };
+        if !coalesce::is_in_flight(canister_id, method, &bytes) {
+            self.rate_limit(key).await?;
+        }
+        Ok(self.call_coalesced(canister_id, method, bytes).await)
+    }
+
+    /// Sends (or joins) a coalesced call with already encoded arguments
+    async fn call_coalesced<R>(&self, canister_id: Principal, method: &str, bytes: Vec<u8>) -> CallResult<R>
+    where
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        let reply = coalesce::call_raw(self.runtime.clone(), canister_id, method, bytes).await?;
+        candid::decode_args(&reply)
+            .map_err(|e| (RejectionCode::CanisterError, format!("Failed to decode reply from {}: {}", method, e)))
+    }
+
+    /// Waits for (or fails fast on) the configured call budget of a pool or ledger
+    ///
+    /// Only the entry of an operation is charged (`quote`, fee and balance lookups, a
+    /// standalone deposit, withdraw, approve or swap). Once funds have moved, the
+    /// remaining legs run unlimited so a trade is never stranded in a pool.
+    async fn rate_limit(&self, key: RateLimitKey) -> ExchangeResult<()> {
+        match &self.config.rate_limit {
+            Some(config) => rate_limit::acquire(self.runtime.as_ref(), config, key).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses calls to a venue or pool whose circuit breaker is open
+    ///
+    /// Only the entry points of a trade (`getPool`, `quote`, a standalone deposit) are
+    /// gated. Later legs always run so funds are never stranded in a pool; their
+    /// outcomes are still fed to the breaker.
+    fn breaker_allow(&self, target: BreakerTarget) -> ExchangeResult<()> {
+        match &self.config.circuit_breaker {
+            Some(config) => circuit_breaker::allow(config, target, self.now_secs()),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds the outcome of a guarded call to its circuit breaker
+    fn breaker_record<T>(&self, target: BreakerTarget, outcome: &ExchangeResult<T>) {
+        if let Some(config) = &self.config.circuit_breaker {
+            circuit_breaker::record(config, target, outcome, self.now_secs());
+        }
+    }
+
+    /// Current time in seconds according to the runtime
+    fn now_secs(&self) -> u64 {
+        self.runtime.time() / 1_000_000_000
+    }
+
     /// Converts internal TokenInfo to ICPSwapToken representation
     fn token_to_icpswap_token(&self, token: &TokenInfo) -> ICPSwapToken {
         ICPSwapToken {
@@ -198,7 +531,7 @@ impl ICPSwapConnector {
     /// Checks token order and returns the correct zeroForOne value
     fn is_zero_for_one(&self, base: &TokenInfo, quote: &TokenInfo) -> bool {
         // ICPSwap requires token0's canister_id to be lexicographically smaller than token1's
-        base.canister_id.to_string() < quote.canister_id.to_string()
+        pricing::is_zero_for_one(&base.canister_id, &quote.canister_id)
     }
 
     /// Queries for SwapPool information from the ICPSwap factory
@@ -216,27 +549,49 @@ impl ICPSwapConnector {
             token1: self.token_to_icpswap_token(token1),
         };
 
-        ic_cdk::println!("Calling getPool with args: {:?}", args); // Debug log
+        self.breaker_allow(BreakerTarget::Venue(self.factory_canister_id))?;
+        debug_log!("Calling getPool with args: {:?}", args); // Debug log
         // Use 'call' for both query and update. IC determines mode based on target method.
-        let result: CallResult<(ICPSwapPoolResult,)> = ic_cdk::api::call::call(
+        let result: CallResult<(ICPSwapPoolResult,)> = self.call(
             self.factory_canister_id,
             "getPool",
             (args,),
         ).await;
-        ic_cdk::println!("getPool call result: {:?}", result); // Debug log
+        debug_log!("getPool call result: {:?}", result); // Debug log
 
-        match result {
+        let outcome = match result {
             Ok((pool_result,)) => match pool_result {
                 ICPSwapPoolResult::ok(pool_data) => Ok(pool_data),
                 ICPSwapPoolResult::err(err) => {
-                    ic_cdk::println!("getPool call returned error: {:?}", err); // Debug log
+                    debug_log!("getPool call returned error: {:?}", err); // Debug log
                     Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                ic_cdk::println!("getPool call failed: {:?} - {}", code, msg); // Debug log
+                debug_log!("getPool call failed: {:?} - {}", code, msg); // Debug log
                 Err(ExchangeError::CanisterCallError(format!("Failed to call getPool: {:?} - {}", code, msg)))
             },
+        };
+        self.breaker_record(BreakerTarget::Venue(self.factory_canister_id), &outcome);
+        outcome
+    }
+
+    /// Lists every pool registered with the ICPSwap factory
+    async fn call_get_pools(&self) -> ExchangeResult<Vec<ICPSwapPoolData>> {
+        let result: CallResult<(ICPSwapPoolsResult,)> = self.call(self.factory_canister_id, "getPools", ()).await;
+        match result {
+            Ok((ICPSwapPoolsResult::ok(pools),)) => Ok(pools),
+            Ok((ICPSwapPoolsResult::err(err),)) => Err(self.map_icpswap_error(err)),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to call getPools: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Fetches pool statistics from the node index, keyed by pool canister ID
+    async fn call_get_pool_overviews(&self, stats_canister_id: Principal) -> ExchangeResult<HashMap<String, ICPSwapPoolOverview>> {
+        let result: CallResult<(Vec<ICPSwapPoolOverview>,)> = self.call(stats_canister_id, "getAllPools", ()).await;
+        match result {
+            Ok((overviews,)) => Ok(overviews.into_iter().map(|overview| (overview.pool.clone(), overview)).collect()),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to call getAllPools: {:?} - {}", code, msg))),
         }
     }
 
@@ -252,133 +607,145 @@ impl ICPSwapConnector {
 
     /// Calls the quote method on the ICPSwap pool canister
     async fn call_quote(&self, pool_id: &Principal, args: ICPSwapQuoteArgs) -> ExchangeResult<Nat> { // Return Nat
-        ic_cdk::println!("Calling quote on pool {} with args: {:?}", pool_id, args); // Debug log
+        self.breaker_allow(BreakerTarget::Pool(*pool_id))?;
+        debug_log!("Calling quote on pool {} with args: {:?}", pool_id, args); // Debug log
         // Use 'call' for both query and update. IC determines mode based on target method.
-        let result: CallResult<(ICPSwapQuoteResult,)> = ic_cdk::api::call::call(
-            *pool_id,
-            "quote",
-            (args,),
-        ).await;
-         ic_cdk::println!("quote call result: {:?}", result); // Debug log
-
-        match result {
+        let result: CallResult<(ICPSwapQuoteResult,)> = metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Quote,
+            self.limited_call(RateLimitKey::Pool(*pool_id), *pool_id, "quote", (args,)),
+        ).await?;
+         debug_log!("quote call result: {:?}", result); // Debug log
+
+        let outcome = match result {
             Ok((quote_result,)) => match quote_result {
                 ICPSwapQuoteResult::ok(amount_nat) => Ok(amount_nat), // Return Nat directly
                 ICPSwapQuoteResult::err(err) => {
-                     ic_cdk::println!("quote call returned error: {:?}", err); // Debug log
+                     debug_log!("quote call returned error: {:?}", err); // Debug log
                      Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                 ic_cdk::println!("quote call failed: {:?} - {}", code, msg); // Debug log
+                 debug_log!("quote call failed: {:?} - {}", code, msg); // Debug log
                  Err(ExchangeError::CanisterCallError(format!("Failed to call quote: {:?} - {}", code, msg)))
             },
-        }
+        };
+        self.breaker_record(BreakerTarget::Pool(*pool_id), &outcome);
+        outcome
     }
 
     /// Calls the swap method on the ICPSwap pool canister
     async fn call_swap(&self, pool_id: &Principal, args: ICPSwapSwapArgs) -> ExchangeResult<candid::Nat> { // Changed return type to Nat
-        ic_cdk::println!("Calling swap on pool {} with args: {:?}", pool_id, args); // Debug log
-        let result: CallResult<(ICPSwapResult,)> = ic_cdk::api::call::call(
-            *pool_id,
-            "swap",
-            (args,),
+        debug_log!("Calling swap on pool {} with args: {:?}", pool_id, args); // Debug log
+        let result: CallResult<(ICPSwapResult,)> = metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Swap,
+            self.call(*pool_id, "swap", (args,)),
         ).await;
-        ic_cdk::println!("swap result: {:?}", result); // Debug log
+        debug_log!("swap result: {:?}", result); // Debug log
 
-        match result {
+        let outcome = match result {
             Ok((swap_result,)) => match swap_result {
                 ICPSwapResult::ok(amount_nat) => Ok(amount_nat), // Return Nat
                 ICPSwapResult::err(err) => {
-                    ic_cdk::println!("swap returned error: {:?}", err); // Debug log
+                    debug_log!("swap returned error: {:?}", err); // Debug log
                     Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                 ic_cdk::println!("swap call failed: {:?} - {}", code, msg); // Debug log
+                 debug_log!("swap call failed: {:?} - {}", code, msg); // Debug log
                  Err(ExchangeError::CanisterCallError(format!("Failed to call swap: {:?} - {}", code, msg)))
             },
-        }
+        };
+        self.breaker_record(BreakerTarget::Pool(*pool_id), &outcome);
+        outcome
     }
 
     /// Calls the deposit method on the ICPSwap pool canister
     async fn call_deposit(&self, pool_id: &Principal, args: ICPSwapDepositArgs) -> ExchangeResult<candid::Nat> { // Changed return type to Nat
-        ic_cdk::println!("Calling deposit on pool {} with args: {:?}", pool_id, args); // Debug log
-        let result: CallResult<(ICPSwapResult,)> = ic_cdk::api::call::call(
-            *pool_id,
-            "deposit",
-            (args,),
+        debug_log!("Calling deposit on pool {} with args: {:?}", pool_id, args); // Debug log
+        let result: CallResult<(ICPSwapResult,)> = metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Deposit,
+            self.call(*pool_id, "deposit", (args,)),
         ).await;
-        ic_cdk::println!("deposit result: {:?}", result); // Debug log
+        debug_log!("deposit result: {:?}", result); // Debug log
 
-        match result {
+        let outcome = match result {
             Ok((deposit_result,)) => match deposit_result {
                 ICPSwapResult::ok(amount_nat) => Ok(amount_nat), // Return Nat
                 ICPSwapResult::err(err) => {
-                    ic_cdk::println!("deposit returned error: {:?}", err); // Debug log
+                    debug_log!("deposit returned error: {:?}", err); // Debug log
                     Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                ic_cdk::println!("deposit call failed: {:?} - {}", code, msg); // Debug log
+                debug_log!("deposit call failed: {:?} - {}", code, msg); // Debug log
                 Err(ExchangeError::CanisterCallError(format!("Failed to call deposit: {:?} - {}", code, msg)))
             },
-        }
+        };
+        self.breaker_record(BreakerTarget::Pool(*pool_id), &outcome);
+        outcome
     }
 
     /// Calls the depositFrom method on the ICPSwap pool canister
     async fn call_deposit_from(&self, pool_id: &Principal, args: ICPSwapDepositFromArgs) -> ExchangeResult<candid::Nat> { // Changed return type to Nat
-        ic_cdk::println!("Calling depositFrom on pool {} with args: {:?}", pool_id, args);
-        let result: CallResult<(ICPSwapResult,)> = ic_cdk::api::call::call(
-            *pool_id,
-            "depositFrom",
-            (args,),
+        debug_log!("Calling depositFrom on pool {} with args: {:?}", pool_id, args);
+        let result: CallResult<(ICPSwapResult,)> = metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Deposit,
+            self.call(*pool_id, "depositFrom", (args,)),
         ).await;
-        ic_cdk::println!("depositFrom result: {:?}", result);
+        debug_log!("depositFrom result: {:?}", result);
 
-        match result {
+        let outcome = match result {
             Ok((deposit_result,)) => match deposit_result {
                 ICPSwapResult::ok(amount_nat) => Ok(amount_nat), // Return Nat
                 ICPSwapResult::err(err) => {
-                    ic_cdk::println!("depositFrom returned error: {:?}", err);
+                    debug_log!("depositFrom returned error: {:?}", err);
                     Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                ic_cdk::println!("depositFrom call failed: {:?} - {}", code, msg);
+                debug_log!("depositFrom call failed: {:?} - {}", code, msg);
                 Err(ExchangeError::CanisterCallError(format!("Failed to call depositFrom: {:?} - {}", code, msg)))
             },
-        }
+        };
+        self.breaker_record(BreakerTarget::Pool(*pool_id), &outcome);
+        outcome
     }
 
     /// Calls the withdraw method on the ICPSwap pool canister
     async fn call_withdraw(&self, pool_id: &Principal, args: ICPSwapWithdrawArgs) -> ExchangeResult<candid::Nat> { // Changed return type to Nat
-        ic_cdk::println!("Calling withdraw on pool {} with args: {:?}", pool_id, args); // Debug log
-        let result: CallResult<(ICPSwapResult,)> = ic_cdk::api::call::call(
-            *pool_id,
-            "withdraw",
-            (args,),
+        debug_log!("Calling withdraw on pool {} with args: {:?}", pool_id, args); // Debug log
+        let result: CallResult<(ICPSwapResult,)> = metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Withdraw,
+            self.call(*pool_id, "withdraw", (args,)),
         ).await;
-        ic_cdk::println!("withdraw result: {:?}", result); // Debug log
+        debug_log!("withdraw result: {:?}", result); // Debug log
 
-        match result {
+        let outcome = match result {
             Ok((withdraw_result,)) => match withdraw_result {
                 ICPSwapResult::ok(amount_nat) => Ok(amount_nat), // Return Nat
                 ICPSwapResult::err(err) => {
-                     ic_cdk::println!("withdraw returned error: {:?}", err); // Debug log
+                     debug_log!("withdraw returned error: {:?}", err); // Debug log
                     Err(self.map_icpswap_error(err))
                 },
             },
             Err((code, msg)) => {
-                 ic_cdk::println!("withdraw call failed: {:?} - {}", code, msg); // Debug log
+                 debug_log!("withdraw call failed: {:?} - {}", code, msg); // Debug log
                 Err(ExchangeError::CanisterCallError(format!("Failed to call withdraw: {:?} - {}", code, msg)))
             },
-        }
+        };
+        self.breaker_record(BreakerTarget::Pool(*pool_id), &outcome);
+        outcome
     }
     
     /// Query user unused balance
     async fn call_get_user_unused_balance(&self, pool_id: &Principal, user: &Principal) -> ExchangeResult<(u128, u128)> {
-        let result: CallResult<(ICPSwapBalanceResult,)> = ic_cdk::api::call::call(
+        self.rate_limit(RateLimitKey::Pool(*pool_id)).await?;
+        let result: CallResult<(ICPSwapBalanceResult,)> = self.call(
             *pool_id,
             "getUserUnusedBalance",
             (user,),
@@ -400,29 +767,42 @@ impl ICPSwapConnector {
         }
     }
     
-    /// Execute ICRC1 token transfer to the SwapPool subaccount
-    async fn transfer_token_to_pool_subaccount(&self, token: &TokenInfo, pool_id: &Principal, amount: u128, fee: u64) -> ExchangeResult<()> {
-        ic_cdk::println!("Transferring token {} to pool {} subaccount", token.canister_id, pool_id);
-        let caller = ic_cdk::caller();
-
-        // Execute transfer based on token standard
+    /// Execute ICRC1 token transfer to the SwapPool subaccount; returns the ledger block index, if any
+    async fn transfer_token_to_pool_subaccount(&self, token: &TokenInfo, pool_id: &Principal, amount: u128, fee: u64) -> ExchangeResult<Option<u128>> {
+        debug_log!("Transferring token {} to pool {} subaccount", token.canister_id, pool_id);
         match token.standard {
-            TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
-                // Generate subaccount for the caller
-                let subaccount = utils::principal_to_subaccount(&caller);
-                let pool_subaccount = utils::principal_to_subaccount(pool_id);
+            TokenStandard::DIP20 | TokenStandard::EXT => {
+                // DIP20/EXT doesn't need this step, as they use Workflow 2, transferring directly from the user via depositFrom
+                debug_log!("DIP20/EXT tokens use Workflow 2, skipping transfer_token_to_pool_subaccount");
+                Ok(None)
+            },
+            _ => {
+                // The pool credits deposits made to its subaccount derived from the depositing canister
+                let to = Account::with_subaccount(*pool_id, Some(utils::subaccount_from_principal(&self.runtime.id())));
+                self.transfer_from_subaccount(token, self.subaccount, &to, amount, fee).await
+            },
+        }
+    }
 
-                // Define ICRC Account structure
-                #[derive(CandidType, Deserialize)]
-                struct Account {
-                    owner: Principal,
-                    subaccount: Option<Vec<u8>>,
-                }
+    /// Transfer tokens from one of this canister's subaccounts; returns the ledger block index,
+    /// or `None` for standards whose transfers have none (EXT)
+    async fn transfer_from_subaccount(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128, fee: u64) -> ExchangeResult<Option<u128>> {
+        metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Transfer,
+            self.transfer_on_ledger(token, from_subaccount, to, amount, fee),
+        ).await
+    }
 
+    /// Submit a transfer to the token's ledger according to its standard
+    async fn transfer_on_ledger(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128, fee: u64) -> ExchangeResult<Option<u128>> {
+        // Execute transfer based on token standard; the ICP ledger implements ICRC-1
+        match token.standard {
+            TokenStandard::ICRC1 | TokenStandard::ICRC2 | TokenStandard::ICP => {
                 // Define ICRC Transfer arguments
                 #[derive(CandidType)]
                 struct TransferArgs {
-                    from_subaccount: Option<Vec<u8>>,
+                    from_subaccount: Option<Subaccount>,
                     to: Account,
                     amount: candid::Nat,
                     fee: Option<candid::Nat>,
@@ -430,16 +810,10 @@ impl ICPSwapConnector {
                     created_at_time: Option<u64>,
                 }
 
-                // Create the destination account
-                let to_account = Account {
-                    owner: *pool_id,
-                    subaccount: Some(pool_subaccount.to_vec()),
-                };
-
                 // Create transfer arguments
                 let transfer_args = TransferArgs {
-                    from_subaccount: Some(subaccount.to_vec()),
-                    to: to_account,
+                    from_subaccount,
+                    to: to.clone(),
                     amount: candid::Nat::from(amount),
                     fee: Some(candid::Nat::from(fee)), // Assuming fee is required, adjust if optional
                     memo: None,
@@ -447,7 +821,7 @@ impl ICPSwapConnector {
                 };
 
                 // Call the transfer
-                let call_result: CallResult<(ICRC1TransferResult,)> = ic_cdk::api::call::call(
+                let call_result: CallResult<(ICRC1TransferResult,)> = self.call(
                     token.canister_id,
                     "icrc1_transfer",
                     (transfer_args,),
@@ -456,8 +830,10 @@ impl ICPSwapConnector {
                 match call_result {
                     Ok((transfer_result,)) => match transfer_result {
                         ICRC1TransferResult::Ok(block_index) => {
-                            ic_cdk::println!("ICRC transfer successful, block index: {}", block_index);
-                            Ok(())
+                            debug_log!("ICRC transfer successful, block index: {}", block_index);
+                            u128::try_from(block_index.0.clone())
+                                .map(Some)
+                                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert block index {} to u128: {}", block_index, e)))
                         },
                         ICRC1TransferResult::Err(err) => {
                             let error_msg = match &err {
@@ -478,109 +854,581 @@ impl ICPSwapConnector {
                                 TransferError::GenericError { error_code, message } => 
                                     format!("Generic error {}: {}", error_code, message),
                             };
-                            ic_cdk::println!("ICRC transfer error: {}", error_msg);
+                            debug_log!("ICRC transfer error: {}", error_msg);
                             Err(ExchangeError::TokenTransferFailed(format!("ICRC transfer failed: {}", error_msg)))
                         }
                     },
                     Err((code, msg)) => {
-                        ic_cdk::println!("ICRC transfer call failed: {:?} - {}", code, msg);
+                        debug_log!("ICRC transfer call failed: {:?} - {}", code, msg);
                         Err(ExchangeError::TokenTransferFailed(
                             format!("ICRC transfer failed: {:?} - {}", code, msg)
                         ))
                     },
                 }
             },
-            TokenStandard::ICP => {
-                // Special handling logic for ICP, as it uses ic-ledger-types
-                ic_cdk::println!("Handling ICP transfer");
-                let caller_subaccount_bytes = utils::principal_to_subaccount(&caller);
-                // Convert to subaccount format
-                let mut from_subaccount_array = [0u8; 32];
-                for (i, byte) in caller_subaccount_bytes.iter().enumerate().take(32) {
-                    from_subaccount_array[i] = *byte;
-                }
-                let from_subaccount = ic_ledger_types::Subaccount(from_subaccount_array);
+            TokenStandard::DIP20 => self.dip20_transfer(token, from_subaccount, to, amount).await.map(Some),
+            // EXT ledgers keep no block log, so there is no index to report
+            TokenStandard::EXT => self.ext_transfer(token, from_subaccount, to, amount).await.map(|()| None),
+        }
+    }
 
-                let pool_subaccount_bytes = utils::principal_to_subaccount(pool_id);
-                let mut pool_subaccount_array = [0u8; 32];
-                 for (i, byte) in pool_subaccount_bytes.iter().enumerate().take(32) {
-                    pool_subaccount_array[i] = *byte;
+    /// Legacy ICP transfer to an account identifier, for destinations that only publish one
+    ///
+    /// Account-based transfers go through ICRC-1 on the same ledger; this path exists
+    /// for legacy addresses (e.g. exchange deposit addresses) and numeric memos.
+    pub async fn transfer_to_account_identifier(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: AccountIdentifier, amount: u128, memo: u64) -> ExchangeResult<u128> {
+        if token.standard != TokenStandard::ICP {
+            return Err(ExchangeError::InvalidTokenStandard);
+        }
+        let fee = self.get_token_fee(token).await?;
+        metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Transfer,
+            self.icp_legacy_transfer(token, from_subaccount, to, amount, fee, memo),
+        ).await
+    }
+
+    /// Call the ICP ledger's legacy `transfer` endpoint; returns the block index
+    async fn icp_legacy_transfer(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: AccountIdentifier, amount: u128, fee: u128, memo: u64) -> ExchangeResult<u128> {
+        self.rate_limit(RateLimitKey::Ledger(token.canister_id)).await?;
+        debug_log!("Handling legacy ICP transfer to {}", to);
+        let amount = u64::try_from(amount)
+            .map_err(|_| ExchangeError::InvalidParameters(format!("ICP amount {} exceeds u64 e8s", amount)))?;
+        let fee = u64::try_from(fee)
+            .map_err(|_| ExchangeError::InternalError(format!("ICP fee {} exceeds u64 e8s", fee)))?;
+        let transfer_args = ic_ledger_types::TransferArgs {
+            memo: ic_ledger_types::Memo(memo),
+            amount: Tokens::from_e8s(amount),
+            fee: Tokens::from_e8s(fee),
+            from_subaccount: from_subaccount.map(ic_ledger_types::Subaccount),
+            to,
+            created_at_time: None,
+        };
+
+        let call_result: CallResult<(ICPTransferResult,)> = self.call(
+            token.canister_id,
+            "transfer",
+            (transfer_args,),
+        ).await;
+
+        match call_result {
+            Ok((transfer_result,)) => match transfer_result {
+                ICPTransferResult::Ok(block_index) => {
+                    debug_log!("ICP transfer successful, block index: {}", block_index);
+                    Ok(block_index as u128)
+                },
+                ICPTransferResult::Err(err) => {
+                    let error_msg = match err {
+                        ICPTransferError::BadFee { expected_fee } =>
+                            format!("Bad fee, expected: {} e8s", expected_fee.e8s()),
+                        ICPTransferError::InsufficientFunds { balance } =>
+                            format!("Insufficient funds, balance: {} e8s", balance.e8s()),
+                        ICPTransferError::TxTooOld { allowed_window_nanos } =>
+                            format!("Transaction too old, allowed window: {}ns", allowed_window_nanos),
+                        ICPTransferError::TxCreatedInFuture =>
+                            "Transaction created in the future".to_string(),
+                        ICPTransferError::TxDuplicate { duplicate_of } =>
+                            format!("Duplicate transaction of block index: {}", duplicate_of),
+                    };
+                    debug_log!("ICP transfer failed: {}", error_msg);
+                    Err(ExchangeError::TokenTransferFailed(format!("ICP transfer failed: {}", error_msg)))
                 }
-                let pool_subaccount = ic_ledger_types::Subaccount(pool_subaccount_array);
-
-                // Build the target account identifier
-                let to_account_identifier = AccountIdentifier::new(pool_id, &pool_subaccount);
-
-                // Prepare ICP transfer arguments
-                let transfer_args = ic_ledger_types::TransferArgs {
-                    memo: ic_ledger_types::Memo(0), // Use a default memo or make it configurable
-                    amount: Tokens::from_e8s(amount as u64), // Assuming amount fits in u64 E8s
-                    fee: Tokens::from_e8s(10_000), // Standard ICP transfer fee
-                    from_subaccount: Some(from_subaccount), // Keep this Some(from_subaccount) as per original logic before erroneous edit
-                    to: to_account_identifier,
-                    created_at_time: None,
-                };
+            },
+            Err((code, msg)) => {
+                debug_log!("ICP transfer call failed: {:?} - {}", code, msg);
+                Err(ExchangeError::TokenTransferFailed(format!("ICP transfer call failed: {:?} - {}", code, msg)))
+            },
+        }
+    }
 
-                // Call the ICP ledger's transfer method
-                let ledger_canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")
-                     .expect("Failed to parse ICP ledger canister ID"); // Use hardcoded ICP ledger ID as before
-                let call_result: CallResult<(ICPTransferResult,)> = ic_cdk::api::call::call(
-                    ledger_canister_id, // Use hardcoded ICP ledger ID
-                    "transfer",
-                    (transfer_args,),
-                ).await;
-                
-                match call_result {
-                    Ok((transfer_result,)) => match transfer_result {
-                        ICPTransferResult::Ok(block_index) => {
-                            ic_cdk::println!("ICP transfer successful, block index: {}", block_index);
-                            Ok(())
-                        },
-                        ICPTransferResult::Err(err) => {
-                            let error_msg = match err {
-                                ICPTransferError::BadFee { expected_fee } => 
-                                    format!("Bad fee, expected: {} e8s", expected_fee.e8s()),
-                                ICPTransferError::InsufficientFunds { balance } => 
-                                    format!("Insufficient funds, balance: {} e8s", balance.e8s()),
-                                ICPTransferError::TxTooOld { allowed_window_nanos } => 
-                                    format!("Transaction too old, allowed window: {}ns", allowed_window_nanos),
-                                ICPTransferError::TxCreatedInFuture => 
-                                    "Transaction created in the future".to_string(),
-                                ICPTransferError::TxDuplicate { duplicate_of } => 
-                                    format!("Duplicate transaction of block index: {}", duplicate_of),
-                            };
-                            ic_cdk::println!("ICP transfer failed: {}", error_msg);
-                            Err(ExchangeError::TokenTransferFailed(
-                                format!("ICP transfer failed: {}", error_msg)
-                            ))
-                        }
-                    },
-                    Err((code, msg)) => {
-                        ic_cdk::println!("ICP transfer call failed: {:?} - {}", code, msg);
-                        Err(ExchangeError::TokenTransferFailed(
-                            format!("ICP transfer call failed: {:?} - {}", code, msg)
-                        ))
-                    },
+    /// DIP20 tokens have no subaccounts; reject accounts that use one
+    fn require_dip20_account(account: &Account) -> ExchangeResult<()> {
+        if account.is_default() {
+            Ok(())
+        } else {
+            Err(ExchangeError::InvalidParameters("DIP20 tokens do not support subaccounts".to_string()))
+        }
+    }
+
+    /// EXT addresses an account by principal, or by account identifier when a subaccount is used
+    fn ext_user(account: &Account) -> EXTUser {
+        if account.is_default() {
+            EXTUser::principal(account.owner)
+        } else {
+            let subaccount = ic_ledger_types::Subaccount(account.effective_subaccount());
+            EXTUser::address(AccountIdentifier::new(&account.owner, &subaccount).to_string())
+        }
+    }
+
+    /// Query a DIP20 balance via `balanceOf`
+    async fn dip20_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> {
+        Self::require_dip20_account(account)?;
+        let result: CallResult<(candid::Nat,)> = self.call(token.canister_id, "balanceOf", (account.owner,)).await;
+        match result {
+            Ok((balance,)) => u128::try_from(balance.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert DIP20 balance {} to u128: {}", balance, e))),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to query DIP20 balance: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Query an EXT fungible token balance via `balance`
+    async fn ext_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> {
+        let request = EXTBalanceRequest {
+            user: Self::ext_user(account),
+            token: token.canister_id.to_string(),
+        };
+        let result: CallResult<(EXTBalanceResponse,)> = self.call(token.canister_id, "balance", (request,)).await;
+        match result {
+            Ok((EXTBalanceResponse::ok(balance),)) => u128::try_from(balance.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert EXT balance {} to u128: {}", balance, e))),
+            Ok((EXTBalanceResponse::err(e),)) => Err(ExchangeError::UnsupportedToken(format!("EXT balance error: {:?}", e))),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to query EXT balance: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Transfer DIP20 tokens; returns the transaction index
+    async fn dip20_transfer(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<u128> {
+        Self::require_dip20_account(&Account::with_subaccount(self.runtime.id(), from_subaccount))?;
+        Self::require_dip20_account(to)?;
+        debug_log!("Calling DIP20 transfer of {} to {}", amount, to.owner);
+        let result: CallResult<(DIP20TxReceipt,)> = self.call(
+            token.canister_id,
+            "transfer",
+            (to.owner, candid::Nat::from(amount)),
+        ).await;
+        match result {
+            Ok((DIP20TxReceipt::Ok(tx_index),)) => {
+                debug_log!("DIP20 transfer successful, tx index: {}", tx_index);
+                u128::try_from(tx_index.0.clone())
+                    .map_err(|e| ExchangeError::InternalError(format!("Failed to convert tx index {} to u128: {}", tx_index, e)))
+            },
+            Ok((DIP20TxReceipt::Err(e),)) => {
+                debug_log!("DIP20 transfer returned error: {:?}", e);
+                match e {
+                    DIP20TxError::InsufficientBalance => Err(ExchangeError::InsufficientFunds),
+                    other => Err(ExchangeError::TokenTransferFailed(format!("DIP20 transfer failed: {:?}", other))),
                 }
             },
-            TokenStandard::DIP20 | TokenStandard::EXT => {
-                // DIP20/EXT doesn't need this step, as they use Workflow 2, transferring directly from the user via depositFrom
-                ic_cdk::println!("DIP20/EXT tokens use Workflow 2, skipping transfer_token_to_pool_subaccount");
+            Err((code, msg)) => {
+                debug_log!("DIP20 transfer call failed: {:?} - {}", code, msg);
+                Err(ExchangeError::TokenTransferFailed(format!("DIP20 transfer failed: {:?} - {}", code, msg)))
+            },
+        }
+    }
+
+    /// Transfer EXT fungible tokens; returns the transferred balance reported by the token
+    async fn ext_transfer(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<()> {
+        let request = EXTTransferRequest {
+            from: EXTUser::principal(self.runtime.id()),
+            to: Self::ext_user(to),
+            token: token.canister_id.to_string(),
+            amount: candid::Nat::from(amount),
+            memo: serde_bytes::ByteBuf::new(),
+            notify: false,
+            subaccount: from_subaccount.map(|sub| serde_bytes::ByteBuf::from(sub.to_vec())),
+        };
+        debug_log!("Calling EXT transfer with args: {:?}", &request);
+        let result: CallResult<(EXTTransferResponse,)> = self.call(token.canister_id, "transfer", (request,)).await;
+        match result {
+            Ok((EXTTransferResponse::ok(balance),)) => {
+                // The reply carries the sender's remaining balance, not a transaction ID
+                debug_log!("EXT transfer successful, remaining balance: {}", balance);
                 Ok(())
             },
+            Ok((EXTTransferResponse::err(e),)) => {
+                debug_log!("EXT transfer returned error: {:?}", e);
+                match e {
+                    EXTTransferError::InsufficientBalance => Err(ExchangeError::InsufficientFunds),
+                    other => Err(ExchangeError::TokenTransferFailed(format!("EXT transfer failed: {:?}", other))),
+                }
+            },
+            Err((code, msg)) => {
+                debug_log!("EXT transfer call failed: {:?} - {}", code, msg);
+                Err(ExchangeError::TokenTransferFailed(format!("EXT transfer failed: {:?} - {}", code, msg)))
+            },
+        }
+    }
+
+    /// Look up a token's transfer fee from its ledger
+    pub async fn get_token_fee(&self, token: &TokenInfo) -> ExchangeResult<u128> {
+        let (method, fallback) = match token.standard {
+            TokenStandard::ICRC1 | TokenStandard::ICRC2 => ("icrc1_fee", None),
+            TokenStandard::ICP => ("icrc1_fee", Some(DEFAULT_FEE.e8s() as u128)),
+            TokenStandard::DIP20 => ("getTokenFee", None),
+            // EXT has no standard fee query; fungible EXT tokens charge no transfer fee
+            TokenStandard::EXT => return Ok(0),
+        };
+        let result: CallResult<(candid::Nat,)> = self.limited_call(RateLimitKey::Ledger(token.canister_id), token.canister_id, method, ()).await?;
+        match (result, fallback) {
+            (Ok((fee,)), _) => u128::try_from(fee.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert fee {} to u128: {}", fee, e))),
+            (Err(_), Some(fee)) => Ok(fee),
+            (Err((code, msg)), None) => Err(ExchangeError::CanisterCallError(format!("Failed to query {} fee: {:?} - {}", token.symbol, code, msg))),
         }
     }
     
+    /// Query the amount `owner` has approved `spender` to pull; zero for tokens without allowances
+    async fn get_allowance(&self, token: &TokenInfo, owner: &Account, spender: &Principal) -> ExchangeResult<u128> {
+        self.rate_limit(RateLimitKey::Ledger(token.canister_id)).await?;
+        let result: CallResult<(candid::Nat,)> = match token.standard {
+            TokenStandard::ICRC2 | TokenStandard::ICP => {
+                let args = ICRC2AllowanceArgs {
+                    account: owner.clone(),
+                    spender: Account::new(*spender),
+                };
+                let result: CallResult<(ICRC2Allowance,)> = self.call(token.canister_id, "icrc2_allowance", (args,)).await;
+                result.map(|(allowance,)| (allowance.allowance,))
+            },
+            TokenStandard::DIP20 => {
+                Self::require_dip20_account(owner)?;
+                self.call(token.canister_id, "allowance", (owner.owner, *spender)).await
+            },
+            TokenStandard::ICRC1 | TokenStandard::EXT => return Ok(0),
+        };
+        match result {
+            Ok((allowance,)) => u128::try_from(allowance.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert allowance {} to u128: {}", allowance, e))),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to query {} allowance: {:?} - {}", token.symbol, code, msg))),
+        }
+    }
+
+    /// Token of a listed pool, described by the node index overview if there is one and by
+    /// the metadata cache otherwise; `None` if neither knows it, since asking its ledger here
+    /// would cost a call per listed pool
+    fn listed_token(canister_id: Principal, standard: TokenStandard, overview: Option<(&String, f64)>) -> Option<TokenInfo> {
+        let (symbol, decimals) = match overview {
+            Some((symbol, decimals)) => {
+                let metadata = (symbol.clone(), decimals as u8);
+                TOKEN_METADATA.with(|cache| cache.borrow_mut().insert(canister_id, metadata.clone()));
+                metadata
+            }
+            None => TOKEN_METADATA.with(|cache| cache.borrow().get(&canister_id).cloned())?,
+        };
+        Some(TokenInfo { canister_id, symbol, decimals, standard })
+    }
+
+    /// Look up a token's symbol and decimals from its ledger, caching the answer
+    pub async fn get_token_metadata(&self, canister_id: Principal, standard: &TokenStandard) -> ExchangeResult<(String, u8)> {
+        if let Some(cached) = TOKEN_METADATA.with(|cache| cache.borrow().get(&canister_id).cloned()) {
+            return Ok(cached);
+        }
+        let key = RateLimitKey::Ledger(canister_id);
+        let metadata = match standard {
+            TokenStandard::ICRC1 | TokenStandard::ICRC2 | TokenStandard::ICP => {
+                let result: CallResult<(Vec<(String, ICRC1MetadataValue)>,)> =
+                    self.limited_call(key, canister_id, "icrc1_metadata", ()).await?;
+                let (entries,) = result.map_err(|(code, msg)| {
+                    ExchangeError::CanisterCallError(format!("Failed to call icrc1_metadata on {}: {:?} - {}", canister_id, code, msg))
+                })?;
+                let symbol = entries.iter().find_map(|(name, value)| match (name.as_str(), value) {
+                    ("icrc1:symbol", ICRC1MetadataValue::Text(symbol)) => Some(symbol.clone()),
+                    _ => None,
+                });
+                let decimals = entries.iter().find_map(|(name, value)| match (name.as_str(), value) {
+                    ("icrc1:decimals", ICRC1MetadataValue::Nat(decimals)) => u8::try_from(decimals.0.clone()).ok(),
+                    _ => None,
+                });
+                match (symbol, decimals) {
+                    (Some(symbol), Some(decimals)) => (symbol, decimals),
+                    _ => return Err(ExchangeError::InternalError(format!("Ledger {} publishes no symbol or decimals", canister_id))),
+                }
+            },
+            TokenStandard::DIP20 => {
+                let symbol: CallResult<(String,)> = self.limited_call(key, canister_id, "symbol", ()).await?;
+                let decimals: CallResult<(u8,)> = self.limited_call(key, canister_id, "decimals", ()).await?;
+                match (symbol, decimals) {
+                    (Ok((symbol,)), Ok((decimals,))) => (symbol, decimals),
+                    (Err((code, msg)), _) | (_, Err((code, msg))) => {
+                        return Err(ExchangeError::CanisterCallError(format!("Failed to query DIP20 metadata of {}: {:?} - {}", canister_id, code, msg)));
+                    }
+                }
+            },
+            TokenStandard::EXT => {
+                return Err(ExchangeError::UnsupportedToken(format!("EXT token {} publishes no standard metadata", canister_id)));
+            },
+        };
+        TOKEN_METADATA.with(|cache| cache.borrow_mut().insert(canister_id, metadata.clone()));
+        Ok(metadata)
+    }
+
+    /// Query a pool's metadata (current price, tick and liquidity)
+    async fn call_metadata(&self, pool_id: &Principal) -> ExchangeResult<ICPSwapPoolMetadata> {
+        let result: CallResult<(ICPSwapPoolMetadataResult,)> = self.limited_call(RateLimitKey::Pool(*pool_id), *pool_id, "metadata", ()).await?;
+        match result {
+            Ok((ICPSwapPoolMetadataResult::ok(metadata),)) => Ok(metadata),
+            Ok((ICPSwapPoolMetadataResult::err(err),)) => Err(self.map_icpswap_error(err)),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to call metadata: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Query the liquidity positions a principal holds in a pool
+    async fn call_get_user_positions(&self, pool_id: &Principal, user: &Principal) -> ExchangeResult<Vec<ICPSwapUserPosition>> {
+        self.rate_limit(RateLimitKey::Pool(*pool_id)).await?;
+        let result: CallResult<(ICPSwapUserPositionsResult,)> = self.call(*pool_id, "getUserPositionsByPrincipal", (*user,)).await;
+        match result {
+            Ok((ICPSwapUserPositionsResult::ok(positions),)) => Ok(positions),
+            Ok((ICPSwapUserPositionsResult::err(err),)) => Err(self.map_icpswap_error(err)),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to call getUserPositionsByPrincipal: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Query one page of a pool's initialized ticks
+    async fn call_get_ticks(&self, pool_id: &Principal, offset: u64, limit: u64) -> ExchangeResult<ICPSwapTicksPage> {
+        let result: CallResult<(ICPSwapTicksResult,)> = self.limited_call(RateLimitKey::Pool(*pool_id), *pool_id, "getTicks", (Nat::from(offset), Nat::from(limit))).await?;
+        match result {
+            Ok((ICPSwapTicksResult::ok(page),)) => Ok(page),
+            Ok((ICPSwapTicksResult::err(err),)) => Err(self.map_icpswap_error(err)),
+            Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to call getTicks: {:?} - {}", code, msg))),
+        }
+    }
+
+    /// Capture the price, liquidity and initialized ticks of the pool between two tokens
+    pub async fn get_pool_snapshot(&self, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<PoolSnapshot> {
+        let pool_data = self.get_pool_canister(base, quote).await?;
+        let pool_id = pool_data.canisterId;
+        let metadata = self.call_metadata(&pool_id).await?;
+
+        let mut ticks = Vec::new();
+        let mut offset = 0u64;
+        loop {
+            let page = self.call_get_ticks(&pool_id, offset, TICKS_PAGE_SIZE).await?;
+            let fetched = page.content.len() as u64;
+            for info in page.content {
+                let liquidity_net = i128::try_from(info.liquidityNet.0.clone())
+                    .map_err(|e| ExchangeError::InternalError(format!("Failed to convert liquidityNet {} to i128: {}", info.liquidityNet, e)))?;
+                ticks.push(TickLiquidity { tick: int_to_tick(&info.tickIndex)?, liquidity_net });
+            }
+            offset += fetched;
+            let total = u64::try_from(page.totalElements.0.clone()).unwrap_or(0);
+            if fetched == 0 || offset >= total {
+                break;
+            }
+        }
+        ticks.sort_by_key(|t| t.tick);
+
+        let parse_token = |address: &str| Principal::from_text(address)
+            .map_err(|e| ExchangeError::InternalError(format!("Invalid token principal {}: {}", address, e)));
+        Ok(PoolSnapshot {
+            pool_id,
+            token0: parse_token(&metadata.token0.address)?,
+            token1: parse_token(&metadata.token1.address)?,
+            fee: u64::try_from(metadata.fee.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert pool fee {} to u64: {}", metadata.fee, e)))?,
+            sqrt_price_x96: metadata.sqrtPriceX96.clone(),
+            tick: int_to_tick(&metadata.tick)?,
+            liquidity: u128::try_from(metadata.liquidity.0.clone())
+                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert liquidity {} to u128: {}", metadata.liquidity, e)))?,
+            ticks,
+            timestamp: self.now_secs(),
+        })
+    }
+
+    /// Quote a trade locally from a fresh snapshot and return it with its deviation (%) from the pool's own quote
+    pub async fn check_local_quote(&self, params: &TradeParams) -> ExchangeResult<(LocalQuote, f64)> {
+        let input_token = match params.direction {
+            TradeDirection::Buy => &params.pair.quote_token,
+            TradeDirection::Sell => &params.pair.base_token,
+        };
+        let snapshot = self.get_pool_snapshot(&params.pair.base_token, &params.pair.quote_token).await?;
+        let local = quote_engine::quote_trade(&snapshot, &input_token.canister_id, params.amount)?;
+        let on_chain = self.get_quote_internal(&snapshot.pool_id, snapshot.fee, params).await?;
+        let deviation = quote_engine::quote_deviation(local.output_amount, on_chain.output_amount);
+        debug_log!("Local quote {} vs on-chain {} ({:.4}% deviation)", local.output_amount, on_chain.output_amount, deviation);
+        Ok((local, deviation))
+    }
+
+    /// Collect unused balances, allowances and LP positions held in one pool
+    async fn pool_holdings(&self, pool_data: &ICPSwapPoolData, token0: &TokenInfo, token1: &TokenInfo, account: &Account) -> ExchangeResult<Vec<Holding>> {
+        let pool_id = pool_data.canisterId;
+        let mut holdings = Vec::new();
+        let mut push = |token: &TokenInfo, location: HoldingLocation, amount: u128| {
+            if amount > 0 {
+                holdings.push(Holding { token: token.clone(), location, amount, value: None });
+            }
+        };
+
+        // Pools key balances and positions by the calling principal, not by subaccount
+        let (unused0, unused1) = self.call_get_user_unused_balance(&pool_id, &account.owner).await?;
+        push(token0, HoldingLocation::PoolUnused { pool_id }, unused0);
+        push(token1, HoldingLocation::PoolUnused { pool_id }, unused1);
+
+        for token in [token0, token1] {
+            let allowance = self.get_allowance(token, account, &pool_id).await?;
+            push(token, HoldingLocation::Allowance { spender: pool_id }, allowance);
+        }
+
+        let positions = self.call_get_user_positions(&pool_id, &account.owner).await?;
+        if !positions.is_empty() {
+            let metadata = self.call_metadata(&pool_id).await?;
+            let sqrt_price = nat_to_f64(&metadata.sqrtPriceX96) / 2f64.powi(96);
+            for position in positions {
+                let tick_lower = int_to_i64(&position.tickLower)?;
+                let tick_upper = int_to_i64(&position.tickUpper)?;
+                let (amount0, amount1) = position_amounts(nat_to_f64(&position.liquidity), sqrt_price, tick_lower, tick_upper);
+                let position_id = u128::try_from(position.id.0.clone())
+                    .map_err(|e| ExchangeError::InternalError(format!("Failed to convert position id {} to u128: {}", position.id, e)))?;
+                let owed0 = u128::try_from(position.tokensOwed0.0.clone())
+                    .map_err(|e| ExchangeError::InternalError(format!("Failed to convert tokensOwed0 {} of position {} to u128: {}", position.tokensOwed0, position_id, e)))?;
+                let owed1 = u128::try_from(position.tokensOwed1.0.clone())
+                    .map_err(|e| ExchangeError::InternalError(format!("Failed to convert tokensOwed1 {} of position {} to u128: {}", position.tokensOwed1, position_id, e)))?;
+                let location = HoldingLocation::LiquidityPosition { pool_id, position_id };
+                push(token0, location.clone(), (amount0 as u128).saturating_add(owed0));
+                push(token1, location, (amount1 as u128).saturating_add(owed1));
+            }
+        }
+
+        Ok(holdings)
+    }
+
+    /// Ledger transfer fee of a token, as passed to the pool and the ledger in every leg
+    async fn trade_fee(&self, token: &TokenInfo) -> ExchangeResult<u64> {
+        let fee = self.get_token_fee(token).await?;
+        u64::try_from(fee)
+            .map_err(|_| ExchangeError::InternalError(format!("{} fee {} exceeds u64", token.symbol, fee)))
+    }
+
+    /// Plan a trade by running the read-only steps of `execute_icpswap_trade`
+    async fn plan_icpswap_trade(&self, params: &TradeParams) -> TradePlan {
+        let (input_token, output_token) = match params.direction {
+            TradeDirection::Buy => (&params.pair.quote_token, &params.pair.base_token),
+            TradeDirection::Sell => (&params.pair.base_token, &params.pair.quote_token),
+        };
+        let mut plan = TradePlan {
+            pool_id: None,
+            input_token: input_token.clone(),
+            output_token: output_token.clone(),
+            steps: Vec::new(),
+            input_ledger_fees: 0,
+            output_ledger_fees: 0,
+            expected_output: 0,
+            min_output: 0,
+            price_impact: 0.0,
+            estimated_cycles: 0,
+            blockers: Vec::new(),
+        };
+
+        // 1. Validate trade parameters
+        if let Err(e) = utils::validate_trade_params_at(params, self.now_secs()) {
+            plan.blockers.push(format!("Invalid trade parameters: {}", e));
+        }
+
+        // 2. Get pool information; nothing else can be checked without a pool
+        let pool_data = match self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await {
+            Ok(pool_data) => pool_data,
+            Err(e) => {
+                plan.blockers.push(format!("Pool lookup failed: {}", e));
+                plan.estimated_cycles = utils::ESTIMATED_CYCLES_PER_CALL;
+                return plan;
+            }
+        };
+        let pool_id = pool_data.canisterId;
+        plan.pool_id = Some(pool_id);
+        let mut read_calls = 1u128;
+
+        // 3. Get quote and the pool price it is measured against
+        read_calls += 2;
+        let quote = match Self::pool_fee_ppm(&pool_data) {
+            Ok(pool_fee) => self.get_quote_internal(&pool_id, pool_fee, params).await,
+            Err(e) => Err(e),
+        };
+        match quote {
+            Ok(quote) => {
+                plan.expected_output = quote.output_amount;
+                plan.min_output = (quote.output_amount as f64 * (1.0 - params.slippage_tolerance / 100.0)) as u128;
+                plan.price_impact = quote.price_impact;
+            },
+            Err(e) => plan.blockers.push(format!("Quote failed: {}", e)),
+        }
+
+        // 4. Ledger fees
+        read_calls += 2;
+        let (input_fee, output_fee) = match (self.trade_fee(input_token).await, self.trade_fee(output_token).await) {
+            (Ok(input_fee), Ok(output_fee)) => (input_fee as u128, output_fee as u128),
+            (Err(e), _) | (_, Err(e)) => {
+                plan.blockers.push(format!("Fee lookup failed: {}", e));
+                (0, 0)
+            }
+        };
+
+        // 5. Balance check
+        read_calls += 1;
+        let account = self.account();
+        match self.get_token_balance(input_token, &account).await {
+            Ok(available) if available < params.amount.saturating_add(input_fee) => plan.blockers.push(format!(
+                "Insufficient {} balance: {} available, {} plus fee {} required",
+                input_token.symbol, available, params.amount, input_fee
+            )),
+            Ok(_) => {},
+            Err(e) => plan.blockers.push(format!("Balance check failed: {}", e)),
+        }
+
+        // 6. Deposit steps, following the same workflow selection as execution
+        let use_subaccount_transfer = matches!(input_token.standard, TokenStandard::ICRC2 | TokenStandard::ICP) && !account.is_default();
+        if input_token.standard == TokenStandard::ICRC1 || use_subaccount_transfer {
+            plan.steps.push(TradeStep::Transfer {
+                token: input_token.canister_id,
+                to: Account::with_subaccount(pool_id, Some(utils::subaccount_from_principal(&self.runtime.id()))),
+                amount: params.amount,
+                fee: input_fee,
+            });
+            plan.steps.push(TradeStep::Deposit { pool_id, token: input_token.canister_id, amount: params.amount, fee: input_fee });
+            plan.input_ledger_fees += input_fee * 2;
+        } else {
+            read_calls += 1;
+            let current_allowance = match self.get_allowance(input_token, &account, &pool_id).await {
+                Ok(allowance) => allowance,
+                Err(e) => {
+                    plan.blockers.push(format!("Allowance check failed: {}", e));
+                    0
+                }
+            };
+            // Execution always re-approves the pool; approvals on ICRC-2 ledgers are charged a fee
+            let approve_fee = match input_token.standard {
+                TokenStandard::ICRC2 | TokenStandard::ICP => input_fee,
+                _ => 0,
+            };
+            plan.steps.push(TradeStep::Approve {
+                token: input_token.canister_id,
+                spender: pool_id,
+                amount: params.amount.saturating_mul(100),
+                current_allowance,
+                fee: approve_fee,
+            });
+            plan.steps.push(TradeStep::DepositFrom { pool_id, token: input_token.canister_id, amount: params.amount, fee: input_fee });
+            plan.input_ledger_fees += approve_fee + input_fee;
+        }
+
+        // 7. Swap and withdraw
+        plan.steps.push(TradeStep::Swap { pool_id, amount_in: params.amount, min_amount_out: plan.min_output });
+        plan.steps.push(TradeStep::Withdraw { pool_id, token: output_token.canister_id, amount: plan.expected_output, fee: output_fee });
+        plan.output_ledger_fees += output_fee;
+
+        // 8. Proceeds land on the default subaccount and are forwarded to the trading subaccount
+        if !account.is_default() {
+            plan.steps.push(TradeStep::Transfer {
+                token: output_token.canister_id,
+                to: account,
+                amount: plan.expected_output.saturating_sub(output_fee * 2),
+                fee: output_fee,
+            });
+            plan.output_ledger_fees += output_fee;
+        }
+
+        plan.estimated_cycles = (read_calls + plan.steps.len() as u128) * utils::ESTIMATED_CYCLES_PER_CALL;
+        plan
+    }
+
     /// Execute trade based on token standard
     async fn execute_icpswap_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
         // 1. Validate trade parameters
-        utils::validate_trade_params(params)?;
+        utils::validate_trade_params_at(params, self.now_secs())?;
         
         // 2. Get pool information
         let pool_data = self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await?;
         
         // 3. Get quote
-        let quote_result = self.get_quote_internal(&pool_data.canisterId, params).await?;
+        let pool_fee_u64 = Self::pool_fee_ppm(&pool_data)?;
+        let quote_result = self.get_quote_internal(&pool_data.canisterId, pool_fee_u64, params).await?;
         
         // 4. Determine input and output tokens
         let (input_token, output_token) = match params.direction {
@@ -598,29 +1446,58 @@ impl ICPSwapConnector {
         let amount_out_minimum = (quote_result.output_amount as f64 * (1.0 - params.slippage_tolerance / 100.0)) as u128;
         let amount_out_minimum_str = amount_out_minimum.to_string();
         
-        // 7. Get pool_fee (u64) and calculate input_token_fee (Nat) beforehand
-        let pool_fee_u64 = u64::try_from(pool_data.fee.0.clone())
-            .map_err(|e| ExchangeError::InternalError(format!("Failed to convert pool fee Nat {:?} to u64: {}", pool_data.fee.0, e)))?;
-        let input_token_fee = match input_token.standard {
-            TokenStandard::ICRC1 | TokenStandard::ICP => 10000_u64,
-            TokenStandard::ICRC2 => 10_u64, 
-            _ => 0_u64, 
-        };
+        // 7. Calculate input_token_fee (Nat) beforehand
+        let input_token_fee = self.trade_fee(input_token).await?;
         let input_token_fee_nat = candid::Nat::from(input_token_fee);
+        // Looked up before any funds move, while a rate limit can still refuse the trade
+        let withdraw_fee_u64 = self.trade_fee(output_token).await?;
+
+        // Pre-trade balance check so legacy tokens fail fast instead of mid-workflow
+        let available = self.get_token_balance(input_token, &self.account()).await?;
+        if available < amount_in_u128.saturating_add(input_token_fee as u128) {
+            debug_log!("Insufficient {} balance: {} < {} + fee {}", input_token.symbol, available, amount_in_u128, input_token_fee);
+            return Err(ExchangeError::InsufficientFunds);
+        }
 
-        // 8. Define caller and swap_result
-        let caller = ic_cdk::caller(); 
+        // 8. Define swap_result, and track the fees and ledger blocks of the trade
         let mut swap_result = candid::Nat::from(0u64); // Ensure this initialization is correct
+        let mut fees = FeeBreakdown {
+            pool_fee: utils::pool_fee_amount(amount_in_u128, pool_fee_u64),
+            ..FeeBreakdown::default()
+        };
+        let mut ledger_transactions = Vec::new();
+        let mut expected_legs: Vec<(&TokenInfo, ExpectedTransfer)> = Vec::new();
+
+        // depositFrom always pulls from the default subaccount, so ICRC2 tokens (ICP included)
+        // held in another subaccount are moved with a transfer instead (Workflow 1)
+        let use_subaccount_transfer = matches!(input_token.standard, TokenStandard::ICRC2 | TokenStandard::ICP) && !self.account().is_default();
 
         // 9. Choose different trade flows based on token standard
-        match input_token.standard {
-            // --- Workflow 1 (ICRC1 & ICP) --- 
-            TokenStandard::ICRC1  => {
-                ic_cdk::println!("Executing Workflow 1 for {:?}", input_token.standard);
+        match (&input_token.standard, use_subaccount_transfer) {
+            // --- Workflow 1 (ICRC1, and ICRC2/ICP from a subaccount) --- 
+            (TokenStandard::ICRC1, _) | (TokenStandard::ICRC2 | TokenStandard::ICP, true) => {
+                debug_log!("Executing Workflow 1 for {:?}", input_token.standard);
                 
                 // Step 2: Transfer token to SwapPool's subaccount (using u64 fee)
-                ic_cdk::println!("Transferring token to pool subaccount");
-                self.transfer_token_to_pool_subaccount(input_token, &pool_data.canisterId, amount_in_u128, input_token_fee).await?;
+                debug_log!("Transferring token to pool subaccount");
+                let transfer_block = self.transfer_token_to_pool_subaccount(input_token, &pool_data.canisterId, amount_in_u128, input_token_fee).await?;
+                if let Some(block_index) = transfer_block {
+                    ledger_transactions.push(LedgerTransaction {
+                        ledger: input_token.canister_id,
+                        block_index,
+                        operation: LedgerOperation::Transfer,
+                    });
+                    expected_legs.push((input_token, ExpectedTransfer {
+                        block_index: Some(block_index),
+                        from_owner: None,
+                        to: Account::with_subaccount(pool_data.canisterId, Some(utils::subaccount_from_principal(&self.runtime.id()))),
+                        amounts: vec![amount_in_u128],
+                        memo: Self::sent_memo(input_token),
+                        check_memo: true,
+                    }));
+                }
+                // One fee for the transfer into the pool subaccount, one for the pool's deposit
+                fees.input_transfer_fee = 2 * input_token_fee as u128;
                 
                 // Step 3: Call deposit method (using Nat fee)
                 let deposit_args = ICPSwapDepositArgs {
@@ -629,16 +1506,16 @@ impl ICPSwapConnector {
                     amount: amount_in_nat.clone(),
                 };
                 
-                ic_cdk::println!("Depositing token to pool with token fee: {}", input_token_fee);
+                debug_log!("Depositing token to pool with token fee: {}", input_token_fee);
                 let deposit_result = match self.call_deposit(&pool_data.canisterId, deposit_args).await {
                     Ok(result) => result,
                     Err(e) => {
-                        ic_cdk::println!("Deposit failed: {:?}", e);
+                        debug_log!("Deposit failed: {:?}", e);
                         // Potentially call handle_deposit_failure here if needed, considering async context
                         return Err(e);
                     }
                 };
-                ic_cdk::println!("Deposit result: {}", deposit_result);
+                debug_log!("Deposit result: {}", deposit_result);
 
                 // Step 4: Execute swap
                 let swap_args = ICPSwapSwapArgs {
@@ -646,22 +1523,30 @@ impl ICPSwapConnector {
                     amountIn: amount_in_str, 
                     amountOutMinimum: amount_out_minimum_str,
                 };
-                ic_cdk::println!("Executing swap");
+                debug_log!("Executing swap");
                 swap_result = self.call_swap(&pool_data.canisterId, swap_args).await
                    .map_err(|e| {
-                        ic_cdk::println!("Swap failed directly: {:?}", e);
+                        debug_log!("Swap failed directly: {:?}", e);
                         // Potentially call handle_swap_failure here
                         e 
                     })?; 
-                ic_cdk::println!("Swap result: {}", swap_result);
+                debug_log!("Swap result: {}", swap_result);
             },
-            // --- Workflow 2 (ICRC2, DIP20, EXT) --- 
-            TokenStandard::DIP20 | TokenStandard::EXT | TokenStandard::ICRC2 | TokenStandard::ICP => {
-                ic_cdk::println!("Executing Workflow 2 for {:?}", input_token.standard);
+            // --- Workflow 2 (ICRC2, ICP, DIP20, EXT) --- 
+            (TokenStandard::DIP20 | TokenStandard::EXT | TokenStandard::ICRC2 | TokenStandard::ICP, _) => {
+                debug_log!("Executing Workflow 2 for {:?}", input_token.standard);
                 
-                // Step 2: NO APPROVE CALL HERE. User MUST approve the pool BEFORE calling execute_trade.
-                self.approve_token(input_token, &pool_data.canisterId, amount_in_u128 * 100).await?;
-                ic_cdk::println!("Skipping internal approve. Assuming user pre-approved pool {:?}", pool_data.canisterId);
+                // Step 2: Approve the pool to pull the input, with the same headroom as the plan's Approve step
+                let approve_block = self.approve_with_block(input_token, &pool_data.canisterId, amount_in_u128.saturating_mul(100)).await?;
+                if let Some(block_index) = approve_block {
+                    ledger_transactions.push(LedgerTransaction {
+                        ledger: input_token.canister_id,
+                        block_index,
+                        operation: LedgerOperation::Approve,
+                    });
+                    fees.approve_fee = input_token_fee as u128;
+                }
+                fees.input_transfer_fee = input_token_fee as u128;
 
                 // Step 3: Call depositFrom method (using Nat fee)
                 let deposit_args = ICPSwapDepositFromArgs {
@@ -670,14 +1555,23 @@ impl ICPSwapConnector {
                     amount: amount_in_nat.clone(),
                 };
                 
-                ic_cdk::println!("Calling depositFrom with token fee: {}", input_token_fee);
+                debug_log!("Calling depositFrom with token fee: {}", input_token_fee);
                 let deposit_result = self.call_deposit_from(&pool_data.canisterId, deposit_args).await
                     .map_err(|e| {
-                        ic_cdk::println!("DepositFrom failed directly: {:?}", e);
+                        debug_log!("DepositFrom failed directly: {:?}", e);
                         // Potentially call handle_deposit_failure here
                         e 
                     })?; 
-                ic_cdk::println!("DepositFrom result: {}", deposit_result);
+                debug_log!("DepositFrom result: {}", deposit_result);
+                // The pool pulls the input itself, so its block index is unknown and has to be searched for
+                expected_legs.push((input_token, ExpectedTransfer {
+                    block_index: None,
+                    from_owner: Some(self.runtime.id()),
+                    to: Account::new(pool_data.canisterId),
+                    amounts: vec![amount_in_u128, amount_in_u128.saturating_sub(input_token_fee as u128)],
+                    memo: None,
+                    check_memo: false,
+                }));
                 
                  // Step 4: Execute swap
                  let swap_args = ICPSwapSwapArgs {
@@ -685,58 +1579,166 @@ impl ICPSwapConnector {
                      amountIn: amount_in_str,
                      amountOutMinimum: amount_out_minimum_str,
                  };
-                ic_cdk::println!("Executing swap");
+                debug_log!("Executing swap");
                  swap_result = self.call_swap(&pool_data.canisterId, swap_args).await
                     .map_err(|e| {
-                         ic_cdk::println!("Swap failed directly: {:?}", e);
+                         debug_log!("Swap failed directly: {:?}", e);
                          // Potentially call handle_swap_failure here
                          e
                      })?; 
-                ic_cdk::println!("Swap result: {}", swap_result);
+                debug_log!("Swap result: {}", swap_result);
             },
         }
         
         // 10. Step 5: Withdraw output token (Common for all workflows)
-        let withdraw_fee_u64 = match output_token.standard {
-            TokenStandard::ICRC1 | TokenStandard::ICP => 10000_u64,
-            TokenStandard::ICRC2 => 10_u64, 
-            TokenStandard::DIP20 => 0_u64, 
-            TokenStandard::EXT => 0_u64,
-        };
         let withdraw_fee_nat = candid::Nat::from(withdraw_fee_u64); // Convert fee to Nat
         let withdraw_args = ICPSwapWithdrawArgs {
             fee: withdraw_fee_nat,                       // Pass Nat fee
             token: output_token.canister_id.to_string(),
             amount: swap_result.clone(),                 // Pass Nat amount directly (use swap_result)
         };
-        ic_cdk::println!("Withdrawing output token with args: {:?}", withdraw_args);
+        debug_log!("Withdrawing output token with args: {:?}", withdraw_args);
         let withdraw_result_nat = match self.call_withdraw(&pool_data.canisterId, withdraw_args).await {
             Ok(result) => result, // Result is Nat
             Err(e) => {
-                ic_cdk::println!("Withdraw failed: {:?}", e);
+                debug_log!("Withdraw failed: {:?}", e);
                 // Potentially call handle_withdraw_failure here
                 return Err(e);
             }
         };
         
-        // 11. Build trade result - Use swap_result (Nat) which represents the amount before withdrawal fee
-        let final_output_amount_u128 = u128::try_from(swap_result.0.clone()).map_err(|e| { // Use swap_result for final amount
+        // 11. The swap result is the gross output; the withdraw result is what actually reached the wallet
+        let swap_output_u128 = u128::try_from(swap_result.0.clone()).map_err(|e| {
             ExchangeError::InternalError(format!("Failed to convert final swap result Nat {:?} to u128: {}", swap_result.0, e))
         })?;
+        let mut net_output = u128::try_from(withdraw_result_nat.0.clone()).map_err(|e| {
+            ExchangeError::InternalError(format!("Failed to convert withdraw result Nat {:?} to u128: {}", withdraw_result_nat.0, e))
+        })?;
+        fees.withdraw_fee = withdraw_fee_u64 as u128;
+        // The pool sends the withdrawal, so its block index is unknown and has to be searched for
+        expected_legs.push((output_token, ExpectedTransfer {
+            block_index: None,
+            from_owner: Some(pool_data.canisterId),
+            to: Account::new(self.runtime.id()),
+            amounts: vec![net_output, net_output.saturating_sub(withdraw_fee_u64 as u128)],
+            memo: None,
+            check_memo: false,
+        }));
+
+        // 12. Withdrawals land on the default subaccount; forward proceeds to the trading subaccount
+        if !self.account().is_default() {
+            let forward_amount = net_output.saturating_sub(withdraw_fee_u64 as u128);
+            if forward_amount > 0 {
+                let forward_block = self.transfer_from_subaccount(output_token, None, &self.account(), forward_amount, withdraw_fee_u64).await?;
+                if let Some(block_index) = forward_block {
+                    ledger_transactions.push(LedgerTransaction {
+                        ledger: output_token.canister_id,
+                        block_index,
+                        operation: LedgerOperation::Transfer,
+                    });
+                    expected_legs.push((output_token, ExpectedTransfer {
+                        block_index: Some(block_index),
+                        from_owner: None,
+                        to: self.account(),
+                        amounts: vec![forward_amount],
+                        memo: Self::sent_memo(output_token),
+                        check_memo: true,
+                    }));
+                }
+                fees.withdraw_fee += withdraw_fee_u64 as u128;
+            }
+            net_output = forward_amount;
+        }
+        // Execution price is taken at the swap, before withdraw and forwarding fees
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, params.amount, swap_output_u128);
         let trade_result = TradeResult {
             input_amount: params.amount,
-            output_amount: final_output_amount_u128, // Use the amount calculated from swap_result
-            fee_amount: (params.amount as f64 * (pool_fee_u64 as f64 / 1_000_000.0)) as u128, 
-            price: if params.amount > 0 { final_output_amount_u128 as f64 / params.amount as f64 } else { 0.0 },
-            timestamp: utils::current_timestamp_secs(),
-            transaction_id: Some(format!("icpswap_{}_{}", pool_data.canisterId.to_string(), utils::current_timestamp_nanos())),
+            output_amount: net_output,
+            fee_amount: fees.pool_fee,
+            price,
+            inverse_price,
+            timestamp: self.now_secs(),
+            transaction_id: utils::ledger_transaction_id(&ledger_transactions),
+            fees,
+            ledger_transactions,
         };
+
+        // 13. Confirm each leg on its ledger and record the trade for auditing
+        self.journal_trade(&pool_data.canisterId, input_token, output_token, &trade_result, expected_legs).await;
         
         Ok(trade_result)
     }
+
+    /// Memo this connector attaches to its own transfers of a token
+    fn sent_memo(token: &TokenInfo) -> Option<Vec<u8>> {
+        match token.standard {
+            // ICRC-1 transfers without a memo are recorded by the ICP ledger with legacy memo 0
+            TokenStandard::ICP => Some(verification::icp_memo_bytes(0)),
+            _ => None,
+        }
+    }
+
+    /// Verify the ledger legs of an executed trade and add it to the trade journal
+    ///
+    /// Verification never fails the trade, since its funds have already moved;
+    /// discrepancies are flagged on the journal entry instead.
+    async fn journal_trade(
+        &self,
+        pool_id: &Principal,
+        input_token: &TokenInfo,
+        output_token: &TokenInfo,
+        trade_result: &TradeResult,
+        expected_legs: Vec<(&TokenInfo, ExpectedTransfer)>,
+    ) {
+        let mut legs = Vec::with_capacity(expected_legs.len());
+        for (token, expected) in expected_legs {
+            // Legs on ledgers without a block log are recorded as unsupported without a call
+            let allowed = if verification::has_block_log(&token.standard) {
+                self.rate_limit(RateLimitKey::Ledger(token.canister_id)).await
+            } else {
+                Ok(())
+            };
+            let check = match allowed {
+                Ok(()) => verification::verify_transfer(self.runtime.as_ref(), token, &expected, verification::DEFAULT_LOOKBACK_BLOCKS).await,
+                Err(e) => TransferCheck {
+                    ledger: token.canister_id,
+                    block_index: expected.block_index,
+                    to: expected.to.clone(),
+                    amount: expected.amounts.first().copied().unwrap_or(0),
+                    memo: expected.memo.clone(),
+                    verification: TransferVerification::Failed(e.to_string()),
+                },
+            };
+            legs.push(check);
+        }
+
+        let discrepancies = journal::leg_discrepancies(&legs);
+        if !discrepancies.is_empty() {
+            debug_log!("Trade on pool {} flagged: {:?}", pool_id, discrepancies);
+        }
+        journal::record(JournalEntry {
+            id: 0,
+            timestamp: trade_result.timestamp,
+            exchange: ExchangeType::ICPSwap,
+            pool_id: *pool_id,
+            input_token: input_token.canister_id,
+            output_token: output_token.canister_id,
+            input_amount: trade_result.input_amount,
+            output_amount: trade_result.output_amount,
+            transaction_id: trade_result.transaction_id.clone(),
+            legs,
+            discrepancies,
+        });
+    }
     
-    /// Internal method to get a quote
-    async fn get_quote_internal(&self, pool_id: &Principal, params: &TradeParams) -> ExchangeResult<QuoteResult> {
+    /// Pool fee in millionths, as listed by the factory
+    fn pool_fee_ppm(pool_data: &ICPSwapPoolData) -> ExchangeResult<u64> {
+        u64::try_from(pool_data.fee.0.clone())
+            .map_err(|e| ExchangeError::InternalError(format!("Failed to convert pool fee Nat {:?} to u64: {}", pool_data.fee.0, e)))
+    }
+
+    /// Internal method to get a quote from a pool charging `pool_fee` (millionths)
+    async fn get_quote_internal(&self, pool_id: &Principal, pool_fee: u64, params: &TradeParams) -> ExchangeResult<QuoteResult> {
         // Determine input and output tokens
         let (input_token, output_token) = match params.direction {
             TradeDirection::Buy => (&params.pair.quote_token, &params.pair.base_token),
@@ -761,30 +1763,40 @@ impl ICPSwapConnector {
             Err(e) => return Err(ExchangeError::InternalError(format!("Failed to convert quote BigUint {:?} to u128: {}", quote_amount_nat.0, e))), 
         };
 
-        // Calculate fee (0.3%)
-        let fee_amount = (params.amount as f64 * 0.003) as u128;
+        let fee_amount = utils::pool_fee_amount(params.amount, pool_fee);
+
+        // The pool quotes only the output; impact is measured against its current spot price
+        let metadata = self.call_metadata(pool_id).await?;
+        let sqrt_price = quote_engine::sqrt_price_from_nat(&metadata.sqrtPriceX96)?;
+        let price_impact = quote_engine::price_impact(sqrt_price, zero_for_one, params.amount.saturating_sub(fee_amount), quote_amount_u128);
         
-        // Calculate price - handle potential division by zero
-        let price = if params.amount > 0 {
-            quote_amount_u128 as f64 / params.amount as f64 
-        } else {
-            0.0
-        };
+        // Calculate the base/quote price in human units; zero when nothing would be exchanged
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, params.amount, quote_amount_u128);
 
         // Construct quote result
         let quote_result = QuoteResult {
             input_amount: params.amount,
             output_amount: quote_amount_u128,
             price,
+            inverse_price,
             fee_amount,
-            price_impact: 0.0, // Price impact not directly available from ICPSwap API, can be improved later
+            price_impact,
         };
 
         Ok(quote_result)
     }
 
-    /// Approve token
-    async fn approve_token(&self, token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<()> {
+    /// Approve token; returns the ledger block index for ledgers that record approvals
+    async fn approve_with_block(&self, token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<Option<u128>> {
+        metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::Approve,
+            self.approve_on_ledger(token, spender, amount),
+        ).await
+    }
+
+    /// Submit an approval to the token's ledger according to its standard
+    async fn approve_on_ledger(&self, token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<Option<u128>> {
         match token.standard {
             TokenStandard::DIP20 => {
                 // DIP20 approve
@@ -806,8 +1818,8 @@ impl ICPSwapConnector {
                     amount: candid::Nat::from(amount),
                 };
                 
-                ic_cdk::println!("Calling DIP20 approve with args: {:?}", &args);
-                let result: CallResult<(DIP20ApproveResult,)> = ic_cdk::api::call::call(
+                debug_log!("Calling DIP20 approve with args: {:?}", &args);
+                let result: CallResult<(DIP20ApproveResult,)> = self.call(
                     token.canister_id,
                     "approve",
                     (args,),
@@ -816,16 +1828,16 @@ impl ICPSwapConnector {
                 match result {
                     Ok((approve_result,)) => match approve_result {
                         DIP20ApproveResult::ok(()) => {
-                            ic_cdk::println!("DIP20 approve successful");
-                            Ok(())
+                            debug_log!("DIP20 approve successful");
+                            Ok(None)
                         },
                         DIP20ApproveResult::err(e) => {
-                            ic_cdk::println!("DIP20 approve returned error: {}", e);
+                            debug_log!("DIP20 approve returned error: {}", e);
                             Err(ExchangeError::TokenApprovalFailed(e))
                         },
                     },
                     Err((code, msg)) => {
-                        ic_cdk::println!("DIP20 approve call failed: {:?} - {}", code, msg);
+                        debug_log!("DIP20 approve call failed: {:?} - {}", code, msg);
                         Err(ExchangeError::CanisterCallError(
                             format!("DIP20 approve failed: {:?} - {}", code, msg)
                         ))
@@ -854,8 +1866,8 @@ impl ICPSwapConnector {
                     allowance: candid::Nat::from(amount),
                 };
                 
-                ic_cdk::println!("Calling EXT approve with args: {:?}", &args);
-                let result: CallResult<(EXTApproveResult,)> = ic_cdk::api::call::call(
+                debug_log!("Calling EXT approve with args: {:?}", &args);
+                let result: CallResult<(EXTApproveResult,)> = self.call(
                     token.canister_id,
                     "approve",
                     (args,),
@@ -864,16 +1876,16 @@ impl ICPSwapConnector {
                 match result {
                     Ok((approve_result,)) => match approve_result {
                         EXTApproveResult::ok(()) => {
-                            ic_cdk::println!("EXT approve successful");
-                            Ok(())
+                            debug_log!("EXT approve successful");
+                            Ok(None)
                         },
                         EXTApproveResult::err(e) => {
-                            ic_cdk::println!("EXT approve returned error: {}", e);
+                            debug_log!("EXT approve returned error: {}", e);
                             Err(ExchangeError::TokenApprovalFailed(e))
                         },
                     },
                     Err((code, msg)) => {
-                        ic_cdk::println!("EXT approve call failed: {:?} - {}", code, msg);
+                        debug_log!("EXT approve call failed: {:?} - {}", code, msg);
                         Err(ExchangeError::CanisterCallError(
                             format!("EXT approve failed: {:?} - {}", code, msg)
                         ))
@@ -924,8 +1936,8 @@ impl ICPSwapConnector {
                     amount: candid::Nat::from(amount),
                 };
                 
-                ic_cdk::println!("Calling icrc2_approve with correct spender Account: {:?}", &args);
-                let result: CallResult<(ICRCApproveResult,)> = ic_cdk::api::call::call(
+                debug_log!("Calling icrc2_approve with correct spender Account: {:?}", &args);
+                let result: CallResult<(ICRCApproveResult,)> = self.call(
                     token.canister_id,
                     "icrc2_approve",
                     (args,),
@@ -933,9 +1945,11 @@ impl ICPSwapConnector {
                 
                 match result {
                     Ok((approve_result,)) => match approve_result {
-                        ICRCApproveResult::Ok(_) => {
-                            ic_cdk::println!("ICRC2 approve successful");
-                            Ok(())
+                        ICRCApproveResult::Ok(block_index) => {
+                            debug_log!("ICRC2 approve successful, block index: {}", block_index);
+                            u128::try_from(block_index.0.clone())
+                                .map(Some)
+                                .map_err(|e| ExchangeError::InternalError(format!("Failed to convert block index {} to u128: {}", block_index, e)))
                         },
                         ICRCApproveResult::Err(e) => {
                             let error_msg = match &e {
@@ -958,12 +1972,12 @@ impl ICPSwapConnector {
                                 ApproveError::GenericError { error_code, message } => 
                                     format!("Generic error {}: {}", error_code, message),
                             };
-                            ic_cdk::println!("ICRC2 approve returned error: {}", error_msg);
+                            debug_log!("ICRC2 approve returned error: {}", error_msg);
                             Err(ExchangeError::TokenApprovalFailed(error_msg))
                         },
                     },
                     Err((code, msg)) => {
-                        ic_cdk::println!("ICRC2 approve call failed: {:?} - {}", code, msg);
+                        debug_log!("ICRC2 approve call failed: {:?} - {}", code, msg);
                         Err(ExchangeError::CanisterCallError(
                             format!("ICRC2 approve failed: {:?} - {}", code, msg)
                         ))
@@ -976,11 +1990,11 @@ impl ICPSwapConnector {
 
     /// Handle deposit failure
     async fn handle_deposit_failure(&self, pool_id: &Principal, user: &Principal, token: &TokenInfo) -> ExchangeResult<()> {
-        ic_cdk::println!("Handling deposit failure for user {} in pool {}", user, pool_id);
+        debug_log!("Handling deposit failure for user {} in pool {}", user, pool_id);
         
         // Try to get the user's unused balance in the pool
         let balances = self.call_get_user_unused_balance(pool_id, user).await?;
-        ic_cdk::println!("User unused balance: token0={}, token1={}", balances.0, balances.1);
+        debug_log!("User unused balance: token0={}, token1={}", balances.0, balances.1);
         
         // Balance handling strategy can be implemented based on business requirements
         // For example, one could try calling deposit again, or withdraw tokens from the pool
@@ -990,11 +2004,11 @@ impl ICPSwapConnector {
 
     /// Handle swap failure
     async fn handle_swap_failure(&self, pool_id: &Principal, user: &Principal, params: &TradeParams) -> ExchangeResult<()> {
-        ic_cdk::println!("Handling swap failure for user {} in pool {}", user, pool_id);
+        debug_log!("Handling swap failure for user {} in pool {}", user, pool_id);
         
         // Get user's unused balance in the pool
         let balances = self.call_get_user_unused_balance(pool_id, user).await?;
-        ic_cdk::println!("User unused balance: token0={}, token1={}", balances.0, balances.1);
+        debug_log!("User unused balance: token0={}, token1={}", balances.0, balances.1);
         
         // Determine if tokens need to be withdrawn
         // Based on business requirements, decide whether to automatically withdraw tokens or let the user handle it manually
@@ -1004,29 +2018,28 @@ impl ICPSwapConnector {
 
     /// Handle withdraw failure
     async fn handle_withdraw_failure(&self, pool_id: &Principal, user: &Principal, token: &TokenInfo) -> ExchangeResult<()> {
-        ic_cdk::println!("Handling withdraw failure for user {} in pool {}", user, pool_id);
+        debug_log!("Handling withdraw failure for user {} in pool {}", user, pool_id);
         
         // Get user's unused balance in the pool
         let balances = self.call_get_user_unused_balance(pool_id, user).await?;
-        ic_cdk::println!("User unused balance: token0={}, token1={}", balances.0, balances.1);
+        debug_log!("User unused balance: token0={}, token1={}", balances.0, balances.1);
         
         // Check transaction logs to determine if the transfer was successful
         // Note: Since we haven't implemented getTransferLogs, this is just a framework example
         
         // If the transaction is confirmed failed, record the error and notify administrators
-        ic_cdk::println!("Withdraw failed, transfer logs need to be verified manually");
+        debug_log!("Withdraw failed, transfer logs need to be verified manually");
         
         Ok(())
     }
 
     /// Add a method to check the current balance
     async fn check_token_balance(&self, token: &TokenInfo) -> ExchangeResult<String> {
-        let canister_id = ic_cdk::id();
-        let balance = self.get_token_balance(token, &canister_id).await?;
+        let balance = self.get_token_balance(token, &self.account()).await?;
         Ok(format!("Current balance: {}", balance))
     }
 
-    pub async fn execute_call_trade_no_slippage (
+    async fn call_trade_no_slippage(
         &self, params: &TradeParams,pool_data: &PoolInfo
     ) -> ExchangeResult<TradeResult> {
 
@@ -1040,32 +2053,41 @@ impl ICPSwapConnector {
         let amount_in_u128 = params.amount;
         let amount_in_str = amount_in_u128.to_string();
 
+        // The swap is this operation's only leg, so it carries the call budget
+        self.rate_limit(RateLimitKey::Pool(pool_data.pool_id)).await?;
+
         // Step 4: Execute swap
         let swap_args = ICPSwapSwapArgs {
             zeroForOne: zero_for_one,
             amountIn: amount_in_str,
             amountOutMinimum: "0".to_string(),
         };
-        ic_cdk::println!("Executing swap");
+        debug_log!("Executing swap");
         let swap_result = self.call_swap(&pool_data.pool_id, swap_args).await
             .map_err(|e| {
-                ic_cdk::println!("Swap failed directly: {:?}", e);
+                debug_log!("Swap failed directly: {:?}", e);
                 // Potentially call handle_swap_failure here
                 e
             })?;
-        ic_cdk::println!("Swap result: {}", swap_result);
+        debug_log!("Swap result: {}", swap_result);
         let final_output_amount_u128 = u128::try_from(swap_result.0.clone()).map_err(|e| { // Use swap_result for final amount
             ExchangeError::InternalError(format!("Failed to convert final swap result Nat {:?} to u128: {}", swap_result.0, e))
         })?;
         let pool_fee_u64 = u64::try_from(pool_data.fee)
             .map_err(|e| ExchangeError::InternalError(format!("Failed to convert pool fee Nat {:?} to u64: {}", pool_data.fee, e)))?;
+        // The output stays in the pool, so the only fee is the pool's and no ledger block is created
+        let pool_fee = utils::pool_fee_amount(params.amount, pool_fee_u64);
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, params.amount, final_output_amount_u128);
         let trade_result = TradeResult {
             input_amount: params.amount,
             output_amount: final_output_amount_u128, // Use the amount calculated from swap_result
-            fee_amount: (params.amount as f64 * (pool_fee_u64 as f64 / 1_000_000.0)) as u128,
-            price: if params.amount > 0 { final_output_amount_u128 as f64 / params.amount as f64 } else { 0.0 },
-            timestamp: utils::current_timestamp_secs(),
-            transaction_id: Some(format!("icpswap_{}_{}", pool_data.pool_id.to_string(), utils::current_timestamp_nanos())),
+            fee_amount: pool_fee,
+            price,
+            inverse_price,
+            timestamp: self.now_secs(),
+            transaction_id: None,
+            fees: FeeBreakdown { pool_fee, ..FeeBreakdown::default() },
+            ledger_transactions: Vec::new(),
         };
 
         Ok(trade_result)
@@ -1079,38 +2101,119 @@ impl Exchange for ICPSwapConnector {
         ExchangeType::ICPSwap
     }
     
-    /// Get the status of the exchange
+    /// Get the status of the exchange from the factory's pool listing
     async fn get_status(&self) -> ExchangeResult<ExchangeStatus> {
-        // Simplified handling here, actual implementation could call ICPSwap API for detailed status
+        let started = self.runtime.time();
+        // The listing call is never refused, so status checks double as breaker probes
+        let venue = BreakerTarget::Venue(self.factory_canister_id);
+        let listing = self.call_get_pools().await;
+        self.breaker_record(venue, &listing);
+        let breaker_config = self.config.circuit_breaker.clone().unwrap_or_default();
+        let pools = match listing {
+            Ok(pools) => pools,
+            Err(e) => {
+                debug_log!("getPools failed: {:?}", e);
+                let health = health::record_failure(&self.factory_canister_id, e.to_string());
+                return Ok(ExchangeStatus {
+                    exchange_type: ExchangeType::ICPSwap,
+                    is_available: health.is_available(),
+                    supported_tokens: vec![],
+                    supported_pairs: vec![],
+                    pools: vec![],
+                    latency_ms: None,
+                    consecutive_failures: health.consecutive_failures,
+                    last_error: health.last_error,
+                    circuit_breaker: circuit_breaker::status(&breaker_config, venue),
+                    tripped_pools: circuit_breaker::tripped_pools(&breaker_config),
+                    last_updated: self.now_secs(),
+                });
+            }
+        };
+        let latency_ms = self.runtime.time().saturating_sub(started) / 1_000_000;
+        let health = health::record_success(&self.factory_canister_id, latency_ms, self.now_secs());
+
+        // Statistics are best effort; the factory listing alone is enough to trade
+        let overviews = match self.stats_canister_id {
+            Some(stats_canister_id) => self.call_get_pool_overviews(stats_canister_id).await.unwrap_or_else(|e| {
+                debug_log!("getAllPools failed, listing pools without statistics: {:?}", e);
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+
+        let mut supported_tokens: Vec<TokenInfo> = Vec::new();
+        let mut supported_pairs = Vec::new();
+        let mut pool_stats = Vec::new();
+        for pool in pools {
+            let (token0_id, token1_id) = match (Principal::from_text(&pool.token0.address), Principal::from_text(&pool.token1.address)) {
+                (Ok(token0_id), Ok(token1_id)) => (token0_id, token1_id),
+                _ => continue, // Non-principal token addresses cannot be traded through this connector
+            };
+            let overview = overviews.get(&pool.canisterId.to_string());
+            pool_stats.push(PoolStats {
+                pool_id: pool.canisterId,
+                token0: token0_id,
+                token1: token1_id,
+                fee: u64::try_from(pool.fee.0.clone()).unwrap_or(0),
+                tvl_usd: overview.map(|o| o.tvlUSD),
+                volume_24h_usd: overview.map(|o| o.volumeUSD1d),
+            });
+
+            // Pools are advertised when their symbols and decimals are known without a ledger
+            // call, from the node index or from tokens this canister has already resolved
+            let (standard0, standard1) = match (
+                string_to_token_standard(&pool.token0.standard),
+                string_to_token_standard(&pool.token1.standard),
+            ) {
+                (Ok(standard0), Ok(standard1)) => (standard0, standard1),
+                _ => continue,
+            };
+            let token0 = Self::listed_token(token0_id, standard0, overview.map(|o| (&o.token0Symbol, o.token0Decimals)));
+            let token1 = Self::listed_token(token1_id, standard1, overview.map(|o| (&o.token1Symbol, o.token1Decimals)));
+            let (token0, token1) = match (token0, token1) {
+                (Some(token0), Some(token1)) => (token0, token1),
+                _ => {
+                    debug_log!("Skipping pool {} without cached token metadata", pool.canisterId);
+                    continue;
+                }
+            };
+            for token in [&token0, &token1] {
+                if !supported_tokens.iter().any(|t| t.canister_id == token.canister_id) {
+                    supported_tokens.push(token.clone());
+                }
+            }
+            supported_pairs.push(TradingPair {
+                base_token: token0,
+                quote_token: token1,
+                exchange: ExchangeType::ICPSwap,
+            });
+        }
+
         Ok(ExchangeStatus {
             exchange_type: ExchangeType::ICPSwap,
-            is_available: true,
-            supported_tokens: vec![],
-            supported_pairs: vec![],
-            last_updated: utils::current_timestamp_secs(),
+            is_available: health.is_available(),
+            supported_tokens,
+            supported_pairs,
+            pools: pool_stats,
+            latency_ms: Some(latency_ms),
+            consecutive_failures: health.consecutive_failures,
+            last_error: health.last_error,
+            circuit_breaker: circuit_breaker::status(&breaker_config, venue),
+            tripped_pools: circuit_breaker::tripped_pools(&breaker_config),
+            last_updated: self.now_secs(),
         })
     }
     
     /// Query token balance
-    async fn get_token_balance(&self, token: &TokenInfo, owner: &Principal) -> ExchangeResult<u128> {
-        // Need to call different interfaces based on token standard
+    async fn get_token_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> {
+        self.rate_limit(RateLimitKey::Ledger(token.canister_id)).await?;
+        // Need to call different interfaces based on token standard; the ICP ledger implements ICRC-1
         match token.standard {
-            TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
-                #[derive(CandidType, Serialize)]
-                struct Account {
-                    owner: Principal,
-                    subaccount: Option<serde_bytes::ByteBuf>,
-                }
-                
-                let account = Account {
-                    owner: *owner,
-                    subaccount: None,
-                };
-                
-                let result: CallResult<(candid::Nat,)> = ic_cdk::api::call::call(
+            TokenStandard::ICRC1 | TokenStandard::ICRC2 | TokenStandard::ICP => {
+                let result: CallResult<(candid::Nat,)> = self.call(
                     token.canister_id,
                     "icrc1_balance_of",
-                    (account,),
+                    (account.clone(),),
                 ).await;
                 
                 match result {
@@ -1118,32 +2221,8 @@ impl Exchange for ICPSwapConnector {
                     Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to query ICRC balance: {:?} - {}", code, msg))),
                 }
             },
-            TokenStandard::ICP => {
-                // ICP Ledger Canister ID (Mainnet)
-                let ledger_canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")
-                    .expect("Failed to parse ICP ledger canister ID");
-
-                // Calculate account identifier from principal
-                let account_identifier = AccountIdentifier::new(&owner, &DEFAULT_SUBACCOUNT);
-
-                // Prepare arguments for account_balance
-                let args = AccountBalanceArgs {
-                    account: account_identifier,
-                };
-
-                // Call account_balance on the ICP ledger
-                let result: CallResult<(Tokens,)> = ic_cdk::api::call::call(
-                    ledger_canister_id,
-                    "account_balance",
-                    (args,),
-                ).await;
-
-                match result {
-                    Ok((tokens,)) => Ok(tokens.e8s() as u128), // Balance is in e8s (u64), cast to u128
-                    Err((code, msg)) => Err(ExchangeError::CanisterCallError(format!("Failed to query ICP balance: {:?} - {}", code, msg))),
-                }
-            }
-            _ => Err(ExchangeError::NotImplemented),
+            TokenStandard::DIP20 => self.dip20_balance(token, account).await,
+            TokenStandard::EXT => self.ext_balance(token, account).await,
         }
     }
     
@@ -1166,12 +2245,23 @@ impl Trading for ICPSwapConnector {
         let pool_data = self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await?;
         
         // Call internal get quote method
-        self.get_quote_internal(&pool_data.canisterId, params).await
+        self.get_quote_internal(&pool_data.canisterId, Self::pool_fee_ppm(&pool_data)?, params).await
+    }
+    
+    /// Plan a trade without moving funds
+    async fn simulate_trade(&self, params: &TradeParams) -> ExchangeResult<TradePlan> {
+        Ok(self.plan_icpswap_trade(params).await)
+    }
+
+    /// Compute the depth curve locally from one pool snapshot instead of quoting each size on chain
+    async fn get_depth_curve(&self, pair: &TradingPair, sizes: &[u128]) -> ExchangeResult<DepthCurve> {
+        let snapshot = self.get_pool_snapshot(&pair.base_token, &pair.quote_token).await?;
+        depth::local_depth_curve(pair, &snapshot, sizes, self.now_secs())
     }
     
     /// Execute a trade
     async fn execute_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
-        self.execute_icpswap_trade(params).await
+        metering::metered(self.runtime.as_ref(), MeteredOperation::Trade, self.execute_icpswap_trade(params)).await
     }
 
     async fn execute_call_trade(
@@ -1181,7 +2271,8 @@ impl Trading for ICPSwapConnector {
         let pool_data = self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await?;
 
         // 3. Get quote
-        let quote_result = self.get_quote_internal(&pool_data.canisterId, params).await?;
+        let pool_fee_u64 = Self::pool_fee_ppm(&pool_data)?;
+        let quote_result = self.get_quote_internal(&pool_data.canisterId, pool_fee_u64, params).await?;
 
         // 4. Determine input and output tokens
         let (input_token, output_token) = match params.direction {
@@ -1203,31 +2294,44 @@ impl Trading for ICPSwapConnector {
             amountIn: amount_in_str,
             amountOutMinimum: amount_out_minimum_str,
         };
-        ic_cdk::println!("Executing swap");
+        debug_log!("Executing swap");
         let swap_result = self.call_swap(&pool_data.canisterId, swap_args).await
             .map_err(|e| {
-                ic_cdk::println!("Swap failed directly: {:?}", e);
+                debug_log!("Swap failed directly: {:?}", e);
                 // Potentially call handle_swap_failure here
                 e
             })?;
-        ic_cdk::println!("Swap result: {}", swap_result);
+        debug_log!("Swap result: {}", swap_result);
         let final_output_amount_u128 = u128::try_from(swap_result.0.clone()).map_err(|e| { // Use swap_result for final amount
             ExchangeError::InternalError(format!("Failed to convert final swap result Nat {:?} to u128: {}", swap_result.0, e))
         })?;
-        let pool_fee_u64 = u64::try_from(pool_data.fee.0.clone())
-            .map_err(|e| ExchangeError::InternalError(format!("Failed to convert pool fee Nat {:?} to u64: {}", pool_data.fee.0, e)))?;
+        // The output stays in the pool, so the only fee is the pool's and no ledger block is created
+        let pool_fee = utils::pool_fee_amount(params.amount, pool_fee_u64);
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, params.amount, final_output_amount_u128);
         let trade_result = TradeResult {
             input_amount: params.amount,
             output_amount: final_output_amount_u128, // Use the amount calculated from swap_result
-            fee_amount: (params.amount as f64 * (pool_fee_u64 as f64 / 1_000_000.0)) as u128,
-            price: if params.amount > 0 { final_output_amount_u128 as f64 / params.amount as f64 } else { 0.0 },
-            timestamp: utils::current_timestamp_secs(),
-            transaction_id: Some(format!("icpswap_{}_{}", pool_data.canisterId.to_string(), utils::current_timestamp_nanos())),
+            fee_amount: pool_fee,
+            price,
+            inverse_price,
+            timestamp: self.now_secs(),
+            transaction_id: None,
+            fees: FeeBreakdown { pool_fee, ..FeeBreakdown::default() },
+            ledger_transactions: Vec::new(),
         };
 
         Ok(trade_result)
     }
 
+    /// Swap the unused pool balance in an already-resolved pool, accepting any output amount
+    async fn execute_call_trade_no_slippage(&self, params: &TradeParams, pool_data: &PoolInfo) -> ExchangeResult<TradeResult> {
+        metering::metered(
+            self.runtime.as_ref(),
+            MeteredOperation::CallTrade,
+            self.call_trade_no_slippage(params, pool_data),
+        ).await
+    }
+
     /// Execute multiple trades in a batch
     async fn execute_batch_trade(&self, params: &BatchTradeParams) -> ExchangeResult<BatchTradeResult> {
         let mut results = Vec::new();
@@ -1252,7 +2356,7 @@ impl Trading for ICPSwapConnector {
         Ok(BatchTradeResult {
             results,
             all_succeeded,
-            timestamp: utils::current_timestamp_secs(),
+            timestamp: self.now_secs(),
         })
     }
     
@@ -1267,7 +2371,8 @@ impl Trading for ICPSwapConnector {
 #[async_trait]
 impl TokenOperations for ICPSwapConnector {
     async fn approve_token(&self,token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<()> {
-        self.approve_token(token, spender, amount).await
+        self.rate_limit(RateLimitKey::Ledger(token.canister_id)).await?;
+        self.approve_with_block(token, spender, amount).await.map(|_| ())
     }
     /// Deposit tokens into the exchange
     async fn deposit_token(&self, params: &TradeParams,token: &TokenInfo, amount: u128) -> ExchangeResult<u128> {
@@ -1276,16 +2381,14 @@ impl TokenOperations for ICPSwapConnector {
                 Err(ExchangeError::InternalError("Unsupported type: ICRC1".to_string()))
             }
             TokenStandard::ICRC2| TokenStandard::ICP | TokenStandard::EXT|TokenStandard::DIP20=> {
-                let input_token_fee = match token.standard {
-                    TokenStandard::ICRC1 | TokenStandard::ICP => 10000_u64,
-                    TokenStandard::ICRC2 => 1_000_000,
-                    _ => 0_u64,
-                };
+                let input_token_fee = self.trade_fee(token).await?;
                 let input_token_fee_nat = candid::Nat::from(input_token_fee);
                 let pool_data = self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await?;
-                // Step 2: NO APPROVE CALL HERE. User MUST approve the pool BEFORE calling execute_trade.
-                self.approve_token(token, &pool_data.canisterId, amount * 100).await?;
-                ic_cdk::println!("Skipping internal approve. Assuming user pre-approved pool {:?}", pool_data.canisterId);
+                // A standalone deposit starts a trade, so it is gated and charged like a quote
+                self.breaker_allow(BreakerTarget::Pool(pool_data.canisterId))?;
+                self.rate_limit(RateLimitKey::Pool(pool_data.canisterId)).await?;
+                // Step 2: Approve the pool to pull the deposit
+                self.approve_with_block(token, &pool_data.canisterId, amount.saturating_mul(100)).await?;
                 let amount_nat = candid::Nat::from(amount);
                 // Step 3: Call depositFrom method (using Nat fee)
                 let deposit_args = ICPSwapDepositFromArgs {
@@ -1294,14 +2397,14 @@ impl TokenOperations for ICPSwapConnector {
                     amount: amount_nat.clone(),
                 };
 
-                ic_cdk::println!("Calling depositFrom with token fee: {}", input_token_fee_nat);
+                debug_log!("Calling depositFrom with token fee: {}", input_token_fee_nat);
                 let deposit_result = self.call_deposit_from(&pool_data.canisterId, deposit_args).await
                     .map_err(|e| {
-                        ic_cdk::println!("DepositFrom failed directly: {:?}", e);
+                        debug_log!("DepositFrom failed directly: {:?}", e);
                         // Potentially call handle_deposit_failure here
                         e
                     })?;
-                ic_cdk::println!("DepositFrom result: {}", deposit_result);
+                debug_log!("DepositFrom result: {}", deposit_result);
                 let deposit_result_u128 = u128::try_from(deposit_result.0.clone())
                     .map_err(|e| ExchangeError::InternalError(format!("Failed to convert withdraw result Nat {:?} to u128: {}", deposit_result.0, e)))?;
 
@@ -1314,23 +2417,19 @@ impl TokenOperations for ICPSwapConnector {
     /// Withdraw tokens from the exchange
     async fn withdraw_token(&self, params: &TradeParams,token: &TokenInfo, amount: u128) -> ExchangeResult<u128> { 
         let pool_data = self.get_pool_canister(&params.pair.base_token, &params.pair.quote_token).await?;
-        let withdraw_fee_u64 = match token.standard {
-            TokenStandard::ICRC1 | TokenStandard::ICP => 10000_u64,
-            TokenStandard::ICRC2 => 1_000_000,
-            TokenStandard::DIP20 => 0_u64,
-            TokenStandard::EXT => 0_u64,
-        };
+        self.rate_limit(RateLimitKey::Pool(pool_data.canisterId)).await?;
+        let withdraw_fee_u64 = self.trade_fee(token).await?;
         let withdraw_fee_nat = candid::Nat::from(withdraw_fee_u64); // Convert fee to Nat
         let withdraw_args = ICPSwapWithdrawArgs {
             fee: withdraw_fee_nat,                       // Pass Nat fee
             token: token.canister_id.to_string(),
             amount: Nat::from(amount.clone()),                 // Pass Nat amount directly (use swap_result)
         };
-        ic_cdk::println!("Withdrawing output token with args: {:?}", withdraw_args);
+        debug_log!("Withdrawing output token with args: {:?}", withdraw_args);
         let withdraw_result_nat = match self.call_withdraw(&pool_data.canisterId, withdraw_args).await {
             Ok(result) => result, // Result is Nat
             Err(e) => {
-                ic_cdk::println!("Withdraw failed: {:?}", e);
+                debug_log!("Withdraw failed: {:?}", e);
                 // Potentially call handle_withdraw_failure here
                 return Err(e);
             }
@@ -1351,9 +2450,94 @@ impl TokenOperations for ICPSwapConnector {
         Ok((balance0_u128,balance1_u128,pool_data.token0.address))
     }
     
-    /// Query the user's total balance within the exchange
-    async fn get_exchange_balance(&self, _token: &TokenInfo, _user: &Principal) -> ExchangeResult<(u128,u128)> { // Added underscores
-        Err(ExchangeError::NotImplemented)
+    /// Break down an account's holdings across its wallet and the pools between the given tokens
+    async fn get_exchange_balance(&self, tokens: &[TokenInfo], account: &Account, valuation_token: &TokenInfo) -> ExchangeResult<HoldingsBreakdown> {
+        let tokens = holdings::holdings_tokens(tokens, valuation_token);
+        let mut holdings = Vec::new();
+
+        for token in &tokens {
+            let balance = self.get_token_balance(token, account).await?;
+            if balance > 0 {
+                holdings.push(Holding { token: token.clone(), location: HoldingLocation::Wallet, amount: balance, value: None });
+            }
+        }
+
+        // ICPSwap has no per-user pool index, so inspect the pool of every pair of known tokens.
+        // A pool that cannot be read is reported and skipped rather than failing the breakdown.
+        let mut unreadable_pools = Vec::new();
+        for (i, token_a) in tokens.iter().enumerate() {
+            for token_b in &tokens[i + 1..] {
+                let pool_data = match self.get_pool_canister(token_a, token_b).await {
+                    Ok(pool_data) => pool_data,
+                    Err(ExchangeError::PoolNotFound) => continue,
+                    Err(e) => {
+                        debug_log!("Pool lookup for {}/{} failed: {:?}", token_a.symbol, token_b.symbol, e);
+                        unreadable_pools.push(PoolReadError {
+                            pool_id: None,
+                            token0: token_a.canister_id,
+                            token1: token_b.canister_id,
+                            error: e.to_string(),
+                        });
+                        continue;
+                    }
+                };
+                let (token0, token1) = if pool_data.token0.address == token_a.canister_id.to_string() {
+                    (token_a, token_b)
+                } else {
+                    (token_b, token_a)
+                };
+                match self.pool_holdings(&pool_data, token0, token1, account).await {
+                    Ok(pool_holdings) => holdings.extend(pool_holdings),
+                    Err(e) => {
+                        debug_log!("Reading holdings in pool {} failed: {:?}", pool_data.canisterId, e);
+                        unreadable_pools.push(PoolReadError {
+                            pool_id: Some(pool_data.canisterId),
+                            token0: token0.canister_id,
+                            token1: token1.canister_id,
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(holdings::value_holdings(self, account.clone(), valuation_token, holdings, unreadable_pools, self.now_secs()).await)
+    }
+
+    /// Transfer tokens from one of this canister's subaccounts to another account
+    async fn transfer_token(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<Option<u128>> {
+        let fee = self.trade_fee(token).await?;
+        self.transfer_from_subaccount(token, from_subaccount, to, amount, fee).await
+    }
+}
+
+// Convert a Nat to f64 (lossy for very large values, used for price math only)
+fn nat_to_f64(value: &Nat) -> f64 {
+    value.0.to_string().parse::<f64>().unwrap_or(0.0)
+}
+
+// Convert a candid Int tick to i64
+fn int_to_i64(value: &Int) -> ExchangeResult<i64> {
+    i64::try_from(value.0.clone())
+        .map_err(|e| ExchangeError::InternalError(format!("Failed to convert tick {} to i64: {}", value, e)))
+}
+
+// Convert a candid Int tick to i32, rejecting values outside the i32 range
+fn int_to_tick(value: &Int) -> ExchangeResult<i32> {
+    i32::try_from(value.0.clone())
+        .map_err(|e| ExchangeError::InternalError(format!("Failed to convert tick {} to i32: {}", value, e)))
+}
+
+// Token amounts represented by a concentrated-liquidity position at the given sqrt price
+fn position_amounts(liquidity: f64, sqrt_price: f64, tick_lower: i64, tick_upper: i64) -> (f64, f64) {
+    let sqrt_lower = 1.0001f64.powf(tick_lower as f64 / 2.0);
+    let sqrt_upper = 1.0001f64.powf(tick_upper as f64 / 2.0);
+    if sqrt_price <= sqrt_lower {
+        (liquidity * (sqrt_upper - sqrt_lower) / (sqrt_lower * sqrt_upper), 0.0)
+    } else if sqrt_price < sqrt_upper {
+        (liquidity * (sqrt_upper - sqrt_price) / (sqrt_price * sqrt_upper), liquidity * (sqrt_price - sqrt_lower))
+    } else {
+        (0.0, liquidity * (sqrt_upper - sqrt_lower))
     }
 }
 
@@ -1441,4 +2625,269 @@ impl LiquidityPool for ICPSwapConnector {
         // Placeholder implementation, needs refinement in actual application
         Err(ExchangeError::NotImplemented)
     }
-} 
\ No newline at end of file
+} 
+#[async_trait]
+impl ExchangeConnector for ICPSwapConnector {
+    /// ICPSwap V3 pools: market swaps for every token standard, liquidity management not yet wired up
+    fn capabilities(&self) -> ExchangeCapabilities {
+        ExchangeCapabilities {
+            exchange_type: ExchangeType::ICPSwap,
+            supports_liquidity: false,
+            supports_batch_trades: true,
+            supports_call_trades: true,
+            supports_subaccounts: true,
+            supports_trade_history: false,
+            token_standards: vec![
+                TokenStandard::ICRC1,
+                TokenStandard::ICRC2,
+                TokenStandard::DIP20,
+                TokenStandard::EXT,
+                TokenStandard::ICP,
+            ],
+            order_kinds: vec![OrderKind::Market],
+        }
+    }
+
+    /// Canister account this connector trades from
+    fn account(&self) -> Account {
+        ICPSwapConnector::account(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use strategy_common::runtime::MockRuntime;
+
+    const FEE: u64 = 10_000;
+
+    fn factory() -> Principal {
+        Principal::from_slice(&[20])
+    }
+
+    fn pool() -> Principal {
+        Principal::from_slice(&[21])
+    }
+
+    fn token(id: u8, symbol: &str) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    fn sell_params(amount: u128) -> TradeParams {
+        TradeParams {
+            pair: TradingPair {
+                base_token: token(10, "BASE"),
+                quote_token: token(11, "QUOTE"),
+                exchange: ExchangeType::ICPSwap,
+            },
+            direction: TradeDirection::Sell,
+            amount,
+            slippage_tolerance: 1.0,
+            deadline_secs: None,
+        }
+    }
+
+    /// Connector over a mock runtime scripted for one ICRC-1 sell of the base token
+    fn connector() -> (ICPSwapConnector, Arc<MockRuntime>) {
+        connector_with_limits(None)
+    }
+
+    fn connector_with_limits(rate_limit: Option<RateLimitConfig>) -> (ICPSwapConnector, Arc<MockRuntime>) {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[1]), Principal::anonymous()));
+        runtime.set_time(1_700_000_000_000_000_000);
+        let params = sell_params(0);
+        let (base, quote) = (params.pair.base_token, params.pair.quote_token);
+        runtime.set_default_reply(factory(), "getPool", (ICPSwapPoolResult::ok(ICPSwapPoolData {
+            fee: Nat::from(3000u64),
+            key: "BASE_QUOTE_3000".to_string(),
+            tickSpacing: Int::from(60),
+            token0: ICPSwapToken { address: base.canister_id.to_string(), standard: "ICRC1".to_string() },
+            token1: ICPSwapToken { address: quote.canister_id.to_string(), standard: "ICRC1".to_string() },
+            canisterId: pool(),
+        }),));
+        for ledger in [base.canister_id, quote.canister_id] {
+            runtime.set_default_reply(ledger, "icrc1_fee", (Nat::from(FEE),));
+            runtime.set_default_reply(ledger, "icrc1_balance_of", (Nat::from(10_000_000u64),));
+        }
+        runtime.set_default_reply(pool(), "quote", (ICPSwapQuoteResult::ok(Nat::from(2_000_000u64)),));
+        runtime.set_default_reply(pool(), "metadata", (ICPSwapPoolMetadataResult::ok(ICPSwapPoolMetadata {
+            fee: Nat::from(3000u64),
+            key: "BASE_QUOTE_3000".to_string(),
+            sqrtPriceX96: Nat::from(1u128 << 96), // Spot price 1
+            tick: Int::from(0),
+            liquidity: Nat::from(1_000_000_000_000u64),
+            token0: ICPSwapToken { address: base.canister_id.to_string(), standard: "ICRC1".to_string() },
+            token1: ICPSwapToken { address: quote.canister_id.to_string(), standard: "ICRC1".to_string() },
+        }),));
+        runtime.set_default_reply(base.canister_id, "icrc1_transfer", (ICRC1TransferResult::Ok(Nat::from(5u64)),));
+        runtime.set_default_reply(pool(), "deposit", (ICPSwapResult::ok(Nat::from(1_000_000u64)),));
+        runtime.set_default_reply(pool(), "swap", (ICPSwapResult::ok(Nat::from(1_990_000u64)),));
+        runtime.set_default_reply(pool(), "withdraw", (ICPSwapResult::ok(Nat::from(1_990_000u64 - FEE)),));
+
+        let config = ExchangeConfig {
+            exchange_type: ExchangeType::ICPSwap,
+            canister_id: factory(),
+            default_slippage: 1.0,
+            max_slippage: 5.0,
+            timeout_secs: 30,
+            retry_count: 0,
+            rate_limit,
+            circuit_breaker: None,
+        };
+        (ICPSwapConnector::with_runtime(config, runtime.clone()), runtime)
+    }
+
+    /// Methods called up to and including the withdrawal, leaving out ledger verification
+    fn trade_calls(runtime: &MockRuntime) -> Vec<String> {
+        let methods: Vec<String> = runtime.calls().into_iter().map(|call| call.method).collect();
+        match methods.iter().position(|method| method == "withdraw") {
+            Some(end) => methods[..=end].to_vec(),
+            None => methods,
+        }
+    }
+
+    #[test]
+    fn icrc1_sell_deposits_swaps_and_withdraws() {
+        let (connector, runtime) = connector();
+        let result = block_on(connector.execute_trade(&sell_params(1_000_000))).unwrap();
+
+        assert_eq!(
+            trade_calls(&runtime),
+            ["getPool", "quote", "metadata", "icrc1_fee", "icrc1_fee", "icrc1_balance_of", "icrc1_transfer", "deposit", "swap", "withdraw"]
+        );
+
+        // The input goes to the pool's subaccount for this canister, then is credited by deposit
+        let (deposit,): (ICPSwapDepositArgs,) = runtime.call_args("deposit", 0).unwrap();
+        assert_eq!(deposit.amount, Nat::from(1_000_000u64));
+        assert_eq!(deposit.fee, Nat::from(FEE));
+        assert_eq!(deposit.token, token(10, "BASE").canister_id.to_string());
+
+        // Slippage applies to the quoted output
+        let (swap,): (ICPSwapSwapArgs,) = runtime.call_args("swap", 0).unwrap();
+        assert_eq!(swap.amountIn, "1000000");
+        assert_eq!(swap.amountOutMinimum, "1980000");
+
+        // The whole swap output is withdrawn, in the output token
+        let (withdraw,): (ICPSwapWithdrawArgs,) = runtime.call_args("withdraw", 0).unwrap();
+        assert_eq!(withdraw.amount, Nat::from(1_990_000u64));
+        assert_eq!(withdraw.token, token(11, "QUOTE").canister_id.to_string());
+
+        assert_eq!(result.input_amount, 1_000_000);
+        assert_eq!(result.output_amount, 1_990_000 - FEE as u128);
+        assert_eq!(result.fees.pool_fee, 3_000);
+        assert_eq!(result.fees.input_transfer_fee, 2 * FEE as u128);
+        assert_eq!(result.fees.withdraw_fee, FEE as u128);
+        assert_eq!(result.ledger_transactions.len(), 1);
+        assert_eq!(result.ledger_transactions[0].block_index, 5);
+        assert_eq!(result.timestamp, 1_700_000_000);
+    }
+
+    #[test]
+    fn rejected_swap_fails_the_trade_without_withdrawing() {
+        let (connector, runtime) = connector();
+        runtime.push_reject(pool(), "swap", RejectionCode::CanisterError, "swap trapped");
+
+        let result = block_on(connector.execute_trade(&sell_params(1_000_000)));
+        assert!(matches!(result, Err(ExchangeError::CanisterCallError(_))));
+        assert_eq!(runtime.call_count("deposit"), 1);
+        assert_eq!(runtime.call_count("withdraw"), 0);
+    }
+
+    #[test]
+    fn pool_error_on_deposit_is_mapped() {
+        let (connector, runtime) = connector();
+        runtime.push_reply(pool(), "deposit", (ICPSwapResult::err(ICPSwapError::InsufficientFunds),));
+
+        let result = block_on(connector.execute_trade(&sell_params(1_000_000)));
+        assert!(matches!(result, Err(ExchangeError::InsufficientFunds)));
+        assert_eq!(runtime.call_count("swap"), 0);
+    }
+
+    #[test]
+    fn short_balance_fails_before_moving_funds() {
+        let (connector, runtime) = connector();
+        runtime.push_reply(token(10, "BASE").canister_id, "icrc1_balance_of", (Nat::from(1_000_000u64),));
+
+        let result = block_on(connector.execute_trade(&sell_params(1_000_000)));
+        assert!(matches!(result, Err(ExchangeError::InsufficientFunds)));
+        assert_eq!(runtime.call_count("icrc1_transfer"), 0);
+    }
+
+    #[test]
+    fn rate_limit_charges_the_entry_and_lets_later_legs_through() {
+        // Exactly the budget the read-only steps need: the quote and pool price, and fee and balance lookups
+        let (connector, runtime) = connector_with_limits(Some(RateLimitConfig {
+            pool_capacity: 2,
+            pool_refill_per_sec: 0.0,
+            ledger_capacity: 2,
+            ledger_refill_per_sec: 0.0,
+            mode: RateLimitMode::FailFast,
+        }));
+        assert!(block_on(connector.execute_trade(&sell_params(1_000_000))).is_ok());
+        assert_eq!(runtime.call_count("withdraw"), 1);
+
+        // The spent budget refuses the next trade before any funds move
+        let result = block_on(connector.execute_trade(&sell_params(1_000_000)));
+        assert!(matches!(result, Err(ExchangeError::RateLimit)));
+        assert_eq!(runtime.call_count("icrc1_transfer"), 1);
+    }
+
+    #[test]
+    fn status_lists_only_pools_with_cached_metadata() {
+        let (connector, runtime) = connector();
+        let connector = connector.with_stats_canister(None);
+        let listed = |token0: u8, token1: u8, pool_id: u8| ICPSwapPoolData {
+            fee: Nat::from(3000u64),
+            key: format!("{}_{}_3000", token0, token1),
+            tickSpacing: Int::from(60),
+            token0: ICPSwapToken { address: Principal::from_slice(&[token0]).to_string(), standard: "ICRC1".to_string() },
+            token1: ICPSwapToken { address: Principal::from_slice(&[token1]).to_string(), standard: "ICRC1".to_string() },
+            canisterId: Principal::from_slice(&[pool_id]),
+        };
+        runtime.push_reply(factory(), "getPools", (ICPSwapPoolsResult::ok(vec![listed(10, 11, 21), listed(12, 13, 22)]),));
+        for (id, symbol) in [(10, "BASE"), (11, "QUOTE")] {
+            TOKEN_METADATA.with(|cache| cache.borrow_mut().insert(Principal::from_slice(&[id]), (symbol.to_string(), 8)));
+        }
+
+        let status = block_on(connector.get_status()).unwrap();
+
+        // The listing is the only call; the pool of unresolved tokens is left out instead of
+        // asking their ledgers
+        assert_eq!(runtime.calls().len(), 1);
+        assert_eq!(status.pools.len(), 2);
+        assert_eq!(status.supported_pairs.len(), 1);
+        assert_eq!(status.supported_pairs[0].base_token.symbol, "BASE");
+        assert_eq!(status.supported_tokens.len(), 2);
+    }
+
+    #[test]
+    fn ext_transfer_reports_no_block_index() {
+        let (connector, runtime) = connector();
+        let ext = TokenInfo { standard: TokenStandard::EXT, ..token(12, "EXT") };
+        // EXT replies with the sender's remaining balance, which must not pass for a block index
+        runtime.push_reply(ext.canister_id, "transfer", (EXTTransferResponse::ok(Nat::from(9_000u64)),));
+
+        let block_index = block_on(connector.transfer_token(&ext, None, &Account::new(Principal::from_slice(&[30])), 1_000)).unwrap();
+
+        assert_eq!(block_index, None);
+        assert_eq!(runtime.call_count("transfer"), 1);
+    }
+
+    #[test]
+    fn quote_charges_the_pool_fee_and_measures_impact_against_spot() {
+        let (connector, runtime) = connector();
+        // 1% short of spot on what is left after the 0.3% pool fee
+        runtime.push_reply(pool(), "quote", (ICPSwapQuoteResult::ok(Nat::from(987_030u64)),));
+
+        let quote = block_on(connector.get_quote(&sell_params(1_000_000))).unwrap();
+
+        assert_eq!(quote.fee_amount, 3_000);
+        assert!((quote.price_impact - 1.0).abs() < 1e-9, "price impact {}", quote.price_impact);
+    }
+}
@@ -0,0 +1,219 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use strategy_common::debug_log;
+
+use crate::types::*;
+
+/// Stable memory holding the trade journal
+pub type JournalMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Entries kept before the oldest are dropped
+pub const MAX_JOURNAL_ENTRIES: u64 = 1000;
+
+/// Audit record of one executed trade and the ledger proof of its legs
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub timestamp: u64,            // Seconds since the epoch
+    pub exchange: ExchangeType,
+    pub pool_id: Principal,
+    pub input_token: Principal,
+    pub output_token: Principal,
+    pub input_amount: u128,
+    pub output_amount: u128,       // Net output reported by the trade
+    pub transaction_id: Option<String>,
+    pub legs: Vec<TransferCheck>,
+    pub discrepancies: Vec<String>, // Empty when every leg was confirmed on its ledger
+}
+
+impl JournalEntry {
+    /// Whether any leg could not be confirmed as expected
+    pub fn is_flagged(&self) -> bool {
+        !self.discrepancies.is_empty()
+    }
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode journal entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode journal entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Entries keyed by id; ids only grow and the oldest is evicted first, so they stay contiguous
+    static JOURNAL: RefCell<Option<StableBTreeMap<u64, JournalEntry, JournalMemory>>> = RefCell::new(None);
+}
+
+/// Opens the canister's trade journal; call from both `init` and `post_upgrade`
+pub fn init(memory: JournalMemory) {
+    JOURNAL.with(|journal| {
+        *journal.borrow_mut() = Some(StableBTreeMap::init(memory));
+    });
+}
+
+/// Whether `init` has opened the journal
+pub fn is_initialized() -> bool {
+    JOURNAL.with(|journal| journal.borrow().is_some())
+}
+
+/// Summarise legs that were not verified, one line per problem
+pub fn leg_discrepancies(legs: &[TransferCheck]) -> Vec<String> {
+    let mut found = Vec::new();
+    for leg in legs {
+        let block = leg.block_index.map(|index| index.to_string()).unwrap_or_else(|| "?".to_string());
+        match &leg.verification {
+            TransferVerification::Verified { .. } | TransferVerification::Unsupported => {}
+            TransferVerification::Mismatch { discrepancies, .. } => {
+                for discrepancy in discrepancies {
+                    found.push(format!("{}:{} {}", leg.ledger, block, discrepancy));
+                }
+            }
+            TransferVerification::NotFound => found.push(format!("{}:{} transfer not found on ledger", leg.ledger, block)),
+            TransferVerification::Failed(error) => found.push(format!("{}:{} verification failed: {}", leg.ledger, block, error)),
+        }
+    }
+    found
+}
+
+/// Add an entry, assigning it the next id, and return that id
+///
+/// Trades are never failed for want of a journal, so canisters that did not open
+/// one only lose the record (and get None).
+pub fn record(mut entry: JournalEntry) -> Option<u64> {
+    JOURNAL.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        let journal = match journal.as_mut() {
+            Some(journal) => journal,
+            None => {
+                debug_log!("Trade journal not initialized; dropping entry for pool {}", entry.pool_id);
+                return None;
+            }
+        };
+        let id = journal.last_key_value().map_or(0, |(last, _)| last + 1);
+        entry.id = id;
+        while journal.len() >= MAX_JOURNAL_ENTRIES {
+            match journal.first_key_value() {
+                Some((oldest, _)) => journal.remove(&oldest),
+                None => break,
+            };
+        }
+        journal.insert(id, entry);
+        Some(id)
+    })
+}
+
+/// Number of entries held
+pub fn len() -> u64 {
+    JOURNAL.with(|journal| journal.borrow().as_ref().map_or(0, |journal| journal.len()))
+}
+
+/// Entries newest first, skipping `offset` and returning at most `limit`
+pub fn entries(offset: u64, limit: u64) -> Vec<JournalEntry> {
+    JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        let journal = match journal.as_ref() {
+            Some(journal) => journal,
+            None => return Vec::new(),
+        };
+        let (first, last) = match (journal.first_key_value(), journal.last_key_value()) {
+            (Some((first, _)), Some((last, _))) => (first, last),
+            _ => return Vec::new(),
+        };
+        (first..=last)
+            .rev()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .filter_map(|id| journal.get(&id))
+            .collect()
+    })
+}
+
+/// Entries with at least one discrepancy, newest first
+pub fn flagged_entries() -> Vec<JournalEntry> {
+    JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        let mut flagged: Vec<JournalEntry> = match journal.as_ref() {
+            Some(journal) => journal.iter().map(|(_, entry)| entry).filter(|entry| entry.is_flagged()).collect(),
+            None => Vec::new(),
+        };
+        flagged.reverse();
+        flagged
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    fn open() {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        init(manager.get(MemoryId::new(0)));
+    }
+
+    fn entry(flagged: bool) -> JournalEntry {
+        JournalEntry {
+            id: 0,
+            timestamp: 0,
+            exchange: ExchangeType::ICPSwap,
+            pool_id: Principal::anonymous(),
+            input_token: Principal::anonymous(),
+            output_token: Principal::anonymous(),
+            input_amount: 1,
+            output_amount: 1,
+            transaction_id: None,
+            legs: Vec::new(),
+            discrepancies: if flagged { vec!["transfer not found".to_string()] } else { Vec::new() },
+        }
+    }
+
+    #[test]
+    fn records_are_dropped_without_a_journal() {
+        assert_eq!(record(entry(false)), None);
+        assert!(entries(0, 10).is_empty());
+    }
+
+    #[test]
+    fn entries_are_listed_newest_first() {
+        open();
+        for _ in 0..5 {
+            record(entry(false));
+        }
+        let ids: Vec<u64> = entries(1, 2).iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![3, 2]);
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_at_capacity() {
+        open();
+        for _ in 0..MAX_JOURNAL_ENTRIES + 3 {
+            record(entry(false));
+        }
+        assert_eq!(len(), MAX_JOURNAL_ENTRIES);
+        let all = entries(0, u64::MAX);
+        assert_eq!(all.first().map(|entry| entry.id), Some(MAX_JOURNAL_ENTRIES + 2));
+        assert_eq!(all.last().map(|entry| entry.id), Some(3));
+    }
+
+    #[test]
+    fn flagged_entries_are_filtered() {
+        open();
+        record(entry(true));
+        record(entry(false));
+        record(entry(true));
+        let ids: Vec<u64> = flagged_entries().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![2, 0]);
+    }
+}
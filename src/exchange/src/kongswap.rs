@@ -2,25 +2,34 @@ use async_trait::async_trait;
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::Arc;
 use ic_cdk::api::call::CallResult;
 
 use crate::error::*;
 use crate::types::*;
 use crate::traits::*;
 use crate::utils;
+use strategy_common::runtime::{IcRuntime, Runtime};
 
 /// KongSwap exchange connector
 pub struct KongSwapConnector {
     config: ExchangeConfig,
     factory_canister_id: Principal,  // KongSwap Factory Canister ID
+    runtime: Arc<dyn Runtime>,       // System API used for calls, time and caller
 }
 
 impl KongSwapConnector {
     /// Creates a new instance of the KongSwap connector
     pub fn new(config: ExchangeConfig) -> Self {
+        Self::with_runtime(config, Arc::new(IcRuntime))
+    }
+
+    /// Creates a connector that performs all system calls through the given runtime
+    pub fn with_runtime(config: ExchangeConfig, runtime: Arc<dyn Runtime>) -> Self {
         Self {
             factory_canister_id: config.canister_id.clone(),
             config,
+            runtime,
         }
     }
     
@@ -44,7 +53,7 @@ impl Exchange for KongSwapConnector {
     }
     
     /// Queries the token balance
-    async fn get_token_balance(&self, token: &TokenInfo, owner: &Principal) -> ExchangeResult<u128> {
+    async fn get_token_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> {
         // KongSwap integration not yet complete, returning unimplemented
         Err(ExchangeError::NotImplemented)
     }
@@ -64,6 +73,18 @@ impl Trading for KongSwapConnector {
         Err(ExchangeError::NotImplemented)
     }
     
+    /// Plans a trade without executing it
+    async fn simulate_trade(&self, params: &TradeParams) -> ExchangeResult<TradePlan> {
+        // KongSwap integration not yet complete, returning unimplemented
+        Err(ExchangeError::NotImplemented)
+    }
+
+    /// Gets the depth curve of a pair
+    async fn get_depth_curve(&self, pair: &TradingPair, sizes: &[u128]) -> ExchangeResult<DepthCurve> {
+        // KongSwap integration not yet complete, returning unimplemented
+        Err(ExchangeError::NotImplemented)
+    }
+    
     /// Executes a trade
     async fn execute_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
         // KongSwap integration not yet complete, returning unimplemented
@@ -74,6 +95,10 @@ impl Trading for KongSwapConnector {
         Err(ExchangeError::NotImplemented)
     }
 
+    async fn execute_call_trade_no_slippage(&self, params: &TradeParams, pool: &PoolInfo) -> ExchangeResult<TradeResult> {
+        Err(ExchangeError::NotImplemented)
+    }
+
     /// Executes a batch trade
     async fn execute_batch_trade(&self, params: &BatchTradeParams) -> ExchangeResult<BatchTradeResult> {
         // KongSwap integration not yet complete, returning unimplemented
@@ -107,8 +132,14 @@ impl TokenOperations for KongSwapConnector {
         Err(ExchangeError::NotImplemented)
     }
     
-    /// Queries the user's balance in the exchange
-    async fn get_exchange_balance(&self, token: &TokenInfo, user: &Principal) -> ExchangeResult<(u128,u128)> {
+    /// Queries the user's holdings in the exchange
+    async fn get_exchange_balance(&self, tokens: &[TokenInfo], account: &Account, valuation_token: &TokenInfo) -> ExchangeResult<HoldingsBreakdown> {
+        // KongSwap integration not yet complete, returning unimplemented
+        Err(ExchangeError::NotImplemented)
+    }
+
+    /// Transfers tokens to another account
+    async fn transfer_token(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<Option<u128>> {
         // KongSwap integration not yet complete, returning unimplemented
         Err(ExchangeError::NotImplemented)
     }
@@ -143,4 +174,25 @@ impl LiquidityPool for KongSwapConnector {
         // KongSwap integration not yet complete, returning unimplemented
         Err(ExchangeError::NotImplemented)
     }
-} 
\ No newline at end of file
+} 
+#[async_trait]
+impl ExchangeConnector for KongSwapConnector {
+    /// KongSwap integration not yet complete, so no features are advertised
+    fn capabilities(&self) -> ExchangeCapabilities {
+        ExchangeCapabilities {
+            exchange_type: ExchangeType::KongSwap,
+            supports_liquidity: false,
+            supports_batch_trades: false,
+            supports_call_trades: false,
+            supports_subaccounts: false,
+            supports_trade_history: false,
+            token_standards: vec![],
+            order_kinds: vec![],
+        }
+    }
+
+    /// Canister account this connector trades from
+    fn account(&self) -> Account {
+        Account::new(self.runtime.id())
+    }
+}
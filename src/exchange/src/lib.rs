@@ -3,6 +3,20 @@ pub mod error;
 pub mod traits;
 pub mod icpswap;
 pub mod kongswap;
+pub mod simulated;
+pub mod oracle;
+pub mod trigger_orders;
+pub mod quote_engine;
+pub mod holdings;
+pub mod depth;
+pub mod pricing;
+pub mod health;
+pub mod journal;
+pub mod verification;
+pub mod rate_limit;
+pub mod circuit_breaker;
+pub mod coalesce;
+pub mod metering;
 pub mod utils;
 pub mod factory;
 pub mod examples;
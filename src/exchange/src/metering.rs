@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use strategy_common::runtime::Runtime;
+
+/// Exchange and strategy operations whose cost is metered
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MeteredOperation {
+    Quote,
+    Trade,      // Full deposit → swap → withdraw trade
+    CallTrade,
+    Deposit,    // Pool deposit or depositFrom call
+    Swap,
+    Withdraw,
+    Transfer,   // Ledger transfer
+    Approve,
+    HedgeCycle, // One self-hedging execution across all of its split trades
+}
+
+/// Aggregated cost of one operation type
+///
+/// Cycles are measured as the drop in the canister balance, so they include
+/// execution, inter-canister call fees and attached payments. Operations that run
+/// concurrently in one call context (e.g. split trades under `join_all`) overlap,
+/// so their individual figures are upper bounds.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OperationCost {
+    pub count: u64,
+    pub failures: u64,
+    pub total_instructions: u128,
+    pub max_instructions: u64,
+    pub total_cycles: u128,
+    pub max_cycles: u128,
+}
+
+impl OperationCost {
+    /// Mean instructions per operation
+    pub fn average_instructions(&self) -> u128 {
+        if self.count == 0 { 0 } else { self.total_instructions / self.count as u128 }
+    }
+
+    /// Mean cycles per operation
+    pub fn average_cycles(&self) -> u128 {
+        if self.count == 0 { 0 } else { self.total_cycles / self.count as u128 }
+    }
+}
+
+/// Cost of one operation type, as returned by canister queries
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OperationCostReport {
+    pub operation: MeteredOperation,
+    pub cost: OperationCost,
+    pub average_instructions: u128,
+    pub average_cycles: u128,
+}
+
+thread_local! {
+    // Totals per operation type across every connector and strategy in the canister
+    static COSTS: RefCell<BTreeMap<MeteredOperation, OperationCost>> = RefCell::new(BTreeMap::new());
+}
+
+/// Add one measured operation to the aggregate
+pub fn record(operation: MeteredOperation, instructions: u64, cycles: u128, succeeded: bool) {
+    COSTS.with(|costs| {
+        let mut costs = costs.borrow_mut();
+        let cost = costs.entry(operation).or_default();
+        cost.count += 1;
+        if !succeeded {
+            cost.failures += 1;
+        }
+        cost.total_instructions = cost.total_instructions.saturating_add(instructions as u128);
+        cost.max_instructions = cost.max_instructions.max(instructions);
+        cost.total_cycles = cost.total_cycles.saturating_add(cycles);
+        cost.max_cycles = cost.max_cycles.max(cycles);
+    });
+}
+
+/// Run `future` and record its instruction and cycles cost under `operation`
+pub async fn metered<F, T, E>(runtime: &dyn Runtime, operation: MeteredOperation, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let instructions_before = runtime.instruction_counter();
+    let cycles_before = runtime.cycles_balance();
+
+    let result = future.await;
+
+    let instructions = runtime.instruction_counter().saturating_sub(instructions_before);
+    let cycles = cycles_before.saturating_sub(runtime.cycles_balance());
+    record(operation, instructions, cycles, result.is_ok());
+    result
+}
+
+/// Costs aggregated so far, one entry per operation type
+pub fn operation_costs() -> Vec<OperationCostReport> {
+    COSTS.with(|costs| {
+        costs
+            .borrow()
+            .iter()
+            .map(|(operation, cost)| OperationCostReport {
+                operation: *operation,
+                cost: cost.clone(),
+                average_instructions: cost.average_instructions(),
+                average_cycles: cost.average_cycles(),
+            })
+            .collect()
+    })
+}
+
+/// Clear all aggregated costs
+pub fn reset() {
+    COSTS.with(|costs| costs.borrow_mut().clear());
+}
@@ -0,0 +1,717 @@
+use async_trait::async_trait;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::error::*;
+use crate::pricing;
+use crate::traits::*;
+use crate::types::*;
+use strategy_common::debug_log;
+use strategy_common::runtime::{self, Runtime};
+use strategy_common::scheduler::{self, MissedRunPolicy, Schedule};
+
+/// Stable memory type used by the oracle
+pub type OracleMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Scheduler job ID of periodic price sampling
+pub const SAMPLING_JOB_ID: &str = "oracle_price_sampling";
+
+/// Mainnet exchange rate canister (XRC)
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+
+/// Cycles the XRC charges per `get_exchange_rate` call
+const XRC_CALL_CYCLES: u128 = 1_000_000_000;
+
+/// Oracle configuration
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OracleConfig {
+    pub sample_interval_secs: u64,
+    pub twap_window_secs: u64,
+    pub max_observations: u64,   // Per feed; the oldest observations are pruned first
+    pub max_deviation_bps: u64,  // Allowed distance between a quote and the TWAP
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        Self {
+            sample_interval_secs: 60,
+            twap_window_secs: 30 * 60,
+            max_observations: 1_440,
+            max_deviation_bps: 200,
+        }
+    }
+}
+
+/// Symbols used to look up a feed's reference rate in the XRC
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReferenceAssets {
+    pub base_symbol: String,
+    pub quote_symbol: String,
+}
+
+/// A trading pair whose price is sampled by the oracle
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PriceFeed {
+    pub pair: TradingPair,
+    pub sample_amount: Option<u128>,  // Base amount quoted per sample; defaults to one whole token
+    pub reference: Option<ReferenceAssets>,
+}
+
+impl Storable for PriceFeed {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A sampled price, in quote tokens per base token (human units)
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PriceObservation {
+    pub timestamp: u64,  // Seconds
+    pub price: f64,
+}
+
+impl Storable for PriceObservation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.price.to_bits().to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let timestamp = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let price = f64::from_bits(u64::from_be_bytes(bytes[8..16].try_into().unwrap()));
+        Self { timestamp, price }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 16, is_fixed_size: true };
+}
+
+/// Observation key; big-endian encoding keeps each feed's observations ordered by time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ObservationKey {
+    feed_id: u32,
+    timestamp: u64,
+}
+
+impl Storable for ObservationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(12);
+        bytes.extend_from_slice(&self.feed_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            feed_id: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 12, is_fixed_size: true };
+}
+
+/// Price oracle backed by stable memory
+pub struct PriceOracle {
+    config: OracleConfig,
+    feeds: StableBTreeMap<u32, PriceFeed, OracleMemory>,
+    observations: StableBTreeMap<ObservationKey, PriceObservation, OracleMemory>,
+    held: HashMap<u32, u64>,  // Observations stored per feed; counted once per feed after each upgrade
+}
+
+impl PriceOracle {
+    /// Opens (or creates) the oracle in the given memories
+    pub fn init(feeds_memory: OracleMemory, observations_memory: OracleMemory, config: OracleConfig) -> Self {
+        Self {
+            config,
+            feeds: StableBTreeMap::init(feeds_memory),
+            observations: StableBTreeMap::init(observations_memory),
+            held: HashMap::new(),
+        }
+    }
+
+    /// Current configuration
+    pub fn config(&self) -> &OracleConfig {
+        &self.config
+    }
+
+    /// Replaces the configuration
+    pub fn set_config(&mut self, config: OracleConfig) {
+        self.config = config;
+    }
+
+    /// Registers a feed for a pair, returning the existing ID if it is already tracked
+    pub fn add_feed(&mut self, feed: PriceFeed) -> u32 {
+        if let Some(feed_id) = self.find_feed(&feed.pair) {
+            self.feeds.insert(feed_id, feed);
+            return feed_id;
+        }
+        let feed_id = self.feeds.len() as u32;
+        self.feeds.insert(feed_id, feed);
+        feed_id
+    }
+
+    /// Finds the feed tracking a pair on the same exchange
+    pub fn find_feed(&self, pair: &TradingPair) -> Option<u32> {
+        self.feeds.iter()
+            .find(|(_, feed)| {
+                feed.pair.exchange == pair.exchange
+                    && feed.pair.base_token.canister_id == pair.base_token.canister_id
+                    && feed.pair.quote_token.canister_id == pair.quote_token.canister_id
+            })
+            .map(|(feed_id, _)| feed_id)
+    }
+
+    /// All registered feeds
+    pub fn feeds(&self) -> Vec<(u32, PriceFeed)> {
+        self.feeds.iter().collect()
+    }
+
+    /// Gets a feed by ID
+    pub fn get_feed(&self, feed_id: u32) -> Option<PriceFeed> {
+        self.feeds.get(&feed_id)
+    }
+
+    /// Stores an observation and prunes the feed down to `max_observations`
+    pub fn record(&mut self, feed_id: u32, timestamp: u64, price: f64) -> ExchangeResult<()> {
+        if !self.feeds.contains_key(&feed_id) {
+            return Err(ExchangeError::InvalidParameters(format!("Unknown price feed: {}", feed_id)));
+        }
+        if !price.is_finite() || price <= 0.0 {
+            return Err(ExchangeError::InvalidParameters(format!("Invalid price observation: {}", price)));
+        }
+
+        let mut held = self.held_observations(feed_id);
+        let replaced = self.observations.insert(ObservationKey { feed_id, timestamp }, PriceObservation { timestamp, price });
+        if replaced.is_none() {
+            held += 1;
+        }
+
+        // Evict oldest first; a feed's first key is its oldest observation
+        while held > self.config.max_observations {
+            let Some((oldest, _)) = self.observations.range(Self::feed_range(feed_id)).next() else {
+                break;
+            };
+            self.observations.remove(&oldest);
+            held -= 1;
+        }
+        self.held.insert(feed_id, held);
+        Ok(())
+    }
+
+    /// Number of observations stored for a feed
+    fn held_observations(&mut self, feed_id: u32) -> u64 {
+        if let Some(held) = self.held.get(&feed_id) {
+            return *held;
+        }
+        let held = self.observations.range(Self::feed_range(feed_id)).count() as u64;
+        self.held.insert(feed_id, held);
+        held
+    }
+
+    fn feed_range(feed_id: u32) -> std::ops::RangeInclusive<ObservationKey> {
+        ObservationKey { feed_id, timestamp: 0 }..=ObservationKey { feed_id, timestamp: u64::MAX }
+    }
+
+    /// Observations of a feed at or after `since`, oldest first
+    pub fn observations(&self, feed_id: u32, since: u64) -> Vec<PriceObservation> {
+        self.observations
+            .range(ObservationKey { feed_id, timestamp: since }..=ObservationKey { feed_id, timestamp: u64::MAX })
+            .map(|(_, observation)| observation)
+            .collect()
+    }
+
+    /// Most recent observation of a feed
+    pub fn latest(&self, feed_id: u32) -> Option<PriceObservation> {
+        self.observations.range(Self::feed_range(feed_id)).map(|(_, observation)| observation).last()
+    }
+
+    /// Time-weighted average price over `[now - window_secs, now]`
+    ///
+    /// Each observation's price holds until the next one. The observation in force at
+    /// the start of the window counts from the window start; without one, the average
+    /// starts at the first observation inside the window.
+    pub fn twap(&self, feed_id: u32, window_secs: u64, now: u64) -> Option<f64> {
+        let start = now.saturating_sub(window_secs);
+        let mut segment: Option<PriceObservation> = None;
+        let mut weighted_sum = 0.0;
+        let mut total_secs = 0u64;
+
+        let in_range = self.observations
+            .range(ObservationKey { feed_id, timestamp: 0 }..=ObservationKey { feed_id, timestamp: now })
+            .map(|(_, observation)| observation);
+        for observation in in_range {
+            if observation.timestamp <= start {
+                // Latest observation before the window sets the opening price
+                segment = Some(PriceObservation { timestamp: start, price: observation.price });
+                continue;
+            }
+            if let Some(previous) = segment {
+                let secs = observation.timestamp - previous.timestamp;
+                weighted_sum += previous.price * secs as f64;
+                total_secs += secs;
+            }
+            segment = Some(observation);
+        }
+
+        let last = segment?;
+        let secs = now - last.timestamp;
+        weighted_sum += last.price * secs as f64;
+        total_secs += secs;
+
+        if total_secs == 0 {
+            // Only a single observation taken exactly now
+            Some(last.price)
+        } else {
+            Some(weighted_sum / total_secs as f64)
+        }
+    }
+
+    /// Checks a quoted price against the feed's TWAP; returns the deviation in bps
+    pub fn check_price(&self, feed_id: u32, price: f64, now: u64) -> ExchangeResult<u64> {
+        let twap = self.twap(feed_id, self.config.twap_window_secs, now)
+            .ok_or_else(|| ExchangeError::PriceDeviation(format!("No oracle observations for feed {}", feed_id)))?;
+        let deviation = deviation_bps(price, twap);
+        if deviation > self.config.max_deviation_bps {
+            return Err(ExchangeError::PriceDeviation(format!(
+                "Price {} deviates {} bps from TWAP {} (max {} bps)",
+                price, deviation, twap, self.config.max_deviation_bps
+            )));
+        }
+        Ok(deviation)
+    }
+}
+
+/// Absolute distance between a price and a reference, in basis points
+pub fn deviation_bps(price: f64, reference: f64) -> u64 {
+    if reference <= 0.0 {
+        return u64::MAX;
+    }
+    ((price - reference).abs() / reference * 10_000.0).round() as u64
+}
+
+/// Price in quote tokens per base token (human units) implied by a quote
+pub fn quote_price(params: &TradeParams, quote: &QuoteResult) -> Option<f64> {
+    pricing::normalized_price(&params.pair, &params.direction, quote.input_amount, quote.output_amount)
+        .map(|normalized| normalized.price)
+}
+
+/// Source of reference prices used to sanity-check pool prices
+#[async_trait]
+pub trait ReferencePriceSource: Send + Sync {
+    /// Rate of `base_symbol` in units of `quote_symbol`
+    async fn get_rate(&self, base_symbol: &str, quote_symbol: &str) -> ExchangeResult<f64>;
+}
+
+/// XRC asset class
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum XrcAssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+/// XRC asset
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct XrcAsset {
+    symbol: String,
+    class: XrcAssetClass,
+}
+
+/// XRC `get_exchange_rate` request
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct XrcGetExchangeRateRequest {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: Option<u64>,
+}
+
+/// Subset of the XRC rate metadata needed to scale the rate
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct XrcExchangeRateMetadata {
+    decimals: u32,
+}
+
+/// Subset of the XRC exchange rate record
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct XrcExchangeRate {
+    rate: u64,
+    timestamp: u64,
+    metadata: XrcExchangeRateMetadata,
+}
+
+/// XRC error variants
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum XrcExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+/// XRC `get_exchange_rate` result
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum XrcGetExchangeRateResult {
+    Ok(XrcExchangeRate),
+    Err(XrcExchangeRateError),
+}
+
+/// Reference prices from the IC exchange rate canister
+pub struct XrcPriceSource {
+    canister_id: Principal,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl XrcPriceSource {
+    /// Uses the mainnet XRC
+    pub fn new(runtime: Arc<dyn Runtime>) -> Self {
+        Self {
+            canister_id: Principal::from_text(XRC_CANISTER_ID).expect("Invalid XRC canister ID"),
+            runtime,
+        }
+    }
+
+    /// Uses an XRC deployed at a custom canister ID (e.g. a local replica)
+    pub fn with_canister(canister_id: Principal, runtime: Arc<dyn Runtime>) -> Self {
+        Self { canister_id, runtime }
+    }
+
+    fn crypto_asset(symbol: &str) -> XrcAsset {
+        XrcAsset { symbol: symbol.to_uppercase(), class: XrcAssetClass::Cryptocurrency }
+    }
+}
+
+#[async_trait]
+impl ReferencePriceSource for XrcPriceSource {
+    async fn get_rate(&self, base_symbol: &str, quote_symbol: &str) -> ExchangeResult<f64> {
+        let request = XrcGetExchangeRateRequest {
+            base_asset: Self::crypto_asset(base_symbol),
+            quote_asset: Self::crypto_asset(quote_symbol),
+            timestamp: None,
+        };
+        let result: (XrcGetExchangeRateResult,) = runtime::call_with_payment(
+            self.runtime.as_ref(),
+            self.canister_id,
+            "get_exchange_rate",
+            (request,),
+            XRC_CALL_CYCLES,
+        )
+        .await
+        .map_err(|(code, msg)| ExchangeError::CanisterCallError(format!("XRC call failed: {:?} - {}", code, msg)))?;
+
+        match result.0 {
+            XrcGetExchangeRateResult::Ok(rate) => {
+                Ok(rate.rate as f64 / 10f64.powi(rate.metadata.decimals as i32))
+            }
+            XrcGetExchangeRateResult::Err(err) => {
+                Err(ExchangeError::CanisterCallError(format!("XRC returned error: {:?}", err)))
+            }
+        }
+    }
+}
+
+/// Fixed reference rates for tests and local deployments
+#[derive(Default)]
+pub struct MockPriceSource {
+    rates: Mutex<HashMap<(String, String), f64>>,
+}
+
+impl MockPriceSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the rate returned for a symbol pair
+    pub fn set_rate(&self, base_symbol: &str, quote_symbol: &str, rate: f64) {
+        let mut rates = self.rates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rates.insert((base_symbol.to_uppercase(), quote_symbol.to_uppercase()), rate);
+    }
+}
+
+#[async_trait]
+impl ReferencePriceSource for MockPriceSource {
+    async fn get_rate(&self, base_symbol: &str, quote_symbol: &str) -> ExchangeResult<f64> {
+        let rates = self.rates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rates.get(&(base_symbol.to_uppercase(), quote_symbol.to_uppercase()))
+            .copied()
+            .ok_or_else(|| ExchangeError::UnsupportedToken(format!("No mock rate for {}/{}", base_symbol, quote_symbol)))
+    }
+}
+
+thread_local! {
+    // Oracle instance of the running canister, opened by `init`
+    static ORACLE: RefCell<Option<PriceOracle>> = RefCell::new(None);
+}
+
+/// Opens the canister's oracle; call from both `init` and `post_upgrade`
+pub fn init(feeds_memory: OracleMemory, observations_memory: OracleMemory, config: OracleConfig) {
+    ORACLE.with(|oracle| {
+        *oracle.borrow_mut() = Some(PriceOracle::init(feeds_memory, observations_memory, config));
+    });
+}
+
+/// Runs a closure against the canister's oracle
+pub fn with_oracle<T>(f: impl FnOnce(&mut PriceOracle) -> T) -> ExchangeResult<T> {
+    ORACLE.with(|oracle| {
+        oracle.borrow_mut()
+            .as_mut()
+            .map(f)
+            .ok_or_else(|| ExchangeError::InternalError("Price oracle not initialized".to_string()))
+    })
+}
+
+/// Quotes a feed and returns its current price in human units
+pub async fn sample_feed(connector: &dyn Trading, feed: &PriceFeed) -> ExchangeResult<f64> {
+    let amount = feed.sample_amount
+        .unwrap_or_else(|| 10u128.pow(feed.pair.base_token.decimals as u32));
+    let params = TradeParams {
+        pair: feed.pair.clone(),
+        direction: TradeDirection::Sell,
+        amount,
+        slippage_tolerance: 0.0,
+        deadline_secs: None,
+    };
+    let quote = connector.get_quote(&params).await?;
+    quote_price(&params, &quote)
+        .ok_or_else(|| ExchangeError::InternalError("Quote returned a zero amount".to_string()))
+}
+
+/// Samples every registered feed once; failures are logged and skipped
+pub async fn sample_all(
+    connector_for: &dyn Fn(&TradingPair) -> ExchangeResult<Box<dyn Trading>>,
+    now: u64,
+) -> usize {
+    let feeds = match with_oracle(|oracle| oracle.feeds()) {
+        Ok(feeds) => feeds,
+        Err(e) => {
+            debug_log!("Oracle sampling skipped: {}", e);
+            return 0;
+        }
+    };
+
+    let mut recorded = 0;
+    for (feed_id, feed) in feeds {
+        let price = match connector_for(&feed.pair) {
+            Ok(connector) => sample_feed(connector.as_ref(), &feed).await,
+            Err(e) => Err(e),
+        };
+        match price.and_then(|price| with_oracle(|oracle| oracle.record(feed_id, now, price))?) {
+            Ok(()) => recorded += 1,
+            Err(e) => debug_log!("Failed to sample feed {} ({}/{}): {}", feed_id, feed.pair.base_token.symbol, feed.pair.quote_token.symbol, e),
+        }
+    }
+    recorded
+}
+
+/// Sample all feeds every `sample_interval_secs` through the scheduler, timed by `runtime`
+///
+/// Call from both `init` and `post_upgrade` once the scheduler is open; the job itself
+/// persists, but its handler has to be registered again.
+pub fn schedule_sampling<F>(runtime: Arc<dyn Runtime>, connector_for: F) -> Result<(), String>
+where
+    F: Fn(&TradingPair) -> ExchangeResult<Box<dyn Trading>> + 'static,
+{
+    let interval_secs = with_oracle(|oracle| oracle.config().sample_interval_secs).map_err(|e| e.to_string())?;
+    let connector_for = Rc::new(connector_for);
+    scheduler::register_handler(SAMPLING_JOB_ID, move || {
+        let connector_for = connector_for.clone();
+        let runtime = runtime.clone();
+        async move {
+            let now = runtime.time() / 1_000_000_000;
+            sample_all(connector_for.as_ref(), now).await;
+            Ok(())
+        }
+    });
+    if scheduler::get_job(SAMPLING_JOB_ID).is_some() {
+        return Ok(());
+    }
+    scheduler::schedule(
+        SAMPLING_JOB_ID,
+        Schedule::Interval { seconds: interval_secs },
+        MissedRunPolicy::Skip,
+        interval_secs,
+    )
+    .map(|_| ())
+}
+
+/// Stops periodic sampling
+pub fn stop_sampling() -> Result<(), String> {
+    scheduler::cancel(SAMPLING_JOB_ID).map(|_| ())
+}
+
+/// Compares a feed's TWAP with its reference rate; returns the deviation in bps
+pub async fn check_reference(source: &dyn ReferencePriceSource, feed_id: u32, now: u64) -> ExchangeResult<u64> {
+    let (feed, twap, max_deviation_bps) = with_oracle(|oracle| {
+        let window = oracle.config().twap_window_secs;
+        (oracle.get_feed(feed_id), oracle.twap(feed_id, window, now), oracle.config().max_deviation_bps)
+    })?;
+    let feed = feed.ok_or_else(|| ExchangeError::InvalidParameters(format!("Unknown price feed: {}", feed_id)))?;
+    let reference = feed.reference
+        .ok_or_else(|| ExchangeError::InvalidParameters(format!("Feed {} has no reference assets", feed_id)))?;
+    let twap = twap.ok_or_else(|| ExchangeError::PriceDeviation(format!("No oracle observations for feed {}", feed_id)))?;
+
+    let rate = source.get_rate(&reference.base_symbol, &reference.quote_symbol).await?;
+    let deviation = deviation_bps(twap, rate);
+    if deviation > max_deviation_bps {
+        return Err(ExchangeError::PriceDeviation(format!(
+            "TWAP {} deviates {} bps from reference rate {} (max {} bps)",
+            twap, deviation, rate, max_deviation_bps
+        )));
+    }
+    Ok(deviation)
+}
+
+/// Executes a trade only if its quote is within `max_deviation_bps` of the pair's TWAP
+pub async fn execute_guarded_trade(connector: &dyn Trading, params: &TradeParams, now: u64) -> ExchangeResult<TradeResult> {
+    let quote = connector.get_quote(params).await?;
+    let price = quote_price(params, &quote)
+        .ok_or_else(|| ExchangeError::InternalError("Quote returned a zero amount".to_string()))?;
+    with_oracle(|oracle| {
+        let feed_id = oracle.find_feed(&params.pair)
+            .ok_or_else(|| ExchangeError::PriceDeviation("No oracle feed for trading pair".to_string()))?;
+        oracle.check_price(feed_id, price, now)
+    })??;
+    connector.execute_trade(params).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    fn config() -> OracleConfig {
+        OracleConfig {
+            sample_interval_secs: 60,
+            twap_window_secs: 600,
+            max_observations: 3,
+            max_deviation_bps: 100,
+        }
+    }
+
+    fn token(id: u8, symbol: &str) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    fn feed() -> PriceFeed {
+        PriceFeed {
+            pair: TradingPair {
+                base_token: token(1, "ICP"),
+                quote_token: token(2, "ckUSDC"),
+                exchange: ExchangeType::Simulated,
+            },
+            sample_amount: None,
+            reference: Some(ReferenceAssets {
+                base_symbol: "ICP".to_string(),
+                quote_symbol: "USDC".to_string(),
+            }),
+        }
+    }
+
+    // Opens the thread's oracle with one registered feed
+    fn open() -> u32 {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        init(manager.get(MemoryId::new(0)), manager.get(MemoryId::new(1)), config());
+        with_oracle(|oracle| oracle.add_feed(feed())).unwrap()
+    }
+
+    #[test]
+    fn record_keeps_the_newest_observations() {
+        let feed_id = open();
+        with_oracle(|oracle| {
+            for (timestamp, price) in [(10, 1.0), (20, 2.0), (30, 3.0), (40, 4.0), (50, 5.0)] {
+                oracle.record(feed_id, timestamp, price).unwrap();
+            }
+            // Re-recording a timestamp replaces it without counting twice
+            oracle.record(feed_id, 50, 5.5).unwrap();
+
+            let kept: Vec<u64> = oracle.observations(feed_id, 0).iter().map(|o| o.timestamp).collect();
+            assert_eq!(kept, vec![30, 40, 50]);
+            assert_eq!(oracle.latest(feed_id).unwrap().price, 5.5);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn pruning_one_feed_leaves_the_others() {
+        let first = open();
+        let mut other = feed();
+        other.pair.quote_token = token(3, "ckBTC");
+        with_oracle(|oracle| {
+            let second = oracle.add_feed(other);
+            oracle.record(second, 5, 0.001).unwrap();
+            for timestamp in [10, 20, 30, 40] {
+                oracle.record(first, timestamp, 1.0).unwrap();
+            }
+            assert_eq!(oracle.observations(first, 0).len(), 3);
+            assert_eq!(oracle.observations(second, 0).len(), 1);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn twap_weights_prices_by_time_held() {
+        let feed_id = open();
+        with_oracle(|oracle| {
+            oracle.record(feed_id, 0, 10.0).unwrap();
+            oracle.record(feed_id, 300, 20.0).unwrap();
+            // 10.0 for the first 300s of the window, 20.0 for the last 300s
+            assert_eq!(oracle.twap(feed_id, 600, 600), Some(15.0));
+            assert!(oracle.check_price(feed_id, 15.1, 600).is_ok());
+            assert!(matches!(oracle.check_price(feed_id, 20.0, 600), Err(ExchangeError::PriceDeviation(_))));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn reference_check_uses_the_mock_rate() {
+        let feed_id = open();
+        with_oracle(|oracle| oracle.record(feed_id, 100, 8.0)).unwrap().unwrap();
+
+        let source = MockPriceSource::new();
+        source.set_rate("icp", "usdc", 8.04);
+        assert_eq!(block_on(check_reference(&source, feed_id, 200)).unwrap(), 50);
+
+        source.set_rate("ICP", "USDC", 10.0);
+        assert!(matches!(block_on(check_reference(&source, feed_id, 200)), Err(ExchangeError::PriceDeviation(_))));
+    }
+
+    #[test]
+    fn reference_check_fails_without_a_mock_rate() {
+        let feed_id = open();
+        with_oracle(|oracle| oracle.record(feed_id, 100, 8.0)).unwrap().unwrap();
+        let source = MockPriceSource::new();
+        assert!(matches!(block_on(check_reference(&source, feed_id, 200)), Err(ExchangeError::UnsupportedToken(_))));
+    }
+}
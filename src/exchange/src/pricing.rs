@@ -0,0 +1,133 @@
+use candid::Principal;
+
+use crate::types::*;
+
+/// Whether a swap from `input` to `output` runs token0 → token1 in a pool
+///
+/// Pools order their tokens by the text form of the ledger principal.
+pub fn is_zero_for_one(input: &Principal, output: &Principal) -> bool {
+    input.to_string() < output.to_string()
+}
+
+/// Tokens of a pair in pool order (token0, token1)
+pub fn pool_order<'a>(a: &'a TokenInfo, b: &'a TokenInfo) -> (&'a TokenInfo, &'a TokenInfo) {
+    if is_zero_for_one(&a.canister_id, &b.canister_id) {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Input and output tokens of a trade in the given direction
+pub fn trade_tokens<'a>(pair: &'a TradingPair, direction: &TradeDirection) -> (&'a TokenInfo, &'a TokenInfo) {
+    match direction {
+        TradeDirection::Buy => (&pair.quote_token, &pair.base_token),
+        TradeDirection::Sell => (&pair.base_token, &pair.quote_token),
+    }
+}
+
+/// Convert a raw amount to human units
+pub fn to_human(amount: u128, decimals: u8) -> f64 {
+    amount as f64 / 10f64.powi(decimals as i32)
+}
+
+/// Price of the pair's base token in quote tokens, in human units, for any trade or quote
+///
+/// A Sell turns base into quote and a Buy turns quote into base, so both report the
+/// same orientation. None when either amount is zero.
+pub fn normalized_price(pair: &TradingPair, direction: &TradeDirection, input_amount: u128, output_amount: u128) -> Option<NormalizedPrice> {
+    if input_amount == 0 || output_amount == 0 {
+        return None;
+    }
+    let (base_amount, quote_amount) = match direction {
+        TradeDirection::Sell => (input_amount, output_amount),
+        TradeDirection::Buy => (output_amount, input_amount),
+    };
+    let base = to_human(base_amount, pair.base_token.decimals);
+    let quote = to_human(quote_amount, pair.quote_token.decimals);
+    Some(NormalizedPrice {
+        base_token: pair.base_token.canister_id,
+        quote_token: pair.quote_token.canister_id,
+        price: quote / base,
+        inverse: base / quote,
+    })
+}
+
+/// (price, inverse) of a trade for result structs; zero when nothing was exchanged
+pub fn price_pair(pair: &TradingPair, direction: &TradeDirection, input_amount: u128, output_amount: u128) -> (f64, f64) {
+    match normalized_price(pair, direction, input_amount, output_amount) {
+        Some(normalized) => (normalized.price, normalized.inverse),
+        None => (0.0, 0.0),
+    }
+}
+
+/// Normalized price carried by a trade result
+pub fn trade_price(pair: &TradingPair, result: &TradeResult) -> Option<NormalizedPrice> {
+    if result.price <= 0.0 {
+        return None;
+    }
+    Some(NormalizedPrice {
+        base_token: pair.base_token.canister_id,
+        quote_token: pair.quote_token.canister_id,
+        price: result.price,
+        inverse: result.inverse_price,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(id: u8, symbol: &str, decimals: u8) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    // 8-decimal base priced in a 6-decimal quote
+    fn pair() -> TradingPair {
+        TradingPair {
+            base_token: token(1, "BASE", 8),
+            quote_token: token(2, "QUOTE", 6),
+            exchange: ExchangeType::ICPSwap,
+        }
+    }
+
+    #[test]
+    fn trade_tokens_follow_the_direction() {
+        let pair = pair();
+        let (input, output) = trade_tokens(&pair, &TradeDirection::Sell);
+        assert_eq!((input.symbol.as_str(), output.symbol.as_str()), ("BASE", "QUOTE"));
+        let (input, output) = trade_tokens(&pair, &TradeDirection::Buy);
+        assert_eq!((input.symbol.as_str(), output.symbol.as_str()), ("QUOTE", "BASE"));
+    }
+
+    #[test]
+    fn pool_order_is_independent_of_argument_order() {
+        let (a, b) = (token(1, "A", 8), token(2, "B", 8));
+        let first = pool_order(&a, &b).0.canister_id;
+        assert_eq!(pool_order(&b, &a).0.canister_id, first);
+        assert!(is_zero_for_one(&first, &pool_order(&a, &b).1.canister_id));
+    }
+
+    #[test]
+    fn buy_and_sell_report_the_same_orientation() {
+        let pair = pair();
+        // 2 BASE for 5 QUOTE either way round
+        let sell = normalized_price(&pair, &TradeDirection::Sell, 200_000_000, 5_000_000).unwrap();
+        let buy = normalized_price(&pair, &TradeDirection::Buy, 5_000_000, 200_000_000).unwrap();
+        assert!((sell.price - 2.5).abs() < 1e-12);
+        assert!((buy.price - 2.5).abs() < 1e-12);
+        assert!((sell.inverse - 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn empty_trades_have_no_price() {
+        let pair = pair();
+        assert!(normalized_price(&pair, &TradeDirection::Sell, 0, 5).is_none());
+        assert_eq!(price_pair(&pair, &TradeDirection::Sell, 5, 0), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,479 @@
+use candid::{Nat, Principal};
+use primitive_types::{U256, U512};
+
+use crate::error::*;
+use crate::types::*;
+
+/// Lowest tick a V3 pool can reach
+pub const MIN_TICK: i32 = -887272;
+/// Highest tick a V3 pool can reach
+pub const MAX_TICK: i32 = 887272;
+
+/// sqrtPriceX96 at `MIN_TICK`
+pub const MIN_SQRT_RATIO: u128 = 4295128739;
+/// sqrtPriceX96 at `MAX_TICK`, in decimal
+pub const MAX_SQRT_RATIO: &str = "1461446703485210103287273052203988822378723970342";
+
+// Pool fees are expressed in ppm
+const FEE_DENOMINATOR: u64 = 1_000_000;
+
+// sqrt(1.0001^-2^i) in Q128.128 for each bit i > 0 of the absolute tick
+const TICK_RATIOS: [(u32, u128); 19] = [
+    (0x2, 0xfff97272373d413259a46990580e213a),
+    (0x4, 0xfff2e50f5f656932ef12357cf3c7fdcc),
+    (0x8, 0xffe5caca7e10e4e61c3624eaa0941cd0),
+    (0x10, 0xffcb9843d60f6159c9db58835c926644),
+    (0x20, 0xff973b41fa98c081472e6896dfb254c0),
+    (0x40, 0xff2ea16466c96a3843ec78b326b52861),
+    (0x80, 0xfe5dee046a99a2a811c461f1969c3053),
+    (0x100, 0xfcbe86c7900a88aedcffc83b479aa3a4),
+    (0x200, 0xf987a7253ac413176f2b074cf7815e54),
+    (0x400, 0xf3392b0822b70005940c7a398e4b70f3),
+    (0x800, 0xe7159475a2c29b7443b29c7fa6e889d9),
+    (0x1000, 0xd097f3bdfd2022b8845ad8f792aa5825),
+    (0x2000, 0xa9f746462d870fdf8a65dc1f90e061e5),
+    (0x4000, 0x70d869a156d2a1b890bb3df62baf32f7),
+    (0x8000, 0x31be135f97d08fd981231505542fcfa6),
+    (0x10000, 0x9aa508b5b7a84e1c677de54f3e99bc9),
+    (0x20000, 0x5d6af8dedb81196699c329225ee604),
+    (0x40000, 0x2216e584f5fa1ea926041bedfe98),
+    (0x80000, 0x48a170391f7dc42444e8fa2),
+];
+
+fn overflow(what: &str) -> ExchangeError {
+    ExchangeError::InternalError(format!("Overflow in {}", what))
+}
+
+fn max_sqrt_ratio() -> U256 {
+    U256::from_dec_str(MAX_SQRT_RATIO).expect("Invalid MAX_SQRT_RATIO")
+}
+
+/// Parses a pool's sqrtPriceX96
+pub fn sqrt_price_from_nat(value: &Nat) -> ExchangeResult<U256> {
+    U256::from_dec_str(&value.0.to_string())
+        .map_err(|e| ExchangeError::InternalError(format!("Invalid sqrtPriceX96 {}: {:?}", value, e)))
+}
+
+/// Encodes a sqrtPriceX96 for candid
+pub fn sqrt_price_to_nat(value: U256) -> Nat {
+    Nat::from(value.low_u128()) + Nat::from((value >> 128).low_u128()) * Nat::from(1u128 << 64) * Nat::from(1u128 << 64)
+}
+
+/// Approximate value of a Q64.96 sqrt price as a plain number, for reporting only
+pub fn sqrt_price_to_f64(value: U256) -> f64 {
+    let high = (value >> 128).low_u128() as f64 * 2f64.powi(128);
+    (high + value.low_u128() as f64) / 2f64.powi(96)
+}
+
+fn to_u128(value: U256, what: &str) -> ExchangeResult<u128> {
+    if value > U256::from(u128::MAX) {
+        return Err(overflow(what));
+    }
+    Ok(value.low_u128())
+}
+
+// floor(a * b / denominator) with a 512-bit intermediate
+fn mul_div(a: U256, b: U256, denominator: U256) -> ExchangeResult<U256> {
+    let quotient = a.full_mul(b) / U512::from(denominator);
+    U256::try_from(quotient).map_err(|_| overflow("mul_div"))
+}
+
+// ceil(a * b / denominator) with a 512-bit intermediate
+fn mul_div_rounding_up(a: U256, b: U256, denominator: U256) -> ExchangeResult<U256> {
+    let product = a.full_mul(b);
+    let denominator = U512::from(denominator);
+    let mut quotient = product / denominator;
+    if !(product % denominator).is_zero() {
+        quotient += U512::one();
+    }
+    U256::try_from(quotient).map_err(|_| overflow("mul_div_rounding_up"))
+}
+
+fn div_rounding_up(a: U256, b: U256) -> U256 {
+    let quotient = a / b;
+    if (a % b).is_zero() { quotient } else { quotient + U256::one() }
+}
+
+/// sqrtPriceX96 at a tick, where price = 1.0001^tick in raw token1 per token0
+///
+/// Matches the pool's own integer math bit for bit.
+pub fn sqrt_ratio_at_tick(tick: i32) -> ExchangeResult<U256> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(ExchangeError::InvalidParameters(format!("Tick {} is out of range", tick)));
+    }
+    let abs_tick = tick.unsigned_abs();
+    let mut ratio = if abs_tick & 0x1 != 0 {
+        U256::from(0xfffcb933bd6fad37aa2d162d1a594001u128)
+    } else {
+        U256::one() << 128
+    };
+    for (bit, factor) in TICK_RATIOS {
+        if abs_tick & bit != 0 {
+            ratio = (ratio * U256::from(factor)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+
+    // Q128.128 to Q64.96, rounding up so the result never falls below the tick's price
+    let rounded = if (ratio & U256::from(u32::MAX)).is_zero() { U256::zero() } else { U256::one() };
+    Ok((ratio >> 32) + rounded)
+}
+
+/// Greatest tick whose sqrt price does not exceed `sqrt_price_x96`
+pub fn tick_at_sqrt_ratio(sqrt_price_x96: U256) -> ExchangeResult<i32> {
+    if sqrt_price_x96 < U256::from(MIN_SQRT_RATIO) || sqrt_price_x96 >= max_sqrt_ratio() {
+        return Err(ExchangeError::InvalidParameters(format!("sqrtPriceX96 {} is out of range", sqrt_price_x96)));
+    }
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_ratio_at_tick(mid)? <= sqrt_price_x96 {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+// Token0 between two sqrt prices for the given liquidity
+fn amount0_delta(sqrt_a: U256, sqrt_b: U256, liquidity: u128, round_up: bool) -> ExchangeResult<U256> {
+    let (lower, upper) = if sqrt_a > sqrt_b { (sqrt_b, sqrt_a) } else { (sqrt_a, sqrt_b) };
+    let numerator1 = U256::from(liquidity) << 96;
+    let numerator2 = upper - lower;
+    if round_up {
+        Ok(div_rounding_up(mul_div_rounding_up(numerator1, numerator2, upper)?, lower))
+    } else {
+        Ok(mul_div(numerator1, numerator2, upper)? / lower)
+    }
+}
+
+// Token1 between two sqrt prices for the given liquidity
+fn amount1_delta(sqrt_a: U256, sqrt_b: U256, liquidity: u128, round_up: bool) -> ExchangeResult<U256> {
+    let (lower, upper) = if sqrt_a > sqrt_b { (sqrt_b, sqrt_a) } else { (sqrt_a, sqrt_b) };
+    let q96 = U256::one() << 96;
+    if round_up {
+        mul_div_rounding_up(U256::from(liquidity), upper - lower, q96)
+    } else {
+        mul_div(U256::from(liquidity), upper - lower, q96)
+    }
+}
+
+// Sqrt price after adding `amount` of the input token, rounded against the trader
+fn next_sqrt_price_from_input(sqrt_price: U256, liquidity: u128, amount: U256, zero_for_one: bool) -> ExchangeResult<U256> {
+    if amount.is_zero() {
+        return Ok(sqrt_price);
+    }
+    if zero_for_one {
+        let numerator1 = U256::from(liquidity) << 96;
+        let (product, product_overflowed) = amount.overflowing_mul(sqrt_price);
+        if !product_overflowed {
+            let (denominator, denominator_overflowed) = numerator1.overflowing_add(product);
+            if !denominator_overflowed {
+                return mul_div_rounding_up(numerator1, sqrt_price, denominator);
+            }
+        }
+        let denominator = (numerator1 / sqrt_price).checked_add(amount).ok_or_else(|| overflow("next_sqrt_price_from_input"))?;
+        Ok(div_rounding_up(numerator1, denominator))
+    } else {
+        let quotient = mul_div(amount, U256::one() << 96, U256::from(liquidity))?;
+        sqrt_price.checked_add(quotient).ok_or_else(|| overflow("next_sqrt_price_from_input"))
+    }
+}
+
+/// One exact-input swap step within a single liquidity range
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwapStep {
+    pub sqrt_price_next: U256,
+    pub amount_in: U256,   // Input swapped, excluding the fee
+    pub amount_out: U256,
+    pub fee_amount: U256,  // Pool fee, in input token units
+}
+
+/// Moves the price from `sqrt_price` towards `sqrt_price_target` with at most `amount_remaining` input
+///
+/// The direction follows from the two prices; `fee` is in ppm.
+pub fn compute_swap_step(
+    sqrt_price: U256,
+    sqrt_price_target: U256,
+    liquidity: u128,
+    amount_remaining: U256,
+    fee: u64,
+) -> ExchangeResult<SwapStep> {
+    if fee >= FEE_DENOMINATOR {
+        return Err(ExchangeError::InvalidParameters(format!("Pool fee {} ppm is out of range", fee)));
+    }
+    let zero_for_one = sqrt_price >= sqrt_price_target;
+    let fee_complement = U256::from(FEE_DENOMINATOR - fee);
+    let remaining_less_fee = mul_div(amount_remaining, fee_complement, U256::from(FEE_DENOMINATOR))?;
+
+    let amount_to_target = if zero_for_one {
+        amount0_delta(sqrt_price_target, sqrt_price, liquidity, true)?
+    } else {
+        amount1_delta(sqrt_price, sqrt_price_target, liquidity, true)?
+    };
+    let sqrt_price_next = if remaining_less_fee >= amount_to_target {
+        sqrt_price_target
+    } else {
+        next_sqrt_price_from_input(sqrt_price, liquidity, remaining_less_fee, zero_for_one)?
+    };
+    let reached_target = sqrt_price_next == sqrt_price_target;
+
+    let (amount_in, amount_out) = if zero_for_one {
+        let amount_in = if reached_target { amount_to_target } else { amount0_delta(sqrt_price_next, sqrt_price, liquidity, true)? };
+        (amount_in, amount1_delta(sqrt_price_next, sqrt_price, liquidity, false)?)
+    } else {
+        let amount_in = if reached_target { amount_to_target } else { amount1_delta(sqrt_price, sqrt_price_next, liquidity, true)? };
+        (amount_in, amount0_delta(sqrt_price, sqrt_price_next, liquidity, false)?)
+    };
+
+    let fee_amount = if reached_target {
+        mul_div_rounding_up(amount_in, U256::from(fee), fee_complement)?
+    } else {
+        // The whole remainder was used; whatever was not swapped is the fee
+        amount_remaining - amount_in
+    };
+
+    Ok(SwapStep { sqrt_price_next, amount_in, amount_out, fee_amount })
+}
+
+/// Quote an exact-input swap against a pool snapshot, crossing initialized ticks as needed
+///
+/// Follows the V3 swap loop in Q64.96 integer math, so amounts match the pool's own
+/// quote: each step swaps within one liquidity range, taking the pool fee from the
+/// input, and crossing a tick adjusts active liquidity by its net amount.
+pub fn quote_exact_input(snapshot: &PoolSnapshot, zero_for_one: bool, amount_in: u128) -> ExchangeResult<LocalQuote> {
+    let sqrt_price_start = sqrt_price_from_nat(&snapshot.sqrt_price_x96)?;
+    let sqrt_price_limit = if zero_for_one {
+        U256::from(MIN_SQRT_RATIO) + U256::one()
+    } else {
+        max_sqrt_ratio() - U256::one()
+    };
+    let mut remaining = U256::from(amount_in);
+    let mut sqrt_price = sqrt_price_start;
+    let mut tick = snapshot.tick;
+    let mut liquidity = snapshot.liquidity;
+    let mut amount_out = U256::zero();
+    let mut fee_total = U256::zero();
+    let mut ticks_crossed = 0u32;
+
+    // Initialized ticks in the direction of travel, nearest first
+    let next_ticks: Vec<&TickLiquidity> = if zero_for_one {
+        snapshot.ticks.iter().rev().filter(|t| t.tick <= snapshot.tick).collect()
+    } else {
+        snapshot.ticks.iter().filter(|t| t.tick > snapshot.tick).collect()
+    };
+    let mut index = 0;
+
+    while !remaining.is_zero() && sqrt_price != sqrt_price_limit {
+        let (target_tick, liquidity_net) = match next_ticks.get(index) {
+            Some(next) => (next.tick, Some(next.liquidity_net)),
+            None => (if zero_for_one { MIN_TICK } else { MAX_TICK }, None),
+        };
+        let sqrt_price_at_target = sqrt_ratio_at_tick(target_tick)?;
+        let target = if zero_for_one {
+            sqrt_price_at_target.max(sqrt_price_limit)
+        } else {
+            sqrt_price_at_target.min(sqrt_price_limit)
+        };
+
+        let step_start = sqrt_price;
+        let step = compute_swap_step(sqrt_price, target, liquidity, remaining, snapshot.fee)?;
+        remaining -= step.amount_in + step.fee_amount;
+        amount_out += step.amount_out;
+        fee_total += step.fee_amount;
+        sqrt_price = step.sqrt_price_next;
+
+        if sqrt_price == sqrt_price_at_target {
+            if let Some(net) = liquidity_net {
+                // Crossing downwards removes the liquidity that crossing upwards adds
+                let delta = if zero_for_one { net.checked_neg().ok_or_else(|| overflow("liquidity_net"))? } else { net };
+                liquidity = liquidity.checked_add_signed(delta)
+                    .ok_or_else(|| ExchangeError::InternalError(format!("Liquidity underflow crossing tick {}", target_tick)))?;
+                ticks_crossed += 1;
+                index += 1;
+            }
+            tick = if zero_for_one { target_tick - 1 } else { target_tick };
+        } else if sqrt_price != step_start {
+            tick = tick_at_sqrt_ratio(sqrt_price)?;
+        }
+    }
+
+    let input_used = amount_in - to_u128(remaining, "remaining input")?;
+    let output_amount = to_u128(amount_out, "output amount")?;
+    let fee_amount = to_u128(fee_total, "fee amount")?;
+
+    Ok(LocalQuote {
+        input_amount: input_used,
+        output_amount,
+        fee_amount,
+        price_impact: price_impact(sqrt_price_start, zero_for_one, input_used.saturating_sub(fee_amount), output_amount),
+        sqrt_price_x96_after: sqrt_price_to_nat(sqrt_price),
+        tick_after: tick,
+        ticks_crossed,
+        fully_filled: remaining.is_zero(),
+    })
+}
+
+/// How far (%) a swap of `swapped` input, after the pool fee, for `amount_out` fell short
+/// of the spot price at `sqrt_price_x96`
+///
+/// Impact is reported as a percentage, so float precision is enough here.
+pub fn price_impact(sqrt_price_x96: U256, zero_for_one: bool, swapped: u128, amount_out: u128) -> f64 {
+    let start = sqrt_price_to_f64(sqrt_price_x96);
+    let spot_price = if zero_for_one { start * start } else { 1.0 / (start * start) };
+    let swapped = swapped as f64;
+    if swapped > 0.0 && spot_price > 0.0 {
+        ((1.0 - (amount_out as f64 / swapped) / spot_price) * 100.0).max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Quote selling `amount_in` of `input_token` into a pool snapshot
+pub fn quote_trade(snapshot: &PoolSnapshot, input_token: &Principal, amount_in: u128) -> ExchangeResult<LocalQuote> {
+    let zero_for_one = if *input_token == snapshot.token0 {
+        true
+    } else if *input_token == snapshot.token1 {
+        false
+    } else {
+        return Err(ExchangeError::InvalidParameters(format!(
+            "Token {} is not traded in pool {}",
+            input_token, snapshot.pool_id
+        )));
+    };
+    quote_exact_input(snapshot, zero_for_one, amount_in)
+}
+
+/// Relative difference between a local and an on-chain output amount, as a percentage
+pub fn quote_deviation(local_output: u128, on_chain_output: u128) -> f64 {
+    if on_chain_output == 0 {
+        return if local_output == 0 { 0.0 } else { 100.0 };
+    }
+    (local_output as f64 - on_chain_output as f64).abs() / on_chain_output as f64 * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const E18: u128 = 1_000_000_000_000_000_000;
+
+    fn q96() -> U256 {
+        U256::one() << 96
+    }
+
+    fn dec(value: &str) -> U256 {
+        U256::from_dec_str(value).unwrap()
+    }
+
+    // Pool at tick 0 with 1e18 liquidity over [-600, 600] and another 5e17 over [-60, 60]
+    fn snapshot() -> PoolSnapshot {
+        PoolSnapshot {
+            pool_id: Principal::from_slice(&[7]),
+            token0: Principal::from_slice(&[1]),
+            token1: Principal::from_slice(&[2]),
+            fee: 3000,
+            sqrt_price_x96: sqrt_price_to_nat(q96()),
+            tick: 0,
+            liquidity: 3 * E18 / 2,
+            ticks: vec![
+                TickLiquidity { tick: -600, liquidity_net: E18 as i128 },
+                TickLiquidity { tick: -60, liquidity_net: (E18 / 2) as i128 },
+                TickLiquidity { tick: 60, liquidity_net: -((E18 / 2) as i128) },
+                TickLiquidity { tick: 600, liquidity_net: -(E18 as i128) },
+            ],
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn sqrt_ratio_matches_the_pool_bounds() {
+        assert_eq!(sqrt_ratio_at_tick(MIN_TICK).unwrap(), U256::from(MIN_SQRT_RATIO));
+        assert_eq!(sqrt_ratio_at_tick(MAX_TICK).unwrap(), max_sqrt_ratio());
+        assert_eq!(sqrt_ratio_at_tick(0).unwrap(), q96());
+        assert!(sqrt_ratio_at_tick(MAX_TICK + 1).is_err());
+    }
+
+    #[test]
+    fn tick_lookup_inverts_sqrt_ratio() {
+        for tick in [MIN_TICK, -600, -61, -1, 0, 1, 60, 887271] {
+            let sqrt_price = sqrt_ratio_at_tick(tick).unwrap();
+            assert_eq!(tick_at_sqrt_ratio(sqrt_price).unwrap(), tick);
+            if tick > MIN_TICK {
+                assert_eq!(tick_at_sqrt_ratio(sqrt_price - U256::one()).unwrap(), tick - 1);
+            }
+        }
+        assert!(tick_at_sqrt_ratio(max_sqrt_ratio()).is_err());
+    }
+
+    #[test]
+    fn swap_step_capped_at_the_target() {
+        // Price 1 to 1.01, 2e18 liquidity, 1e18 in, 0.06% fee
+        let step = compute_swap_step(q96(), dec("79623317895830914510639640423"), 2 * E18, U256::from(E18), 600).unwrap();
+        assert_eq!(step.sqrt_price_next, dec("79623317895830914510639640423"));
+        assert_eq!(step.amount_in, U256::from(9975124224178055u128));
+        assert_eq!(step.amount_out, U256::from(9925619580021728u128));
+        assert_eq!(step.fee_amount, U256::from(5988667735148u128));
+    }
+
+    #[test]
+    fn swap_step_spends_the_whole_input() {
+        // Price 1 towards 10, 2e18 liquidity, 1e18 in, 0.06% fee
+        let step = compute_swap_step(q96(), dec("250541448375047931186413801569"), 2 * E18, U256::from(E18), 600).unwrap();
+        assert_eq!(step.sqrt_price_next, dec("118818475322642227089037862318"));
+        assert_eq!(step.amount_in, U256::from(999400000000000000u128));
+        assert_eq!(step.amount_out, U256::from(666399946655997866u128));
+        assert_eq!(step.fee_amount, U256::from(600000000000000u128));
+    }
+
+    #[test]
+    fn quote_within_one_range() {
+        let quote = quote_exact_input(&snapshot(), true, 1_000_000_000_000_000).unwrap();
+        assert_eq!(quote.output_amount, 996337767497203);
+        assert_eq!(quote.fee_amount, 3000000000000);
+        assert_eq!(quote.input_amount, 1_000_000_000_000_000);
+        assert_eq!(quote.sqrt_price_x96_after, Nat::from(79175537173889425755225310580u128));
+        assert_eq!(quote.tick_after, -14);
+        assert_eq!(quote.ticks_crossed, 0);
+        assert!(quote.fully_filled);
+    }
+
+    #[test]
+    fn quote_crossing_a_tick_downwards() {
+        let quote = quote_exact_input(&snapshot(), true, 10 * 1_000_000_000_000_000).unwrap();
+        assert_eq!(quote.output_amount, 9894398499457380);
+        assert_eq!(quote.fee_amount, 30000000000001);
+        assert_eq!(quote.sqrt_price_x96_after, Nat::from(78562905736585838206090410634u128));
+        assert_eq!(quote.tick_after, -169);
+        assert_eq!(quote.ticks_crossed, 1);
+        assert!(quote.fully_filled);
+    }
+
+    #[test]
+    fn quote_crossing_a_tick_upwards() {
+        let snapshot = snapshot();
+        let quote = quote_trade(&snapshot, &snapshot.token1, 10 * 1_000_000_000_000_000).unwrap();
+        assert_eq!(quote.output_amount, 9894398499457380);
+        assert_eq!(quote.sqrt_price_x96_after, Nat::from(79899052568564899118062553770u128));
+        assert_eq!(quote.tick_after, 168);
+        assert_eq!(quote.ticks_crossed, 1);
+    }
+
+    #[test]
+    fn quote_runs_out_of_liquidity() {
+        let quote = quote_exact_input(&snapshot(), true, 100 * E18).unwrap();
+        assert_eq!(quote.input_amount, 32051319365379861);
+        assert_eq!(quote.output_amount, 31050688357092559);
+        assert_eq!(quote.ticks_crossed, 2);
+        assert_eq!(quote.tick_after, MIN_TICK);
+        assert!(!quote.fully_filled);
+    }
+
+    #[test]
+    fn quote_rejects_foreign_tokens() {
+        assert!(quote_trade(&snapshot(), &Principal::from_slice(&[9]), 1).is_err());
+    }
+}
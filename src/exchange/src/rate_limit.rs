@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::Principal;
+use strategy_common::debug_log;
+use strategy_common::runtime::Runtime;
+
+use crate::error::*;
+use crate::types::*;
+
+/// Target of a rate-limited call
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Pool(Principal),   // Swap pool canister
+    Ledger(Principal), // Token ledger canister
+}
+
+/// Token bucket for one call target
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,      // May go negative while calls are queued for future tokens
+    last_refill: u64, // Nanoseconds since the epoch
+}
+
+thread_local! {
+    // One bucket per pool or ledger, so the budget covers every caller in the canister
+    static BUCKETS: RefCell<HashMap<RateLimitKey, Bucket>> = RefCell::new(HashMap::new());
+}
+
+/// Burst capacity and refill rate that apply to a key
+fn limits(config: &RateLimitConfig, key: &RateLimitKey) -> (f64, f64) {
+    match key {
+        RateLimitKey::Pool(_) => (config.pool_capacity as f64, config.pool_refill_per_sec),
+        RateLimitKey::Ledger(_) => (config.ledger_capacity as f64, config.ledger_refill_per_sec),
+    }
+}
+
+/// Take a token for `key`, returning how many nanoseconds the caller must wait before using it
+///
+/// In queue mode the token is reserved up front, so the bucket goes into debt and
+/// later callers wait behind earlier ones instead of racing them for the next token.
+fn try_reserve(config: &RateLimitConfig, key: RateLimitKey, now: u64) -> ExchangeResult<u64> {
+    let (capacity, refill_per_sec) = limits(config, &key);
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: capacity, last_refill: now });
+
+        let elapsed_secs = now.saturating_sub(bucket.last_refill) as f64 / 1_000_000_000.0;
+        bucket.tokens = (bucket.tokens + elapsed_secs * refill_per_sec.max(0.0)).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(0);
+        }
+
+        let max_wait_secs = match config.mode {
+            RateLimitMode::FailFast => return Err(ExchangeError::RateLimit),
+            RateLimitMode::Queue { max_wait_secs } => max_wait_secs,
+        };
+        if refill_per_sec <= 0.0 {
+            return Err(ExchangeError::RateLimit);
+        }
+        let wait_secs = (1.0 - bucket.tokens) / refill_per_sec;
+        if wait_secs > max_wait_secs as f64 {
+            return Err(ExchangeError::RateLimit);
+        }
+        bucket.tokens -= 1.0;
+        Ok((wait_secs * 1_000_000_000.0).ceil() as u64)
+    })
+}
+
+/// Wait for (or fail fast without) a call token for `key`
+pub async fn acquire(runtime: &dyn Runtime, config: &RateLimitConfig, key: RateLimitKey) -> ExchangeResult<()> {
+    let wait_nanos = match try_reserve(config, key, runtime.time()) {
+        Ok(wait_nanos) => wait_nanos,
+        Err(e) => {
+            debug_log!("Rate limit reached for {:?}", key);
+            return Err(e);
+        }
+    };
+    if wait_nanos > 0 {
+        debug_log!("Rate limited call to {:?} queued for {} ns", key, wait_nanos);
+        runtime.sleep(wait_nanos).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use strategy_common::runtime::MockRuntime;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn config(mode: RateLimitMode) -> RateLimitConfig {
+        RateLimitConfig {
+            pool_capacity: 2,
+            pool_refill_per_sec: 1.0,
+            ledger_capacity: 1,
+            ledger_refill_per_sec: 0.5,
+            mode,
+        }
+    }
+
+    fn pool() -> RateLimitKey {
+        RateLimitKey::Pool(Principal::from_slice(&[3]))
+    }
+
+    #[test]
+    fn fail_fast_refuses_once_burst_is_spent() {
+        let config = config(RateLimitMode::FailFast);
+        assert_eq!(try_reserve(&config, pool(), 0).unwrap(), 0);
+        assert_eq!(try_reserve(&config, pool(), 0).unwrap(), 0);
+        assert!(matches!(try_reserve(&config, pool(), 0), Err(ExchangeError::RateLimit)));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_capacity() {
+        let config = config(RateLimitMode::FailFast);
+        try_reserve(&config, pool(), 0).unwrap();
+        try_reserve(&config, pool(), 0).unwrap();
+        assert_eq!(try_reserve(&config, pool(), SECOND).unwrap(), 0);
+        assert!(try_reserve(&config, pool(), SECOND).is_err());
+
+        // A long pause refills to capacity, not beyond it
+        let later = 100 * SECOND;
+        assert_eq!(try_reserve(&config, pool(), later).unwrap(), 0);
+        assert_eq!(try_reserve(&config, pool(), later).unwrap(), 0);
+        assert!(try_reserve(&config, pool(), later).is_err());
+    }
+
+    #[test]
+    fn queued_callers_wait_behind_each_other() {
+        let config = config(RateLimitMode::Queue { max_wait_secs: 3 });
+        try_reserve(&config, pool(), 0).unwrap();
+        try_reserve(&config, pool(), 0).unwrap();
+        assert_eq!(try_reserve(&config, pool(), 0).unwrap(), SECOND);
+        assert_eq!(try_reserve(&config, pool(), 0).unwrap(), 2 * SECOND);
+        assert_eq!(try_reserve(&config, pool(), 0).unwrap(), 3 * SECOND);
+        assert!(try_reserve(&config, pool(), 0).is_err());
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let config = config(RateLimitMode::FailFast);
+        let ledger = RateLimitKey::Ledger(Principal::from_slice(&[3]));
+        try_reserve(&config, ledger, 0).unwrap();
+        assert!(try_reserve(&config, ledger, 0).is_err());
+        assert!(try_reserve(&config, pool(), 0).is_ok());
+    }
+
+    #[test]
+    fn acquire_sleeps_for_the_queued_wait() {
+        let runtime = MockRuntime::new(Principal::anonymous(), Principal::anonymous());
+        let config = config(RateLimitMode::Queue { max_wait_secs: 5 });
+        for _ in 0..3 {
+            block_on(acquire(&runtime, &config, pool())).unwrap();
+        }
+        assert_eq!(runtime.time(), SECOND);
+    }
+}
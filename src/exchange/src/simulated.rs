@@ -0,0 +1,1219 @@
+use async_trait::async_trait;
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::error::*;
+use crate::types::*;
+use crate::traits::*;
+use crate::depth;
+use crate::holdings;
+use crate::pricing;
+use crate::utils;
+use strategy_common::runtime::{IcRuntime, Runtime};
+
+/// Pricing curve of a simulated pool
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum SimulatedCurve {
+    /// Full-range x * y = k pool; swap fees stay in the reserves
+    ConstantProduct { reserve0: u128, reserve1: u128 },
+    /// Liquidity concentrated in a single price range; prices are sqrt(token1 per token0)
+    Concentrated {
+        liquidity: f64,
+        sqrt_price: f64,
+        sqrt_price_lower: f64,
+        sqrt_price_upper: f64,
+    },
+}
+
+/// In-memory pool backing the simulated exchange
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SimulatedPool {
+    pub pool_id: Principal,
+    pub token0: TokenInfo,
+    pub token1: TokenInfo,
+    pub fee: u64,              // Swap fee in ppm, e.g. 3000 for 0.3%
+    pub curve: SimulatedCurve,
+    pub total_shares: u128,    // Outstanding LP shares
+    pub collected_fee0: u128,  // Swap fees paid in token0
+    pub collected_fee1: u128,  // Swap fees paid in token1
+}
+
+/// Deterministic sequence of prices (token1 per token0, raw units) replayed on a pool
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PricePath {
+    prices: Vec<f64>,
+    cursor: usize,
+}
+
+impl PricePath {
+    /// Replays the given prices in order
+    pub fn from_prices(prices: Vec<f64>) -> Self {
+        Self { prices, cursor: 0 }
+    }
+
+    /// Seeded random walk; each step moves the price by at most `volatility_bps`
+    pub fn random_walk(start_price: f64, steps: usize, volatility_bps: u32, seed: u64) -> Self {
+        let mut state = seed.max(1);
+        let mut price = start_price;
+        let mut prices = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            // xorshift64 keeps the path reproducible across runs and platforms
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let unit = (state % 20_001) as f64 / 10_000.0 - 1.0; // [-1, 1]
+            price *= 1.0 + unit * volatility_bps as f64 / 10_000.0;
+            prices.push(price);
+        }
+        Self { prices, cursor: 0 }
+    }
+
+    /// Next price on the path, if any remain
+    fn next_price(&mut self) -> Option<f64> {
+        let price = self.prices.get(self.cursor).copied();
+        if price.is_some() {
+            self.cursor += 1;
+        }
+        price
+    }
+
+    /// Whether every price on the path has been applied
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.prices.len()
+    }
+}
+
+/// Outcome of a swap against a pool curve
+struct SwapOutcome {
+    amount_out: u128,
+    pool_fee: u128,
+    curve: SimulatedCurve,
+    spot_price_before: f64,
+}
+
+#[derive(Default)]
+struct SimulatedState {
+    pools: Vec<SimulatedPool>,
+    price_paths: HashMap<Principal, PricePath>,
+    wallets: HashMap<(Principal, Account), u128>,                  // (token, account) -> balance
+    unused: HashMap<(Principal, Principal), (u128, u128)>,         // (pool, owner) -> (token0, token1)
+    allowances: HashMap<(Principal, Principal, Principal), u128>,  // (token, owner, spender) -> amount
+    positions: HashMap<(Principal, Principal), u128>,              // (pool, owner) -> LP shares
+    transfer_fees: HashMap<Principal, u128>,                       // token -> ledger transfer fee
+    history: Vec<(Principal, TradeHistory)>,
+    next_tx_id: u64,
+}
+
+thread_local! {
+    // The canister's own simulator, shared by every exchange `for_canister` returns.
+    // Heap only: paper-trading pools and balances start over after an upgrade.
+    static CANISTER_STATE: Arc<Mutex<SimulatedState>> = Arc::new(Mutex::new(SimulatedState::default()));
+}
+
+/// Paper-trading exchange backed by in-memory AMM pools
+///
+/// Trades are executed on behalf of the running canister (`Runtime::id`), mirroring
+/// how strategy canisters interact with real DEX pools. Pools, balances and price
+/// paths are seeded through the inherent methods before strategies run.
+pub struct SimulatedExchange {
+    config: ExchangeConfig,
+    runtime: Arc<dyn Runtime>,
+    subaccount: Option<Subaccount>,  // Wallet subaccount trades are funded from
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+/// Constant-product pool to create when a simulator has no pool for the pair yet
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SimulatedPoolSeed {
+    pub token_a: TokenInfo,
+    pub token_b: TokenInfo,
+    pub fee: u64,        // Swap fee in ppm
+    pub amount_a: u128,  // Initial reserve of token_a
+    pub amount_b: u128,  // Initial reserve of token_b
+}
+
+impl SimulatedExchange {
+    /// Creates an empty simulated exchange
+    pub fn new(config: ExchangeConfig) -> Self {
+        Self::with_runtime(config, Arc::new(IcRuntime))
+    }
+
+    /// Creates an empty simulated exchange that reads time and identity from the given runtime
+    pub fn with_runtime(config: ExchangeConfig, runtime: Arc<dyn Runtime>) -> Self {
+        Self {
+            config,
+            runtime,
+            subaccount: None,
+            state: Arc::new(Mutex::new(SimulatedState::default())),
+        }
+    }
+
+    /// The running canister's simulator; every instance shares its pools, balances and history
+    pub fn for_canister(config: ExchangeConfig, runtime: Arc<dyn Runtime>) -> Self {
+        Self {
+            config,
+            runtime,
+            subaccount: None,
+            state: CANISTER_STATE.with(|state| state.clone()),
+        }
+    }
+
+    /// Adds the seeded pool unless the pair already has one; returns the pair's pool ID
+    pub fn seed_pool(&self, seed: &SimulatedPoolSeed) -> Principal {
+        let existing = self.with_state(|s| {
+            Self::find_pool_index(s, &seed.token_a, &seed.token_b).ok().map(|index| s.pools[index].pool_id)
+        });
+        match existing {
+            Some(pool_id) => pool_id,
+            None => self.add_constant_product_pool(seed.token_a.clone(), seed.token_b.clone(), seed.fee, seed.amount_a, seed.amount_b),
+        }
+    }
+
+    /// Trades from (and returns proceeds to) the given wallet subaccount
+    pub fn with_subaccount(mut self, subaccount: Option<Subaccount>) -> Self {
+        self.subaccount = subaccount;
+        self
+    }
+
+    /// Wallet account this exchange trades from
+    pub fn account(&self) -> Account {
+        Account::with_subaccount(self.runtime.id(), self.subaccount)
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut SimulatedState) -> T) -> T {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut state)
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.runtime.time() / 1_000_000_000
+    }
+
+    /// Orders two tokens the same way ICPSwap does (by canister ID text)
+    fn sort_tokens(a: TokenInfo, b: TokenInfo) -> (TokenInfo, TokenInfo) {
+        if a.canister_id.to_string() < b.canister_id.to_string() {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    fn next_pool_id(state: &SimulatedState) -> Principal {
+        let mut bytes = b"sim-pool".to_vec();
+        bytes.extend_from_slice(&(state.pools.len() as u64).to_be_bytes());
+        Principal::from_slice(&bytes)
+    }
+
+    /// Adds a constant-product pool seeded with the given reserves and returns its ID
+    pub fn add_constant_product_pool(&self, token_a: TokenInfo, token_b: TokenInfo, fee: u64, amount_a: u128, amount_b: u128) -> Principal {
+        let a_is_token0 = token_a.canister_id.to_string() < token_b.canister_id.to_string();
+        let (reserve0, reserve1) = if a_is_token0 { (amount_a, amount_b) } else { (amount_b, amount_a) };
+        let (token0, token1) = Self::sort_tokens(token_a, token_b);
+        self.with_state(|s| {
+            let pool_id = Self::next_pool_id(s);
+            s.pools.push(SimulatedPool {
+                pool_id,
+                token0,
+                token1,
+                fee,
+                curve: SimulatedCurve::ConstantProduct { reserve0, reserve1 },
+                total_shares: ((reserve0 as f64) * (reserve1 as f64)).sqrt() as u128,
+                collected_fee0: 0,
+                collected_fee1: 0,
+            });
+            pool_id
+        })
+    }
+
+    /// Adds a concentrated-liquidity pool and returns its ID
+    ///
+    /// Prices are `token_b` per `token_a` in raw units; `liquidity` is the V3 `L` value.
+    pub fn add_concentrated_pool(
+        &self,
+        token_a: TokenInfo,
+        token_b: TokenInfo,
+        fee: u64,
+        price: f64,
+        price_lower: f64,
+        price_upper: f64,
+        liquidity: f64,
+    ) -> ExchangeResult<Principal> {
+        if !(price_lower > 0.0 && price_lower < price && price < price_upper) {
+            return Err(ExchangeError::InvalidParameters("Price must lie strictly inside a positive range".to_string()));
+        }
+        // Pools store prices as token1 per token0, so invert when token_b sorts first
+        let (price, price_lower, price_upper) = if token_a.canister_id.to_string() < token_b.canister_id.to_string() {
+            (price, price_lower, price_upper)
+        } else {
+            (1.0 / price, 1.0 / price_upper, 1.0 / price_lower)
+        };
+        let (token0, token1) = Self::sort_tokens(token_a, token_b);
+        Ok(self.with_state(|s| {
+            let pool_id = Self::next_pool_id(s);
+            s.pools.push(SimulatedPool {
+                pool_id,
+                token0,
+                token1,
+                fee,
+                curve: SimulatedCurve::Concentrated {
+                    liquidity,
+                    sqrt_price: price.sqrt(),
+                    sqrt_price_lower: price_lower.sqrt(),
+                    sqrt_price_upper: price_upper.sqrt(),
+                },
+                total_shares: liquidity as u128,
+                collected_fee0: 0,
+                collected_fee1: 0,
+            });
+            pool_id
+        }))
+    }
+
+    /// Credits tokens to an account's wallet
+    pub fn mint(&self, token: &TokenInfo, account: &Account, amount: u128) {
+        self.with_state(|s| Self::credit_wallet(s, &token.canister_id, account, amount));
+    }
+
+    /// Sets the ledger fee charged whenever a token moves between wallet and pool
+    pub fn set_transfer_fee(&self, token: &TokenInfo, fee: u128) {
+        self.with_state(|s| {
+            s.transfer_fees.insert(token.canister_id, fee);
+        });
+    }
+
+    /// Allowance granted by `owner` to `spender` for a token
+    pub fn allowance(&self, token: &TokenInfo, owner: &Principal, spender: &Principal) -> u128 {
+        self.with_state(|s| s.allowances.get(&(token.canister_id, *owner, *spender)).copied().unwrap_or(0))
+    }
+
+    /// Assigns a price path to a pool, replacing any previous one
+    pub fn set_price_path(&self, pool_id: &Principal, path: PricePath) {
+        self.with_state(|s| {
+            s.price_paths.insert(*pool_id, path);
+        });
+    }
+
+    /// Moves every pool with a price path to its next price; returns the number of pools moved
+    pub fn advance_price_paths(&self) -> usize {
+        self.with_state(|s| {
+            let mut moved = 0;
+            for pool in s.pools.iter_mut() {
+                if let Some(price) = s.price_paths.get_mut(&pool.pool_id).and_then(|path| path.next_price()) {
+                    pool.curve = reprice(&pool.curve, price);
+                    moved += 1;
+                }
+            }
+            moved
+        })
+    }
+
+    /// Snapshot of all pools
+    pub fn pools(&self) -> Vec<SimulatedPool> {
+        self.with_state(|s| s.pools.clone())
+    }
+
+    /// Current spot price of a pool (token1 per token0, raw units)
+    pub fn spot_price(&self, pool_id: &Principal) -> ExchangeResult<f64> {
+        self.with_state(|s| {
+            s.pools.iter()
+                .find(|p| p.pool_id == *pool_id)
+                .map(|p| spot_price(&p.curve))
+                .ok_or(ExchangeError::PoolNotFound)
+        })
+    }
+
+    fn find_pool_index(state: &SimulatedState, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<usize> {
+        state.pools.iter()
+            .position(|p| {
+                (p.token0.canister_id == base.canister_id && p.token1.canister_id == quote.canister_id)
+                    || (p.token0.canister_id == quote.canister_id && p.token1.canister_id == base.canister_id)
+            })
+            .ok_or(ExchangeError::PoolNotFound)
+    }
+
+    fn transfer_fee(state: &SimulatedState, token: &Principal) -> u128 {
+        state.transfer_fees.get(token).copied().unwrap_or(0)
+    }
+
+    fn wallet_balance(state: &SimulatedState, token: &Principal, account: &Account) -> u128 {
+        state.wallets.get(&(*token, Self::normalize(account))).copied().unwrap_or(0)
+    }
+
+    /// Maps an explicit all-zero subaccount onto the default one so both share a balance
+    fn normalize(account: &Account) -> Account {
+        Account::with_subaccount(account.owner, if account.is_default() { None } else { account.subaccount })
+    }
+
+    fn debit_wallet(state: &mut SimulatedState, token: &Principal, account: &Account, amount: u128) -> ExchangeResult<()> {
+        let balance = state.wallets.entry((*token, Self::normalize(account))).or_insert(0);
+        if *balance < amount {
+            return Err(ExchangeError::InsufficientFunds);
+        }
+        *balance -= amount;
+        Ok(())
+    }
+
+    fn credit_wallet(state: &mut SimulatedState, token: &Principal, account: &Account, amount: u128) {
+        *state.wallets.entry((*token, Self::normalize(account))).or_insert(0) += amount;
+    }
+
+    /// Input and output tokens for a trade; Buy spends quote, Sell spends base
+    fn trade_tokens(params: &TradeParams) -> (&TokenInfo, &TokenInfo) {
+        pricing::trade_tokens(&params.pair, &params.direction)
+    }
+
+    fn build_quote(params: &TradeParams, outcome: &SwapOutcome, zero_for_one: bool) -> QuoteResult {
+        let amount_in = params.amount;
+        let execution = if amount_in > 0 { outcome.amount_out as f64 / amount_in as f64 } else { 0.0 };
+        // Spot price expressed as output per input, for comparison with the execution price
+        let spot = if zero_for_one {
+            outcome.spot_price_before
+        } else if outcome.spot_price_before > 0.0 {
+            1.0 / outcome.spot_price_before
+        } else {
+            0.0
+        };
+        let price_impact = if spot > 0.0 { ((spot - execution) / spot * 100.0).max(0.0) } else { 0.0 };
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, amount_in, outcome.amount_out);
+        QuoteResult {
+            input_amount: amount_in,
+            output_amount: outcome.amount_out,
+            price,
+            inverse_price,
+            fee_amount: outcome.pool_fee,
+            price_impact,
+        }
+    }
+
+    /// Ledger fees charged on the way into and out of the pool; trades from the unused balance pay none
+    fn ledger_fees(state: &SimulatedState, params: &TradeParams, from_unused: bool) -> (u128, u128) {
+        if from_unused {
+            return (0, 0);
+        }
+        let (input_token, output_token) = Self::trade_tokens(params);
+        (
+            Self::transfer_fee(state, &input_token.canister_id),
+            Self::transfer_fee(state, &output_token.canister_id),
+        )
+    }
+
+    /// Swaps inside the state, debiting and crediting through the given balance source
+    fn swap_in_state(
+        &self,
+        state: &mut SimulatedState,
+        params: &TradeParams,
+        from_unused: bool,
+    ) -> ExchangeResult<TradeResult> {
+        utils::validate_trade_params_at(params, self.now_secs())?;
+        let owner = self.runtime.id();
+        let index = Self::find_pool_index(state, &params.pair.base_token, &params.pair.quote_token)?;
+        let (input_token, output_token) = Self::trade_tokens(params);
+        let pool_id = state.pools[index].pool_id;
+        let zero_for_one = state.pools[index].token0.canister_id == input_token.canister_id;
+
+        // Wallet trades pay the ledger fee when moving in and out of the pool
+        let (in_fee, out_fee) = Self::ledger_fees(state, params, from_unused);
+        let swap_input = params.amount.checked_sub(in_fee).ok_or(ExchangeError::InvalidAmount)?;
+
+        // Quotes and executions share the same pool state, so slippage cannot occur here;
+        // price moves come only from price paths applied between calls
+        let pool = &state.pools[index];
+        let quoted = swap(&pool.curve, pool.fee, zero_for_one, swap_input)?;
+        let delivered = quoted.amount_out.checked_sub(out_fee).ok_or(ExchangeError::InvalidAmount)?;
+
+        if from_unused {
+            let balances = state.unused.entry((pool_id, owner)).or_insert((0, 0));
+            let available = if zero_for_one { &mut balances.0 } else { &mut balances.1 };
+            if *available < params.amount {
+                return Err(ExchangeError::InsufficientFunds);
+            }
+            *available -= params.amount;
+            if zero_for_one { balances.1 += delivered } else { balances.0 += delivered }
+        } else {
+            let wallet = self.account();
+            Self::debit_wallet(state, &input_token.canister_id, &wallet, params.amount)?;
+            Self::credit_wallet(state, &output_token.canister_id, &wallet, delivered);
+        }
+
+        let pool = &mut state.pools[index];
+        pool.curve = quoted.curve;
+        if zero_for_one { pool.collected_fee0 += quoted.pool_fee } else { pool.collected_fee1 += quoted.pool_fee }
+
+        state.next_tx_id += 1;
+        let transaction_id = format!("sim_{}_{}", pool_id, state.next_tx_id);
+        let timestamp = self.now_secs();
+        let (price, inverse_price) = pricing::price_pair(&params.pair, &params.direction, params.amount, delivered);
+        state.history.push((owner, TradeHistory {
+            trade_id: transaction_id.clone(),
+            pair: params.pair.clone(),
+            direction: params.direction.clone(),
+            input_amount: params.amount,
+            output_amount: delivered,
+            price,
+            timestamp,
+            status: TradeStatus::Completed,
+            transaction_id: Some(transaction_id.clone()),
+        }));
+
+        Ok(TradeResult {
+            input_amount: params.amount,
+            output_amount: delivered,
+            fee_amount: quoted.pool_fee,
+            price,
+            inverse_price,
+            timestamp,
+            transaction_id: Some(transaction_id),
+            fees: FeeBreakdown {
+                pool_fee: quoted.pool_fee,
+                input_transfer_fee: in_fee,
+                approve_fee: 0,
+                withdraw_fee: out_fee,
+            },
+            ledger_transactions: Vec::new(),  // Simulated wallets have no ledger
+        })
+    }
+
+    /// Plans a wallet trade against the current state without changing it
+    fn plan_in_state(&self, state: &SimulatedState, params: &TradeParams) -> TradePlan {
+        let (input_token, output_token) = Self::trade_tokens(params);
+        let mut plan = TradePlan {
+            pool_id: None,
+            input_token: input_token.clone(),
+            output_token: output_token.clone(),
+            steps: Vec::new(),
+            input_ledger_fees: 0,
+            output_ledger_fees: 0,
+            expected_output: 0,
+            min_output: 0,
+            price_impact: 0.0,
+            estimated_cycles: 0,  // No canister calls are made
+            blockers: Vec::new(),
+        };
+
+        if let Err(e) = utils::validate_trade_params_at(params, self.now_secs()) {
+            plan.blockers.push(format!("Invalid trade parameters: {}", e));
+        }
+        let index = match Self::find_pool_index(state, &params.pair.base_token, &params.pair.quote_token) {
+            Ok(index) => index,
+            Err(e) => {
+                plan.blockers.push(format!("Pool lookup failed: {}", e));
+                return plan;
+            }
+        };
+        let pool = &state.pools[index];
+        plan.pool_id = Some(pool.pool_id);
+
+        let in_fee = Self::transfer_fee(state, &input_token.canister_id);
+        let out_fee = Self::transfer_fee(state, &output_token.canister_id);
+        let available = Self::wallet_balance(state, &input_token.canister_id, &self.account());
+        if available < params.amount {
+            plan.blockers.push(format!(
+                "Insufficient {} balance: {} available, {} required",
+                input_token.symbol, available, params.amount
+            ));
+        }
+
+        let zero_for_one = pool.token0.canister_id == input_token.canister_id;
+        match params.amount.checked_sub(in_fee).map(|swap_input| swap(&pool.curve, pool.fee, zero_for_one, swap_input)) {
+            Some(Ok(outcome)) => {
+                let quote = Self::build_quote(params, &outcome, zero_for_one);
+                plan.expected_output = outcome.amount_out.saturating_sub(out_fee);
+                plan.min_output = (plan.expected_output as f64 * (1.0 - params.slippage_tolerance / 100.0)) as u128;
+                plan.price_impact = quote.price_impact;
+                if outcome.amount_out < out_fee {
+                    plan.blockers.push("Output does not cover the withdrawal fee".to_string());
+                }
+            }
+            Some(Err(e)) => plan.blockers.push(format!("Quote failed: {}", e)),
+            None => plan.blockers.push("Amount does not cover the deposit fee".to_string()),
+        }
+
+        plan.steps.push(TradeStep::Deposit { pool_id: pool.pool_id, token: input_token.canister_id, amount: params.amount, fee: in_fee });
+        plan.steps.push(TradeStep::Swap { pool_id: pool.pool_id, amount_in: params.amount.saturating_sub(in_fee), min_amount_out: plan.min_output });
+        plan.steps.push(TradeStep::Withdraw { pool_id: pool.pool_id, token: output_token.canister_id, amount: plan.expected_output, fee: out_fee });
+        plan.input_ledger_fees = in_fee;
+        plan.output_ledger_fees = out_fee;
+        plan
+    }
+}
+
+/// Spot price (token1 per token0, raw units) of a curve
+fn spot_price(curve: &SimulatedCurve) -> f64 {
+    match curve {
+        SimulatedCurve::ConstantProduct { reserve0, reserve1 } => {
+            if *reserve0 == 0 { 0.0 } else { *reserve1 as f64 / *reserve0 as f64 }
+        }
+        SimulatedCurve::Concentrated { sqrt_price, .. } => sqrt_price * sqrt_price,
+    }
+}
+
+/// Real token reserves held by a curve
+fn reserves(curve: &SimulatedCurve) -> (u128, u128) {
+    match curve {
+        SimulatedCurve::ConstantProduct { reserve0, reserve1 } => (*reserve0, *reserve1),
+        SimulatedCurve::Concentrated { liquidity, sqrt_price, sqrt_price_lower, sqrt_price_upper } => (
+            (liquidity * (1.0 / sqrt_price - 1.0 / sqrt_price_upper)).max(0.0) as u128,
+            (liquidity * (sqrt_price - sqrt_price_lower)).max(0.0) as u128,
+        ),
+    }
+}
+
+/// Moves a curve to a target price without changing its liquidity
+fn reprice(curve: &SimulatedCurve, price: f64) -> SimulatedCurve {
+    match curve {
+        SimulatedCurve::ConstantProduct { reserve0, reserve1 } => {
+            let k = *reserve0 as f64 * *reserve1 as f64;
+            SimulatedCurve::ConstantProduct {
+                reserve0: (k / price).sqrt() as u128,
+                reserve1: (k * price).sqrt() as u128,
+            }
+        }
+        SimulatedCurve::Concentrated { liquidity, sqrt_price_lower, sqrt_price_upper, .. } => SimulatedCurve::Concentrated {
+            liquidity: *liquidity,
+            sqrt_price: price.sqrt().clamp(*sqrt_price_lower, *sqrt_price_upper),
+            sqrt_price_lower: *sqrt_price_lower,
+            sqrt_price_upper: *sqrt_price_upper,
+        },
+    }
+}
+
+/// a * b / c, falling back to floating point when the product overflows
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    match a.checked_mul(b) {
+        Some(product) => product / c,
+        None => (a as f64 * b as f64 / c as f64) as u128,
+    }
+}
+
+/// Exact-input swap against a curve; `fee` is in ppm and taken from the input
+fn swap(curve: &SimulatedCurve, fee: u64, zero_for_one: bool, amount_in: u128) -> ExchangeResult<SwapOutcome> {
+    if amount_in == 0 {
+        return Err(ExchangeError::InvalidAmount);
+    }
+    let pool_fee = mul_div(amount_in, fee as u128, 1_000_000);
+    let amount_after_fee = amount_in - pool_fee;
+    let spot_price_before = spot_price(curve);
+
+    let (amount_out, new_curve) = match curve {
+        SimulatedCurve::ConstantProduct { reserve0, reserve1 } => {
+            let (reserve_in, reserve_out) = if zero_for_one { (*reserve0, *reserve1) } else { (*reserve1, *reserve0) };
+            if reserve_in == 0 || reserve_out == 0 {
+                return Err(ExchangeError::InsufficientLiquidity);
+            }
+            let amount_out = mul_div(reserve_out, amount_after_fee, reserve_in.saturating_add(amount_after_fee));
+            if amount_out == 0 || amount_out >= reserve_out {
+                return Err(ExchangeError::InsufficientLiquidity);
+            }
+            // The fee stays in the pool, growing k for liquidity providers
+            let new_in = reserve_in.saturating_add(amount_in);
+            let new_out = reserve_out - amount_out;
+            let (reserve0, reserve1) = if zero_for_one { (new_in, new_out) } else { (new_out, new_in) };
+            (amount_out, SimulatedCurve::ConstantProduct { reserve0, reserve1 })
+        }
+        SimulatedCurve::Concentrated { liquidity, sqrt_price, sqrt_price_lower, sqrt_price_upper } => {
+            if *liquidity <= 0.0 {
+                return Err(ExchangeError::InsufficientLiquidity);
+            }
+            let amount = amount_after_fee as f64;
+            let (next_sqrt_price, out) = if zero_for_one {
+                let next = liquidity * sqrt_price / (liquidity + amount * sqrt_price);
+                if next < *sqrt_price_lower {
+                    return Err(ExchangeError::InsufficientLiquidity);
+                }
+                (next, liquidity * (sqrt_price - next))
+            } else {
+                let next = sqrt_price + amount / liquidity;
+                if next > *sqrt_price_upper {
+                    return Err(ExchangeError::InsufficientLiquidity);
+                }
+                (next, liquidity * (1.0 / sqrt_price - 1.0 / next))
+            };
+            (out.max(0.0) as u128, SimulatedCurve::Concentrated {
+                liquidity: *liquidity,
+                sqrt_price: next_sqrt_price,
+                sqrt_price_lower: *sqrt_price_lower,
+                sqrt_price_upper: *sqrt_price_upper,
+            })
+        }
+    };
+
+    if amount_out == 0 {
+        return Err(ExchangeError::InsufficientLiquidity);
+    }
+    Ok(SwapOutcome { amount_out, pool_fee, curve: new_curve, spot_price_before })
+}
+
+#[async_trait]
+impl Exchange for SimulatedExchange {
+    /// Gets the exchange type
+    fn get_exchange_type(&self) -> ExchangeType {
+        ExchangeType::Simulated
+    }
+
+    /// Gets the exchange status; the simulator is always available
+    async fn get_status(&self) -> ExchangeResult<ExchangeStatus> {
+        let (supported_tokens, supported_pairs, pools) = self.with_state(|s| {
+            let mut tokens: Vec<TokenInfo> = Vec::new();
+            let mut pairs = Vec::new();
+            let mut pools = Vec::new();
+            for pool in &s.pools {
+                for token in [&pool.token0, &pool.token1] {
+                    if !tokens.iter().any(|t| t.canister_id == token.canister_id) {
+                        tokens.push(token.clone());
+                    }
+                }
+                pairs.push(TradingPair {
+                    base_token: pool.token0.clone(),
+                    quote_token: pool.token1.clone(),
+                    exchange: ExchangeType::Simulated,
+                });
+                // Simulated pools have no USD pricing
+                pools.push(PoolStats {
+                    pool_id: pool.pool_id,
+                    token0: pool.token0.canister_id,
+                    token1: pool.token1.canister_id,
+                    fee: pool.fee,
+                    tvl_usd: None,
+                    volume_24h_usd: None,
+                });
+            }
+            (tokens, pairs, pools)
+        });
+        Ok(ExchangeStatus {
+            exchange_type: self.config.exchange_type.clone(),
+            is_available: true,
+            supported_tokens,
+            supported_pairs,
+            pools,
+            latency_ms: Some(0),
+            consecutive_failures: 0,
+            last_error: None,
+            circuit_breaker: BreakerStatus {
+                target: BreakerTarget::Venue(self.config.canister_id),
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                retry_at: None,
+                last_error: None,
+            },
+            tripped_pools: Vec::new(),
+            last_updated: self.now_secs(),
+        })
+    }
+
+    /// Queries the simulated wallet balance
+    async fn get_token_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> {
+        Ok(self.with_state(|s| Self::wallet_balance(s, &token.canister_id, account)))
+    }
+
+    /// Checks if a pool exists for the pair
+    async fn is_pair_supported(&self, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<bool> {
+        Ok(self.with_state(|s| Self::find_pool_index(s, base, quote).is_ok()))
+    }
+}
+
+#[async_trait]
+impl Trading for SimulatedExchange {
+    /// Quotes an exact-input wallet swap without changing pool state
+    ///
+    /// Deducts the same ledger fees as `execute_trade`, so the quote is what a trade delivers.
+    async fn get_quote(&self, params: &TradeParams) -> ExchangeResult<QuoteResult> {
+        self.with_state(|s| {
+            let index = Self::find_pool_index(s, &params.pair.base_token, &params.pair.quote_token)?;
+            let pool = &s.pools[index];
+            let (input_token, _) = Self::trade_tokens(params);
+            let zero_for_one = pool.token0.canister_id == input_token.canister_id;
+            let (in_fee, out_fee) = Self::ledger_fees(s, params, false);
+            let swap_input = params.amount.checked_sub(in_fee).ok_or(ExchangeError::InvalidAmount)?;
+            let mut outcome = swap(&pool.curve, pool.fee, zero_for_one, swap_input)?;
+            outcome.amount_out = outcome.amount_out.checked_sub(out_fee).ok_or(ExchangeError::InvalidAmount)?;
+            Ok(Self::build_quote(params, &outcome, zero_for_one))
+        })
+    }
+
+    /// Plans a wallet trade without changing pool or wallet state
+    async fn simulate_trade(&self, params: &TradeParams) -> ExchangeResult<TradePlan> {
+        Ok(self.with_state(|s| self.plan_in_state(s, params)))
+    }
+
+    /// Quotes every size in both directions against the current pool state
+    async fn get_depth_curve(&self, pair: &TradingPair, sizes: &[u128]) -> ExchangeResult<DepthCurve> {
+        depth::quote_depth_curve(self, pair, sizes, self.now_secs()).await
+    }
+
+    /// Swaps from the canister wallet and returns the output to the wallet
+    async fn execute_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
+        self.with_state(|s| self.swap_in_state(s, params, false))
+    }
+
+    /// Swaps from the unused pool balance, leaving the output in the pool (like ICPSwap's swap call)
+    async fn execute_call_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
+        self.with_state(|s| self.swap_in_state(s, params, true))
+    }
+
+    /// Same swap within a known pool; simulated executions always match their quote
+    async fn execute_call_trade_no_slippage(&self, params: &TradeParams, pool: &PoolInfo) -> ExchangeResult<TradeResult> {
+        let pool_exists = self.with_state(|s| s.pools.iter().any(|p| p.pool_id == pool.pool_id));
+        if !pool_exists {
+            return Err(ExchangeError::PoolNotFound);
+        }
+        self.with_state(|s| self.swap_in_state(s, params, true))
+    }
+
+    /// Executes trades in order
+    async fn execute_batch_trade(&self, params: &BatchTradeParams) -> ExchangeResult<BatchTradeResult> {
+        let mut results = Vec::new();
+        let mut all_succeeded = true;
+
+        for trade_param in &params.trades {
+            let result = self.execute_trade(trade_param).await;
+            if result.is_err() {
+                all_succeeded = false;
+                if params.require_all_success {
+                    return Err(ExchangeError::TransactionFailed("Batch trade failed, one or more trades errored".to_string()));
+                }
+            }
+            results.push(result.map_err(|e| e.to_string()));
+        }
+
+        Ok(BatchTradeResult {
+            results,
+            all_succeeded,
+            timestamp: self.now_secs(),
+        })
+    }
+
+    /// Returns the user's simulated trades, newest first
+    async fn get_trade_history(&self, user: &Principal, limit: usize, offset: usize) -> ExchangeResult<Vec<TradeHistory>> {
+        Ok(self.with_state(|s| {
+            s.history.iter()
+                .rev()
+                .filter(|(owner, _)| owner == user)
+                .skip(offset)
+                .take(limit)
+                .map(|(_, trade)| trade.clone())
+                .collect()
+        }))
+    }
+}
+
+#[async_trait]
+impl TokenOperations for SimulatedExchange {
+    /// Moves tokens from the canister wallet into the pool's unused balance
+    async fn deposit_token(&self, params: &TradeParams, token: &TokenInfo, amount: u128) -> ExchangeResult<u128> {
+        let owner = self.runtime.id();
+        self.with_state(|s| {
+            let index = Self::find_pool_index(s, &params.pair.base_token, &params.pair.quote_token)?;
+            let (pool_id, is_token0) = {
+                let pool = &s.pools[index];
+                if pool.token0.canister_id != token.canister_id && pool.token1.canister_id != token.canister_id {
+                    return Err(ExchangeError::UnsupportedToken(token.symbol.clone()));
+                }
+                (pool.pool_id, pool.token0.canister_id == token.canister_id)
+            };
+            let credited = amount
+                .checked_sub(Self::transfer_fee(s, &token.canister_id))
+                .filter(|credited| *credited > 0)
+                .ok_or(ExchangeError::InvalidAmount)?;
+            Self::debit_wallet(s, &token.canister_id, &self.account(), amount)?;
+            let balances = s.unused.entry((pool_id, owner)).or_insert((0, 0));
+            if is_token0 { balances.0 += credited } else { balances.1 += credited }
+            Ok(credited)
+        })
+    }
+
+    /// Moves tokens from the pool's unused balance back to the canister wallet
+    async fn withdraw_token(&self, params: &TradeParams, token: &TokenInfo, amount: u128) -> ExchangeResult<u128> {
+        let owner = self.runtime.id();
+        self.with_state(|s| {
+            let index = Self::find_pool_index(s, &params.pair.base_token, &params.pair.quote_token)?;
+            let pool_id = s.pools[index].pool_id;
+            let is_token0 = s.pools[index].token0.canister_id == token.canister_id;
+            let received = amount
+                .checked_sub(Self::transfer_fee(s, &token.canister_id))
+                .ok_or(ExchangeError::InvalidAmount)?;
+            let balances = s.unused.entry((pool_id, owner)).or_insert((0, 0));
+            let available = if is_token0 { &mut balances.0 } else { &mut balances.1 };
+            if *available < amount {
+                return Err(ExchangeError::InsufficientFunds);
+            }
+            *available -= amount;
+            Self::credit_wallet(s, &token.canister_id, &self.account(), received);
+            Ok(received)
+        })
+    }
+
+    /// Returns (token0, token1, token0 canister ID) held unused in the pair's pool
+    async fn get_unused_balance(&self, params: &TradeParams, user: &Principal) -> ExchangeResult<(u128, u128, String)> {
+        self.with_state(|s| {
+            let index = Self::find_pool_index(s, &params.pair.base_token, &params.pair.quote_token)?;
+            let pool = &s.pools[index];
+            let (balance0, balance1) = s.unused.get(&(pool.pool_id, *user)).copied().unwrap_or((0, 0));
+            Ok((balance0, balance1, pool.token0.canister_id.to_string()))
+        })
+    }
+
+    /// Breaks down wallet balances, unused pool balances, allowances and LP shares
+    async fn get_exchange_balance(&self, tokens: &[TokenInfo], account: &Account, valuation_token: &TokenInfo) -> ExchangeResult<HoldingsBreakdown> {
+        let tokens = holdings::holdings_tokens(tokens, valuation_token);
+        let holdings = self.with_state(|s| {
+            let mut holdings = Vec::new();
+            let mut push = |token: &TokenInfo, location: HoldingLocation, amount: u128| {
+                if amount > 0 {
+                    holdings.push(Holding { token: token.clone(), location, amount, value: None });
+                }
+            };
+
+            for token in &tokens {
+                push(token, HoldingLocation::Wallet, Self::wallet_balance(s, &token.canister_id, account));
+            }
+
+            let tracked = |token: &TokenInfo| tokens.iter().any(|t| t.canister_id == token.canister_id);
+            for pool in s.pools.iter().filter(|pool| tracked(&pool.token0) || tracked(&pool.token1)) {
+                if let Some((balance0, balance1)) = s.unused.get(&(pool.pool_id, account.owner)).copied() {
+                    push(&pool.token0, HoldingLocation::PoolUnused { pool_id: pool.pool_id }, balance0);
+                    push(&pool.token1, HoldingLocation::PoolUnused { pool_id: pool.pool_id }, balance1);
+                }
+
+                let shares = s.positions.get(&(pool.pool_id, account.owner)).copied().unwrap_or(0);
+                if shares > 0 && pool.total_shares > 0 {
+                    let fraction = shares as f64 / pool.total_shares as f64;
+                    let (reserve0, reserve1) = reserves(&pool.curve);
+                    let location = HoldingLocation::LiquidityPosition { pool_id: pool.pool_id, position_id: 0 };
+                    push(&pool.token0, location.clone(), (reserve0 as f64 * fraction) as u128);
+                    push(&pool.token1, location, (reserve1 as f64 * fraction) as u128);
+                }
+            }
+
+            for ((token_id, owner, spender), amount) in &s.allowances {
+                if *owner != account.owner {
+                    continue;
+                }
+                if let Some(token) = tokens.iter().find(|t| t.canister_id == *token_id) {
+                    push(token, HoldingLocation::Allowance { spender: *spender }, *amount);
+                }
+            }
+            holdings
+        });
+
+        Ok(holdings::value_holdings(self, account.clone(), valuation_token, holdings, Vec::new(), self.now_secs()).await)
+    }
+
+    /// Moves tokens between wallets, charging the token's transfer fee; returns a block index
+    /// except for EXT tokens, whose ledgers report none
+    async fn transfer_token(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<Option<u128>> {
+        let from = Account::with_subaccount(self.runtime.id(), from_subaccount);
+        self.with_state(|s| {
+            let fee = Self::transfer_fee(s, &token.canister_id);
+            let total = amount.checked_add(fee).ok_or(ExchangeError::InvalidAmount)?;
+            Self::debit_wallet(s, &token.canister_id, &from, total)?;
+            Self::credit_wallet(s, &token.canister_id, to, amount);
+            s.next_tx_id += 1;
+            Ok(match token.standard {
+                TokenStandard::EXT => None,
+                _ => Some(s.next_tx_id as u128),
+            })
+        })
+    }
+
+    /// Records an allowance from the canister to a spender
+    async fn approve_token(&self, token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<()> {
+        let owner = self.runtime.id();
+        self.with_state(|s| {
+            s.allowances.insert((token.canister_id, owner, *spender), amount);
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LiquidityPool for SimulatedExchange {
+    /// Gets pool information including current reserves
+    async fn get_pool_info(&self, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<PoolInfo> {
+        self.with_state(|s| {
+            let pool = &s.pools[Self::find_pool_index(s, base, quote)?];
+            let (token0_reserves, token1_reserves) = reserves(&pool.curve);
+            Ok(PoolInfo {
+                pool_id: pool.pool_id,
+                token0: pool.token0.clone(),
+                token1: pool.token1.clone(),
+                fee: pool.fee,
+                total_liquidity: pool.total_shares,
+                token0_reserves,
+                token1_reserves,
+            })
+        })
+    }
+
+    /// Adds liquidity from the canister wallet at the current pool price
+    async fn add_liquidity(&self, params: &LiquidityParams) -> ExchangeResult<LiquidityResult> {
+        let owner = self.runtime.id();
+        let timestamp = self.now_secs();
+        self.with_state(|s| {
+            let index = Self::find_pool_index(s, &params.pair.base_token, &params.pair.quote_token)?;
+            let pool = s.pools[index].clone();
+
+            // Fraction of the existing pool being added, and the amounts actually used
+            let (fraction, amount0, amount1, new_curve) = match &pool.curve {
+                SimulatedCurve::ConstantProduct { reserve0, reserve1 } => {
+                    if *reserve0 == 0 || *reserve1 == 0 {
+                        return Err(ExchangeError::InsufficientLiquidity);
+                    }
+                    let fraction = (params.token0_amount as f64 / *reserve0 as f64)
+                        .min(params.token1_amount as f64 / *reserve1 as f64);
+                    let amount0 = (*reserve0 as f64 * fraction) as u128;
+                    let amount1 = (*reserve1 as f64 * fraction) as u128;
+                    (fraction, amount0, amount1, SimulatedCurve::ConstantProduct {
+                        reserve0: reserve0 + amount0,
+                        reserve1: reserve1 + amount1,
+                    })
+                }
+                SimulatedCurve::Concentrated { liquidity, sqrt_price, sqrt_price_lower, sqrt_price_upper } => {
+                    if *liquidity <= 0.0 {
+                        return Err(ExchangeError::InsufficientLiquidity);
+                    }
+                    let per_l0 = 1.0 / sqrt_price - 1.0 / sqrt_price_upper;
+                    let per_l1 = sqrt_price - sqrt_price_lower;
+                    let added = (params.token0_amount as f64 / per_l0).min(params.token1_amount as f64 / per_l1);
+                    (added / liquidity, (added * per_l0) as u128, (added * per_l1) as u128, SimulatedCurve::Concentrated {
+                        liquidity: liquidity + added,
+                        sqrt_price: *sqrt_price,
+                        sqrt_price_lower: *sqrt_price_lower,
+                        sqrt_price_upper: *sqrt_price_upper,
+                    })
+                }
+            };
+            let shares = (pool.total_shares as f64 * fraction) as u128;
+            if shares == 0 {
+                return Err(ExchangeError::InvalidAmount);
+            }
+
+            let wallet = self.account();
+            let wallet0 = Self::wallet_balance(s, &pool.token0.canister_id, &wallet);
+            let wallet1 = Self::wallet_balance(s, &pool.token1.canister_id, &wallet);
+            if wallet0 < amount0 || wallet1 < amount1 {
+                return Err(ExchangeError::InsufficientFunds);
+            }
+            Self::debit_wallet(s, &pool.token0.canister_id, &wallet, amount0)?;
+            Self::debit_wallet(s, &pool.token1.canister_id, &wallet, amount1)?;
+
+            let stored = &mut s.pools[index];
+            stored.curve = new_curve;
+            stored.total_shares += shares;
+            *s.positions.entry((pool.pool_id, owner)).or_insert(0) += shares;
+            s.next_tx_id += 1;
+
+            Ok(LiquidityResult {
+                liquidity_added: shares,
+                token0_amount: amount0,
+                token1_amount: amount1,
+                pool_id: pool.pool_id,
+                transaction_id: Some(format!("sim_{}_{}", pool.pool_id, s.next_tx_id)),
+                timestamp,
+            })
+        })
+    }
+
+    /// Burns LP shares and returns the proportional reserves to the canister wallet
+    async fn remove_liquidity(&self, pool_id: &Principal, liquidity_amount: u128, min_token0: u128, min_token1: u128) -> ExchangeResult<LiquidityResult> {
+        let owner = self.runtime.id();
+        let timestamp = self.now_secs();
+        self.with_state(|s| {
+            let index = s.pools.iter().position(|p| p.pool_id == *pool_id).ok_or(ExchangeError::PoolNotFound)?;
+            let owned = s.positions.get(&(*pool_id, owner)).copied().unwrap_or(0);
+            if liquidity_amount == 0 || liquidity_amount > owned {
+                return Err(ExchangeError::InvalidAmount);
+            }
+
+            let pool = s.pools[index].clone();
+            let fraction = liquidity_amount as f64 / pool.total_shares as f64;
+            let (reserve0, reserve1) = reserves(&pool.curve);
+            let amount0 = (reserve0 as f64 * fraction) as u128;
+            let amount1 = (reserve1 as f64 * fraction) as u128;
+            if amount0 < min_token0 || amount1 < min_token1 {
+                return Err(ExchangeError::SlippageExceeded);
+            }
+
+            let stored = &mut s.pools[index];
+            stored.curve = match &pool.curve {
+                SimulatedCurve::ConstantProduct { reserve0, reserve1 } => SimulatedCurve::ConstantProduct {
+                    reserve0: reserve0 - amount0,
+                    reserve1: reserve1 - amount1,
+                },
+                SimulatedCurve::Concentrated { liquidity, sqrt_price, sqrt_price_lower, sqrt_price_upper } => SimulatedCurve::Concentrated {
+                    liquidity: liquidity * (1.0 - fraction),
+                    sqrt_price: *sqrt_price,
+                    sqrt_price_lower: *sqrt_price_lower,
+                    sqrt_price_upper: *sqrt_price_upper,
+                },
+            };
+            stored.total_shares -= liquidity_amount;
+            *s.positions.entry((*pool_id, owner)).or_insert(0) -= liquidity_amount;
+            let wallet = self.account();
+            Self::credit_wallet(s, &pool.token0.canister_id, &wallet, amount0);
+            Self::credit_wallet(s, &pool.token1.canister_id, &wallet, amount1);
+            s.next_tx_id += 1;
+
+            Ok(LiquidityResult {
+                liquidity_added: liquidity_amount,
+                token0_amount: amount0,
+                token1_amount: amount1,
+                pool_id: *pool_id,
+                transaction_id: Some(format!("sim_{}_{}", pool_id, s.next_tx_id)),
+                timestamp,
+            })
+        })
+    }
+
+    /// Gets the LP shares a user holds in a pool
+    async fn get_user_liquidity(&self, pool_id: &Principal, user: &Principal) -> ExchangeResult<u128> {
+        Ok(self.with_state(|s| s.positions.get(&(*pool_id, *user)).copied().unwrap_or(0)))
+    }
+}
+
+#[async_trait]
+impl ExchangeConnector for SimulatedExchange {
+    /// The simulator implements every operation for all token standards
+    fn capabilities(&self) -> ExchangeCapabilities {
+        ExchangeCapabilities {
+            exchange_type: ExchangeType::Simulated,
+            supports_liquidity: true,
+            supports_batch_trades: true,
+            supports_call_trades: true,
+            supports_subaccounts: true,
+            supports_trade_history: true,
+            token_standards: vec![
+                TokenStandard::ICRC1,
+                TokenStandard::ICRC2,
+                TokenStandard::DIP20,
+                TokenStandard::EXT,
+                TokenStandard::ICP,
+            ],
+            order_kinds: vec![OrderKind::Market],
+        }
+    }
+
+    /// Wallet account trades are funded from
+    fn account(&self) -> Account {
+        SimulatedExchange::account(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use strategy_common::runtime::MockRuntime;
+
+    fn token(id: u8, symbol: &str) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    fn exchange() -> SimulatedExchange {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[9]), Principal::anonymous()));
+        SimulatedExchange::for_canister(config(), runtime)
+    }
+
+    fn config() -> ExchangeConfig {
+        crate::factory::ExchangeFactory::new().get_config(&ExchangeType::Simulated).unwrap().clone()
+    }
+
+    fn seed() -> SimulatedPoolSeed {
+        SimulatedPoolSeed {
+            token_a: token(1, "AAA"),
+            token_b: token(2, "BBB"),
+            fee: 3000,
+            amount_a: 1_000_000_000,
+            amount_b: 2_000_000_000,
+        }
+    }
+
+    fn sell(amount: u128) -> TradeParams {
+        TradeParams {
+            pair: TradingPair {
+                base_token: token(1, "AAA"),
+                quote_token: token(2, "BBB"),
+                exchange: ExchangeType::Simulated,
+            },
+            direction: TradeDirection::Sell,
+            amount,
+            slippage_tolerance: 0.5,
+            deadline_secs: None,
+        }
+    }
+
+    #[test]
+    fn canister_handles_share_pools_and_balances() {
+        let first = exchange();
+        let pool_id = first.seed_pool(&seed());
+        first.mint(&token(1, "AAA"), &first.account(), 50_000);
+        let price_before = first.spot_price(&pool_id).unwrap();
+
+        // A later handle trades with the balance minted through the first one
+        let second = exchange();
+        assert_eq!(second.pools().len(), 1);
+        block_on(second.execute_trade(&sell(50_000))).unwrap();
+
+        // and the first handle sees the pool move
+        assert_ne!(first.spot_price(&pool_id).unwrap(), price_before);
+    }
+
+    #[test]
+    fn seeding_an_existing_pair_keeps_the_pool() {
+        let exchange = exchange();
+        let pool_id = exchange.seed_pool(&seed());
+        let mut reversed = seed();
+        std::mem::swap(&mut reversed.token_a, &mut reversed.token_b);
+        assert_eq!(exchange.seed_pool(&reversed), pool_id);
+        assert_eq!(exchange.pools().len(), 1);
+    }
+
+    #[test]
+    fn quote_matches_wallet_trade_after_ledger_fees() {
+        let exchange = exchange();
+        exchange.seed_pool(&seed());
+        exchange.set_transfer_fee(&token(1, "AAA"), 10);
+        exchange.set_transfer_fee(&token(2, "BBB"), 20);
+        exchange.mint(&token(1, "AAA"), &exchange.account(), 100_000);
+
+        let quote = block_on(exchange.get_quote(&sell(100_000))).unwrap();
+        let trade = block_on(exchange.execute_trade(&sell(100_000))).unwrap();
+        assert_eq!(quote.output_amount, trade.output_amount);
+        assert_eq!(quote.input_amount, trade.input_amount);
+    }
+
+    #[test]
+    fn standalone_simulators_stay_separate() {
+        let shared = exchange();
+        shared.seed_pool(&seed());
+        let standalone = SimulatedExchange::with_runtime(
+            config(),
+            Arc::new(MockRuntime::new(Principal::from_slice(&[9]), Principal::anonymous())),
+        );
+        assert!(standalone.pools().is_empty());
+    }
+
+    #[test]
+    fn price_paths_step_shared_pools_until_finished() {
+        let simulator = exchange();
+        let pool_id = simulator.seed_pool(&seed());
+        simulator.set_price_path(&pool_id, PricePath::from_prices(vec![2.5, 1.5]));
+
+        // Every canister handle sees the step made through another; reserves round to whole units
+        assert_eq!(simulator.advance_price_paths(), 1);
+        assert!((exchange().spot_price(&pool_id).unwrap() - 2.5).abs() < 1e-6);
+        assert_eq!(simulator.advance_price_paths(), 1);
+        assert!((simulator.spot_price(&pool_id).unwrap() - 1.5).abs() < 1e-6);
+
+        // A finished path leaves the pool where it ended
+        assert_eq!(simulator.advance_price_paths(), 0);
+        assert!((simulator.spot_price(&pool_id).unwrap() - 1.5).abs() < 1e-6);
+    }
+}
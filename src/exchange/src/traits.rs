@@ -14,8 +14,8 @@ pub trait Exchange {
     /// Get the status of the exchange
     async fn get_status(&self) -> ExchangeResult<ExchangeStatus>;
     
-    /// Query token balance
-    async fn get_token_balance(&self, token: &TokenInfo, owner: &Principal) -> ExchangeResult<u128>;
+    /// Query the token balance of an account
+    async fn get_token_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128>;
     
     /// Check if a trading pair is supported
     async fn is_pair_supported(&self, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<bool>;
@@ -27,12 +27,22 @@ pub trait Trading: Exchange {
     /// Get a trading quote
     async fn get_quote(&self, params: &TradeParams) -> ExchangeResult<QuoteResult>;
     
+    /// Plan a trade without executing it, running only read-only checks
+    async fn simulate_trade(&self, params: &TradeParams) -> ExchangeResult<TradePlan>;
+
+    /// Output, effective price and price impact for each input size, both selling and buying base
+    async fn get_depth_curve(&self, pair: &TradingPair, sizes: &[u128]) -> ExchangeResult<DepthCurve>;
+    
     /// Execute a trade
     async fn execute_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult>;
     async fn execute_call_trade(
         &self, params: &TradeParams
     ) -> ExchangeResult<TradeResult>;
 
+    /// Swap the unused pool balance in an already-resolved pool, accepting any output amount;
+    /// available when the connector advertises `supports_call_trades`
+    async fn execute_call_trade_no_slippage(&self, params: &TradeParams, pool: &PoolInfo) -> ExchangeResult<TradeResult>;
+
     /// Execute multiple trades in a batch
     async fn execute_batch_trade(&self, params: &BatchTradeParams) -> ExchangeResult<BatchTradeResult>;
     
@@ -68,7 +78,22 @@ pub trait TokenOperations: Exchange {
     /// Get the user's unused token balance (e.g., balance not in orders or pools)
     async fn get_unused_balance(&self, params: &TradeParams, user: &Principal) -> ExchangeResult<(u128,u128,String)>;
     
-    /// Query the user's total balance within the exchange
-    async fn get_exchange_balance(&self, token: &TokenInfo, user: &Principal) -> ExchangeResult<(u128,u128)>;
+    /// Break down an account's holdings of the given tokens (wallet, pool balances, allowances
+    /// and LP positions), valued in `valuation_token`
+    async fn get_exchange_balance(&self, tokens: &[TokenInfo], account: &Account, valuation_token: &TokenInfo) -> ExchangeResult<HoldingsBreakdown>;
+
+    /// Transfer tokens from one of the canister's subaccounts to another account; returns the block index,
+    /// or `None` for token standards whose transfers have none
+    async fn transfer_token(&self, token: &TokenInfo, from_subaccount: Option<Subaccount>, to: &Account, amount: u128) -> ExchangeResult<Option<u128>>;
     async fn approve_token(&self,token: &TokenInfo, spender: &Principal, amount: u128) -> ExchangeResult<()>;
-} 
\ No newline at end of file
+}
+
+/// Full exchange connector: trading, token operations and liquidity behind one trait object
+#[async_trait]
+pub trait ExchangeConnector: Trading + TokenOperations + LiquidityPool + Send + Sync {
+    /// Describe what this connector supports
+    fn capabilities(&self) -> ExchangeCapabilities;
+
+    /// Account this connector trades from
+    fn account(&self) -> Account;
+}
@@ -0,0 +1,560 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::oracle;
+use crate::traits::*;
+use crate::types::*;
+use strategy_common::debug_log;
+use strategy_common::exchange::{OrderInfo, OrderStatus};
+use strategy_common::runtime::Runtime;
+use strategy_common::scheduler::{self, MissedRunPolicy, Schedule};
+use strategy_common::types::{OrderType, TokenMetadata};
+
+/// Stable memory type used by the trigger order book
+pub type TriggerMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Scheduler job ID used by the price watcher
+const WATCHER_JOB_ID: &str = "trigger_order_watcher";
+
+/// Failed executions after which a triggered order is marked failed
+pub const MAX_EXECUTION_ATTEMPTS: u32 = 3;
+
+/// Condition under which a trigger order fires
+///
+/// Prices are in quote tokens per base token (human units), as quoted for the order's own size.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerKind {
+    LimitBuy,   // Buy base when the price falls to or below the trigger
+    LimitSell,  // Sell base when the price rises to or above the trigger
+    StopLoss,   // Sell base when the price falls to or below the trigger
+    TakeProfit, // Sell base when the price rises to or above the trigger
+}
+
+impl TriggerKind {
+    /// Trade direction of the swap fired by this kind of order
+    pub fn direction(&self) -> TradeDirection {
+        match self {
+            TriggerKind::LimitBuy => TradeDirection::Buy,
+            TriggerKind::LimitSell | TriggerKind::StopLoss | TriggerKind::TakeProfit => TradeDirection::Sell,
+        }
+    }
+
+    /// Whether `price` satisfies the condition for `trigger_price`
+    pub fn is_triggered(&self, price: f64, trigger_price: f64) -> bool {
+        match self {
+            TriggerKind::LimitBuy | TriggerKind::StopLoss => price <= trigger_price,
+            TriggerKind::LimitSell | TriggerKind::TakeProfit => price >= trigger_price,
+        }
+    }
+}
+
+/// Parameters of a new trigger order
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TriggerOrderRequest {
+    pub pair: TradingPair,
+    pub kind: TriggerKind,
+    pub amount: u128,             // Input amount: quote tokens for LimitBuy, base tokens otherwise
+    pub trigger_price: f64,
+    pub slippage_tolerance: f64,  // Applied when the swap fires, as a percentage
+    pub expires_at: Option<u64>,  // Seconds since the epoch; None never expires
+}
+
+/// A trigger order and its progress
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TriggerOrder {
+    pub id: u64,
+    pub request: TriggerOrderRequest,
+    pub status: OrderStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub last_price: Option<f64>,      // Most recent price seen by the watcher
+    pub attempts: u32,                // Executions attempted after the condition held
+    pub result: Option<TradeResult>,
+    pub last_error: Option<String>,
+}
+
+impl Storable for TriggerOrder {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl TriggerOrder {
+    /// Whether the order is still waiting for its condition
+    pub fn is_open(&self) -> bool {
+        self.status == OrderStatus::Pending
+    }
+
+    /// Whether the order has passed its expiry at `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.request.expires_at.map_or(false, |expires_at| now >= expires_at)
+    }
+
+    /// Swap fired when the order triggers
+    pub fn trade_params(&self) -> TradeParams {
+        TradeParams {
+            pair: self.request.pair.clone(),
+            direction: self.request.kind.direction(),
+            amount: self.request.amount,
+            slippage_tolerance: self.request.slippage_tolerance,
+            deadline_secs: None,
+        }
+    }
+
+    /// The order in the strategy-level `OrderInfo` representation
+    pub fn to_order_info(&self) -> OrderInfo {
+        OrderInfo {
+            order_id: self.id.to_string(),
+            trading_pair: strategy_common::types::TradingPair {
+                base_token: token_metadata(&self.request.pair.base_token),
+                quote_token: token_metadata(&self.request.pair.quote_token),
+            },
+            direction: match self.request.kind.direction() {
+                TradeDirection::Buy => OrderType::Buy,
+                TradeDirection::Sell => OrderType::Sell,
+            },
+            amount_in: self.request.amount,
+            amount_out: self.result.as_ref().map_or(0, |result| result.output_amount),
+            status: self.status.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+// Strategy-level metadata of an exchange token; the transfer fee is not known here
+fn token_metadata(token: &TokenInfo) -> TokenMetadata {
+    TokenMetadata {
+        canister_id: token.canister_id,
+        symbol: token.symbol.clone(),
+        decimals: token.decimals,
+        standard: format!("{:?}", token.standard),
+        fee: 0,
+    }
+}
+
+/// Trigger orders backed by stable memory
+pub struct TriggerOrderBook {
+    orders: StableBTreeMap<u64, TriggerOrder, TriggerMemory>,
+}
+
+impl TriggerOrderBook {
+    /// Opens (or creates) the order book in the given memory
+    pub fn init(memory: TriggerMemory) -> Self {
+        Self { orders: StableBTreeMap::init(memory) }
+    }
+
+    /// Validates and stores a new order, returning its ID
+    pub fn place(&mut self, request: TriggerOrderRequest, now: u64) -> ExchangeResult<u64> {
+        if request.amount == 0 {
+            return Err(ExchangeError::InvalidAmount);
+        }
+        if !request.trigger_price.is_finite() || request.trigger_price <= 0.0 {
+            return Err(ExchangeError::InvalidParameters(format!("Invalid trigger price: {}", request.trigger_price)));
+        }
+        if request.expires_at.map_or(false, |expires_at| expires_at <= now) {
+            return Err(ExchangeError::InvalidParameters("Trigger order expires in the past".to_string()));
+        }
+
+        let id = self.orders.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.orders.insert(id, TriggerOrder {
+            id,
+            request,
+            status: OrderStatus::Pending,
+            created_at: now,
+            updated_at: now,
+            last_price: None,
+            attempts: 0,
+            result: None,
+            last_error: None,
+        });
+        Ok(id)
+    }
+
+    /// Cancels an open order; an order whose swap is in flight can no longer be cancelled
+    pub fn cancel(&mut self, id: u64, now: u64) -> ExchangeResult<TriggerOrder> {
+        let mut order = self.orders.get(&id)
+            .ok_or_else(|| ExchangeError::InvalidParameters(format!("Unknown trigger order: {}", id)))?;
+        if !order.is_open() {
+            return Err(ExchangeError::InvalidParameters(format!("Trigger order {} is {:?}", id, order.status)));
+        }
+        if is_executing(id) {
+            return Err(ExchangeError::InvalidParameters(format!("Trigger order {} is executing", id)));
+        }
+        order.status = OrderStatus::Canceled;
+        order.updated_at = now;
+        self.orders.insert(id, order.clone());
+        Ok(order)
+    }
+
+    /// Gets an order by ID
+    pub fn get(&self, id: u64) -> Option<TriggerOrder> {
+        self.orders.get(&id)
+    }
+
+    /// Stores an updated order
+    pub fn update(&mut self, order: TriggerOrder) {
+        self.orders.insert(order.id, order);
+    }
+
+    /// All orders, oldest first
+    pub fn orders(&self) -> Vec<TriggerOrder> {
+        self.orders.iter().map(|(_, order)| order).collect()
+    }
+
+    /// Orders still waiting for their condition, oldest first
+    pub fn open_orders(&self) -> Vec<TriggerOrder> {
+        self.orders.iter().map(|(_, order)| order).filter(|order| order.is_open()).collect()
+    }
+}
+
+thread_local! {
+    // Order book of the running canister, opened by `init`
+    static BOOK: RefCell<Option<TriggerOrderBook>> = RefCell::new(None);
+    // Orders whose swap is in flight, so overlapping watcher ticks do not fire them twice
+    static EXECUTING: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+/// Opens the canister's order book; call from both `init` and `post_upgrade`
+pub fn init(memory: TriggerMemory) {
+    BOOK.with(|book| {
+        *book.borrow_mut() = Some(TriggerOrderBook::init(memory));
+    });
+}
+
+/// Runs a closure against the canister's order book
+pub fn with_book<T>(f: impl FnOnce(&mut TriggerOrderBook) -> T) -> ExchangeResult<T> {
+    BOOK.with(|book| {
+        book.borrow_mut()
+            .as_mut()
+            .map(f)
+            .ok_or_else(|| ExchangeError::InternalError("Trigger order book not initialized".to_string()))
+    })
+}
+
+/// Whether the swap of an order is in flight
+pub fn is_executing(id: u64) -> bool {
+    EXECUTING.with(|executing| executing.borrow().contains(&id))
+}
+
+// Applies `f` to the stored order if it is still open; returns the updated order
+fn update_if_open(id: u64, f: impl FnOnce(&mut TriggerOrder)) -> ExchangeResult<Option<TriggerOrder>> {
+    with_book(|book| {
+        let mut order = book.get(id).filter(|order| order.is_open())?;
+        f(&mut order);
+        book.update(order.clone());
+        Some(order)
+    })
+}
+
+/// Checks one open order and fires its swap if the condition holds; returns whether it filled
+///
+/// The order is re-read after every await, so a cancellation made meanwhile is never overwritten.
+pub async fn check_order(connector: &dyn Trading, order: TriggerOrder, now: u64) -> ExchangeResult<bool> {
+    let id = order.id;
+    if order.is_expired(now) {
+        update_if_open(id, |order| {
+            order.status = OrderStatus::Expired;
+            order.updated_at = now;
+        })?;
+        return Ok(false);
+    }
+
+    let params = order.trade_params();
+    let quote = connector.get_quote(&params).await?;
+    let price = oracle::quote_price(&params, &quote)
+        .ok_or_else(|| ExchangeError::InternalError("Quote returned a zero amount".to_string()))?;
+    let Some(order) = update_if_open(id, |order| {
+        order.last_price = Some(price);
+        order.updated_at = now;
+    })? else {
+        return Ok(false);
+    };
+    if !order.request.kind.is_triggered(price, order.request.trigger_price) {
+        return Ok(false);
+    }
+
+    // Claimed while the order is known to be open; cancel refuses it from here on
+    let claimed = EXECUTING.with(|executing| executing.borrow_mut().insert(id));
+    if !claimed {
+        return Ok(false);
+    }
+    debug_log!("Trigger order {} fired at price {} (trigger {})", id, price, order.request.trigger_price);
+    let result = connector.execute_trade(&params).await;
+
+    let filled = result.is_ok();
+    let updated = update_if_open(id, |stored| {
+        stored.updated_at = now;
+        stored.attempts += 1;
+        match result {
+            Ok(trade) => {
+                stored.status = OrderStatus::Filled;
+                stored.result = Some(trade);
+                stored.last_error = None;
+            }
+            Err(e) => {
+                debug_log!("Trigger order {} failed to execute: {}", id, e);
+                if stored.attempts >= MAX_EXECUTION_ATTEMPTS {
+                    stored.status = OrderStatus::Failed;
+                }
+                stored.last_error = Some(e.to_string());
+            }
+        }
+    });
+    EXECUTING.with(|executing| executing.borrow_mut().remove(&id));
+    if updated?.is_none() {
+        debug_log!("Trigger order {} was closed while its swap was in flight", id);
+    }
+    Ok(filled)
+}
+
+/// Checks every open order once; returns the number filled. Failures are logged and skipped
+pub async fn check_all(
+    connector_for: &dyn Fn(&TradingPair) -> ExchangeResult<Box<dyn Trading>>,
+    now: u64,
+) -> usize {
+    let orders = match with_book(|book| book.open_orders()) {
+        Ok(orders) => orders,
+        Err(e) => {
+            debug_log!("Trigger order check skipped: {}", e);
+            return 0;
+        }
+    };
+
+    let mut filled = 0;
+    for order in orders {
+        let id = order.id;
+        let outcome = match connector_for(&order.request.pair) {
+            Ok(connector) => check_order(connector.as_ref(), order, now).await,
+            Err(e) => Err(e),
+        };
+        match outcome {
+            Ok(true) => filled += 1,
+            Ok(false) => {}
+            Err(e) => debug_log!("Failed to check trigger order {}: {}", id, e),
+        }
+    }
+    filled
+}
+
+/// Check open orders every `interval_secs` through the scheduler, timed by `runtime`
+///
+/// Call from both `init` and `post_upgrade` once the scheduler is open; the job itself
+/// persists, but its handler has to be registered again.
+pub fn schedule_watching<F>(interval_secs: u64, runtime: Arc<dyn Runtime>, connector_for: F) -> Result<(), String>
+where
+    F: Fn(&TradingPair) -> ExchangeResult<Box<dyn Trading>> + 'static,
+{
+    let connector_for = Rc::new(connector_for);
+    scheduler::register_handler(WATCHER_JOB_ID, move || {
+        let connector_for = connector_for.clone();
+        let runtime = runtime.clone();
+        async move {
+            let now = runtime.time() / 1_000_000_000;
+            check_all(connector_for.as_ref(), now).await;
+            Ok(())
+        }
+    });
+    if scheduler::get_job(WATCHER_JOB_ID).is_some() {
+        return Ok(());
+    }
+    scheduler::schedule(
+        WATCHER_JOB_ID,
+        Schedule::Interval { seconds: interval_secs },
+        MissedRunPolicy::Skip,
+        interval_secs,
+    )
+    .map(|_| ())
+}
+
+/// Stops the price watcher
+pub fn stop_watching() -> Result<(), String> {
+    scheduler::cancel(WATCHER_JOB_ID).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::ExchangeFactory;
+    use crate::simulated::{SimulatedExchange, SimulatedPoolSeed};
+    use candid::Principal;
+    use futures::executor::block_on;
+    use futures::future::join;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use strategy_common::runtime::MockRuntime;
+
+    struct YieldOnce(bool);
+
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // Simulator whose quotes and swaps each suspend once, like inter-canister calls
+    struct SlowExchange(SimulatedExchange);
+
+    #[async_trait::async_trait]
+    impl Exchange for SlowExchange {
+        fn get_exchange_type(&self) -> ExchangeType { self.0.get_exchange_type() }
+        async fn get_status(&self) -> ExchangeResult<ExchangeStatus> { self.0.get_status().await }
+        async fn get_token_balance(&self, token: &TokenInfo, account: &Account) -> ExchangeResult<u128> { self.0.get_token_balance(token, account).await }
+        async fn is_pair_supported(&self, base: &TokenInfo, quote: &TokenInfo) -> ExchangeResult<bool> { self.0.is_pair_supported(base, quote).await }
+    }
+
+    #[async_trait::async_trait]
+    impl Trading for SlowExchange {
+        async fn get_quote(&self, params: &TradeParams) -> ExchangeResult<QuoteResult> {
+            YieldOnce(false).await;
+            self.0.get_quote(params).await
+        }
+        async fn simulate_trade(&self, params: &TradeParams) -> ExchangeResult<TradePlan> { self.0.simulate_trade(params).await }
+        async fn get_depth_curve(&self, pair: &TradingPair, sizes: &[u128]) -> ExchangeResult<DepthCurve> { self.0.get_depth_curve(pair, sizes).await }
+        async fn execute_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> {
+            YieldOnce(false).await;
+            self.0.execute_trade(params).await
+        }
+        async fn execute_call_trade(&self, params: &TradeParams) -> ExchangeResult<TradeResult> { self.0.execute_call_trade(params).await }
+        async fn execute_call_trade_no_slippage(&self, params: &TradeParams, pool: &PoolInfo) -> ExchangeResult<TradeResult> { self.0.execute_call_trade_no_slippage(params, pool).await }
+        async fn execute_batch_trade(&self, params: &BatchTradeParams) -> ExchangeResult<BatchTradeResult> { self.0.execute_batch_trade(params).await }
+        async fn get_trade_history(&self, user: &Principal, limit: usize, offset: usize) -> ExchangeResult<Vec<TradeHistory>> { self.0.get_trade_history(user, limit, offset).await }
+    }
+
+    fn token(id: u8, symbol: &str) -> TokenInfo {
+        TokenInfo {
+            canister_id: Principal::from_slice(&[id]),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            standard: TokenStandard::ICRC1,
+        }
+    }
+
+    // Simulated AAA/BBB pool priced around 2 BBB per AAA, with AAA in the canister wallet
+    fn exchange() -> SlowExchange {
+        let config = ExchangeFactory::new().get_config(&ExchangeType::Simulated).unwrap().clone();
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[9]), Principal::anonymous()));
+        let simulator = SimulatedExchange::with_runtime(config, runtime);
+        simulator.seed_pool(&SimulatedPoolSeed {
+            token_a: token(1, "AAA"),
+            token_b: token(2, "BBB"),
+            fee: 3000,
+            amount_a: 1_000_000_000,
+            amount_b: 2_000_000_000,
+        });
+        simulator.mint(&token(1, "AAA"), &simulator.account(), 1_000_000);
+        SlowExchange(simulator)
+    }
+
+    fn request(kind: TriggerKind, trigger_price: f64, expires_at: Option<u64>) -> TriggerOrderRequest {
+        TriggerOrderRequest {
+            pair: TradingPair {
+                base_token: token(1, "AAA"),
+                quote_token: token(2, "BBB"),
+                exchange: ExchangeType::Simulated,
+            },
+            kind,
+            amount: 100_000,
+            trigger_price,
+            slippage_tolerance: 1.0,
+            expires_at,
+        }
+    }
+
+    // Opens the thread's order book and places one order
+    fn place(request: TriggerOrderRequest) -> TriggerOrder {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        init(manager.get(MemoryId::new(0)));
+        let id = with_book(|book| book.place(request, 100)).unwrap().unwrap();
+        with_book(|book| book.get(id)).unwrap().unwrap()
+    }
+
+    fn status(id: u64) -> OrderStatus {
+        with_book(|book| book.get(id)).unwrap().unwrap().status
+    }
+
+    #[test]
+    fn trigger_conditions() {
+        assert!(TriggerKind::LimitBuy.is_triggered(1.0, 1.0));
+        assert!(!TriggerKind::LimitBuy.is_triggered(1.1, 1.0));
+        assert!(TriggerKind::StopLoss.is_triggered(0.9, 1.0));
+        assert!(!TriggerKind::StopLoss.is_triggered(1.1, 1.0));
+        assert!(TriggerKind::LimitSell.is_triggered(1.1, 1.0));
+        assert!(!TriggerKind::LimitSell.is_triggered(0.9, 1.0));
+        assert!(TriggerKind::TakeProfit.is_triggered(1.0, 1.0));
+        assert!(!TriggerKind::TakeProfit.is_triggered(0.9, 1.0));
+    }
+
+    #[test]
+    fn expired_orders_close_without_trading() {
+        let order = place(request(TriggerKind::TakeProfit, 1.0, Some(200)));
+        let exchange = exchange();
+        assert!(!block_on(check_order(&exchange, order.clone(), 200)).unwrap());
+        assert_eq!(status(order.id), OrderStatus::Expired);
+        assert!(with_book(|book| book.place(request(TriggerKind::TakeProfit, 1.0, Some(50)), 100)).unwrap().is_err());
+    }
+
+    #[test]
+    fn untriggered_orders_stay_open() {
+        let order = place(request(TriggerKind::StopLoss, 1.0, None));
+        assert!(!block_on(check_order(&exchange(), order.clone(), 150)).unwrap());
+        let stored = with_book(|book| book.get(order.id)).unwrap().unwrap();
+        assert_eq!(stored.status, OrderStatus::Pending);
+        assert!(stored.last_price.unwrap() > 1.0);
+    }
+
+    #[test]
+    fn cancel_during_the_quote_wins() {
+        let order = place(request(TriggerKind::TakeProfit, 1.0, None));
+        let exchange = exchange();
+        let (checked, canceled) = block_on(join(
+            check_order(&exchange, order.clone(), 150),
+            async { with_book(|book| book.cancel(order.id, 150)).unwrap() },
+        ));
+        assert!(canceled.is_ok());
+        assert!(!checked.unwrap());
+        assert_eq!(status(order.id), OrderStatus::Canceled);
+        assert!(block_on(exchange.0.get_trade_history(&Principal::from_slice(&[9]), 10, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_during_the_swap_is_refused() {
+        let order = place(request(TriggerKind::TakeProfit, 1.0, None));
+        let exchange = exchange();
+        let (checked, canceled) = block_on(join(
+            check_order(&exchange, order.clone(), 150),
+            async {
+                // Let the quote finish so the swap is in flight
+                YieldOnce(false).await;
+                with_book(|book| book.cancel(order.id, 150)).unwrap()
+            },
+        ));
+        assert!(canceled.is_err());
+        assert!(checked.unwrap());
+        assert_eq!(status(order.id), OrderStatus::Filled);
+        assert!(!is_executing(order.id));
+    }
+}
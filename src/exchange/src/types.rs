@@ -1,4 +1,4 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use serde::Serialize;
 use std::collections::HashMap;
 
@@ -9,6 +9,7 @@ pub enum ExchangeType {
     KongSwap,
     Sonic,
     ICDex,
+    Simulated,  // In-memory AMM for paper trading and tests
 }
 
 /// Information about a token
@@ -30,6 +31,44 @@ pub enum TokenStandard {
     ICP,
 }
 
+/// ICRC-1 subaccount
+pub type Subaccount = [u8; 32];
+
+/// ICRC-1 account: an owner principal plus an optional subaccount
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,  // None is the default (all-zero) subaccount
+}
+
+impl Account {
+    /// Account on the owner's default subaccount
+    pub fn new(owner: Principal) -> Self {
+        Self { owner, subaccount: None }
+    }
+
+    /// Account on a specific subaccount of the owner
+    pub fn with_subaccount(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Self { owner, subaccount }
+    }
+
+    /// Subaccount bytes, treating None as the default subaccount
+    pub fn effective_subaccount(&self) -> Subaccount {
+        self.subaccount.unwrap_or([0u8; 32])
+    }
+
+    /// Whether this is the owner's default subaccount
+    pub fn is_default(&self) -> bool {
+        self.effective_subaccount() == [0u8; 32]
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self::new(owner)
+    }
+}
+
 /// Information about a trading pair
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct TradingPair {
@@ -55,15 +94,104 @@ pub struct TradeParams {
     pub deadline_secs: Option<u64>,
 }
 
+/// Fees paid while executing a trade, in raw token units
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FeeBreakdown {
+    pub pool_fee: u128,            // Swap fee kept by the pool, in the input token
+    pub input_transfer_fee: u128,  // Ledger fees to move the input into the pool, in the input token
+    pub approve_fee: u128,         // Ledger fee for the allowance, in the input token
+    pub withdraw_fee: u128,        // Ledger fees to move the output out of the pool, in the output token
+}
+
+/// Ledger operation recorded while executing a trade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LedgerOperation {
+    Approve,
+    Transfer,
+}
+
+/// A ledger block created while executing a trade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerTransaction {
+    pub ledger: Principal,
+    pub block_index: u128,
+    pub operation: LedgerOperation,
+}
+
+/// Outcome of checking a transfer against the ledger block that records it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferVerification {
+    Verified { block_index: u128 },
+    Mismatch { block_index: u128, discrepancies: Vec<String> },
+    NotFound,        // Block missing, archived, or (for pool-initiated transfers) not located
+    Unsupported,     // The token's ledger does not expose its blocks (DIP20, EXT)
+    Failed(String),  // The block query itself failed
+}
+
+/// One leg of a trade, checked against its ledger
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferCheck {
+    pub ledger: Principal,
+    pub block_index: Option<u128>, // None for transfers made by the pool, which are located by scanning recent blocks
+    pub to: Account,
+    pub amount: u128,
+    pub memo: Option<Vec<u8>>,
+    pub verification: TransferVerification,
+}
+
+impl TransferCheck {
+    /// Whether the ledger confirmed the transfer as expected
+    pub fn is_verified(&self) -> bool {
+        matches!(self.verification, TransferVerification::Verified { .. })
+    }
+}
+
 /// Result of a trade operation
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct TradeResult {
     pub input_amount: u128,
-    pub output_amount: u128,
-    pub fee_amount: u128,
-    pub price: f64,
+    pub output_amount: u128,     // Net amount received after all fees
+    pub fee_amount: u128,        // Pool fee, kept for compatibility; see `fees` for the full breakdown
+    pub price: f64,              // Quote tokens per base token in human units, whatever the direction
+    pub inverse_price: f64,      // Base tokens per quote token in human units
     pub timestamp: u64,
-    pub transaction_id: Option<String>,
+    pub transaction_id: Option<String>,  // "<ledger>:<block>" of the last ledger block created, if any
+    pub fees: FeeBreakdown,
+    pub ledger_transactions: Vec<LedgerTransaction>,
+}
+
+/// A single action an exchange would perform while executing a trade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TradeStep {
+    Approve { token: Principal, spender: Principal, amount: u128, current_allowance: u128, fee: u128 },
+    Transfer { token: Principal, to: Account, amount: u128, fee: u128 },
+    Deposit { pool_id: Principal, token: Principal, amount: u128, fee: u128 },
+    DepositFrom { pool_id: Principal, token: Principal, amount: u128, fee: u128 },
+    Swap { pool_id: Principal, amount_in: u128, min_amount_out: u128 },
+    Withdraw { pool_id: Principal, token: Principal, amount: u128, fee: u128 },
+}
+
+/// What executing a trade would do, produced without moving any funds
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TradePlan {
+    pub pool_id: Option<Principal>,
+    pub input_token: TokenInfo,
+    pub output_token: TokenInfo,
+    pub steps: Vec<TradeStep>,
+    pub input_ledger_fees: u128,   // Ledger fees paid in the input token
+    pub output_ledger_fees: u128,  // Ledger fees paid in the output token
+    pub expected_output: u128,
+    pub min_output: u128,          // Expected output after slippage tolerance
+    pub price_impact: f64,
+    pub estimated_cycles: u128,    // Rough cycles cost of the calls in the plan
+    pub blockers: Vec<String>,     // Reasons the trade would fail; empty if it can proceed
+}
+
+impl TradePlan {
+    /// Whether the trade is expected to succeed
+    pub fn is_executable(&self) -> bool {
+        self.blockers.is_empty()
+    }
 }
 
 /// Result of a quote request
@@ -71,11 +199,61 @@ pub struct TradeResult {
 pub struct QuoteResult {
     pub input_amount: u128,
     pub output_amount: u128,
-    pub price: f64,
+    pub price: f64,         // Quote tokens per base token in human units, whatever the direction
+    pub inverse_price: f64, // Base tokens per quote token in human units
     pub fee_amount: u128,
     pub price_impact: f64,  // Price impact, represented as a percentage
 }
 
+/// Price of a pair's base token in its quote token, adjusted for decimals
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NormalizedPrice {
+    pub base_token: Principal,
+    pub quote_token: Principal,
+    pub price: f64,   // Quote tokens per base token
+    pub inverse: f64, // Base tokens per quote token
+}
+
+/// Outcome of trading one input size in one direction
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepthPoint {
+    pub input_amount: u128,   // Raw units of the input token (quote for Buy, base for Sell)
+    pub output_amount: u128,
+    pub effective_price: f64, // Output received per unit of input, in raw units
+    pub price: f64,           // Quote tokens per base token in human units
+    pub price_impact: f64,    // Price impact, represented as a percentage
+    pub fully_filled: bool,   // False when the pool ran out of liquidity before the whole input was swapped
+}
+
+/// How output degrades with size, for a ladder of input sizes in both directions
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepthCurve {
+    pub pair: TradingPair,
+    pub sell: Vec<DepthPoint>, // Selling base for quote, ordered by input size
+    pub buy: Vec<DepthPoint>,  // Buying base with quote, ordered by input size
+    pub timestamp: u64,
+}
+
+impl DepthCurve {
+    /// Points on one side of the curve
+    pub fn side(&self, direction: &TradeDirection) -> &[DepthPoint] {
+        match direction {
+            TradeDirection::Buy => &self.buy,
+            TradeDirection::Sell => &self.sell,
+        }
+    }
+
+    /// Largest input on the ladder that fills completely within `max_price_impact` (%), or 0 if none does
+    pub fn max_input_within_impact(&self, direction: &TradeDirection, max_price_impact: f64) -> u128 {
+        self.side(direction)
+            .iter()
+            .filter(|point| point.fully_filled && point.price_impact <= max_price_impact)
+            .map(|point| point.input_amount)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
 /// Information about a liquidity pool
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct PoolInfo {
@@ -88,6 +266,51 @@ pub struct PoolInfo {
     pub token1_reserves: u128,
 }
 
+/// Liquidity change at an initialized tick of a concentrated-liquidity pool
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TickLiquidity {
+    pub tick: i32,
+    pub liquidity_net: i128, // Liquidity added when the price crosses the tick upwards
+}
+
+/// Point-in-time state of a concentrated-liquidity pool, sufficient to quote swaps locally
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PoolSnapshot {
+    pub pool_id: Principal,
+    pub token0: Principal,
+    pub token1: Principal,
+    pub fee: u64,                  // Trading fee, in parts per million (ppm)
+    pub sqrt_price_x96: Nat,       // Q64.96 sqrt(token1 per token0) in raw units, as reported by the pool
+    pub tick: i32,
+    pub liquidity: u128,           // Liquidity active at the current tick
+    pub ticks: Vec<TickLiquidity>, // Initialized ticks, sorted ascending
+    pub timestamp: u64,
+}
+
+/// Swap computed locally against a pool snapshot
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LocalQuote {
+    pub input_amount: u128,  // Input actually consumed; less than requested when liquidity runs out
+    pub output_amount: u128,
+    pub fee_amount: u128,    // Pool fee, in input token units
+    pub price_impact: f64,   // Price impact, represented as a percentage
+    pub sqrt_price_x96_after: Nat,
+    pub tick_after: i32,
+    pub ticks_crossed: u32,
+    pub fully_filled: bool,
+}
+
+/// Listing and activity of a single pool on an exchange
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PoolStats {
+    pub pool_id: Principal,
+    pub token0: Principal,
+    pub token1: Principal,
+    pub fee: u64,                     // Trading fee in ppm
+    pub tvl_usd: Option<f64>,         // None when the exchange publishes no statistics for the pool
+    pub volume_24h_usd: Option<f64>,
+}
+
 /// Status of an exchange
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ExchangeStatus {
@@ -95,9 +318,89 @@ pub struct ExchangeStatus {
     pub is_available: bool,
     pub supported_tokens: Vec<TokenInfo>,
     pub supported_pairs: Vec<TradingPair>,
+    pub pools: Vec<PoolStats>,
+    pub latency_ms: Option<u64>,      // Latency of the listing call; None if it failed
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub circuit_breaker: BreakerStatus,    // Breaker guarding the venue as a whole
+    pub tripped_pools: Vec<BreakerStatus>, // Pool breakers that are open or half-open
     pub last_updated: u64,
 }
 
+/// State of a circuit breaker
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,   // Calls flow normally
+    Open,     // Calls are refused until the cool-down elapses
+    HalfOpen, // A single probe call is let through to test recovery
+}
+
+/// What a circuit breaker guards
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BreakerTarget {
+    Venue(Principal), // Factory or router canister of an exchange
+    Pool(Principal),  // Swap pool canister
+}
+
+/// Snapshot of one circuit breaker
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BreakerStatus {
+    pub target: BreakerTarget,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub opened_at: Option<u64>, // Seconds since the epoch
+    pub retry_at: Option<u64>,  // When an open breaker lets a probe through
+    pub last_error: Option<String>,
+}
+
+/// Thresholds of the circuit breakers applied to a connector's venue and pools
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32, // Consecutive call failures that open a breaker
+    pub open_secs: u64,         // Cool-down before a half-open probe is allowed
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 300,
+        }
+    }
+}
+
+/// Kind of order an exchange can execute
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum OrderKind {
+    Market,  // Immediate swap at the pool price
+    Limit,   // Resting order filled at a set price
+}
+
+/// Features supported by an exchange connector
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExchangeCapabilities {
+    pub exchange_type: ExchangeType,
+    pub supports_liquidity: bool,       // add_liquidity / remove_liquidity
+    pub supports_batch_trades: bool,
+    pub supports_call_trades: bool,     // Swaps funded from a balance already deposited in the pool, with or without slippage checks
+    pub supports_subaccounts: bool,     // Trading from a non-default subaccount
+    pub supports_trade_history: bool,
+    pub token_standards: Vec<TokenStandard>,
+    pub order_kinds: Vec<OrderKind>,
+}
+
+impl ExchangeCapabilities {
+    /// Whether tokens of the given standard can be traded
+    pub fn supports_standard(&self, standard: &TokenStandard) -> bool {
+        self.token_standards.contains(standard)
+    }
+
+    /// Whether both tokens of a pair can be traded
+    pub fn supports_pair(&self, pair: &TradingPair) -> bool {
+        self.supports_standard(&pair.base_token.standard) && self.supports_standard(&pair.quote_token.standard)
+    }
+}
+
 /// Configuration for an exchange connector
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ExchangeConfig {
@@ -107,6 +410,40 @@ pub struct ExchangeConfig {
     pub max_slippage: f64,
     pub timeout_secs: u64,
     pub retry_count: u8,
+    pub rate_limit: Option<RateLimitConfig>, // None disables call limiting
+    pub circuit_breaker: Option<CircuitBreakerConfig>, // None never trips
+}
+
+/// What happens to a call that finds its bucket empty
+///
+/// Queueing sleeps on a timer, which an update call cannot survive (see `Runtime::sleep`);
+/// only connectors driven from timer or scheduler jobs should queue.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RateLimitMode {
+    FailFast,                    // Return ExchangeError::RateLimit immediately
+    Queue { max_wait_secs: u64 }, // Wait for a token, failing if the wait would exceed the limit
+}
+
+/// Token-bucket limits applied to calls made by a connector
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    pub pool_capacity: u32,        // Burst of calls allowed against a single pool canister
+    pub pool_refill_per_sec: f64,  // Sustained calls per second against a single pool canister
+    pub ledger_capacity: u32,      // Burst of calls allowed against a single token ledger
+    pub ledger_refill_per_sec: f64, // Sustained calls per second against a single token ledger
+    pub mode: RateLimitMode,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            pool_capacity: 10,
+            pool_refill_per_sec: 2.0,
+            ledger_capacity: 20,
+            ledger_refill_per_sec: 5.0,
+            mode: RateLimitMode::FailFast,
+        }
+    }
 }
 
 /// Parameters for executing multiple trades in a batch
@@ -132,7 +469,7 @@ pub struct TradeHistory {
     pub direction: TradeDirection,
     pub input_amount: u128,
     pub output_amount: u128,
-    pub price: f64,
+    pub price: f64,         // Quote tokens per base token in human units
     pub timestamp: u64,
     pub status: TradeStatus,
     pub transaction_id: Option<String>,
@@ -167,4 +504,42 @@ pub struct LiquidityResult {
     pub pool_id: Principal,
     pub transaction_id: Option<String>,
     pub timestamp: u64,
-} 
\ No newline at end of file
+} 
+/// Where a token holding sits
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum HoldingLocation {
+    Wallet,                                                  // Ledger balance of the account
+    PoolUnused { pool_id: Principal },                       // Deposited in a pool but not swapped or withdrawn
+    Allowance { spender: Principal },                        // Approved to a spender; still held in the wallet
+    LiquidityPosition { pool_id: Principal, position_id: u128 },
+}
+
+/// A single token holding, optionally valued in the breakdown's valuation token
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Holding {
+    pub token: TokenInfo,
+    pub location: HoldingLocation,
+    pub amount: u128,
+    pub value: Option<u128>,  // In raw units of the valuation token; None when no price is available
+}
+
+/// Everything an account holds on an exchange, valued in a single quote token
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HoldingsBreakdown {
+    pub account: Account,
+    pub valuation_token: TokenInfo,
+    pub holdings: Vec<Holding>,
+    pub total_value: u128,               // Sum of priced holdings, excluding allowances
+    pub unpriced_tokens: Vec<TokenInfo>, // Tokens that could not be valued and are missing from the total
+    pub unreadable_pools: Vec<PoolReadError>, // Pools whose holdings are missing from the breakdown
+    pub timestamp: u64,
+}
+
+/// A pool whose holdings could not be read
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PoolReadError {
+    pub pool_id: Option<Principal>, // None when the pool lookup itself failed
+    pub token0: Principal,
+    pub token1: Principal,
+    pub error: String,
+}
@@ -2,7 +2,7 @@ use candid::Principal;
 use crate::types::*;
 use crate::error::*;
 use std::time::{SystemTime, UNIX_EPOCH};
-use ic_cdk::api::time as ic_time;
+use strategy_common::runtime::canister_runtime;
 
 /// Converts a Principal to a Blob representation for subaccounts.
 pub fn principal_to_subaccount(principal: &Principal) -> Vec<u8> {
@@ -19,6 +19,16 @@ pub fn principal_to_subaccount(principal: &Principal) -> Vec<u8> {
     default_arr
 }
 
+/// Derives the subaccount a principal owns inside another account (e.g. an ICPSwap pool).
+pub fn subaccount_from_principal(principal: &Principal) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount.copy_from_slice(&principal_to_subaccount(principal));
+    subaccount
+}
+
+/// Estimated cycles charged per inter-canister call made by a connector (call fee plus execution).
+pub const ESTIMATED_CYCLES_PER_CALL: u128 = 2_000_000;
+
 /// Calculates the slippage percentage.
 pub fn calculate_slippage(expected: u128, actual: u128) -> f64 {
     if expected == 0 {
@@ -35,6 +45,17 @@ pub fn calculate_slippage(expected: u128, actual: u128) -> f64 {
     ((expected_f - actual_f) / expected_f) * 100.0
 }
 
+/// Swap fee kept by a pool charging `fee_ppm` on `amount_in`, rounded up like on-chain pools.
+pub fn pool_fee_amount(amount_in: u128, fee_ppm: u64) -> u128 {
+    let fee = amount_in.saturating_mul(fee_ppm as u128);
+    fee / 1_000_000 + if fee % 1_000_000 > 0 { 1 } else { 0 }
+}
+
+/// Transaction ID for a trade: the last ledger block it created, as "<ledger>:<block>".
+pub fn ledger_transaction_id(transactions: &[LedgerTransaction]) -> Option<String> {
+    transactions.last().map(|tx| format!("{}:{}", tx.ledger, tx.block_index))
+}
+
 /// Checks if the slippage exceeds the tolerance.
 pub fn is_slippage_exceeded(expected: u128, actual: u128, tolerance: f64) -> bool {
     calculate_slippage(expected, actual) > tolerance
@@ -42,12 +63,12 @@ pub fn is_slippage_exceeded(expected: u128, actual: u128, tolerance: f64) -> boo
 
 /// Gets the current timestamp in seconds.
 pub fn current_timestamp_secs() -> u64 {
-    ic_time() / 1_000_000_000
+    canister_runtime().time() / 1_000_000_000
 }
 
 /// Gets the current timestamp in nanoseconds.
 pub fn current_timestamp_nanos() -> u64 {
-    ic_time()
+    canister_runtime().time()
 }
 
 /// Generates a unique trade ID.
@@ -78,6 +99,11 @@ pub fn amount_from_human_readable(amount: f64, decimals: u8) -> u128 {
 
 /// Validates the trade parameters.
 pub fn validate_trade_params(params: &TradeParams) -> ExchangeResult<()> {
+    validate_trade_params_at(params, current_timestamp_secs())
+}
+
+/// Validates trade parameters against the given current time (in seconds).
+pub fn validate_trade_params_at(params: &TradeParams, now_secs: u64) -> ExchangeResult<()> {
     // Validate amount
     if params.amount == 0 {
         return Err(ExchangeError::InvalidAmount);
@@ -90,7 +116,7 @@ pub fn validate_trade_params(params: &TradeParams) -> ExchangeResult<()> {
     
     // Validate deadline (if it exists)
     if let Some(deadline) = params.deadline_secs {
-        if deadline < current_timestamp_secs() {
+        if deadline < now_secs {
             return Err(ExchangeError::InvalidParameters("Deadline has passed".to_string()));
         }
     }
@@ -0,0 +1,296 @@
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_ledger_types::{AccountIdentifier, Operation, QueryBlocksResponse};
+use strategy_common::debug_log;
+use strategy_common::runtime::{self, Runtime};
+
+use crate::error::*;
+use crate::types::*;
+
+/// Recent blocks scanned when locating a transfer made by a pool
+pub const DEFAULT_LOOKBACK_BLOCKS: u64 = 100;
+
+/// A transfer expected to appear on a ledger
+#[derive(Clone, Debug)]
+pub struct ExpectedTransfer {
+    pub block_index: Option<u128>,   // Known for our own transfers; None to search recent blocks
+    pub from_owner: Option<Principal>, // Sender to match when searching
+    pub to: Account,
+    pub amounts: Vec<u128>,          // Acceptable amounts; the first is the one reported
+    pub memo: Option<Vec<u8>>,
+    pub check_memo: bool,            // False when the memo was chosen by someone else
+}
+
+/// Transfer fields read from a ledger block, with accounts as account identifiers
+#[derive(Clone, Debug)]
+struct BlockTransfer {
+    from: Option<String>,
+    to: String,
+    amount: u128,
+    memo: Option<Vec<u8>>,
+}
+
+/// ICRC-3 generic block value
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum ICRC3Value {
+    Blob(serde_bytes::ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<ICRC3Value>),
+    Map(Vec<(String, ICRC3Value)>),
+}
+
+/// ICRC-3 block range request
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ICRC3GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+/// ICRC-3 block with its index
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ICRC3BlockWithId {
+    id: Nat,
+    block: ICRC3Value,
+}
+
+/// ICRC-3 block range reply (archived ranges are not followed)
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ICRC3GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<ICRC3BlockWithId>,
+}
+
+impl ICRC3Value {
+    fn field(&self, name: &str) -> Option<&ICRC3Value> {
+        match self {
+            ICRC3Value::Map(entries) => entries.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_text(&self) -> Option<&str> {
+        match self {
+            ICRC3Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_u128(&self) -> Option<u128> {
+        match self {
+            ICRC3Value::Nat(nat) => u128::try_from(nat.0.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            ICRC3Value::Blob(blob) => Some(blob.as_slice()),
+            _ => None,
+        }
+    }
+
+    // ICRC-3 encodes an account as [owner] or [owner, subaccount]
+    fn as_account_id(&self) -> Option<String> {
+        let parts = match self {
+            ICRC3Value::Array(parts) => parts,
+            _ => return None,
+        };
+        let owner = Principal::from_slice(parts.first()?.as_blob()?);
+        let mut subaccount = [0u8; 32];
+        if let Some(bytes) = parts.get(1).and_then(|part| part.as_blob()) {
+            if bytes.len() != 32 {
+                return None;
+            }
+            subaccount.copy_from_slice(bytes);
+        }
+        Some(account_id(&Account::with_subaccount(owner, Some(subaccount))))
+    }
+}
+
+/// Account identifier (hex) of an account, used to compare ICRC and ICP destinations alike
+pub fn account_id(account: &Account) -> String {
+    AccountIdentifier::new(&account.owner, &ic_ledger_types::Subaccount(account.effective_subaccount())).to_string()
+}
+
+/// Memo bytes of a legacy ICP memo, as they are compared against blocks
+pub fn icp_memo_bytes(memo: u64) -> Vec<u8> {
+    memo.to_be_bytes().to_vec()
+}
+
+// Read the transfer recorded in an ICRC-3 block; None when the block is not a transfer
+fn icrc3_transfer(block: &ICRC3Value) -> Option<BlockTransfer> {
+    let tx = block.field("tx")?;
+    let op = tx.field("op").and_then(|op| op.as_text());
+    let btype = block.field("btype").and_then(|btype| btype.as_text());
+    if op != Some("xfer") && !matches!(btype, Some("1xfer") | Some("2xfer")) {
+        return None;
+    }
+    Some(BlockTransfer {
+        from: tx.field("from").and_then(|from| from.as_account_id()),
+        to: tx.field("to")?.as_account_id()?,
+        amount: tx.field("amt")?.as_u128()?,
+        memo: tx.field("memo").and_then(|memo| memo.as_blob()).map(|memo| memo.to_vec()),
+    })
+}
+
+// Fetch a range of ICRC-3 blocks as (index, transfer) pairs, skipping non-transfers
+async fn icrc3_transfers(runtime: &dyn Runtime, ledger: Principal, start: u128, length: u64) -> ExchangeResult<(u128, Vec<(u128, BlockTransfer)>)> {
+    let args = vec![ICRC3GetBlocksArgs { start: Nat::from(start), length: Nat::from(length) }];
+    let result: Result<(ICRC3GetBlocksResult,), _> = runtime::call(runtime, ledger, "icrc3_get_blocks", (args,)).await;
+    let (reply,) = result.map_err(|(code, msg)| {
+        ExchangeError::CanisterCallError(format!("Failed to call icrc3_get_blocks: {:?} - {}", code, msg))
+    })?;
+    let log_length = u128::try_from(reply.log_length.0.clone()).unwrap_or(0);
+    let transfers = reply
+        .blocks
+        .iter()
+        .filter_map(|block| {
+            let index = u128::try_from(block.id.0.clone()).ok()?;
+            icrc3_transfer(&block.block).map(|transfer| (index, transfer))
+        })
+        .collect();
+    Ok((log_length, transfers))
+}
+
+// Fetch a range of ICP ledger blocks as (index, transfer) pairs, skipping non-transfers
+async fn icp_transfers(runtime: &dyn Runtime, ledger: Principal, start: u64, length: u64) -> ExchangeResult<(u128, Vec<(u128, BlockTransfer)>)> {
+    let args = ic_ledger_types::GetBlocksArgs { start, length };
+    let result: Result<(QueryBlocksResponse,), _> = runtime::call(runtime, ledger, "query_blocks", (args,)).await;
+    let (reply,) = result.map_err(|(code, msg)| {
+        ExchangeError::CanisterCallError(format!("Failed to call query_blocks: {:?} - {}", code, msg))
+    })?;
+    let transfers = reply
+        .blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(offset, block)| {
+            let index = reply.first_block_index as u128 + offset as u128;
+            match &block.transaction.operation {
+                Some(Operation::Transfer { from, to, amount, .. }) => Some((index, BlockTransfer {
+                    from: Some(from.to_string()),
+                    to: to.to_string(),
+                    amount: amount.e8s() as u128,
+                    memo: Some(match &block.transaction.icrc1_memo {
+                        Some(memo) => memo.to_vec(),
+                        None => icp_memo_bytes(block.transaction.memo.0),
+                    }),
+                })),
+                _ => None,
+            }
+        })
+        .collect();
+    Ok((reply.chain_length as u128, transfers))
+}
+
+// Fetch transfers in [start, start + length) from whichever block API the token's ledger offers
+async fn ledger_transfers(runtime: &dyn Runtime, token: &TokenInfo, start: u128, length: u64) -> ExchangeResult<(u128, Vec<(u128, BlockTransfer)>)> {
+    match token.standard {
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => icrc3_transfers(runtime, token.canister_id, start, length).await,
+        TokenStandard::ICP => icp_transfers(runtime, token.canister_id, start as u64, length).await,
+        TokenStandard::DIP20 | TokenStandard::EXT => Err(ExchangeError::InvalidTokenStandard),
+    }
+}
+
+// Differences between a block and the transfer we expected it to record
+fn discrepancies(transfer: &BlockTransfer, expected: &ExpectedTransfer) -> Vec<String> {
+    let mut found = Vec::new();
+    let expected_to = account_id(&expected.to);
+    if transfer.to != expected_to {
+        found.push(format!("destination {} does not match expected {}", transfer.to, expected_to));
+    }
+    if !expected.amounts.contains(&transfer.amount) {
+        found.push(format!("amount {} does not match expected {:?}", transfer.amount, expected.amounts));
+    }
+    if expected.check_memo && transfer.memo != expected.memo {
+        found.push(format!("memo {:?} does not match expected {:?}", transfer.memo, expected.memo));
+    }
+    found
+}
+
+/// Whether the token's ledger exposes blocks that transfers can be checked against
+pub fn has_block_log(standard: &TokenStandard) -> bool {
+    !matches!(standard, TokenStandard::DIP20 | TokenStandard::EXT)
+}
+
+/// Check a transfer against the ledger, by block index when known, otherwise by scanning recent blocks
+pub async fn verify_transfer(runtime: &dyn Runtime, token: &TokenInfo, expected: &ExpectedTransfer, lookback: u64) -> TransferCheck {
+    let verification = if !has_block_log(&token.standard) {
+        TransferVerification::Unsupported
+    } else {
+        match expected.block_index {
+            Some(block_index) => verify_block(runtime, token, expected, block_index).await,
+            None => locate_transfer(runtime, token, expected, lookback).await,
+        }
+    };
+    if !matches!(verification, TransferVerification::Verified { .. }) {
+        debug_log!("Transfer on {} not verified: {:?}", token.canister_id, verification);
+    }
+    TransferCheck {
+        ledger: token.canister_id,
+        block_index: match &verification {
+            TransferVerification::Verified { block_index } | TransferVerification::Mismatch { block_index, .. } => Some(*block_index),
+            _ => expected.block_index,
+        },
+        to: expected.to.clone(),
+        amount: expected.amounts.first().copied().unwrap_or(0),
+        memo: expected.memo.clone(),
+        verification,
+    }
+}
+
+// Compare the block at a known index with the expected transfer
+async fn verify_block(runtime: &dyn Runtime, token: &TokenInfo, expected: &ExpectedTransfer, block_index: u128) -> TransferVerification {
+    let transfers = match ledger_transfers(runtime, token, block_index, 1).await {
+        Ok((_, transfers)) => transfers,
+        Err(e) => return TransferVerification::Failed(e.to_string()),
+    };
+    match transfers.iter().find(|(index, _)| *index == block_index) {
+        Some((_, transfer)) => {
+            let discrepancies = discrepancies(transfer, expected);
+            if discrepancies.is_empty() {
+                TransferVerification::Verified { block_index }
+            } else {
+                TransferVerification::Mismatch { block_index, discrepancies }
+            }
+        }
+        None => TransferVerification::NotFound,
+    }
+}
+
+// Search the most recent blocks for a transfer matching the expected one, newest first
+async fn locate_transfer(runtime: &dyn Runtime, token: &TokenInfo, expected: &ExpectedTransfer, lookback: u64) -> TransferVerification {
+    // A zero-length request only reports the current log length
+    let log_length = match ledger_transfers(runtime, token, 0, 0).await {
+        Ok((log_length, _)) => log_length,
+        Err(e) => return TransferVerification::Failed(e.to_string()),
+    };
+    let start = log_length.saturating_sub(lookback as u128);
+    let transfers = match ledger_transfers(runtime, token, start, lookback).await {
+        Ok((_, transfers)) => transfers,
+        Err(e) => return TransferVerification::Failed(e.to_string()),
+    };
+
+    let expected_to = account_id(&expected.to);
+    let expected_from = expected.from_owner.map(|owner| account_id(&Account::new(owner)));
+    let matches_parties = |transfer: &BlockTransfer| {
+        transfer.to == expected_to && (expected_from.is_none() || transfer.from == expected_from)
+    };
+    // Prefer an exact amount match, since concurrent trades may send to the same account
+    let candidate = transfers
+        .iter()
+        .rev()
+        .find(|(_, transfer)| matches_parties(transfer) && expected.amounts.contains(&transfer.amount))
+        .or_else(|| transfers.iter().rev().find(|(_, transfer)| matches_parties(transfer)));
+    match candidate {
+        Some((block_index, transfer)) => {
+            let discrepancies = discrepancies(transfer, expected);
+            if discrepancies.is_empty() {
+                TransferVerification::Verified { block_index: *block_index }
+            } else {
+                TransferVerification::Mismatch { block_index: *block_index, discrepancies }
+            }
+        }
+        None => TransferVerification::NotFound,
+    }
+}
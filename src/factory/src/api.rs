@@ -1,6 +1,7 @@
 use candid::Principal;
 use ic_cdk::api::caller;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use strategy_common::cycles::CyclesRefillReceipt;
 use strategy_common::types::{
     DCAConfig, DeploymentRecord, DeploymentRequest,
     FixedBalanceConfig, LimitOrderConfig, SelfHedgingConfig,
@@ -19,6 +20,7 @@ use crate::state::{
     UserAccount, TransactionRecord, WasmModule,
 };
 use crate::deployment_manager;
+use crate::cycles_refill;
 use crate::timer;
 
 // Maximum transaction limit for queries to prevent DoS
@@ -269,6 +271,12 @@ fn get_cycles_balance() -> u64 {
     ic_cdk::api::canister_balance()
 }
 
+// Called by a strategy canister running low on cycles; the top-up is billed to its owner
+#[update]
+async fn request_cycles_refill(amount: u64) -> Result<CyclesRefillReceipt, String> {
+    cycles_refill::process_refill(caller(), amount).await
+}
+
 #[update]
 async fn withdraw_icp(recipient: Principal, amount_e8s: u64) -> Result<(), String> {
     require_admin()?;
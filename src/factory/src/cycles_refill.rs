@@ -0,0 +1,175 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use strategy_common::cycles::CyclesRefillReceipt;
+use strategy_common::types::StrategyStatus;
+
+use crate::payment::PAYMENT_CONFIG;
+use crate::state::{
+    get_strategy_metadata, check_user_balance, update_user_balance, reverse_user_charge,
+    record_transaction, TransactionType,
+};
+
+// Cycles minting canister, source of the ICP/XDR rate used to price top-ups
+const CYCLES_MINTING_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+
+// Refill limits
+pub struct RefillConfig {
+    pub max_refill_cycles: u64,    // Largest single top-up
+    pub min_interval_secs: u64,    // Minimum time between top-ups of one strategy
+    pub reserve_cycles: u64,       // Factory balance never spent on refills
+}
+
+// Global refill configuration
+pub static REFILL_CONFIG: RefillConfig = RefillConfig {
+    max_refill_cycles: 2_000_000_000_000,   // 2T cycles
+    min_interval_secs: 3_600,               // 1 hour
+    reserve_cycles: 2_000_000_000_000,      // Keep 2T cycles for deployments
+};
+
+// ICP/XDR rate as returned by the cycles minting canister
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+struct IcpXdrConversionRate {
+    timestamp_seconds: u64,
+    xdr_permyriad_per_icp: u64,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+struct IcpXdrConversionRateResponse {
+    data: IcpXdrConversionRate,
+    hash_tree: Vec<u8>,
+    certificate: Vec<u8>,
+}
+
+thread_local! {
+    // Last top-up per strategy canister; resets on upgrade, which only relaxes the interval once
+    static LAST_REFILL: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+}
+
+// Fetch the current ICP/XDR rate from the cycles minting canister
+async fn icp_xdr_rate() -> Result<u64, String> {
+    let cmc = Principal::from_text(CYCLES_MINTING_CANISTER_ID)
+        .map_err(|e| format!("Invalid cycles minting canister ID: {}", e))?;
+    let result: Result<(IcpXdrConversionRateResponse,), _> =
+        ic_cdk::call(cmc, "get_icp_xdr_conversion_rate", ()).await;
+    match result {
+        Ok((response,)) if response.data.xdr_permyriad_per_icp > 0 => Ok(response.data.xdr_permyriad_per_icp),
+        Ok(_) => Err("Cycles minting canister returned a zero ICP/XDR rate".to_string()),
+        Err((code, msg)) => Err(format!("Failed to get ICP/XDR rate: {:?} {}", code, msg)),
+    }
+}
+
+// Status the strategy reports for itself; the registry only records it at deployment
+async fn strategy_status(strategy: Principal) -> Result<StrategyStatus, String> {
+    let result: Result<(StrategyStatus,), _> = ic_cdk::call(strategy, "get_status", ()).await;
+    match result {
+        Ok((status,)) => Ok(status),
+        Err((code, msg)) => Err(format!("Failed to query status of strategy {}: {:?} {}", strategy.to_text(), code, msg)),
+    }
+}
+
+/// ICP cost in e8s of `cycles`, rounded up
+///
+/// One XDR buys 1T cycles, so one ICP buys `xdr_permyriad_per_icp * 10^8` cycles
+/// and each e8s buys `xdr_permyriad_per_icp` cycles.
+pub fn cycles_cost_e8s(cycles: u64, xdr_permyriad_per_icp: u64) -> u64 {
+    cycles.div_ceil(xdr_permyriad_per_icp.max(1))
+}
+
+/// Top up a registered strategy canister and bill its owner
+///
+/// The owner's balance is charged before the deposit and refunded if the
+/// deposit fails, so a concurrent request cannot spend the same balance twice.
+pub async fn process_refill(strategy: Principal, requested_cycles: u64) -> Result<CyclesRefillReceipt, String> {
+    // Only registered strategies can be refilled, and only by themselves
+    let metadata = get_strategy_metadata(strategy)
+        .ok_or_else(|| format!("Caller {} is not a registered strategy", strategy.to_text()))?;
+    if requested_cycles == 0 {
+        return Err("Refill amount must be greater than 0".to_string());
+    }
+    let cycles = requested_cycles.min(REFILL_CONFIG.max_refill_cycles);
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let last_refill = LAST_REFILL.with(|last| last.borrow().get(&strategy).copied());
+    if let Some(last_refill) = last_refill {
+        if now < last_refill.saturating_add(REFILL_CONFIG.min_interval_secs) {
+            return Err(format!(
+                "Strategy was refilled at {}; next refill allowed after {}",
+                last_refill, last_refill + REFILL_CONFIG.min_interval_secs
+            ));
+        }
+    }
+
+    // Claim the interval before any await so concurrent requests are refused;
+    // released again if the refill does not go through
+    LAST_REFILL.with(|last| last.borrow_mut().insert(strategy, now));
+    let result = refill_and_bill(strategy, metadata.owner, cycles).await;
+    if result.is_err() {
+        LAST_REFILL.with(|last| {
+            let mut last = last.borrow_mut();
+            match last_refill {
+                Some(previous) => last.insert(strategy, previous),
+                None => last.remove(&strategy),
+            }
+        });
+    }
+    result
+}
+
+// Price, bill and deposit one top-up
+async fn refill_and_bill(strategy: Principal, owner: Principal, cycles: u64) -> Result<CyclesRefillReceipt, String> {
+    if strategy_status(strategy).await? == StrategyStatus::Terminated {
+        return Err("Terminated strategies are not refilled".to_string());
+    }
+
+    // Verify the factory can spare the cycles
+    let factory_balance = ic_cdk::api::canister_balance();
+    let required = (cycles as u128)
+        + (REFILL_CONFIG.reserve_cycles as u128)
+        + (PAYMENT_CONFIG.min_cycles_balance as u128);
+    if (factory_balance as u128) < required {
+        return Err(format!(
+            "Factory cycles balance too low for refill. Current balance: {} cycles, required: {} cycles",
+            factory_balance, required
+        ));
+    }
+
+    // Price the top-up and bill the owner
+    let rate = icp_xdr_rate().await?;
+    let cost_e8s = cycles_cost_e8s(cycles, rate);
+    if !check_user_balance(owner, cost_e8s)? {
+        return Err(format!(
+            "Owner balance too low for refill: {:.8} ICP required",
+            cost_e8s as f64 / 100_000_000.0
+        ));
+    }
+    let owner_balance_e8s = update_user_balance(owner, cost_e8s, false)?;
+
+    ic_cdk::println!(
+        "Refilling strategy {} with {} cycles for {} e8s billed to {}",
+        strategy.to_text(), cycles, cost_e8s, owner.to_text()
+    );
+    if let Err((code, msg)) = deposit_cycles(CanisterIdRecord { canister_id: strategy }, cycles as u128).await {
+        // Nothing was deposited, so return the charge
+        reverse_user_charge(owner, cost_e8s);
+        return Err(format!("Failed to deposit cycles: {:?} {}", code, msg));
+    }
+
+    record_transaction(
+        owner,
+        cost_e8s,
+        TransactionType::CyclesRefill,
+        format!("Cycles refill of {} cycles for strategy {} (rate {} XDR permyriad/ICP)", cycles, strategy.to_text(), rate),
+    ).await;
+
+    Ok(CyclesRefillReceipt {
+        canister_id: strategy,
+        cycles,
+        cost_e8s,
+        owner_balance_e8s,
+    })
+}
@@ -1,4 +1,5 @@
 pub mod api;
+pub mod cycles_refill;
 pub mod deployment_manager;
 pub mod payment;
 pub mod state;
@@ -47,6 +47,7 @@ pub enum TransactionType {
     AdminAdjustment,
     Withdrawal,
     Transfer,
+    CyclesRefill,
 }
 
 // Transaction record for financial history
@@ -620,6 +621,16 @@ pub fn update_user_balance(user: Principal, amount: u64, is_deposit: bool) -> Re
     Ok(account.balance)
 }
 
+// Return a charge taken by `update_user_balance` whose purchase did not go through;
+// unlike a deposit it leaves the deposit totals alone and takes the charge off the consumed total
+pub fn reverse_user_charge(user: Principal, amount: u64) -> u64 {
+    let mut account = get_user_account(user);
+    account.balance = account.balance.saturating_add(amount);
+    account.total_consumed = account.total_consumed.saturating_sub(amount);
+    store_user_account(account.clone());
+    account.balance
+}
+
 // Check if user has sufficient balance
 pub fn check_user_balance(user: Principal, amount: u64) -> Result<bool, String> {
     Ok(get_user_account(user).balance >= amount)
@@ -854,4 +865,48 @@ pub async fn process_balance_transfer(from: Principal, to: Principal, amount: u6
     ).await;
     
     Ok(())
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn funded_account(id: u8, balance: u64) -> Principal {
+        let owner = Principal::from_slice(&[id]);
+        store_user_account(UserAccount {
+            owner,
+            balance,
+            last_deposit: 1,
+            total_deposited: balance,
+            total_consumed: 0,
+        });
+        owner
+    }
+
+    #[test]
+    fn reversed_charge_restores_the_balance_without_counting_a_deposit() {
+        let owner = funded_account(1, 1_000_000);
+        assert_eq!(update_user_balance(owner, 400_000, false).unwrap(), 600_000);
+
+        // A refill whose deposit failed hands the charge back
+        assert_eq!(reverse_user_charge(owner, 400_000), 1_000_000);
+
+        let account = get_user_account(owner);
+        assert_eq!(account.balance, 1_000_000);
+        assert_eq!(account.total_deposited, 1_000_000);
+        assert_eq!(account.total_consumed, 0);
+        assert_eq!(account.last_deposit, 1);
+    }
+
+    #[test]
+    fn reversal_keeps_earlier_consumption() {
+        let owner = funded_account(2, 1_000_000);
+        update_user_balance(owner, 250_000, false).unwrap();
+        update_user_balance(owner, 400_000, false).unwrap();
+
+        reverse_user_charge(owner, 400_000);
+
+        let account = get_user_account(owner);
+        assert_eq!(account.balance, 750_000);
+        assert_eq!(account.total_consumed, 250_000);
+    }
+}
@@ -0,0 +1,53 @@
+// Minimal strategy built on the shared lifecycle: it only counts its ticks, which
+// makes it a template for new strategies and a compile check for `strategy_canister!`
+
+use async_trait::async_trait;
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use strategy_common::runtime::canister_runtime;
+use strategy_common::strategy::Strategy;
+
+/// Configuration of the example strategy
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExampleConfig {
+    pub interval_secs: u64,
+}
+
+/// State kept between ticks
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ExampleState {
+    pub ticks: u64,
+    pub last_tick: Option<u64>,  // Nanoseconds since the epoch
+}
+
+/// Strategy that records each tick and does nothing else
+pub struct ExampleStrategy;
+
+#[async_trait(?Send)]
+impl Strategy for ExampleStrategy {
+    type Config = ExampleConfig;
+    type State = ExampleState;
+
+    const TIMER_ID: &'static str = "example_strategy";
+
+    fn validate(config: &ExampleConfig) -> Result<(), String> {
+        if config.interval_secs == 0 {
+            return Err("Interval must be at least one second".to_string());
+        }
+        Ok(())
+    }
+
+    fn interval_secs(config: &ExampleConfig) -> u64 {
+        config.interval_secs
+    }
+
+    async fn on_tick(_config: ExampleConfig, state: ExampleState) -> Result<ExampleState, String> {
+        Ok(ExampleState {
+            ticks: state.ticks + 1,
+            last_tick: Some(canister_runtime().time()),
+        })
+    }
+}
+
+strategy_common::strategy_canister!(ExampleStrategy, init_example);
@@ -1,25 +1,59 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::{caller, canister_balance, time};
+use ic_cdk::api::canister_balance;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
 use ic_stable_structures::storable::Storable;
 use std::cell::RefCell;
+use std::sync::Arc;
 use strategy_common::types::{
     OrderSplitType, SelfHedgingConfig, StrategyResult, StrategyStatus, TradingPair, TokenMetadata
 };
-use strategy_common::timer::{self, TimerConfig};
-use exchange::{types as exchange_types, LiquidityPool, TokenInfo};
+use strategy_common::cycles::{self, CyclesConfig};
+use strategy_common::lease::{self, LeaseGuard};
+use strategy_common::scheduler::{self, MissedRunPolicy, Schedule};
+use strategy_common::runtime::Runtime;
+use exchange::{types as exchange_types, TokenInfo};
 use exchange::error as exchange_error;
-use exchange::icpswap::ICPSwapConnector;
-use exchange::traits::{Exchange, Trading, TokenOperations};
+use exchange::factory::{ExchangeFactory, ExchangeHandle};
+use exchange::simulated::{PricePath, SimulatedPoolSeed};
+use exchange::circuit_breaker;
+use exchange::journal::{self, JournalEntry};
+use exchange::metering::{self, MeteredOperation, OperationCostReport};
+use exchange::oracle::{self, OracleConfig, PriceFeed, PriceObservation};
+use exchange::pricing;
+use exchange::trigger_orders::{self, TriggerOrder, TriggerOrderRequest};
+use exchange::traits::{Exchange, ExchangeConnector, Trading, TokenOperations};
 
 // Type definitions for stable storage
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-// Constant for timer ID
+// Constant for the execution job ID
 const EXECUTION_TIMER_ID: &str = "self_hedging_execution";
 
+// Memory IDs for stable storage
+const STATE_MEMORY_ID: u8 = 0;
+const SCHEDULER_MEMORY_ID: u8 = 1;
+const LEASE_MEMORY_ID: u8 = 2;
+const CYCLES_MEMORY_ID: u8 = 3;
+const JOURNAL_MEMORY_ID: u8 = 4;
+const ORACLE_FEEDS_MEMORY_ID: u8 = 5;
+const ORACLE_OBSERVATIONS_MEMORY_ID: u8 = 6;
+const TRIGGER_ORDERS_MEMORY_ID: u8 = 7;
+
+// How often open trigger orders are checked against the pool price
+const TRIGGER_CHECK_INTERVAL_SECS: u64 = 60;
+
+// Execution lease; a holder that stops heartbeating loses it after this long
+const EXECUTION_LEASE_ID: &str = "self_hedging_execution";
+const EXECUTION_LEASE_SECS: u64 = 300;
+
+// How often the cycles balance is checked against the refill threshold
+const CYCLES_CHECK_INTERVAL_SECS: u64 = 3_600;
+
+// Runway below which execution pauses instead of risking a freeze mid-trade
+const MIN_CYCLES_RUNWAY_SECS: u64 = 6 * 3_600;
+
 // State structure
 #[derive(CandidType, Deserialize, Clone, Debug)]
 struct SelfHedgingState {
@@ -34,6 +68,8 @@ struct SelfHedgingState {
     base_token_unused_balance: u128,
     quote_token_unused_balance: u128,
     last_balance_check: Option<u64>,
+    last_price: Option<exchange_types::NormalizedPrice>, // Base/quote price of the most recent trade
+    cycles_config: Option<CyclesConfig>, // Refill settings; the canister that initialized the strategy tops it up
 }
 
 // Implement Storable for SelfHedgingState
@@ -58,7 +94,7 @@ thread_local! {
 
     static STATE: RefCell<StableCell<SelfHedgingState, Memory>> = RefCell::new(
         MEMORY_MANAGER.with(|mm| {
-            let memory = mm.borrow().get(MemoryId::new(0));
+            let memory = mm.borrow().get(MemoryId::new(STATE_MEMORY_ID));
             StableCell::init(
                 memory,
                 SelfHedgingState {
@@ -96,19 +132,18 @@ thread_local! {
                     base_token_unused_balance: 0,
                     quote_token_unused_balance: 0,
                     last_balance_check: None,
+                    last_price: None,
+                    cycles_config: None,
                 }
             ).expect("Failed to initialize stable cell")
         })
     );
-
-    // Add a thread-safe execution status flag
-    static EXECUTION_IN_PROGRESS: RefCell<bool> = RefCell::new(false);
 }
 
 // Helper function to check if caller is the owner
 fn verify_owner() -> Result<(), String> {
     let caller = caller();
-    let id = ic_cdk::id();
+    let id = runtime().id();
     STATE.with(|state| {
         let state = state.borrow();
         let state_data = state.get();
@@ -139,6 +174,147 @@ fn verify_status(expected_statuses: &[StrategyStatus]) -> Result<(), String> {
 #[init]
 fn init() {
     // Initialization will be handled by init_self_hedging
+    open_lease_table();
+    open_scheduler();
+    open_journal();
+    open_oracle();
+    open_trigger_orders();
+    open_cycles_refill();
+}
+
+// Open the stable lease table guarding executions
+fn open_lease_table() {
+    let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(LEASE_MEMORY_ID)));
+    lease::init(memory);
+}
+
+// Open the stable trade journal the exchange connectors record executed trades in
+fn open_journal() {
+    let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(JOURNAL_MEMORY_ID)));
+    journal::init(memory);
+}
+
+// Open the price oracle, track the configured pair and schedule its sampling
+fn open_oracle() {
+    let feeds_memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(ORACLE_FEEDS_MEMORY_ID)));
+    let observations_memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(ORACLE_OBSERVATIONS_MEMORY_ID)));
+    oracle::init(feeds_memory, observations_memory, OracleConfig::default());
+
+    let initialized = STATE.with(|state| state.borrow().get().owner != Principal::anonymous());
+    if initialized {
+        if let Err(e) = track_price_feed() {
+            ic_cdk::println!("Failed to track the trading pair in the oracle: {}", e);
+        }
+    }
+
+    let connector_for = |pair: &exchange_types::TradingPair| exchange_factory(&pair.exchange).create_exchange(&pair.exchange);
+    if let Err(e) = oracle::schedule_sampling(runtime(), connector_for) {
+        ic_cdk::println!("Failed to schedule oracle sampling: {}", e);
+    }
+}
+
+// Open the trigger order book and schedule the price watcher
+fn open_trigger_orders() {
+    let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(TRIGGER_ORDERS_MEMORY_ID)));
+    trigger_orders::init(memory);
+    let connector_for = |pair: &exchange_types::TradingPair| exchange_factory(&pair.exchange).create_exchange(&pair.exchange);
+    if let Err(e) = trigger_orders::schedule_watching(TRIGGER_CHECK_INTERVAL_SECS, runtime(), connector_for) {
+        ic_cdk::println!("Failed to schedule the trigger order watcher: {}", e);
+    }
+}
+
+// Register the configured trading pair as an oracle feed; returns its feed ID
+fn track_price_feed() -> Result<u32, String> {
+    let pair = trading_pair_of_state()?;
+    oracle::with_oracle(|oracle| oracle.add_feed(PriceFeed { pair, sample_amount: None, reference: None }))
+        .map_err(|e| e.to_string())
+}
+
+// The configured trading pair as the exchange module's pair
+fn trading_pair_of_state() -> Result<exchange_types::TradingPair, String> {
+    let config = STATE.with(|state| state.borrow().get().config.clone());
+    Ok(create_trade_params(&config)?.pair)
+}
+
+// Quote the first hedge stage and require it to be within the oracle's bound of the TWAP
+async fn check_oracle_price(direction: exchange_types::TradeDirection, amount: u128) -> Result<u64, String> {
+    let state_data = STATE.with(|state| state.borrow().get().clone());
+    let mut params = create_trade_params(&state_data.config)?;
+    params.direction = direction;
+    params.amount = amount;
+
+    let connector = create_connector(&state_data.config.exchange)?;
+    let quote = connector.get_quote(&params).await.map_err(|e| format!("Failed to quote hedge trade: {}", e))?;
+    let price = oracle::quote_price(&params, &quote).ok_or_else(|| "Quote returned a zero amount".to_string())?;
+
+    let now = time() / 1_000_000_000;
+    oracle::with_oracle(|oracle| {
+        let feed_id = oracle.find_feed(&params.pair)
+            .ok_or_else(|| exchange_error::ExchangeError::PriceDeviation("No oracle feed for trading pair".to_string()))?;
+        oracle.check_price(feed_id, price, now)
+    })
+    .and_then(|checked| checked)
+    .map_err(|e| e.to_string())
+}
+
+// Open the persistent scheduler and register the execution handler
+fn open_scheduler() {
+    let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(SCHEDULER_MEMORY_ID)));
+    scheduler::init(memory);
+    scheduler::register_handler(EXECUTION_TIMER_ID, || async {
+        ic_cdk::println!("Execution job triggered.");
+        // Paper markets move one step per scheduled run, before the strategy reads them
+        advance_simulated_prices();
+        let result = execute_once().await;
+        ic_cdk::println!("execute_once result: {:?}", result); // Log execution result
+        match result {
+            StrategyResult::Success => Ok(()),
+            StrategyResult::Error(e) => Err(e),
+        }
+    });
+}
+
+// Step the canister's simulated pools along their price paths when paper trading
+fn advance_simulated_prices() {
+    let exchange = STATE.with(|state| state.borrow().get().config.exchange.clone());
+    if !matches!(exchange, strategy_common::types::Exchange::Simulated) {
+        return;
+    }
+    match exchange_factory(&exchange_types::ExchangeType::Simulated).create_simulated() {
+        Ok(simulator) => {
+            let moved = simulator.advance_price_paths();
+            if moved > 0 {
+                ic_cdk::println!("Moved {} simulated pools along their price paths.", moved);
+            }
+        }
+        Err(e) => ic_cdk::println!("Failed to create simulated exchange: {}", e),
+    }
+}
+
+// Open the balance samples, apply the stored cycles config and schedule the periodic
+// balance check; samples are taken even before a refill source is configured
+fn open_cycles_refill() {
+    let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(CYCLES_MEMORY_ID)));
+    cycles::open_samples(memory);
+
+    let cycles_config = STATE.with(|state| state.borrow().get().cycles_config.clone());
+    if let Some(cycles_config) = cycles_config {
+        cycles::init(cycles_config);
+    }
+    if let Err(e) = cycles::schedule_refill_checks(CYCLES_CHECK_INTERVAL_SECS) {
+        ic_cdk::println!("Failed to schedule cycles refill checks: {}", e);
+    }
+}
+
+// Schedule periodic execution; a run missed across an upgrade happens once on resume
+fn schedule_execution(check_interval: u64) -> Result<(), String> {
+    scheduler::schedule(
+        EXECUTION_TIMER_ID,
+        Schedule::Interval { seconds: check_interval },
+        MissedRunPolicy::RunOnce,
+        check_interval,
+    )
+    .map(|_| ())
 }
 
 // Helper function to convert TokenMetadata to TokenInfo
@@ -158,7 +334,7 @@ async fn init_self_hedging(owner: Principal, config: SelfHedgingConfig) -> Strat
     let caller_id = caller();
 
     ic_cdk::println!("Starting init_self_hedging: caller={}, owner={}", caller_id, owner);
-    STATE.with(|state_cell| {
+    let result = STATE.with(|state_cell| {
         let mut state_ref_mut = state_cell.borrow_mut();
         let current_state = state_ref_mut.get().clone();
 
@@ -209,6 +385,11 @@ async fn init_self_hedging(owner: Principal, config: SelfHedgingConfig) -> Strat
             base_token_unused_balance: 0,
             quote_token_unused_balance: 0,
             last_balance_check: None,
+            last_price: None,
+            cycles_config: Some(CyclesConfig {
+                refill_source: Some(caller_id),
+                ..CyclesConfig::default()
+            }),
         };
 
         ic_cdk::println!("Saving new state with owner: {}", owner);
@@ -223,7 +404,16 @@ async fn init_self_hedging(owner: Principal, config: SelfHedgingConfig) -> Strat
                 StrategyResult::Error(format!("Failed to initialize: {:?}", e))
             },
         }
-    })
+    });
+
+    // The initializing canister, normally the factory, now refills this one
+    if result == StrategyResult::Success {
+        open_cycles_refill();
+        if let Err(e) = track_price_feed() {
+            ic_cdk::println!("Failed to track the trading pair in the oracle: {}", e);
+        }
+    }
+    result
 }
 
 // Start the strategy
@@ -235,7 +425,7 @@ async fn start() -> StrategyResult {
     }
 
     // Verify the current status allows starting
-    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused,StrategyStatus::Terminated]) {
+    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused, StrategyStatus::PausedLowCycles, StrategyStatus::Terminated]) {
         return StrategyResult::Error(e);
     }
 
@@ -244,10 +434,24 @@ async fn start() -> StrategyResult {
 
     // --- Start: Added Pool Info Fetching and Token Approval ---
     ic_cdk::println!("Fetching pool info and approving tokens...");
-    let connector = create_icpswap_connector(&state_data.config.exchange);
+    let connector = match create_connector(&state_data.config.exchange) {
+        Ok(connector) => connector,
+        Err(e) => return StrategyResult::Error(e),
+    };
     let base_token_info = create_token_info(&state_data.config.trading_pair.base_token);
     let quote_token_info = create_token_info(&state_data.config.trading_pair.quote_token);
 
+    // The strategy swaps balances already deposited in the pool, for both tokens of the pair
+    let capabilities = connector.capabilities();
+    if !capabilities.supports_call_trades {
+        return StrategyResult::Error(format!("{:?} does not support swapping deposited balances", capabilities.exchange_type));
+    }
+    for token in [&base_token_info, &quote_token_info] {
+        if !capabilities.supports_standard(&token.standard) {
+            return StrategyResult::Error(format!("{:?} does not support {:?} tokens ({})", capabilities.exchange_type, token.standard, token.symbol));
+        }
+    }
+
     // Get pool info from ICPSwap connector
     // Assuming the connector trait/impl provides get_pool_info and approve_token
     // And PoolData struct with pool_id field
@@ -305,22 +509,13 @@ async fn start() -> StrategyResult {
     // Get the check interval from config
     let check_interval = state_data.config.check_interval_secs;
 
-    // Set up a timer for automatic execution
-    let timer_config = TimerConfig {
-        id: EXECUTION_TIMER_ID.to_string(),
-        interval_seconds: check_interval,
-        enabled: true,
-    };
-
-    // Setup the periodic execution timer
-    ic_cdk::println!("Setting up execution timer with interval: {}s", check_interval);
-    timer::set_timer(timer_config, || {
-        ic_cdk::spawn(async {
-            ic_cdk::println!("Execution timer triggered.");
-            let result = execute_once().await;
-            ic_cdk::println!("execute_once result: {:?}", result); // Log execution result
-        });
-    });
+    // Schedule automatic execution; the job survives upgrades
+    ic_cdk::println!("Scheduling execution job with interval: {}s", check_interval);
+    if let Err(e) = schedule_execution(check_interval) {
+        let error_msg = format!("Failed to schedule execution: {}", e);
+        ic_cdk::println!("{}", error_msg);
+        return StrategyResult::Error(error_msg);
+    }
 
     // Update the status to Running
     ic_cdk::println!("Updating strategy status to Running...");
@@ -357,8 +552,10 @@ fn pause() -> StrategyResult {
         return StrategyResult::Error(e);
     }
 
-    // Clear the execution timer
-    timer::clear_timer(EXECUTION_TIMER_ID);
+    // Cancel the execution job
+    if let Err(e) = scheduler::cancel(EXECUTION_TIMER_ID) {
+        return StrategyResult::Error(e);
+    }
 
     // Update the status to Paused
     STATE.with(|state| {
@@ -383,8 +580,10 @@ fn stop() -> StrategyResult {
 
     // Any status can be stopped
 
-    // Clear the execution timer
-    timer::clear_timer(EXECUTION_TIMER_ID);
+    // Cancel the execution job
+    if let Err(e) = scheduler::cancel(EXECUTION_TIMER_ID) {
+        return StrategyResult::Error(e);
+    }
 
     // Update the status to Terminated
     STATE.with(|state| {
@@ -399,6 +598,28 @@ fn stop() -> StrategyResult {
     })
 }
 
+// Pause because the canister is about to freeze; the owner restarts it after a top-up
+fn pause_low_cycles() -> StrategyResult {
+    let error_msg = format!(
+        "Strategy paused: cycles balance {} is close to freezing (limit {}, runway {:?}s)",
+        cycles::get_balance(), cycles::freezing_limit(), cycles::runway_secs()
+    );
+    ic_cdk::println!("Error: {}", error_msg);
+
+    if let Err(e) = scheduler::cancel(EXECUTION_TIMER_ID) {
+        ic_cdk::println!("Failed to cancel execution job: {}", e);
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut current_state = state.get().clone();
+        current_state.status = StrategyStatus::PausedLowCycles;
+        if let Err(e) = state.set(current_state) {
+            ic_cdk::println!("Failed to set state to PausedLowCycles: {:?}", e);
+        }
+    });
+    StrategyResult::Error(error_msg)
+}
+
 // Execute the strategy once
 #[update]
 async fn execute_once() -> StrategyResult {
@@ -410,48 +631,46 @@ async fn execute_once() -> StrategyResult {
         return StrategyResult::Error(e);
     }
 
-    // Check if an execution is already in progress
-    let already_executing = EXECUTION_IN_PROGRESS.with(|in_progress| {
-        let is_executing = *in_progress.borrow();
-        if is_executing {
-            true
-        } else {
-            // If no execution is in progress, set the flag to true
-            *in_progress.borrow_mut() = true;
-            false
+    // Pause before the canister freezes rather than die part-way through a hedge
+    if cycles::freeze_imminent(MIN_CYCLES_RUNWAY_SECS) {
+        ic_cdk::println!("Warning: Cycles runway is short ({:?}s). Requesting a refill before executing.", cycles::runway_secs());
+        if let Err(e) = cycles::refill_if_needed().await {
+            ic_cdk::println!("Cycles refill failed: {}", e);
+        }
+        if cycles::freeze_imminent(MIN_CYCLES_RUNWAY_SECS) {
+            return pause_low_cycles();
         }
-    });
-
-    if already_executing {
-        ic_cdk::println!("Warning: Previous execution is still in progress. Skipping.");
-        return StrategyResult::Error("Previous execution still in progress".to_string());
     }
 
-    // Use defer pattern (RAII) to ensure execution state is reset regardless of how the function exits
-    // This is an application of the RAII pattern, ensuring resource cleanup
-    struct ExecutionGuard;
-    impl Drop for ExecutionGuard {
-        fn drop(&mut self) {
-            EXECUTION_IN_PROGRESS.with(|in_progress| {
-                *in_progress.borrow_mut() = false;
-            });
-            ic_cdk::println!("Execution state reset to 'not executing'");
+    // Take the execution lease; it is released when the guard drops, and a lease
+    // left behind by a trap or an upgrade is recovered once it expires
+    let execution_lease = match lease::acquire(EXECUTION_LEASE_ID, EXECUTION_LEASE_SECS) {
+        Ok(guard) => guard,
+        Err(e) => {
+            ic_cdk::println!("Warning: Previous execution is still in progress ({}). Skipping.", e);
+            return StrategyResult::Error("Previous execution still in progress".to_string());
         }
+    };
+    if let Some(stale) = execution_lease.recovered() {
+        // The balances below are re-read from the exchange, so nothing recorded by the
+        // interrupted run is trusted
+        ic_cdk::println!("Warning: Recovered execution lease abandoned at {} (last heartbeat {}).",
+                        stale.acquired_at, stale.heartbeat_at);
     }
-    let _guard = ExecutionGuard; // The guard ensures the state is reset when it goes out of scope
 
     // Get the current state
     let state_data = STATE.with(|state| state.borrow().get().clone());
     ic_cdk::println!("Current state fetched: hold_token={}, transaction_size={}",
                     state_data.config.hold_token, state_data.transaction_size);
 
-    // Check if the previous execution might still be in progress
-    if let Some(last_execution) = state_data.last_execution {
-        // Using a 5-second cooldown period
-        if last_execution.saturating_add(5_000_000_000) > time() { // time() is in nanoseconds
-             ic_cdk::println!("Warning: Previous execution might still be in progress (last: {}, current: {}). Skipping.", last_execution, time());
-            return StrategyResult::Error("Previous execution may still be in progress".to_string());
+    // Skip the cycle while the venue or pool is failing, rather than hammer it
+    let refusing = circuit_breaker::refusing(&breaker_config(), time() / 1_000_000_000);
+    if !refusing.is_empty() {
+        for breaker in &refusing {
+            ic_cdk::println!("Circuit breaker open for {:?} until {:?} (last error: {:?}). Skipping execution cycle.",
+                            breaker.target, breaker.retry_at, breaker.last_error);
         }
+        return StrategyResult::Success;
     }
 
     // --- Balance Check and Amount Determination ---
@@ -464,7 +683,7 @@ async fn execute_once() -> StrategyResult {
         Err(e) => {
             let error_msg = format!("Failed to check exchange balance: {}", e);
             ic_cdk::println!("Error: {}", error_msg);
-            // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS here upon return
+            // Note: The lease guard releases the execution lease here upon return
             return StrategyResult::Error(error_msg);
         },
     };
@@ -488,7 +707,7 @@ async fn execute_once() -> StrategyResult {
         match pause() {
             StrategyResult::Success => {
                 ic_cdk::println!("Strategy paused successfully due to insufficient balance.");
-                // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS here upon return
+                // Note: The lease guard releases the execution lease here upon return
                 return StrategyResult::Error(format!(
                     "Strategy paused: insufficient {} balance ({}) < 5% of transaction size ({}).",
                     hold_token_symbol, hold_token_balance, state_data.transaction_size
@@ -497,7 +716,7 @@ async fn execute_once() -> StrategyResult {
             StrategyResult::Error(e) => {
                  ic_cdk::println!("Error: Failed to pause strategy despite insufficient balance: {}", e);
                 // Even if pausing fails, we should not proceed with the trade
-                // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS here upon return
+                // Note: The lease guard releases the execution lease here upon return
                 return StrategyResult::Error(format!(
                     "Insufficient {} balance ({}) < 5% of transaction size ({}), but failed to pause: {}",
                     hold_token_symbol, hold_token_balance, state_data.transaction_size, e
@@ -521,7 +740,7 @@ async fn execute_once() -> StrategyResult {
         ic_cdk::println!("Warning: Amount to trade is zero. Skipping execution cycle.");
         // Optionally update last_execution time even if no trade happens?
         // For now, just return success without doing anything.
-        // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS here upon return
+        // Note: The lease guard releases the execution lease here upon return
         return StrategyResult::Success; // Or maybe Error("Amount to trade is zero")? Let's return Success for now.
     }
 
@@ -545,37 +764,48 @@ async fn execute_once() -> StrategyResult {
     ic_cdk::println!("Split amounts: {:?}", split_amounts);
 
 
+    // Refuse to trade on a quote that has drifted from the oracle's TWAP
+    match check_oracle_price(initial_direction.clone(), amount_to_trade).await {
+        Ok(deviation) => ic_cdk::println!("Quote is {} bps from the oracle TWAP", deviation),
+        Err(e) => {
+            ic_cdk::println!("Error: Oracle price check failed: {}", e);
+            return StrategyResult::Error(format!("Oracle price check failed: {}", e));
+        }
+    }
+
     // Execute the two-stage hedge trades
     ic_cdk::println!("Executing hedge trades...");
-    match execute_hedge_trades(initial_direction, split_amounts, state_data.order_split_type).await {
-        Ok(volume) => {
-            ic_cdk::println!("Hedge trades executed successfully. Volume generated: {}", volume);
+    let hedge_runtime = runtime();
+    let hedge_trades = execute_hedge_trades(initial_direction, split_amounts, state_data.order_split_type, &execution_lease);
+    match metering::metered(hedge_runtime.as_ref(), MeteredOperation::HedgeCycle, hedge_trades).await {
+        Ok((volume, last_price)) => {
+            ic_cdk::println!("Hedge trades executed successfully. Volume generated: {}, last price: {:?}", volume, last_price);
             // Update state after successful execution
-            update_state_after_execution(volume).await
-             // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS after this block
+            update_state_after_execution(volume, last_price).await
+             // Note: The lease guard releases the execution lease after this block
         },
         Err(e) => {
              let error_msg = format!("Failed to execute hedge trades: {}", e);
              ic_cdk::println!("Error: {}", error_msg);
-             // Note: The ExecutionGuard will reset EXECUTION_IN_PROGRESS here upon return
+             // Note: The lease guard releases the execution lease here upon return
              StrategyResult::Error(error_msg)
         }
     }
-    // Note: The ExecutionGuard goes out of scope here, ensuring the state is reset
+    // Note: The lease guard goes out of scope here, releasing the execution lease
 }
 
 // Check the available balance in ICPSwap
 async fn check_icpswap_balance() -> Result<(u128, u128), String> {
     let state_data = STATE.with(|state| state.borrow().get().clone());
     
-    // Create ICPSwap connector
-    let connector = create_icpswap_connector(&state_data.config.exchange);
+    // Create the exchange connector
+    let connector = create_connector(&state_data.config.exchange)?;
     
     // Create TradeParams
-    let params = create_trade_params(&state_data.config);
+    let params = create_trade_params(&state_data.config)?;
     
     // Query unused balance
-    let user = ic_cdk::id(); // Current canister ID
+    let user = runtime().id(); // Current canister ID
     match connector.get_unused_balance(&params, &user).await {
         Ok((token0_balance, token1_balance,token0)) => {
             // Return balance
@@ -610,28 +840,78 @@ async fn update_unused_balance(base_balance: u128, quote_balance: u128) {
     });
 }
 
-// Create ICPSwap connector
-fn create_icpswap_connector(exchange: &strategy_common::types::Exchange) -> ICPSwapConnector {
-    // ICPSwap factory Canister ID (mainnet)
-    let factory_canister_id = Principal::from_text("4mmnk-kiaaa-aaaag-qbllq-cai")
-        .expect("Failed to parse ICPSwap factory canister ID");
-    
-    // Create exchange configuration
-    let exchange_config = exchange_types::ExchangeConfig {
-        exchange_type: exchange_types::ExchangeType::ICPSwap,
-        canister_id: factory_canister_id,
-        default_slippage: 0.5,
-        max_slippage: 1.0,
-        timeout_secs: 30,
-        retry_count: 3,
-    };
-    
-    // Create connector
-    ICPSwapConnector::new(exchange_config)
+// Replace the runtime used by this canister (native test harnesses)
+pub fn set_runtime(runtime: Arc<dyn Runtime>) {
+    strategy_common::runtime::set_canister_runtime(runtime);
+}
+
+// Current runtime
+fn runtime() -> Arc<dyn Runtime> {
+    strategy_common::runtime::canister_runtime()
+}
+
+// Caller of the current message
+fn caller() -> Principal {
+    runtime().caller()
+}
+
+// Current time in nanoseconds
+fn time() -> u64 {
+    runtime().time()
+}
+
+// Map the configured DEX onto the exchange module's exchange type
+fn exchange_type_of(exchange: &strategy_common::types::Exchange) -> Result<exchange_types::ExchangeType, String> {
+    match exchange {
+        strategy_common::types::Exchange::ICPSwap => Ok(exchange_types::ExchangeType::ICPSwap),
+        strategy_common::types::Exchange::KongSwap => Ok(exchange_types::ExchangeType::KongSwap),
+        strategy_common::types::Exchange::Sonic => Ok(exchange_types::ExchangeType::Sonic),
+        strategy_common::types::Exchange::ICDex => Ok(exchange_types::ExchangeType::ICDex),
+        strategy_common::types::Exchange::Simulated => Ok(exchange_types::ExchangeType::Simulated),
+        other => Err(format!("Exchange {:?} is not supported", other)),
+    }
+}
+
+// Create the connector for the configured exchange
+fn create_connector(exchange: &strategy_common::types::Exchange) -> Result<ExchangeHandle, String> {
+    let exchange_type = exchange_type_of(exchange)?;
+    exchange_factory(&exchange_type)
+        .create_connector(&exchange_type)
+        .map_err(|e| format!("Failed to create {:?} connector: {}", exchange_type, e))
+}
+
+// Exchange factory configured for the strategy's venue
+fn exchange_factory(exchange_type: &exchange_types::ExchangeType) -> ExchangeFactory {
+    let mut factory = ExchangeFactory::with_runtime(runtime());
+
+    if *exchange_type == exchange_types::ExchangeType::ICPSwap {
+        // ICPSwap factory Canister ID (mainnet)
+        let factory_canister_id = Principal::from_text("4mmnk-kiaaa-aaaag-qbllq-cai")
+            .expect("Failed to parse ICPSwap factory canister ID");
+
+        factory.update_config(exchange_types::ExchangeConfig {
+            exchange_type: exchange_types::ExchangeType::ICPSwap,
+            canister_id: factory_canister_id,
+            default_slippage: 0.5,
+            max_slippage: 1.0,
+            timeout_secs: 30,
+            retry_count: 3,
+            // Split trades hit the same pool concurrently; refuse calls beyond the budget rather than flood it
+            rate_limit: Some(exchange_types::RateLimitConfig::default()),
+            circuit_breaker: Some(breaker_config()),
+        });
+    }
+
+    factory
+}
+
+// Circuit breaker thresholds for the strategy's venue and pool
+fn breaker_config() -> exchange_types::CircuitBreakerConfig {
+    exchange_types::CircuitBreakerConfig::default()
 }
 
 // Create TradeParams
-fn create_trade_params(config: &SelfHedgingConfig) -> exchange_types::TradeParams {
+fn create_trade_params(config: &SelfHedgingConfig) -> Result<exchange_types::TradeParams, String> {
     // Convert base_token to exchange module's TokenInfo
     let base_token = exchange_types::TokenInfo {
         canister_id: config.trading_pair.base_token.canister_id,
@@ -652,17 +932,17 @@ fn create_trade_params(config: &SelfHedgingConfig) -> exchange_types::TradeParam
     let trading_pair = exchange_types::TradingPair {
         base_token,
         quote_token,
-        exchange: exchange_types::ExchangeType::ICPSwap,
+        exchange: exchange_type_of(&config.exchange)?,
     };
     
     // Create TradeParams (direction defaults to Buy, will be determined based on balance during execution)
-    exchange_types::TradeParams {
+    Ok(exchange_types::TradeParams {
         pair: trading_pair,
         direction: exchange_types::TradeDirection::Buy,
         amount: config.transaction_size,
         slippage_tolerance: config.slippage_tolerance,
         deadline_secs: None,
-    }
+    })
 }
 
 // Convert token standard string to TokenStandard enum
@@ -742,17 +1022,24 @@ fn split_amount(total_amount: u128, split_count: usize) -> Vec<u128> {
 async fn execute_hedge_trades(
     initial_direction: exchange_types::TradeDirection, // Direction for the FIRST stage (selling hold_token)
     initial_split_amounts: Vec<u128>, // Amounts for the FIRST stage trade(s)
-    split_type: OrderSplitType
-) -> Result<u128, String> {
+    split_type: OrderSplitType,
+    execution_lease: &LeaseGuard, // Heartbeated between stages so long trades keep it
+) -> Result<(u128, Option<exchange_types::NormalizedPrice>), String> {
     ic_cdk::println!("Starting hedge trades: Initial direction={:?}, Split amounts={:?}, Split type={:?}",
                     initial_direction, initial_split_amounts, split_type);
 
     let state_data = STATE.with(|state| state.borrow().get().clone());
-    let connector = create_icpswap_connector(&state_data.config.exchange);
+    let connector = create_connector(&state_data.config.exchange)?;
+    // Hedge trades swap the unused pool balance without a minimum output
+    let capabilities = connector.capabilities();
+    if !capabilities.supports_call_trades {
+        return Err(format!("Hedge trades are not supported on {:?}", capabilities.exchange_type));
+    }
     
-    let mut params = create_trade_params(&state_data.config); // Creates params with default direction/amount
+    let mut params = create_trade_params(&state_data.config)?; // Creates params with default direction/amount
     let pool_data = connector.get_pool_info(&params.pair.base_token,&params.pair.quote_token).await.map_err(|e| format!("Failed to get pool info: {}", e))?;
     let mut total_volume = 0u128;
+    let mut last_price = None; // Normalized, so both stages report the same orientation
 
     // --- Stage 1: Sell Hold Token ---
     params.direction = initial_direction; // Set direction for the first stage
@@ -792,14 +1079,13 @@ async fn execute_hedge_trades(
             trade_params.amount = *amount;
             let pool_data_clone = pool_data.clone();
             let idx = i;
-            let exchange_config = state_data.config.exchange.clone();
+            let local_connector = connector.clone();
             
             ic_cdk::println!("Stage 1: Preparing split order #{}, Amount: {}", idx+1, trade_params.amount);
             
             // Create future for this trade
             let future = async move {
-                // Create a new connector instance for each trade
-                let local_connector = create_icpswap_connector(&exchange_config);
+                // Each trade shares the connector handle
                 (idx, local_connector.execute_call_trade_no_slippage(&trade_params, &pool_data_clone).await)
             };
             
@@ -818,6 +1104,7 @@ async fn execute_hedge_trades(
                         ic_cdk::println!("Stage 1: Trade #{} successful. Input: {}, Output: {}", 
                                         idx+1, trade_result.input_amount, trade_result.output_amount);
                         total_volume = total_volume.saturating_add(trade_result.input_amount);
+                        last_price = pricing::trade_price(&params.pair, &trade_result).or(last_price);
                         first_stage_outputs.push(trade_result.output_amount);
                     },
                     Err(e) => {
@@ -838,6 +1125,7 @@ async fn execute_hedge_trades(
                 Ok(result) => {
                     ic_cdk::println!("Stage 1: Trade successful. Input: {}, Output: {}", result.input_amount, result.output_amount);
                     total_volume = total_volume.saturating_add(result.input_amount); // Add input amount to volume
+                    last_price = pricing::trade_price(&params.pair, &result).or(last_price);
                     first_stage_outputs.push(result.output_amount);
                 },
                 Err(e) => {
@@ -860,9 +1148,13 @@ async fn execute_hedge_trades(
         // Refresh balance even if stage 2 is skipped
         let _ = check_icpswap_balance().await;
         ic_cdk::println!("Final volume for this cycle: {}", total_volume);
-        return Ok(total_volume); // Return volume generated in stage 1
+        return Ok((total_volume, last_price)); // Return volume generated in stage 1
     }
 
+    // Keep the execution lease before the second stage; if it was lost another
+    // execution may already be trading, so stop rather than overlap it
+    execution_lease.heartbeat().map_err(|e| format!("Execution lease lost after stage 1: {}", e))?;
+
     // --- Stage 2: Buy Hold Token Back ---
     // Reverse the trade direction
     params.direction = match params.direction {
@@ -905,15 +1197,14 @@ async fn execute_hedge_trades(
             let mut trade_params = params.clone();
             trade_params.amount = *amount;
             let idx = i;
-            let exchange_config = state_data.config.exchange.clone();
+            let local_connector = connector.clone();
             
             ic_cdk::println!("Stage 2: Preparing split order #{}, Amount: {}", idx+1, trade_params.amount);
             
             // Create future for this trade
             let pool_data_clone = pool_data.clone();
             let future = async move {
-                // Create a new connector instance for each trade
-                let local_connector = create_icpswap_connector(&exchange_config);
+                // Each trade shares the connector handle
                 (idx, local_connector.execute_call_trade_no_slippage(&trade_params,&pool_data_clone).await)
             };
             
@@ -932,6 +1223,7 @@ async fn execute_hedge_trades(
                         ic_cdk::println!("Stage 2: Trade #{} successful. Input: {}, Output: {}", 
                                         idx+1, trade_result.input_amount, trade_result.output_amount);
                         total_volume = total_volume.saturating_add(trade_result.input_amount);
+                        last_price = pricing::trade_price(&params.pair, &trade_result).or(last_price);
                     },
                     Err(e) => {
                         // For Stage 2, errors are non-fatal - log and continue
@@ -950,6 +1242,7 @@ async fn execute_hedge_trades(
                 Ok(result) => {
                     ic_cdk::println!("Stage 2: Trade successful. Input: {}, Output: {}", result.input_amount, result.output_amount);
                     total_volume = total_volume.saturating_add(result.input_amount);
+                    last_price = pricing::trade_price(&params.pair, &result).or(last_price);
                 },
                 Err(e) => {
                     let error_msg = format!("Stage 2 trade failed (Single order): {:?}", e);
@@ -971,11 +1264,11 @@ async fn execute_hedge_trades(
     };
 
     ic_cdk::println!("Hedge trades completed. Final total volume: {}", total_volume);
-    Ok(total_volume)
+    Ok((total_volume, last_price))
 }
 
 // Update state after execution
-async fn update_state_after_execution(volume: u128) -> StrategyResult {
+async fn update_state_after_execution(volume: u128, last_price: Option<exchange_types::NormalizedPrice>) -> StrategyResult {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let mut current_state = state.get().clone();
@@ -985,6 +1278,9 @@ async fn update_state_after_execution(volume: u128) -> StrategyResult {
         current_state.execution_count += 1;
         current_state.last_execution = Some(current_time);
         current_state.volume_generated += volume;
+        if last_price.is_some() {
+            current_state.last_price = last_price;
+        }
 
         // Update state
         match state.set(current_state) {
@@ -1003,6 +1299,12 @@ fn get_status() -> StrategyStatus {
     })
 }
 
+// Get the cycles balance, burn rate and projected runway
+#[query]
+fn get_cycles_summary() -> Result<cycles::CyclesSummary, String> {
+    cycles::get_summary()
+}
+
 // Get the full strategy state (for owner only)
 #[query]
 fn get_state() -> Result<SelfHedgingState, String> {
@@ -1016,6 +1318,107 @@ fn get_state() -> Result<SelfHedgingState, String> {
     })
 }
 
+// Get the trading pair's oracle observations at or after `since` (seconds), oldest first
+#[query]
+fn get_price_observations(since: u64) -> Result<Vec<PriceObservation>, String> {
+    let pair = trading_pair_of_state()?;
+    oracle::with_oracle(|oracle| {
+        oracle.find_feed(&pair)
+            .map(|feed_id| oracle.observations(feed_id, since))
+            .unwrap_or_default()
+    })
+    .map_err(|e| e.to_string())
+}
+
+// Get the trading pair's oracle TWAP over the configured window
+#[query]
+fn get_oracle_twap() -> Result<Option<f64>, String> {
+    let pair = trading_pair_of_state()?;
+    let now = time() / 1_000_000_000;
+    oracle::with_oracle(|oracle| {
+        let window = oracle.config().twap_window_secs;
+        oracle.find_feed(&pair).and_then(|feed_id| oracle.twap(feed_id, window, now))
+    })
+    .map_err(|e| e.to_string())
+}
+
+// Place a trigger order swapping the canister's wallet balance once its price condition holds (for owner only)
+#[update]
+fn place_trigger_order(request: TriggerOrderRequest) -> Result<u64, String> {
+    verify_owner()?;
+    let now = time() / 1_000_000_000;
+    trigger_orders::with_book(|book| book.place(request, now))
+        .and_then(|placed| placed)
+        .map_err(|e| e.to_string())
+}
+
+// Cancel an open trigger order; one whose swap is in flight cannot be cancelled (for owner only)
+#[update]
+fn cancel_trigger_order(id: u64) -> Result<TriggerOrder, String> {
+    verify_owner()?;
+    let now = time() / 1_000_000_000;
+    trigger_orders::with_book(|book| book.cancel(id, now))
+        .and_then(|canceled| canceled)
+        .map_err(|e| e.to_string())
+}
+
+// Get every trigger order, oldest first (for owner only)
+#[query]
+fn get_trigger_orders() -> Result<Vec<TriggerOrder>, String> {
+    verify_owner()?;
+    trigger_orders::with_book(|book| book.orders()).map_err(|e| e.to_string())
+}
+
+// Get executed trades and the ledger verification of their legs, newest first (for owner only)
+#[query]
+fn get_trade_journal(offset: u64, limit: u64) -> Result<Vec<JournalEntry>, String> {
+    verify_owner()?;
+    Ok(journal::entries(offset, limit))
+}
+
+// Get executed trades with legs that could not be verified, newest first (for owner only)
+#[query]
+fn get_flagged_trades() -> Result<Vec<JournalEntry>, String> {
+    verify_owner()?;
+    Ok(journal::flagged_entries())
+}
+
+// Add a pool to the canister's simulated exchange unless the pair has one; returns its pool ID (for owner only)
+#[update]
+fn seed_simulated_pool(seed: SimulatedPoolSeed) -> Result<Principal, String> {
+    verify_owner()?;
+    let simulator = exchange_factory(&exchange_types::ExchangeType::Simulated)
+        .create_simulated()
+        .map_err(|e| format!("Failed to create simulated exchange: {}", e))?;
+    Ok(simulator.seed_pool(&seed))
+}
+
+// Credit the canister's simulated wallet with paper tokens (for owner only)
+#[update]
+fn mint_simulated_tokens(token: TokenInfo, amount: u128) -> Result<(), String> {
+    verify_owner()?;
+    let simulator = exchange_factory(&exchange_types::ExchangeType::Simulated)
+        .create_simulated()
+        .map_err(|e| format!("Failed to create simulated exchange: {}", e))?;
+    simulator.mint(&token, &simulator.account(), amount);
+    Ok(())
+}
+
+// Replay `prices` (token1 per token0, raw units) on a simulated pool, one per scheduled run (for owner only)
+#[update]
+fn set_simulated_price_path(pool_id: Principal, prices: Vec<f64>) -> Result<(), String> {
+    verify_owner()?;
+    if prices.iter().any(|price| !(price.is_finite() && *price > 0.0)) {
+        return Err("Prices must be positive".to_string());
+    }
+    let simulator = exchange_factory(&exchange_types::ExchangeType::Simulated)
+        .create_simulated()
+        .map_err(|e| format!("Failed to create simulated exchange: {}", e))?;
+    simulator.spot_price(&pool_id).map_err(|e| format!("Unknown simulated pool {}: {}", pool_id, e))?;
+    simulator.set_price_path(&pool_id, PricePath::from_prices(prices));
+    Ok(())
+}
+
 // Pre-upgrade hook to preserve state during upgrades
 #[pre_upgrade]
 fn pre_upgrade() {
@@ -1026,6 +1429,27 @@ fn pre_upgrade() {
 #[post_upgrade]
 fn post_upgrade() {
     // State is already restored from stable storage via StableCell
+    open_lease_table();
+    open_scheduler();
+    open_journal();
+    open_oracle();
+    open_trigger_orders();
+    open_cycles_refill();
+
+    // Timers are gone after an upgrade; the persisted job re-arms them
+    let (status, check_interval) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.get().status.clone(), state.get().config.check_interval_secs)
+    });
+    if status != StrategyStatus::Running {
+        let _ = scheduler::cancel(EXECUTION_TIMER_ID);
+    } else if scheduler::get_job(EXECUTION_TIMER_ID).is_none() {
+        // Upgraded from a build that kept its timer on the heap
+        if let Err(e) = schedule_execution(check_interval) {
+            ic_cdk::println!("Failed to reschedule execution after upgrade: {}", e);
+        }
+    }
+    scheduler::resume();
 }
 
 #[update]
@@ -1036,7 +1460,7 @@ fn update_volume_config(transaction_size: u128, split_type: OrderSplitType) -> S
     }
 
     // Only allow update if not running
-    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused]) {
+    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused, StrategyStatus::PausedLowCycles]) {
         return StrategyResult::Error(format!("Cannot update configuration while strategy is running. Please pause first: {}", e));
     }
 
@@ -1092,6 +1516,12 @@ fn get_volume_stats() -> VolumeStats {
     })
 }
 
+// Get the measured instruction and cycles cost of each exchange operation
+#[query]
+fn get_operation_costs() -> Vec<OperationCostReport> {
+    metering::operation_costs()
+}
+
 // Update configuration information
 #[update]
 fn update_config(
@@ -1107,7 +1537,7 @@ fn update_config(
     }
 
     // Only allow update if not running
-    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused]) {
+    if let Err(e) = verify_status(&[StrategyStatus::Created, StrategyStatus::Paused, StrategyStatus::PausedLowCycles]) {
         return StrategyResult::Error(format!("Cannot update configuration while strategy is running. Please pause first: {}", e));
     }
     if hold_token == Principal::anonymous() {
@@ -1159,8 +1589,14 @@ async fn deposit_to_exchange(token_type: String, amount: u128) -> StrategyResult
     }
 
     let state_data = STATE.with(|state| state.borrow().get().clone());
-    let connector = create_icpswap_connector(&state_data.config.exchange);
-    let params = create_trade_params(&state_data.config);
+    let connector = match create_connector(&state_data.config.exchange) {
+        Ok(connector) => connector,
+        Err(e) => return StrategyResult::Error(e),
+    };
+    let params = match create_trade_params(&state_data.config) {
+        Ok(params) => params,
+        Err(e) => return StrategyResult::Error(e),
+    };
 
     // Determine which token to deposit
     let token = if token_type.to_lowercase() == "base" {
@@ -1191,8 +1627,14 @@ async fn withdraw_from_exchange(token_type: String, amount: u128) -> StrategyRes
     }
 
     let state_data = STATE.with(|state| state.borrow().get().clone());
-    let connector = create_icpswap_connector(&state_data.config.exchange);
-    let params = create_trade_params(&state_data.config);
+    let connector = match create_connector(&state_data.config.exchange) {
+        Ok(connector) => connector,
+        Err(e) => return StrategyResult::Error(e),
+    };
+    let params = match create_trade_params(&state_data.config) {
+        Ok(params) => params,
+        Err(e) => return StrategyResult::Error(e),
+    };
 
     // Determine which token to withdraw
     let token = if token_type.to_lowercase() == "base" {
@@ -1262,6 +1704,23 @@ async fn refresh_balance() -> StrategyResult {
     }
 }
 
+// Get a breakdown of everything the strategy holds (wallet, pool balances, allowances, LP positions),
+// valued in the quote token
+#[update]
+async fn get_holdings() -> Result<exchange_types::HoldingsBreakdown, String> {
+    verify_owner()?;
+
+    let state_data = STATE.with(|state| state.borrow().get().clone());
+    let connector = create_connector(&state_data.config.exchange)?;
+    let params = create_trade_params(&state_data.config)?;
+    let tokens = [params.pair.base_token.clone(), params.pair.quote_token.clone()];
+
+    connector
+        .get_exchange_balance(&tokens, &connector.account(), &params.pair.quote_token)
+        .await
+        .map_err(|e| format!("Failed to get holdings: {}", e))
+}
+
 // Get trading pair information
 #[derive(CandidType, Deserialize, Clone, Debug)]
 struct TradingPairInfo {
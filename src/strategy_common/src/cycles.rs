@@ -1,24 +1,70 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::main::{
-    canister_status, deposit_cycles, CanisterIdRecord, CanisterStatusResponse,
+    canister_status, CanisterIdRecord, CanisterStatusResponse,
 };
-use ic_cdk::api::{canister_balance, id};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
 use serde::Serialize;
 use std::cell::{Cell, RefCell};
 
+use crate::debug_log;
+use crate::runtime::{self, canister_runtime};
+use crate::scheduler::{self, MissedRunPolicy, Schedule};
+
+/// Method a refill source exposes to top up a strategy canister
+pub const REFILL_METHOD: &str = "request_cycles_refill";
+
+/// Scheduler job ID of the periodic balance check
+pub const REFILL_JOB_ID: &str = "cycles_refill_check";
+
+/// Stable memory holding balance samples
+pub type CyclesMemory = VirtualMemory<DefaultMemoryImpl>;
+
+// Samples kept; a month at the hourly check interval
+const MAX_SAMPLES: u64 = 24 * 30;
+
+const HOUR_SECS: u64 = 3_600;
+const DAY_SECS: u64 = 86_400;
+
 /// Cycles management configuration
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct CyclesConfig {
     pub warning_threshold: u64,
     pub critical_threshold: u64,
     pub refill_amount: u64,
-    pub refill_source: Option<Principal>,
+    pub refill_source: Option<Principal>,  // Canister that tops this one up, normally the factory
+}
+
+impl Default for CyclesConfig {
+    fn default() -> Self {
+        Self {
+            warning_threshold: 500_000_000_000,
+            critical_threshold: 100_000_000_000,
+            refill_amount: 1_000_000_000_000,
+            refill_source: None,
+        }
+    }
+}
+
+/// Top-up made by a refill source, as returned from `REFILL_METHOD`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CyclesRefillReceipt {
+    pub canister_id: Principal,
+    pub cycles: u64,
+    pub cost_e8s: u64,           // Charged to the strategy owner's balance at the source
+    pub owner_balance_e8s: u64,  // Owner's remaining balance at the source
 }
 
 thread_local! {
     static WARNING_THRESHOLD: Cell<u64> = Cell::new(500_000_000_000);
     static CRITICAL_THRESHOLD: Cell<u64> = Cell::new(100_000_000_000);
     static CONFIG: RefCell<Option<CyclesConfig>> = RefCell::new(None);
+    // Set while a refill request is in flight, so overlapping checks do not ask twice
+    static REFILL_IN_FLIGHT: Cell<bool> = Cell::new(false);
+    // Balance samples keyed by time in seconds, opened by `open_samples`
+    static SAMPLES: RefCell<Option<StableBTreeMap<u64, u64, CyclesMemory>>> = RefCell::new(None);
+    // Balance at which the canister freezes, when `refresh_freezing_limit` could read it
+    static FREEZING_LIMIT: Cell<Option<u64>> = Cell::new(None);
 }
 
 /// Initialize cycles management with configuration
@@ -30,7 +76,7 @@ pub fn init(config: CyclesConfig) {
 
 /// Get current cycles balance
 pub fn get_balance() -> u64 {
-    canister_balance()
+    u64::try_from(canister_runtime().cycles_balance()).unwrap_or(u64::MAX)
 }
 
 /// Check if cycles are below warning threshold
@@ -49,30 +95,90 @@ pub fn is_below_critical_threshold() -> bool {
     })
 }
 
-/// Request cycles refill from the source canister
-pub async fn request_cycles_refill() -> Result<(), String> {
-    let self_id = id();
-    let config = CONFIG.with(|c| c.borrow().clone());
-    
-    if let Some(config) = config {
-        if let Some(source) = config.refill_source {
-            let args = CanisterIdRecord { canister_id: self_id };
-            let result = deposit_cycles(args, config.refill_amount as u128).await;
-            match result {
-                Ok(_) => Ok(()),
-                Err((_, e)) => Err(format!("Failed to request cycles: {}", e)),
-            }
-        } else {
-            return Err("No refill source configured".to_string());
+/// Current configuration, if `init` was called
+pub fn get_config() -> Option<CyclesConfig> {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+/// Ask the refill source to top this canister up by `refill_amount`
+///
+/// The source pays from its own balance and bills the strategy owner; cycles
+/// cannot be pulled, so a canister never deposits to itself.
+pub async fn request_cycles_refill() -> Result<CyclesRefillReceipt, String> {
+    let config = get_config().ok_or_else(|| "Cycles config not initialized".to_string())?;
+    let source = config.refill_source.ok_or_else(|| "No refill source configured".to_string())?;
+
+    if REFILL_IN_FLIGHT.with(|in_flight| in_flight.replace(true)) {
+        return Err("Cycles refill already in progress".to_string());
+    }
+    // Cleared however the request ends, including a trap after the await
+    struct RefillGuard;
+    impl Drop for RefillGuard {
+        fn drop(&mut self) {
+            REFILL_IN_FLIGHT.with(|in_flight| in_flight.set(false));
         }
-    } else {
-        Err("Cycles config not initialized".to_string())
     }
+    let _guard = RefillGuard;
+
+    let result: Result<(Result<CyclesRefillReceipt, String>,), _> =
+        runtime::call(canister_runtime().as_ref(), source, REFILL_METHOD, (config.refill_amount,)).await;
+    match result {
+        Ok((Ok(receipt),)) => {
+            debug_log!(
+                "Cycles refilled by {}: {} cycles for {} e8s, balance now {}",
+                source, receipt.cycles, receipt.cost_e8s, get_balance()
+            );
+            Ok(receipt)
+        }
+        Ok((Err(e),)) => Err(format!("Refill source {} refused: {}", source, e)),
+        Err((code, msg)) => Err(format!("Failed to request cycles from {}: {:?} {}", source, code, msg)),
+    }
+}
+
+/// Request a refill when the balance is below the warning threshold
+///
+/// Returns None when no refill was needed.
+pub async fn refill_if_needed() -> Result<Option<CyclesRefillReceipt>, String> {
+    if !is_below_warning_threshold() {
+        return Ok(None);
+    }
+    request_cycles_refill().await.map(Some)
+}
+
+/// Sample the balance every `interval_secs` through the scheduler and, once a refill
+/// source is configured, top the canister up when it runs below the warning threshold
+///
+/// Samples are recorded whether or not refills are configured, so the burn rate and
+/// runway are known for every canister that opened its samples.
+/// Call from both `init` and `post_upgrade` once the scheduler is open; the job itself
+/// persists, but its handler has to be registered again.
+pub fn schedule_refill_checks(interval_secs: u64) -> Result<(), String> {
+    scheduler::register_handler(REFILL_JOB_ID, || async {
+        // Sampled before any refill, so the burn rate sees the balance it ran down to
+        record_sample()?;
+        if let Err(e) = refresh_freezing_limit().await {
+            debug_log!("Freezing limit unavailable, using the critical threshold: {}", e);
+        }
+        if get_config().map_or(true, |config| config.refill_source.is_none()) {
+            return Ok(());
+        }
+        refill_if_needed().await.map(|_| ())
+    });
+    if scheduler::get_job(REFILL_JOB_ID).is_some() {
+        return Ok(());
+    }
+    scheduler::schedule(
+        REFILL_JOB_ID,
+        Schedule::Interval { seconds: interval_secs },
+        MissedRunPolicy::RunOnce,
+        interval_secs,
+    )
+    .map(|_| ())
 }
 
 /// Get canister status including cycles information
 pub async fn get_canister_status() -> Result<CanisterStatusResponse, String> {
-    let args = CanisterIdRecord { canister_id: id() };
+    let args = CanisterIdRecord { canister_id: canister_runtime().id() };
     let result = canister_status(args).await;
     
     match result {
@@ -94,6 +200,130 @@ fn critical_threshold_set(threshold: u64) {
     CRITICAL_THRESHOLD.with(|t| t.set(threshold));
 }
 
+/// Opens the canister's balance samples; call from both `init` and `post_upgrade`
+pub fn open_samples(memory: CyclesMemory) {
+    SAMPLES.with(|samples| {
+        *samples.borrow_mut() = Some(StableBTreeMap::init(memory));
+    });
+}
+
+/// Record the current balance, dropping the oldest samples beyond the retention limit
+pub fn record_sample() -> Result<(), String> {
+    let now = time_secs();
+    let balance = get_balance();
+    SAMPLES.with(|samples| {
+        let mut samples = samples.borrow_mut();
+        let samples = samples.as_mut().ok_or_else(|| "Cycles samples not opened".to_string())?;
+        samples.insert(now, balance);
+        while samples.len() > MAX_SAMPLES {
+            match samples.first_key_value() {
+                Some((oldest, _)) => samples.remove(&oldest),
+                None => break,
+            };
+        }
+        Ok(())
+    })
+}
+
+/// Whether `open_samples` has been called
+pub fn samples_opened() -> bool {
+    SAMPLES.with(|samples| samples.borrow().is_some())
+}
+
+/// Number of stored samples
+pub fn sample_count() -> u64 {
+    SAMPLES.with(|samples| samples.borrow().as_ref().map_or(0, |samples| samples.len()))
+}
+
+/// Cycles burned per `per_secs` over the last `window_secs`
+///
+/// Only decreases between samples count, so refills do not hide spending.
+/// None until the window holds two points at different times.
+fn burn_rate(window_secs: u64, per_secs: u64) -> Option<u64> {
+    let now = time_secs();
+    let mut points: Vec<(u64, u64)> = SAMPLES.with(|samples| {
+        samples.borrow()
+            .as_ref()
+            .map(|samples| samples.range(now.saturating_sub(window_secs)..).collect())
+            .unwrap_or_default()
+    });
+    points.push((now, get_balance()));
+
+    let elapsed = points.last()?.0.saturating_sub(points.first()?.0);
+    if elapsed == 0 {
+        return None;
+    }
+    let burned: u128 = points
+        .windows(2)
+        .map(|pair| pair[0].1.saturating_sub(pair[1].1) as u128)
+        .sum();
+    Some((burned * per_secs as u128 / elapsed as u128).min(u64::MAX as u128) as u64)
+}
+
+/// Cycles burned per hour, averaged over the last day
+pub fn burn_rate_per_hour() -> Option<u64> {
+    burn_rate(DAY_SECS, HOUR_SECS)
+}
+
+/// Cycles burned per day, averaged over the last week
+pub fn burn_rate_per_day() -> Option<u64> {
+    burn_rate(7 * DAY_SECS, DAY_SECS)
+}
+
+/// Read the freezing limit from the canister's own status
+///
+/// Only controllers may read canister status, so this fails for canisters controlled
+/// solely by the factory; `freezing_limit` then falls back to the critical threshold
+/// and the summary reports the limit as estimated.
+pub async fn refresh_freezing_limit() -> Result<u64, String> {
+    let status = get_canister_status().await?;
+    let idle_per_day = u128::try_from(&status.idle_cycles_burned_per_day.0)
+        .map_err(|e| format!("Idle burn out of range: {}", e))?;
+    let threshold_secs = u128::try_from(&status.settings.freezing_threshold.0)
+        .map_err(|e| format!("Freezing threshold out of range: {}", e))?;
+    let limit = (idle_per_day * threshold_secs / DAY_SECS as u128).min(u64::MAX as u128) as u64;
+    FREEZING_LIMIT.with(|l| l.set(Some(limit)));
+    Ok(limit)
+}
+
+/// Balance at which the canister freezes, or the critical threshold when it is unknown
+pub fn freezing_limit() -> u64 {
+    FREEZING_LIMIT.with(|l| l.get())
+        .unwrap_or_else(|| CRITICAL_THRESHOLD.with(|t| t.get()))
+}
+
+/// Whether `freezing_limit` is the critical threshold standing in for an unread limit
+pub fn freezing_limit_estimated() -> bool {
+    FREEZING_LIMIT.with(|l| l.get()).is_none()
+}
+
+/// Seconds until the balance reaches the freezing limit at the current burn rate
+///
+/// Uses the higher of the daily and weekly rates; None while no burn is measured.
+pub fn runway_secs() -> Option<u64> {
+    let per_hour = [burn_rate_per_hour(), burn_rate_per_day().map(|per_day| per_day / 24)]
+        .into_iter()
+        .flatten()
+        .max()
+        .filter(|rate| *rate > 0)?;
+    let spendable = get_balance().saturating_sub(freezing_limit()) as u128;
+    Some((spendable * HOUR_SECS as u128 / per_hour as u128).min(u64::MAX as u128) as u64)
+}
+
+/// Whether the canister is about to freeze: below the critical threshold, or
+/// projected to reach the freezing limit within `min_runway_secs`
+///
+/// Strategies check this before a cycle and pause instead of freezing mid-trade.
+pub fn freeze_imminent(min_runway_secs: u64) -> bool {
+    is_below_critical_threshold()
+        || get_balance() <= freezing_limit()
+        || runway_secs().map_or(false, |runway| runway < min_runway_secs)
+}
+
+fn time_secs() -> u64 {
+    canister_runtime().time() / 1_000_000_000
+}
+
 /// Get cycles management summary
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct CyclesSummary {
@@ -102,6 +332,13 @@ pub struct CyclesSummary {
     pub critical_threshold: u64,
     pub is_below_warning: bool,
     pub is_below_critical: bool,
+    pub freezing_limit: u64,               // Falls back to the critical threshold when unreadable
+    pub freezing_limit_estimated: bool,    // True while the canister cannot read its own status
+    pub burn_rate_per_hour: Option<u64>,   // Averaged over the last day
+    pub burn_rate_per_day: Option<u64>,    // Averaged over the last week
+    pub runway_secs: Option<u64>,          // Until the freezing limit at the current burn rate
+    pub freeze_at: Option<u64>,            // Projected, in seconds since the epoch
+    pub sample_count: u64,
 }
 
 /// Get cycles management summary
@@ -110,11 +347,95 @@ pub fn get_summary() -> Result<CyclesSummary, String> {
     let warning_threshold = WARNING_THRESHOLD.with(|t| t.get());
     let critical_threshold = CRITICAL_THRESHOLD.with(|t| t.get());
     
+    let runway_secs = runway_secs();
+
     Ok(CyclesSummary {
         balance,
         warning_threshold,
         critical_threshold,
         is_below_warning: balance < warning_threshold,
         is_below_critical: balance < critical_threshold,
+        freezing_limit: freezing_limit(),
+        freezing_limit_estimated: freezing_limit_estimated(),
+        burn_rate_per_hour: burn_rate_per_hour(),
+        burn_rate_per_day: burn_rate_per_day(),
+        runway_secs,
+        freeze_at: runway_secs.map(|runway| time_secs().saturating_add(runway)),
+        sample_count: sample_count(),
     })
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{set_canister_runtime, MockRuntime};
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use std::sync::Arc;
+
+    const START: u64 = 1_700_000_000;
+    const T: u64 = 1_000_000_000_000;
+
+    /// Fresh samples on the current test thread, with default thresholds and the clock at `START`
+    fn setup(balance: u64) -> Arc<MockRuntime> {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[1]), Principal::anonymous()));
+        runtime.set_time(START * 1_000_000_000);
+        runtime.set_cycles_balance(balance as u128);
+        set_canister_runtime(runtime.clone());
+        init(CyclesConfig::default());
+        open_samples(MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)));
+        runtime
+    }
+
+    /// Move the clock on by `hours` and sample `balance`
+    fn sample_after(runtime: &MockRuntime, hours: u64, balance: u64) {
+        runtime.advance_time(hours * HOUR_SECS * 1_000_000_000);
+        runtime.set_cycles_balance(balance as u128);
+        record_sample().unwrap();
+    }
+
+    #[test]
+    fn refill_does_not_hide_spending() {
+        let runtime = setup(10 * T);
+        record_sample().unwrap();
+        sample_after(&runtime, 1, 9 * T);
+        // Topped up between samples
+        sample_after(&runtime, 1, 19 * T);
+        sample_after(&runtime, 1, 18 * T);
+
+        // 2T burned over three hours; the refill counts as no burn rather than negative burn
+        assert_eq!(burn_rate_per_hour(), Some(2 * T / 3));
+        // 17.9T above the estimated freezing limit lasts a little under 27 hours
+        let runway = runway_secs().unwrap();
+        assert!((26 * HOUR_SECS..27 * HOUR_SECS).contains(&runway), "runway {}", runway);
+    }
+
+    #[test]
+    fn flat_or_rising_balance_has_no_runway() {
+        let runtime = setup(5 * T);
+        record_sample().unwrap();
+        sample_after(&runtime, 1, 5 * T);
+        assert_eq!(burn_rate_per_hour(), Some(0));
+        assert_eq!(runway_secs(), None);
+
+        // Only refills since the last sample: still no burn, never a negative rate
+        sample_after(&runtime, 1, 7 * T);
+        assert_eq!(burn_rate_per_hour(), Some(0));
+        assert_eq!(runway_secs(), None);
+        assert!(!freeze_imminent(DAY_SECS));
+        assert_eq!(get_summary().unwrap().freeze_at, None);
+    }
+
+    #[test]
+    fn unread_freezing_limit_is_estimated_from_the_critical_threshold() {
+        let runtime = setup(5 * T);
+        let summary = get_summary().unwrap();
+        assert!(summary.freezing_limit_estimated);
+        assert_eq!(summary.freezing_limit, CyclesConfig::default().critical_threshold);
+        // A single point measures no burn yet
+        assert_eq!(summary.burn_rate_per_hour, None);
+        assert_eq!(summary.runway_secs, None);
+
+        // At the estimated limit the canister counts as about to freeze, burn or not
+        runtime.set_cycles_balance(CyclesConfig::default().critical_threshold as u128);
+        assert!(freeze_imminent(0));
+    }
+}
@@ -41,6 +41,7 @@ pub enum OrderStatus {
     Filled,
     Canceled,
     Failed,
+    Expired,
 }
 
 /// Order information
@@ -0,0 +1,301 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::debug_log;
+use crate::runtime::canister_runtime;
+
+/// Stable memory holding the lease table
+pub type LeaseMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// A held lease; expires unless its holder heartbeats within `ttl_secs`
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Lease {
+    pub name: String,
+    pub token: u64,        // Identifies the holder; only it may heartbeat or release
+    pub acquired_at: u64,  // Seconds since the epoch
+    pub heartbeat_at: u64,
+    pub expires_at: u64,
+    pub ttl_secs: u64,
+}
+
+impl Storable for Lease {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode lease"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode lease")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Lease table of the running canister, opened by `init`
+    static LEASES: RefCell<Option<StableBTreeMap<String, Lease, LeaseMemory>>> = RefCell::new(None);
+    // Distinguishes tokens issued within the same nanosecond
+    static TOKEN_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+/// Opens the canister's lease table; call from both `init` and `post_upgrade`
+///
+/// Leases held before an upgrade stay in place and expire normally, since their
+/// holders' call contexts did not survive it.
+pub fn init(memory: LeaseMemory) {
+    LEASES.with(|leases| {
+        *leases.borrow_mut() = Some(StableBTreeMap::init(memory));
+    });
+}
+
+/// Whether `init` has opened the lease table
+pub fn is_initialized() -> bool {
+    LEASES.with(|leases| leases.borrow().is_some())
+}
+
+fn with_leases<T>(f: impl FnOnce(&mut StableBTreeMap<String, Lease, LeaseMemory>) -> T) -> Result<T, String> {
+    LEASES.with(|leases| {
+        leases.borrow_mut()
+            .as_mut()
+            .map(f)
+            .ok_or_else(|| "Lease table not initialized".to_string())
+    })
+}
+
+fn now_secs() -> u64 {
+    canister_runtime().time() / 1_000_000_000
+}
+
+fn new_token() -> u64 {
+    let count = TOKEN_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter = counter.wrapping_add(1);
+        *counter
+    });
+    canister_runtime().time().wrapping_add(count)
+}
+
+/// Take lease `name` for `ttl_secs`, recovering it if its holder let it expire
+///
+/// Fails while another holder's lease is live. A recovered lease means the previous
+/// holder stopped part-way, so the caller should re-read external state rather than
+/// trust what it last recorded; `LeaseGuard::recovered` returns the stale lease.
+pub fn acquire(name: &str, ttl_secs: u64) -> Result<LeaseGuard, String> {
+    let now = now_secs();
+    let token = new_token();
+    let recovered = with_leases(|leases| {
+        let stale = match leases.get(&name.to_string()) {
+            Some(current) if current.expires_at > now => {
+                return Err(format!(
+                    "Lease {} held by {} until {} (last heartbeat {})",
+                    name, current.token, current.expires_at, current.heartbeat_at
+                ));
+            }
+            other => other,
+        };
+        leases.insert(name.to_string(), Lease {
+            name: name.to_string(),
+            token,
+            acquired_at: now,
+            heartbeat_at: now,
+            expires_at: now.saturating_add(ttl_secs),
+            ttl_secs,
+        });
+        Ok(stale)
+    })??;
+
+    if let Some(stale) = &recovered {
+        debug_log!(
+            "Recovered stale lease {} from holder {} (acquired {}, last heartbeat {}, expired {})",
+            name, stale.token, stale.acquired_at, stale.heartbeat_at, stale.expires_at
+        );
+    }
+    Ok(LeaseGuard {
+        name: name.to_string(),
+        token,
+        recovered,
+        released: false,
+    })
+}
+
+/// Current holder of lease `name`, live or expired
+pub fn get(name: &str) -> Option<Lease> {
+    with_leases(|leases| leases.get(&name.to_string())).ok().flatten()
+}
+
+/// Drop lease `name` whatever its holder, e.g. after an operator checked it is stuck
+pub fn force_release(name: &str) -> Result<Option<Lease>, String> {
+    let removed = with_leases(|leases| leases.remove(&name.to_string()))?;
+    if let Some(lease) = &removed {
+        debug_log!("Lease {} force-released from holder {}", name, lease.token);
+    }
+    Ok(removed)
+}
+
+/// A held lease, released when dropped
+///
+/// Dropping also runs when an await in the holder traps, so only an upgrade or a
+/// trap outside the holder leaves the lease behind to expire.
+pub struct LeaseGuard {
+    name: String,
+    token: u64,
+    recovered: Option<Lease>,
+    released: bool,
+}
+
+impl LeaseGuard {
+    /// Holder token of this lease
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// The expired lease this one replaced, if any
+    pub fn recovered(&self) -> Option<&Lease> {
+        self.recovered.as_ref()
+    }
+
+    /// Extend the lease by its TTL; fails if it expired and was taken over
+    ///
+    /// Call between long steps, and stop work that would conflict with a new holder on error.
+    pub fn heartbeat(&self) -> Result<(), String> {
+        let now = now_secs();
+        with_leases(|leases| {
+            let mut lease = match leases.get(&self.name) {
+                Some(lease) if lease.token == self.token => lease,
+                Some(other) => return Err(format!("Lease {} was taken over by {}", self.name, other.token)),
+                None => return Err(format!("Lease {} was released", self.name)),
+            };
+            if lease.expires_at <= now {
+                // Expired but not yet taken; renew rather than race a recovery
+                debug_log!("Lease {} heartbeat after expiry at {}", self.name, lease.expires_at);
+            }
+            lease.heartbeat_at = now;
+            lease.expires_at = now.saturating_add(lease.ttl_secs);
+            leases.insert(self.name.clone(), lease);
+            Ok(())
+        })?
+    }
+
+    /// Release the lease now
+    pub fn release(mut self) {
+        self.release_inner();
+    }
+
+    fn release_inner(&mut self) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+        let _ = with_leases(|leases| {
+            // Never remove a lease that was recovered by someone else
+            if leases.get(&self.name).map_or(false, |lease| lease.token == self.token) {
+                leases.remove(&self.name);
+            }
+        });
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        self.release_inner();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{set_canister_runtime, MockRuntime};
+    use candid::Principal;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use std::sync::Arc;
+
+    const START: u64 = 1_700_000_000;
+
+    /// Fresh lease table on the current test thread, with the clock at `START`
+    fn setup() -> Arc<MockRuntime> {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[1]), Principal::anonymous()));
+        runtime.set_time(START * 1_000_000_000);
+        set_canister_runtime(runtime.clone());
+        init(MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)));
+        runtime
+    }
+
+    fn advance_secs(runtime: &MockRuntime, secs: u64) {
+        runtime.advance_time(secs * 1_000_000_000);
+    }
+
+    #[test]
+    fn live_lease_refuses_a_second_holder() {
+        let _runtime = setup();
+        let guard = acquire("execution", 60).unwrap();
+        assert!(guard.recovered().is_none());
+        assert!(acquire("execution", 60).is_err());
+
+        let lease = get("execution").unwrap();
+        assert_eq!(lease.token, guard.token());
+        assert_eq!(lease.expires_at, START + 60);
+    }
+
+    #[test]
+    fn expired_lease_is_recovered_with_its_stale_holder() {
+        let runtime = setup();
+        let first = acquire("execution", 60).unwrap();
+        let first_token = first.token();
+        // Left behind as if the holder's call context died in an upgrade
+        std::mem::forget(first);
+
+        advance_secs(&runtime, 60);
+        let second = acquire("execution", 60).unwrap();
+        let stale = second.recovered().expect("expired lease should be reported");
+        assert_eq!(stale.token, first_token);
+        assert_eq!(stale.expires_at, START + 60);
+        assert_eq!(get("execution").unwrap().token, second.token());
+    }
+
+    #[test]
+    fn heartbeat_extends_the_lease_until_it_is_taken_over() {
+        let runtime = setup();
+        let first = acquire("execution", 60).unwrap();
+        advance_secs(&runtime, 30);
+        first.heartbeat().unwrap();
+        assert_eq!(get("execution").unwrap().expires_at, START + 90);
+
+        // Still live at START + 60 thanks to the heartbeat
+        advance_secs(&runtime, 30);
+        assert!(acquire("execution", 60).is_err());
+
+        advance_secs(&runtime, 30);
+        let second = acquire("execution", 60).unwrap();
+        assert!(first.heartbeat().is_err());
+        // The failed heartbeat leaves the new holder's lease alone
+        assert_eq!(get("execution").unwrap().token, second.token());
+    }
+
+    #[test]
+    fn dropping_the_guard_releases_the_lease() {
+        let _runtime = setup();
+        {
+            let _guard = acquire("execution", 60).unwrap();
+        }
+        assert!(get("execution").is_none());
+        assert!(acquire("execution", 60).unwrap().recovered().is_none());
+    }
+
+    #[test]
+    fn stale_guard_does_not_remove_a_recovered_lease() {
+        let runtime = setup();
+        let first = acquire("execution", 60).unwrap();
+        advance_secs(&runtime, 61);
+        let second = acquire("execution", 60).unwrap();
+
+        drop(first);
+        assert_eq!(get("execution").unwrap().token, second.token());
+
+        second.release();
+        assert!(get("execution").is_none());
+    }
+}
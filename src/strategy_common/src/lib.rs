@@ -1,7 +1,12 @@
 pub mod types;
 pub mod exchange;
+#[deprecated(note = "heap timers are lost on upgrade; register a job with `scheduler` instead")]
 pub mod timer;
 pub mod cycles;
+pub mod runtime;
+pub mod scheduler;
+pub mod lease;
+pub mod strategy;
 
 pub use types::{
     StrategyType, StrategyStatus, TokenMetadata, TradingPair, OrderType, 
@@ -12,6 +17,7 @@ pub use types::{
 };
 pub use types::Exchange;
 
+#[allow(deprecated)]
 pub mod timer_utils {
     pub use crate::timer::*;
 }
@@ -0,0 +1,348 @@
+use async_trait::async_trait;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Abstraction over the IC system API used by exchange connectors and strategies.
+///
+/// Production code uses `IcRuntime`; native tests use `MockRuntime` to script
+/// canister replies, inject failures and control the clock.
+#[async_trait]
+pub trait Runtime: Send + Sync {
+    /// Perform an inter-canister call with Candid-encoded arguments
+    async fn call_raw(&self, canister_id: Principal, method: &str, args: Vec<u8>, payment: u128) -> CallResult<Vec<u8>>;
+
+    /// Current time in nanoseconds since the epoch
+    fn time(&self) -> u64;
+
+    /// Principal that sent the current message
+    fn caller(&self) -> Principal;
+
+    /// Principal of the running canister
+    fn id(&self) -> Principal;
+
+    /// Suspend the current task for the given number of nanoseconds
+    ///
+    /// On the IC this waits on a one-shot timer. An update call that sleeps with no
+    /// inter-canister call outstanding gets rejected as "did not reply" while the task
+    /// carries on in the timer's context, so only sleep from timer or scheduler jobs.
+    async fn sleep(&self, nanos: u64);
+
+    /// Instructions executed so far in the current call context, including before earlier awaits
+    fn instruction_counter(&self) -> u64;
+
+    /// Cycles balance of the running canister
+    fn cycles_balance(&self) -> u128;
+}
+
+thread_local! {
+    // Runtime used by the framework modules (scheduler, leases, cycles, strategy records)
+    static CANISTER_RUNTIME: RefCell<Arc<dyn Runtime>> = RefCell::new(Arc::new(IcRuntime));
+}
+
+/// Replace the runtime used by this canister's framework modules (native test harnesses)
+pub fn set_canister_runtime(runtime: Arc<dyn Runtime>) {
+    CANISTER_RUNTIME.with(|r| *r.borrow_mut() = runtime);
+}
+
+/// Runtime used by this canister's framework modules; `IcRuntime` unless replaced
+pub fn canister_runtime() -> Arc<dyn Runtime> {
+    CANISTER_RUNTIME.with(|r| r.borrow().clone())
+}
+
+/// Runtime backed by the real IC system API
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IcRuntime;
+
+#[async_trait]
+impl Runtime for IcRuntime {
+    async fn call_raw(&self, canister_id: Principal, method: &str, args: Vec<u8>, payment: u128) -> CallResult<Vec<u8>> {
+        ic_cdk::api::call::call_raw128(canister_id, method, args, payment).await
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn caller(&self) -> Principal {
+        ic_cdk::api::caller()
+    }
+
+    fn id(&self) -> Principal {
+        ic_cdk::api::id()
+    }
+
+    async fn sleep(&self, nanos: u64) {
+        let state = Arc::new(Mutex::new(SleepState::default()));
+        let timer_state = state.clone();
+        ic_cdk_timers::set_timer(Duration::from_nanos(nanos), move || {
+            let mut state = timer_state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            state.done = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        Sleep { state }.await
+    }
+
+    fn instruction_counter(&self) -> u64 {
+        // Counter 1 spans the whole call context, so it keeps counting across awaits
+        ic_cdk::api::performance_counter(1)
+    }
+
+    fn cycles_balance(&self) -> u128 {
+        ic_cdk::api::canister_balance128()
+    }
+}
+
+#[derive(Default)]
+struct SleepState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Future completed by a one-shot timer
+struct Sleep {
+    state: Arc<Mutex<SleepState>>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Typed inter-canister call through a runtime
+pub async fn call<T, R>(runtime: &dyn Runtime, canister_id: Principal, method: &str, args: T) -> CallResult<R>
+where
+    T: ArgumentEncoder + Send,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    call_with_payment(runtime, canister_id, method, args, 0).await
+}
+
+/// Typed inter-canister call through a runtime, attaching cycles
+pub async fn call_with_payment<T, R>(
+    runtime: &dyn Runtime,
+    canister_id: Principal,
+    method: &str,
+    args: T,
+    payment: u128,
+) -> CallResult<R>
+where
+    T: ArgumentEncoder + Send,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    let bytes = encode_args(args)
+        .map_err(|e| (RejectionCode::CanisterError, format!("Failed to encode arguments for {}: {}", method, e)))?;
+    let reply = runtime.call_raw(canister_id, method, bytes, payment).await?;
+    decode_args(&reply)
+        .map_err(|e| (RejectionCode::CanisterError, format!("Failed to decode reply from {}: {}", method, e)))
+}
+
+/// Print a debug line to the canister log, or to stdout when running natively
+pub fn debug_print(message: &str) {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::print(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    println!("{}", message);
+}
+
+/// `println!`-style debug logging that also works outside a canister
+#[macro_export]
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        $crate::runtime::debug_print(&format!($($arg)*))
+    };
+}
+
+/// Inter-canister call recorded by `MockRuntime`
+#[derive(Clone, Debug)]
+pub struct RecordedCall {
+    pub canister_id: Principal,
+    pub method: String,
+    pub args: Vec<u8>,
+    pub payment: u128,
+}
+
+struct MockState {
+    time: u64,
+    caller: Principal,
+    id: Principal,
+    queued: HashMap<(Principal, String), VecDeque<CallResult<Vec<u8>>>>,
+    defaults: HashMap<(Principal, String), CallResult<Vec<u8>>>,
+    calls: Vec<RecordedCall>,
+    instructions: u64,
+    cycles: u128,
+}
+
+/// Scriptable runtime for native tests
+///
+/// Replies are matched by canister and method. Queued replies are consumed in
+/// order; once a queue is empty the default reply for that method (if any) is
+/// returned. Calls without a scripted reply are rejected with `DestinationInvalid`.
+pub struct MockRuntime {
+    state: Mutex<MockState>,
+}
+
+impl MockRuntime {
+    /// Create a mock runtime for canister `id`, with messages sent by `caller`
+    pub fn new(id: Principal, caller: Principal) -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                time: 0,
+                caller,
+                id,
+                queued: HashMap::new(),
+                defaults: HashMap::new(),
+                calls: Vec::new(),
+                instructions: 0,
+                cycles: 0,
+            }),
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut MockState) -> T) -> T {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut state)
+    }
+
+    /// Set the current time in nanoseconds
+    pub fn set_time(&self, nanos: u64) {
+        self.with_state(|s| s.time = nanos);
+    }
+
+    /// Advance the clock by the given number of nanoseconds
+    pub fn advance_time(&self, nanos: u64) {
+        self.with_state(|s| s.time = s.time.saturating_add(nanos));
+    }
+
+    /// Add to the instruction counter, simulating work done by the canister
+    pub fn add_instructions(&self, instructions: u64) {
+        self.with_state(|s| s.instructions = s.instructions.saturating_add(instructions));
+    }
+
+    /// Set the canister's cycles balance; call payments are deducted from it
+    pub fn set_cycles_balance(&self, cycles: u128) {
+        self.with_state(|s| s.cycles = cycles);
+    }
+
+    /// Change the caller reported for subsequent messages
+    pub fn set_caller(&self, caller: Principal) {
+        self.with_state(|s| s.caller = caller);
+    }
+
+    /// Queue a successful reply for the next call to `method` on `canister_id`
+    pub fn push_reply<R: ArgumentEncoder>(&self, canister_id: Principal, method: &str, reply: R) {
+        let bytes = encode_args(reply).expect("Failed to encode mock reply");
+        self.push_raw(canister_id, method, Ok(bytes));
+    }
+
+    /// Queue a rejection for the next call to `method` on `canister_id`
+    pub fn push_reject(&self, canister_id: Principal, method: &str, code: RejectionCode, message: &str) {
+        self.push_raw(canister_id, method, Err((code, message.to_string())));
+    }
+
+    /// Queue a raw reply for the next call to `method` on `canister_id`
+    pub fn push_raw(&self, canister_id: Principal, method: &str, reply: CallResult<Vec<u8>>) {
+        self.with_state(|s| {
+            s.queued
+                .entry((canister_id, method.to_string()))
+                .or_insert_with(VecDeque::new)
+                .push_back(reply);
+        });
+    }
+
+    /// Reply to every call to `method` on `canister_id` once its queue is empty
+    pub fn set_default_reply<R: ArgumentEncoder>(&self, canister_id: Principal, method: &str, reply: R) {
+        let bytes = encode_args(reply).expect("Failed to encode mock reply");
+        self.with_state(|s| {
+            s.defaults.insert((canister_id, method.to_string()), Ok(bytes));
+        });
+    }
+
+    /// All calls made so far, in order
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.with_state(|s| s.calls.clone())
+    }
+
+    /// Number of calls made to `method` on any canister
+    pub fn call_count(&self, method: &str) -> usize {
+        self.with_state(|s| s.calls.iter().filter(|c| c.method == method).count())
+    }
+
+    /// Decode the arguments of the `index`-th call to `method`
+    pub fn call_args<R: for<'a> ArgumentDecoder<'a>>(&self, method: &str, index: usize) -> Option<R> {
+        let args = self.with_state(|s| {
+            s.calls.iter().filter(|c| c.method == method).nth(index).map(|c| c.args.clone())
+        })?;
+        decode_args(&args).ok()
+    }
+}
+
+#[async_trait]
+impl Runtime for MockRuntime {
+    async fn call_raw(&self, canister_id: Principal, method: &str, args: Vec<u8>, payment: u128) -> CallResult<Vec<u8>> {
+        self.with_state(|s| {
+            s.calls.push(RecordedCall {
+                canister_id,
+                method: method.to_string(),
+                args,
+                payment,
+            });
+            s.cycles = s.cycles.saturating_sub(payment);
+
+            let key = (canister_id, method.to_string());
+            if let Some(reply) = s.queued.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                return reply;
+            }
+            match s.defaults.get(&key) {
+                Some(reply) => reply.clone(),
+                None => Err((
+                    RejectionCode::DestinationInvalid,
+                    format!("No mock reply scripted for {}.{}", canister_id, method),
+                )),
+            }
+        })
+    }
+
+    fn time(&self) -> u64 {
+        self.with_state(|s| s.time)
+    }
+
+    fn caller(&self) -> Principal {
+        self.with_state(|s| s.caller)
+    }
+
+    fn id(&self) -> Principal {
+        self.with_state(|s| s.id)
+    }
+
+    /// Advances the mock clock instead of waiting
+    async fn sleep(&self, nanos: u64) {
+        self.advance_time(nanos);
+    }
+
+    fn instruction_counter(&self) -> u64 {
+        self.with_state(|s| s.instructions)
+    }
+
+    fn cycles_balance(&self) -> u128 {
+        self.with_state(|s| s.cycles)
+    }
+}
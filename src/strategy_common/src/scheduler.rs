@@ -0,0 +1,511 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::debug_log;
+use crate::runtime::canister_runtime;
+
+/// Stable memory holding the job table
+pub type SchedulerMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Work run when a job fires
+pub type JobHandler = Rc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<(), String>>>>>;
+
+// Upper bound on the cron search, one week plus a day of minutes
+const CRON_SEARCH_MINUTES: u64 = 8 * 24 * 60;
+
+/// When a job runs
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Schedule {
+    Interval { seconds: u64 },
+    Cron(CronSpec),
+    Once { at: u64 },  // Seconds since the epoch
+}
+
+/// Cron-like schedule evaluated in UTC; an empty list matches any value
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct CronSpec {
+    pub minutes: Vec<u8>,       // 0-59
+    pub hours: Vec<u8>,         // 0-23
+    pub days_of_week: Vec<u8>,  // 0-6, Sunday is 0
+}
+
+/// What to do with a run that could not start on time, e.g. across an upgrade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MissedRunPolicy {
+    Skip,     // Drop missed runs and wait for the next slot
+    RunOnce,  // Run once now, however many slots were missed
+}
+
+/// A persisted job definition with its run history
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub schedule: Schedule,
+    pub missed_run_policy: MissedRunPolicy,
+    pub grace_secs: u64,                        // Lateness tolerated before a run counts as missed
+    pub enabled: bool,
+    pub next_run_at: Option<u64>,               // Seconds since the epoch; None once a one-shot job is done
+    pub last_run_at: Option<u64>,
+    pub last_result: Option<Result<(), String>>,
+    pub run_count: u64,
+    pub missed_runs: u64,
+}
+
+impl Storable for Job {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode job"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode job")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Job table of the running canister, opened by `init`
+    static JOBS: RefCell<Option<StableBTreeMap<String, Job, SchedulerMemory>>> = RefCell::new(None);
+    // Handlers are code, not data, so canisters register them again after every upgrade
+    static HANDLERS: RefCell<HashMap<String, JobHandler>> = RefCell::new(HashMap::new());
+    // Jobs whose handler is in flight, so a slow run is not overlapped by the next one
+    static RUNNING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Single timer armed for the earliest due job
+    static ARMED: RefCell<Option<TimerId>> = RefCell::new(None);
+}
+
+fn now_secs() -> u64 {
+    canister_runtime().time() / 1_000_000_000
+}
+
+/// Opens the canister's job table; call from both `init` and `post_upgrade`
+///
+/// Jobs are not re-armed until `resume` is called, after the handlers are registered.
+pub fn init(memory: SchedulerMemory) {
+    JOBS.with(|jobs| {
+        *jobs.borrow_mut() = Some(StableBTreeMap::init(memory));
+    });
+}
+
+/// Whether `init` has opened the job table
+pub fn is_initialized() -> bool {
+    JOBS.with(|jobs| jobs.borrow().is_some())
+}
+
+fn with_jobs<T>(f: impl FnOnce(&mut StableBTreeMap<String, Job, SchedulerMemory>) -> T) -> Result<T, String> {
+    JOBS.with(|jobs| {
+        jobs.borrow_mut()
+            .as_mut()
+            .map(f)
+            .ok_or_else(|| "Scheduler not initialized".to_string())
+    })
+}
+
+/// Register the work run by job `id`
+pub fn register_handler<F, Fut>(id: &str, handler: F)
+where
+    F: Fn() -> Fut + 'static,
+    Fut: Future<Output = Result<(), String>> + 'static,
+{
+    let handler: JobHandler = Rc::new(move || Box::pin(handler()));
+    HANDLERS.with(|handlers| {
+        handlers.borrow_mut().insert(id.to_string(), handler);
+    });
+}
+
+fn validate(schedule: &Schedule, now: u64) -> Result<(), String> {
+    match schedule {
+        Schedule::Interval { seconds } if *seconds == 0 => Err("Interval must be greater than zero".to_string()),
+        Schedule::Cron(spec) => {
+            let in_range = |values: &[u8], max: u8| values.iter().all(|v| *v <= max);
+            if !in_range(&spec.minutes, 59) || !in_range(&spec.hours, 23) || !in_range(&spec.days_of_week, 6) {
+                return Err(format!("Cron field out of range: {:?}", spec));
+            }
+            Ok(())
+        }
+        Schedule::Once { at } if *at < now => Err(format!("One-shot time {} is in the past", at)),
+        _ => Ok(()),
+    }
+}
+
+/// First cron slot strictly after `after`
+fn next_cron(spec: &CronSpec, after: u64) -> Option<u64> {
+    let matches = |values: &[u8], value: u64| values.is_empty() || values.iter().any(|v| *v as u64 == value);
+    let first_minute = after / 60 + 1;
+    (first_minute..first_minute + CRON_SEARCH_MINUTES)
+        .map(|minute| minute * 60)
+        .find(|t| {
+            // 1970-01-01 was a Thursday
+            matches(&spec.minutes, (t / 60) % 60)
+                && matches(&spec.hours, (t / 3600) % 24)
+                && matches(&spec.days_of_week, (t / 86_400 + 4) % 7)
+        })
+}
+
+/// First run of a newly scheduled job
+fn first_run(schedule: &Schedule, now: u64) -> Option<u64> {
+    match schedule {
+        Schedule::Interval { seconds } => Some(now.saturating_add(*seconds)),
+        Schedule::Cron(spec) => next_cron(spec, now),
+        Schedule::Once { at } => Some(*at),
+    }
+}
+
+/// Next slot after `now` for a job that was due at `due`, with the number of slots passed over
+fn advance(schedule: &Schedule, due: u64, now: u64) -> (Option<u64>, u64) {
+    match schedule {
+        Schedule::Interval { seconds } => {
+            let seconds = (*seconds).max(1);
+            // Keep the original phase so runs do not drift
+            let passed = (now - due) / seconds;
+            (Some(due + (passed + 1) * seconds), passed)
+        }
+        Schedule::Cron(spec) => {
+            let mut passed = 0;
+            let mut slot = next_cron(spec, due);
+            while let Some(t) = slot {
+                if t > now {
+                    break;
+                }
+                passed += 1;
+                slot = next_cron(spec, t);
+            }
+            (slot, passed)
+        }
+        Schedule::Once { .. } => (None, 0),
+    }
+}
+
+/// Create or replace job `id`; its run history is kept when the job already exists
+pub fn schedule(id: &str, schedule: Schedule, missed_run_policy: MissedRunPolicy, grace_secs: u64) -> Result<Job, String> {
+    let now = now_secs();
+    validate(&schedule, now)?;
+    let next_run_at = first_run(&schedule, now)
+        .ok_or_else(|| format!("Schedule {:?} never fires", schedule))?;
+    let job = with_jobs(|jobs| {
+        let mut job = jobs.get(&id.to_string()).unwrap_or(Job {
+            id: id.to_string(),
+            schedule: schedule.clone(),
+            missed_run_policy: missed_run_policy.clone(),
+            grace_secs,
+            enabled: true,
+            next_run_at: None,
+            last_run_at: None,
+            last_result: None,
+            run_count: 0,
+            missed_runs: 0,
+        });
+        job.schedule = schedule;
+        job.missed_run_policy = missed_run_policy;
+        job.grace_secs = grace_secs;
+        job.enabled = true;
+        job.next_run_at = Some(next_run_at);
+        jobs.insert(id.to_string(), job.clone());
+        job
+    })?;
+    debug_log!("Scheduled job {} for {}", id, next_run_at);
+    rearm();
+    Ok(job)
+}
+
+/// Remove job `id`; its handler stays registered
+pub fn cancel(id: &str) -> Result<Option<Job>, String> {
+    let removed = with_jobs(|jobs| jobs.remove(&id.to_string()))?;
+    rearm();
+    Ok(removed)
+}
+
+/// Stop or restart job `id` without losing its definition
+///
+/// A re-enabled job whose run fell due meanwhile follows its missed-run policy.
+pub fn set_enabled(id: &str, enabled: bool) -> Result<(), String> {
+    with_jobs(|jobs| {
+        let mut job = jobs.get(&id.to_string()).ok_or_else(|| format!("Job {} not found", id))?;
+        job.enabled = enabled;
+        jobs.insert(id.to_string(), job);
+        Ok::<(), String>(())
+    })??;
+    rearm();
+    Ok(())
+}
+
+/// Job `id`, if scheduled
+pub fn get_job(id: &str) -> Option<Job> {
+    with_jobs(|jobs| jobs.get(&id.to_string())).ok().flatten()
+}
+
+/// All scheduled jobs
+pub fn jobs() -> Vec<Job> {
+    with_jobs(|jobs| jobs.iter().map(|(_, job)| job).collect()).unwrap_or_default()
+}
+
+/// Re-arm persisted jobs; call from `post_upgrade` once every handler is registered
+///
+/// Runs that fell due while the canister was upgrading follow their missed-run policy.
+pub fn resume() {
+    let count = jobs().iter().filter(|job| job.enabled && job.next_run_at.is_some()).count();
+    debug_log!("Resuming scheduler with {} active jobs", count);
+    rearm();
+}
+
+fn has_handler(id: &str) -> bool {
+    HANDLERS.with(|handlers| handlers.borrow().contains_key(id))
+}
+
+/// Arm the timer for the earliest enabled job with a handler
+fn rearm() {
+    ARMED.with(|armed| {
+        if let Some(timer) = armed.borrow_mut().take() {
+            ic_cdk_timers::clear_timer(timer);
+        }
+    });
+    let next = jobs()
+        .into_iter()
+        .filter(|job| job.enabled && has_handler(&job.id))
+        .filter_map(|job| job.next_run_at)
+        .min();
+    if let Some(next) = next {
+        let delay = Duration::from_secs(next.saturating_sub(now_secs()));
+        let timer = arm_timer(delay);
+        ARMED.with(|armed| *armed.borrow_mut() = timer);
+    }
+}
+
+// Timers only exist inside a canister; natively `tick` is driven by hand
+#[cfg(target_arch = "wasm32")]
+fn arm_timer(delay: Duration) -> Option<TimerId> {
+    Some(ic_cdk_timers::set_timer(delay, tick))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn arm_timer(_delay: Duration) -> Option<TimerId> {
+    None
+}
+
+// Start a run the way `ic_cdk::spawn` does, polling it once up front; natively nothing
+// wakes it again, which suits mock runtimes whose calls complete without suspending
+#[cfg(target_arch = "wasm32")]
+fn spawn(run: impl Future<Output = ()> + 'static) {
+    ic_cdk::spawn(run);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn(run: impl Future<Output = ()> + 'static) {
+    let mut run = Box::pin(run);
+    let _ = run.as_mut().poll(&mut std::task::Context::from_waker(&futures::task::noop_waker()));
+}
+
+/// Fire every due job, then arm the timer for the next one
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))] // Only the timer calls it in a canister
+fn tick() {
+    ARMED.with(|armed| *armed.borrow_mut() = None);
+    let now = now_secs();
+    let due: Vec<Job> = jobs()
+        .into_iter()
+        .filter(|job| job.enabled && has_handler(&job.id) && job.next_run_at.map_or(false, |at| at <= now))
+        .collect();
+
+    for mut job in due {
+        let scheduled = job.next_run_at.unwrap_or(now);
+        let (next_run_at, passed) = advance(&job.schedule, scheduled, now);
+        let late = now - scheduled > job.grace_secs;
+        let busy = RUNNING.with(|running| running.borrow().contains(&job.id));
+        let run = !busy && (!late || job.missed_run_policy == MissedRunPolicy::RunOnce);
+
+        // Slots passed over never run; the due slot counts as missed unless it runs now
+        job.missed_runs += passed + if run { 0 } else { 1 };
+        job.next_run_at = next_run_at;
+        if next_run_at.is_none() {
+            job.enabled = false;
+        }
+        if run {
+            job.last_run_at = Some(now);
+            job.run_count += 1;
+        } else {
+            debug_log!("Job {} missed its run at {} ({})", job.id, scheduled, if busy { "still running" } else { "late" });
+        }
+        let id = job.id.clone();
+        // Persist the advanced schedule before running so an upgrade mid-run cannot repeat it
+        if let Err(e) = with_jobs(|jobs| jobs.insert(id.clone(), job)) {
+            debug_log!("Failed to update job {}: {}", id, e);
+            continue;
+        }
+        if run {
+            spawn_run(id);
+        }
+    }
+    rearm();
+}
+
+fn spawn_run(id: String) {
+    let handler = match HANDLERS.with(|handlers| handlers.borrow().get(&id).cloned()) {
+        Some(handler) => handler,
+        None => return,
+    };
+    RUNNING.with(|running| running.borrow_mut().insert(id.clone()));
+    spawn(async move {
+        // Cleared however the run ends, including a trap after an await
+        struct RunGuard(String);
+        impl Drop for RunGuard {
+            fn drop(&mut self) {
+                RUNNING.with(|running| running.borrow_mut().remove(&self.0));
+            }
+        }
+        let _guard = RunGuard(id.clone());
+
+        let result = handler().await;
+        if let Err(e) = &result {
+            debug_log!("Job {} failed: {}", id, e);
+        }
+        let _ = with_jobs(|jobs| {
+            if let Some(mut job) = jobs.get(&id) {
+                job.last_result = Some(result);
+                jobs.insert(id.clone(), job);
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{set_canister_runtime, MockRuntime};
+    use candid::Principal;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    const START: u64 = 1_700_000_000;
+
+    /// Fresh job table on the current test thread, with the clock at `START`
+    fn setup() -> (Arc<MockRuntime>, MemoryManager<DefaultMemoryImpl>) {
+        let runtime = Arc::new(MockRuntime::new(Principal::from_slice(&[1]), Principal::anonymous()));
+        runtime.set_time(START * 1_000_000_000);
+        set_canister_runtime(runtime.clone());
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        init(manager.get(MemoryId::new(0)));
+        (runtime, manager)
+    }
+
+    /// Handler counting its runs and failing when `fail` is set
+    fn counting_handler(id: &str, fail: bool) -> Rc<Cell<u32>> {
+        let runs = Rc::new(Cell::new(0));
+        let counter = runs.clone();
+        register_handler(id, move || {
+            let counter = counter.clone();
+            async move {
+                counter.set(counter.get() + 1);
+                if fail { Err("boom".to_string()) } else { Ok(()) }
+            }
+        });
+        runs
+    }
+
+    fn advance_secs(runtime: &MockRuntime, secs: u64) {
+        runtime.advance_time(secs * 1_000_000_000);
+    }
+
+    #[test]
+    fn late_interval_runs_once_and_keeps_its_phase() {
+        let (runtime, _manager) = setup();
+        let runs = counting_handler("catch_up", false);
+        schedule("catch_up", Schedule::Interval { seconds: 60 }, MissedRunPolicy::RunOnce, 10).unwrap();
+
+        // Due at START + 60; four more slots pass before the tick at START + 300
+        advance_secs(&runtime, 300);
+        tick();
+
+        let job = get_job("catch_up").unwrap();
+        assert_eq!(runs.get(), 1);
+        assert_eq!(job.run_count, 1);
+        assert_eq!(job.missed_runs, 4);
+        assert_eq!(job.next_run_at, Some(START + 360));
+        assert_eq!(job.last_result, Some(Ok(())));
+    }
+
+    #[test]
+    fn late_interval_with_skip_policy_waits_for_the_next_slot() {
+        let (runtime, _manager) = setup();
+        let runs = counting_handler("skipper", false);
+        schedule("skipper", Schedule::Interval { seconds: 60 }, MissedRunPolicy::Skip, 10).unwrap();
+
+        advance_secs(&runtime, 300);
+        tick();
+        assert_eq!(runs.get(), 0);
+        assert_eq!(get_job("skipper").unwrap().missed_runs, 5);
+
+        // On time again at the next slot
+        advance_secs(&runtime, 60);
+        tick();
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    fn one_shot_job_runs_once_then_retires() {
+        let (runtime, _manager) = setup();
+        let runs = counting_handler("once", false);
+        schedule("once", Schedule::Once { at: START + 30 }, MissedRunPolicy::Skip, 10).unwrap();
+
+        advance_secs(&runtime, 30);
+        tick();
+        let job = get_job("once").unwrap();
+        assert_eq!(runs.get(), 1);
+        assert_eq!(job.next_run_at, None);
+        assert!(!job.enabled);
+
+        advance_secs(&runtime, 3_600);
+        tick();
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    fn jobs_wait_for_their_handler_after_an_upgrade() {
+        let (runtime, manager) = setup();
+        counting_handler("durable", false);
+        schedule("durable", Schedule::Interval { seconds: 60 }, MissedRunPolicy::RunOnce, 10).unwrap();
+
+        // An upgrade keeps the job table but drops every handler
+        HANDLERS.with(|handlers| handlers.borrow_mut().clear());
+        init(manager.get(MemoryId::new(0)));
+        advance_secs(&runtime, 90);
+        tick();
+        let job = get_job("durable").unwrap();
+        assert_eq!(job.run_count, 0);
+        assert_eq!(job.next_run_at, Some(START + 60));
+
+        // Registered again in post_upgrade, the overdue run follows its policy
+        let runs = counting_handler("durable", false);
+        resume();
+        tick();
+        assert_eq!(runs.get(), 1);
+        assert_eq!(get_job("durable").unwrap().next_run_at, Some(START + 120));
+    }
+
+    #[test]
+    fn failed_runs_are_recorded_and_the_job_keeps_running() {
+        let (runtime, _manager) = setup();
+        let runs = counting_handler("flaky", true);
+        schedule("flaky", Schedule::Interval { seconds: 60 }, MissedRunPolicy::Skip, 10).unwrap();
+
+        advance_secs(&runtime, 60);
+        tick();
+        assert_eq!(get_job("flaky").unwrap().last_result, Some(Err("boom".to_string())));
+        assert!(RUNNING.with(|running| running.borrow().is_empty()));
+
+        advance_secs(&runtime, 60);
+        tick();
+        assert_eq!(runs.get(), 2);
+        assert_eq!(get_job("flaky").unwrap().run_count, 2);
+    }
+}
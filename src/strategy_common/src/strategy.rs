@@ -0,0 +1,489 @@
+use async_trait::async_trait;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::cycles;
+use crate::debug_log;
+use crate::lease;
+use crate::runtime::canister_runtime;
+use crate::scheduler::{self, MissedRunPolicy, Schedule};
+use crate::types::{StrategyResult, StrategyStatus};
+
+/// Stable memory handed to strategies by the shared memory manager
+pub type StrategyMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Memory holding the strategy record; strategies allocate their own structures from later IDs
+pub const RECORD_MEMORY_ID: u8 = 0;
+
+/// Memory holding the scheduler's job table
+pub const SCHEDULER_MEMORY_ID: u8 = 1;
+
+/// Memory holding the execution lease
+pub const LEASE_MEMORY_ID: u8 = 2;
+
+/// Memory holding the cycles balance samples
+pub const CYCLES_MEMORY_ID: u8 = 3;
+
+/// Seconds between cycles balance samples (and refill checks, once a refill source is set)
+pub const CYCLES_CHECK_INTERVAL_SECS: u64 = 3_600;
+
+/// Stable cell holding the record of strategy `S`
+pub type RecordCell<S> = StableCell<StrategyRecord<<S as Strategy>::Config, <S as Strategy>::State>, StrategyMemory>;
+
+/// Thread-local slot of the record cell, as declared by `strategy_canister!`
+pub type RecordStore<S> = LocalKey<RefCell<RecordCell<S>>>;
+
+thread_local! {
+    // One memory manager per canister, shared by the framework and the strategy
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+}
+
+/// A trading strategy run by the shared lifecycle
+///
+/// The framework owns the owner check, status transitions, timers and stable
+/// storage; a strategy only validates its configuration and acts on each tick.
+#[async_trait(?Send)]
+pub trait Strategy: 'static {
+    /// Configuration supplied at initialization
+    type Config: CandidType + DeserializeOwned + Clone + std::fmt::Debug;
+    /// Strategy-specific state persisted between ticks
+    type State: CandidType + DeserializeOwned + Clone + Default;
+
+    /// Scheduler job ID of the execution job, also naming its execution lease
+    const TIMER_ID: &'static str;
+
+    /// Seconds a tick may run before its lease is considered abandoned
+    const LEASE_SECS: u64 = 300;
+
+    /// Cycles runway below which the strategy pauses itself rather than freeze mid-tick
+    const MIN_RUNWAY_SECS: u64 = 6 * 3_600;
+
+    /// Reject configurations the strategy cannot run with
+    fn validate(config: &Self::Config) -> Result<(), String>;
+
+    /// Seconds between ticks
+    fn interval_secs(config: &Self::Config) -> u64;
+
+    /// One execution; returns the state to persist
+    ///
+    /// On error the previous state is kept and the error is recorded on the strategy record.
+    async fn on_tick(config: Self::Config, state: Self::State) -> Result<Self::State, String>;
+
+    /// Preparation before the strategy starts running, e.g. token approvals
+    async fn on_start(_config: Self::Config, state: Self::State) -> Result<Self::State, String> {
+        Ok(state)
+    }
+}
+
+/// Persistent record of a strategy: the lifecycle fields plus the strategy's own state
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StrategyRecord<C, D> {
+    pub owner: Principal,
+    pub config: Option<C>,            // None until the strategy is initialized
+    pub status: StrategyStatus,
+    pub last_execution: Option<u64>,  // Nanoseconds since the epoch
+    pub execution_count: u64,
+    pub last_error: Option<String>,
+    pub state: D,
+}
+
+impl<C, D: Default> Default for StrategyRecord<C, D> {
+    fn default() -> Self {
+        Self {
+            owner: Principal::anonymous(),
+            config: None,
+            status: StrategyStatus::Created,
+            last_execution: None,
+            execution_count: 0,
+            last_error: None,
+            state: D::default(),
+        }
+    }
+}
+
+impl<C, D> Storable for StrategyRecord<C, D>
+where
+    C: CandidType + DeserializeOwned,
+    D: CandidType + DeserializeOwned,
+{
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode strategy record"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode strategy record")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Virtual memory `id` from the shared memory manager
+pub fn memory(id: u8) -> StrategyMemory {
+    MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(id)))
+}
+
+/// Open (or create) the record cell of strategy `S`
+pub fn init_record_cell<S: Strategy>() -> RecordCell<S> {
+    StableCell::init(memory(RECORD_MEMORY_ID), StrategyRecord::default()).expect("Failed to initialize strategy record")
+}
+
+/// Current record of strategy `S`
+pub fn record<S: Strategy>(store: &'static RecordStore<S>) -> StrategyRecord<S::Config, S::State> {
+    store.with(|cell| cell.borrow().get().clone())
+}
+
+/// Apply `update` to the stored record
+pub fn update_record<S: Strategy>(
+    store: &'static RecordStore<S>,
+    update: impl FnOnce(&mut StrategyRecord<S::Config, S::State>),
+) -> Result<(), String> {
+    store.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut record = cell.get().clone();
+        update(&mut record);
+        cell.set(record).map(|_| ()).map_err(|e| format!("Failed to save strategy record: {:?}", e))
+    })
+}
+
+/// Check that the caller is the owner or the canister itself
+pub fn verify_owner<S: Strategy>(store: &'static RecordStore<S>) -> Result<(), String> {
+    let runtime = canister_runtime();
+    let caller = runtime.caller();
+    let owner = store.with(|cell| cell.borrow().get().owner);
+    if caller != owner && caller != runtime.id() {
+        return Err("Caller is not the owner".to_string());
+    }
+    Ok(())
+}
+
+/// Check that the strategy is in one of the expected statuses
+pub fn verify_status<S: Strategy>(store: &'static RecordStore<S>, expected: &[StrategyStatus]) -> Result<(), String> {
+    let status = store.with(|cell| cell.borrow().get().status.clone());
+    if !expected.contains(&status) {
+        return Err(format!("Invalid strategy status: {:?}, expected one of: {:?}", status, expected));
+    }
+    Ok(())
+}
+
+fn to_result(result: Result<(), String>) -> StrategyResult {
+    match result {
+        Ok(()) => StrategyResult::Success,
+        Err(e) => StrategyResult::Error(e),
+    }
+}
+
+/// Store the owner and validated configuration of a fresh strategy
+pub fn initialize<S: Strategy>(store: &'static RecordStore<S>, owner: Principal, config: S::Config) -> StrategyResult {
+    if record::<S>(store).config.is_some() {
+        return StrategyResult::Error("Strategy already initialized".to_string());
+    }
+    if let Err(e) = S::validate(&config) {
+        return StrategyResult::Error(e);
+    }
+    debug_log!("Initializing strategy {} for owner {}", S::TIMER_ID, owner);
+    to_result(update_record::<S>(store, |record| {
+        record.owner = owner;
+        record.config = Some(config);
+        record.status = StrategyStatus::Created;
+    }))
+}
+
+/// Replace the configuration, rescheduling the job of a running strategy
+pub fn update_config<S: Strategy>(store: &'static RecordStore<S>, config: S::Config) -> StrategyResult {
+    if let Err(e) = verify_owner::<S>(store) {
+        return StrategyResult::Error(e);
+    }
+    if let Err(e) = S::validate(&config) {
+        return StrategyResult::Error(e);
+    }
+    if let Err(e) = update_record::<S>(store, |record| record.config = Some(config)) {
+        return StrategyResult::Error(e);
+    }
+    if record::<S>(store).status == StrategyStatus::Running {
+        if let Err(e) = schedule_ticks::<S>(store) {
+            return StrategyResult::Error(e);
+        }
+    }
+    StrategyResult::Success
+}
+
+/// Run `on_start`, schedule the execution job and mark the strategy running
+pub async fn start<S: Strategy>(store: &'static RecordStore<S>) -> StrategyResult {
+    if let Err(e) = verify_owner::<S>(store) {
+        return StrategyResult::Error(e);
+    }
+    if let Err(e) = verify_status::<S>(
+        store,
+        &[StrategyStatus::Created, StrategyStatus::Paused, StrategyStatus::PausedLowCycles, StrategyStatus::Terminated],
+    ) {
+        return StrategyResult::Error(e);
+    }
+    let current = record::<S>(store);
+    let config = match current.config {
+        Some(config) => config,
+        None => return StrategyResult::Error("Strategy not initialized".to_string()),
+    };
+    if let Err(e) = S::validate(&config) {
+        return StrategyResult::Error(e);
+    }
+
+    let state = match S::on_start(config, current.state).await {
+        Ok(state) => state,
+        Err(e) => return StrategyResult::Error(e),
+    };
+    if let Err(e) = schedule_ticks::<S>(store) {
+        return StrategyResult::Error(e);
+    }
+    if let Err(e) = update_record::<S>(store, |record| {
+        record.state = state;
+        record.status = StrategyStatus::Running;
+    }) {
+        return StrategyResult::Error(e);
+    }
+    debug_log!("Strategy {} started", S::TIMER_ID);
+    StrategyResult::Success
+}
+
+/// Cancel the execution job and mark the strategy paused
+pub fn pause<S: Strategy>(store: &'static RecordStore<S>) -> StrategyResult {
+    if let Err(e) = verify_owner::<S>(store) {
+        return StrategyResult::Error(e);
+    }
+    if let Err(e) = verify_status::<S>(store, &[StrategyStatus::Running]) {
+        return StrategyResult::Error(e);
+    }
+    open_scheduler::<S>(store);
+    if let Err(e) = scheduler::cancel(S::TIMER_ID) {
+        return StrategyResult::Error(e);
+    }
+    to_result(update_record::<S>(store, |record| record.status = StrategyStatus::Paused))
+}
+
+/// Cancel the execution job and mark the strategy terminated; allowed from any status
+pub fn stop<S: Strategy>(store: &'static RecordStore<S>) -> StrategyResult {
+    if let Err(e) = verify_owner::<S>(store) {
+        return StrategyResult::Error(e);
+    }
+    open_scheduler::<S>(store);
+    if let Err(e) = scheduler::cancel(S::TIMER_ID) {
+        return StrategyResult::Error(e);
+    }
+    to_result(update_record::<S>(store, |record| record.status = StrategyStatus::Terminated))
+}
+
+/// Pause a strategy whose canister is about to freeze; the owner restarts it after a top-up
+pub fn pause_low_cycles<S: Strategy>(store: &'static RecordStore<S>) -> StrategyResult {
+    open_scheduler::<S>(store);
+    let _ = scheduler::cancel(S::TIMER_ID);
+    let message = format!(
+        "Strategy paused: cycles balance {} is close to freezing (limit {}, runway {:?}s)",
+        cycles::get_balance(), cycles::freezing_limit(), cycles::runway_secs()
+    );
+    debug_log!("{}", message);
+    let _ = update_record::<S>(store, |record| {
+        record.status = StrategyStatus::PausedLowCycles;
+        record.last_error = Some(message.clone());
+    });
+    StrategyResult::Error(message)
+}
+
+/// Run one tick of a running strategy under its execution lease
+pub async fn execute_once<S: Strategy>(store: &'static RecordStore<S>) -> StrategyResult {
+    if let Err(e) = verify_status::<S>(store, &[StrategyStatus::Running]) {
+        return StrategyResult::Error(e);
+    }
+    if cycles::freeze_imminent(S::MIN_RUNWAY_SECS) {
+        return pause_low_cycles::<S>(store);
+    }
+    if !lease::is_initialized() {
+        lease::init(memory(LEASE_MEMORY_ID));
+    }
+    // Released when dropped; a lease left by a trap or an upgrade expires and is recovered
+    let execution_lease = match lease::acquire(S::TIMER_ID, S::LEASE_SECS) {
+        Ok(guard) => guard,
+        Err(_) => return StrategyResult::Error("Previous execution still in progress".to_string()),
+    };
+    if let Some(stale) = execution_lease.recovered() {
+        debug_log!("Strategy {} recovered an execution abandoned at {}", S::TIMER_ID, stale.acquired_at);
+    }
+
+    let current = record::<S>(store);
+    let config = match current.config {
+        Some(config) => config,
+        None => return StrategyResult::Error("Strategy not initialized".to_string()),
+    };
+    let outcome = S::on_tick(config, current.state).await;
+    let now = canister_runtime().time();
+    let error = outcome.as_ref().err().cloned();
+    let saved = update_record::<S>(store, |record| {
+        record.last_execution = Some(now);
+        record.execution_count += 1;
+        record.last_error = error;
+        if let Ok(state) = outcome {
+            record.state = state;
+        }
+    });
+    match (record::<S>(store).last_error, saved) {
+        (Some(e), _) | (None, Err(e)) => {
+            debug_log!("Strategy {} tick failed: {}", S::TIMER_ID, e);
+            StrategyResult::Error(e)
+        }
+        (None, Ok(())) => StrategyResult::Success,
+    }
+}
+
+/// Open the scheduler and register the execution job's handler, once per canister instance
+///
+/// Also opens the cycles samples and keeps the balance sampling job scheduled, whatever
+/// the strategy's status.
+pub fn open_scheduler<S: Strategy>(store: &'static RecordStore<S>) {
+    if !scheduler::is_initialized() {
+        scheduler::init(memory(SCHEDULER_MEMORY_ID));
+    }
+    if !cycles::samples_opened() {
+        cycles::open_samples(memory(CYCLES_MEMORY_ID));
+    }
+    if let Err(e) = cycles::schedule_refill_checks(CYCLES_CHECK_INTERVAL_SECS) {
+        debug_log!("Failed to schedule cycles sampling: {}", e);
+    }
+    scheduler::register_handler(S::TIMER_ID, move || async move {
+        match execute_once::<S>(store).await {
+            StrategyResult::Success => Ok(()),
+            StrategyResult::Error(e) => Err(e),
+        }
+    });
+}
+
+/// Schedule the periodic execution job from the stored configuration
+///
+/// A tick missed across an upgrade runs once as soon as the canister is back.
+pub fn schedule_ticks<S: Strategy>(store: &'static RecordStore<S>) -> Result<(), String> {
+    let interval_seconds = match record::<S>(store).config {
+        Some(config) => S::interval_secs(&config),
+        None => return Err("Strategy not initialized".to_string()),
+    };
+    open_scheduler::<S>(store);
+    scheduler::schedule(
+        S::TIMER_ID,
+        Schedule::Interval { seconds: interval_seconds },
+        MissedRunPolicy::RunOnce,
+        interval_seconds,
+    )
+    .map(|_| ())
+}
+
+/// Open the framework's storage and start cycles sampling on a fresh install
+pub fn init<S: Strategy>(store: &'static RecordStore<S>) {
+    open_scheduler::<S>(store);
+    scheduler::resume();
+}
+
+/// Re-register the execution handler and resume the persisted job of a running strategy
+pub fn post_upgrade<S: Strategy>(store: &'static RecordStore<S>) {
+    open_scheduler::<S>(store);
+    if record::<S>(store).status != StrategyStatus::Running {
+        // A job left behind by a status change that did not complete
+        let _ = scheduler::cancel(S::TIMER_ID);
+    } else if scheduler::get_job(S::TIMER_ID).is_none() {
+        // Upgraded from a build that kept its timer on the heap
+        if let Err(e) = schedule_ticks::<S>(store) {
+            debug_log!("Failed to reschedule strategy {}: {}", S::TIMER_ID, e);
+        }
+    }
+    scheduler::resume();
+}
+
+/// Declare the record storage and standard lifecycle endpoints of a strategy canister
+///
+/// Generates `<init_method>(owner, config)`, `start`, `pause`, `stop`, `execute_once`,
+/// `update_strategy_config`, `get_status`, `get_cycles_summary`, `get_record` and the
+/// `init` and `post_upgrade` hooks.
+/// The strategy crate must depend on `ic-cdk` and `candid`, which the generated
+/// endpoints name directly. `src/strategies/example` is a complete strategy canister.
+///
+/// ```ignore
+/// strategy_common::strategy_canister!(ExampleStrategy, init_example);
+/// ```
+#[macro_export]
+macro_rules! strategy_canister {
+    ($strategy:ty, $init_method:ident) => {
+        thread_local! {
+            static STRATEGY_RECORD: ::std::cell::RefCell<$crate::strategy::RecordCell<$strategy>> =
+                ::std::cell::RefCell::new($crate::strategy::init_record_cell::<$strategy>());
+        }
+
+        #[ic_cdk::update]
+        fn $init_method(
+            owner: ::candid::Principal,
+            config: <$strategy as $crate::strategy::Strategy>::Config,
+        ) -> $crate::types::StrategyResult {
+            $crate::strategy::initialize::<$strategy>(&STRATEGY_RECORD, owner, config)
+        }
+
+        #[ic_cdk::update]
+        async fn start() -> $crate::types::StrategyResult {
+            $crate::strategy::start::<$strategy>(&STRATEGY_RECORD).await
+        }
+
+        #[ic_cdk::update]
+        fn pause() -> $crate::types::StrategyResult {
+            $crate::strategy::pause::<$strategy>(&STRATEGY_RECORD)
+        }
+
+        #[ic_cdk::update]
+        fn stop() -> $crate::types::StrategyResult {
+            $crate::strategy::stop::<$strategy>(&STRATEGY_RECORD)
+        }
+
+        #[ic_cdk::update]
+        async fn execute_once() -> $crate::types::StrategyResult {
+            if let Err(e) = $crate::strategy::verify_owner::<$strategy>(&STRATEGY_RECORD) {
+                return $crate::types::StrategyResult::Error(e);
+            }
+            $crate::strategy::execute_once::<$strategy>(&STRATEGY_RECORD).await
+        }
+
+        #[ic_cdk::update]
+        fn update_strategy_config(
+            config: <$strategy as $crate::strategy::Strategy>::Config,
+        ) -> $crate::types::StrategyResult {
+            $crate::strategy::update_config::<$strategy>(&STRATEGY_RECORD, config)
+        }
+
+        #[ic_cdk::query]
+        fn get_status() -> $crate::types::StrategyStatus {
+            $crate::strategy::record::<$strategy>(&STRATEGY_RECORD).status
+        }
+
+        #[ic_cdk::query]
+        fn get_cycles_summary() -> Result<$crate::cycles::CyclesSummary, String> {
+            $crate::cycles::get_summary()
+        }
+
+        #[ic_cdk::query]
+        fn get_record() -> $crate::strategy::StrategyRecord<
+            <$strategy as $crate::strategy::Strategy>::Config,
+            <$strategy as $crate::strategy::Strategy>::State,
+        > {
+            $crate::strategy::record::<$strategy>(&STRATEGY_RECORD)
+        }
+
+        #[ic_cdk::init]
+        fn init() {
+            $crate::strategy::init::<$strategy>(&STRATEGY_RECORD);
+        }
+
+        #[ic_cdk::post_upgrade]
+        fn post_upgrade() {
+            $crate::strategy::post_upgrade::<$strategy>(&STRATEGY_RECORD);
+        }
+    };
+}
@@ -32,6 +32,9 @@ pub fn clear_timer(timer_id: &str) {
 }
 
 /// Set up a periodic timer with the given interval and callback
+///
+/// The timer lives on the heap and is lost on upgrade; jobs that must keep running
+/// across upgrades belong in `scheduler`.
 pub fn set_timer<F>(config: TimerConfig, callback: F) 
 where
     F: FnMut() + 'static,
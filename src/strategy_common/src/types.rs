@@ -21,6 +21,7 @@ pub enum StrategyStatus {
     Paused,
     EmergencyStopped,
     Terminated,
+    PausedLowCycles,  // Paused automatically before the canister ran out of cycles
 }
 
 /// Defines the supported DEXes
@@ -31,6 +32,7 @@ pub enum Exchange {
     Sonic,
     InfinitySwap,
     ICDex,
+    Simulated,  // Paper trading against the canister's in-memory pools
 }
 
 /// Defines token metadata